@@ -0,0 +1,652 @@
+use anyhow::{bail, Result};
+use libp2p::identity::PublicKey;
+use libp2p::request_response::{self, json, ProtocolSupport};
+use libp2p::StreamProtocol;
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use std::collections::{BTreeMap, HashMap};
+
+use crate::crypto::{self, SignedData};
+use crate::storage::{Entry, Value};
+use crate::whitelist::Revocation;
+
+/// Protocol name used for state reconciliation between peers. 1.1.0 carries
+/// binary and chunked values in `Entry`, 1.2.0 reconciles one namespace per
+/// request, 1.3.0 carries the author's signature of each entry, 1.4.0 hashes
+/// signatures and pages responses by bucket, 1.5.0 relays revocations.
+pub const PROTOCOL: &str = "/p2p-sync/reconcile/1.5.0";
+
+/// Number of leaves in the digest tree. Keys are assigned to a leaf by the
+/// first byte of their SHA-256 hash.
+pub const BUCKET_COUNT: usize = 256;
+
+/// Reconciliation payloads can carry many entries, so allow more than the
+/// codec's 1MB request default.
+const MAX_PAYLOAD_SIZE: u64 = 16 * 1024 * 1024;
+
+/// Size of the entries sent in one message. Entries that do not fit are
+/// requested again from `next_bucket` and `next_key`, so namespaces and
+/// buckets of any size reconcile.
+pub const PAGE_SIZE: usize = 4 * 1024 * 1024;
+
+pub type Hash = [u8; 32];
+
+pub type Behaviour = json::Behaviour<ReconcileRequest, ReconcileResponse>;
+
+pub type Event = request_response::Event<ReconcileRequest, ReconcileResponse>;
+
+/// Requests exchanged by the reconciliation protocol
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub enum ReconcileRequest {
+    /// Digest of the requester's entries in a namespace, sent when a
+    /// connection is established
+    Digests {
+        namespace: String,
+        digest: StoreDigest,
+        /// Only reconcile buckets from this index on, to continue a paged
+        /// reconciliation
+        #[serde(default)]
+        from_bucket: u16,
+        /// Only reconcile keys of `from_bucket` after this one, to continue
+        /// within a bucket that did not fit in one page
+        #[serde(default)]
+        after_key: Option<String>,
+    },
+    /// Entries the requester holds in buckets that differed, sent after
+    /// merging the responder's entries
+    Entries {
+        namespace: String,
+        entries: Vec<Entry>,
+    },
+    /// Revocations signed by admin peers, sent to a peer when it subscribes
+    /// so it learns of those issued while it was offline
+    Revocations {
+        revocations: Vec<SignedData<Revocation>>,
+    },
+}
+
+/// Responses of the reconciliation protocol
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub enum ReconcileResponse {
+    /// Both stores have the same root digest
+    InSync,
+    /// The responder's entries for the buckets whose digest differed, as
+    /// many as fit in `PAGE_SIZE`
+    Entries {
+        namespace: String,
+        buckets: Vec<u16>,
+        entries: Vec<Entry>,
+        /// `after_key` of the request: keys of its bucket up to this one are
+        /// not part of the page
+        #[serde(default)]
+        after_key: Option<String>,
+        /// Bucket the next page starts at
+        #[serde(default)]
+        next_bucket: Option<u16>,
+        /// Last key sent of `next_bucket` when it was split, keys after it
+        /// are not part of the page
+        #[serde(default)]
+        next_key: Option<String>,
+    },
+    /// Pushed entries or revocations were received
+    Ack,
+    /// The requester may not read the namespace, or the responder does not
+    /// take part in it
+    Denied,
+}
+
+/// Two-level Merkle tree over the key space: one hash per bucket and a root
+/// hash over all buckets
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct StoreDigest {
+    pub root: Hash,
+    pub buckets: Vec<Hash>,
+}
+
+impl StoreDigest {
+    pub fn from_entries(entries: &[Entry]) -> Self {
+        let mut leaves: Vec<Vec<Hash>> = vec![Vec::new(); BUCKET_COUNT];
+        for entry in entries {
+            leaves[bucket_of(&entry.key) as usize].push(entry_hash(entry));
+        }
+
+        let buckets: Vec<Hash> = leaves
+            .into_iter()
+            .map(|mut hashes| {
+                // 到着順に依存しないようにソートしてからハッシュする
+                hashes.sort_unstable();
+                let mut hasher = Sha256::new();
+                for hash in hashes {
+                    hasher.update(hash);
+                }
+                hasher.finalize().into()
+            })
+            .collect();
+
+        let mut hasher = Sha256::new();
+        for bucket in &buckets {
+            hasher.update(bucket);
+        }
+
+        Self {
+            root: hasher.finalize().into(),
+            buckets,
+        }
+    }
+
+    /// Indices of the buckets whose hashes differ from `other`
+    pub fn differing_buckets(&self, other: &StoreDigest) -> Vec<u16> {
+        if self.root == other.root {
+            return Vec::new();
+        }
+
+        // 形式の異なるダイジェストは全バケットを対象にする
+        if self.buckets.len() != other.buckets.len() {
+            return (0..BUCKET_COUNT as u16).collect();
+        }
+
+        self.buckets
+            .iter()
+            .zip(&other.buckets)
+            .enumerate()
+            .filter(|(_, (a, b))| a != b)
+            .map(|(index, _)| index as u16)
+            .collect()
+    }
+}
+
+pub fn new_behaviour() -> Behaviour {
+    let codec = json::codec::Codec::default()
+        .set_request_size_maximum(MAX_PAYLOAD_SIZE)
+        .set_response_size_maximum(MAX_PAYLOAD_SIZE);
+
+    request_response::Behaviour::with_codec(
+        codec,
+        [(StreamProtocol::new(PROTOCOL), ProtocolSupport::Full)],
+        request_response::Config::default(),
+    )
+}
+
+pub fn bucket_of(key: &str) -> u16 {
+    Sha256::digest(key.as_bytes())[0] as u16
+}
+
+fn entry_hash(entry: &Entry) -> Hash {
+    let mut hasher = Sha256::new();
+    hasher.update(entry.key.as_bytes());
+    hasher.update([0]);
+    hasher.update(entry.timestamp.wall_ms.to_be_bytes());
+    hasher.update(entry.timestamp.logical.to_be_bytes());
+    hasher.update(entry.timestamp.origin.as_bytes());
+    hasher.update([0]);
+    match &entry.value {
+        Some(Value::Text(text)) => {
+            hasher.update([1]);
+            hasher.update(text.as_bytes());
+        }
+        Some(Value::Bytes(data)) => {
+            hasher.update([2]);
+            hasher.update(data);
+        }
+        Some(Value::Chunked(manifest)) => {
+            hasher.update([3]);
+            hasher.update(manifest.size.to_be_bytes());
+            for hash in &manifest.chunks {
+                hasher.update(hash.as_bytes());
+            }
+        }
+        None => hasher.update([0]),
+    }
+    // 署名のない・不正な署名の複製も違いとして検出し、突き合わせで置き換える
+    match &entry.signature {
+        Some(signature) => {
+            hasher.update([1]);
+            hasher.update(signature);
+        }
+        None => hasher.update([0]),
+    }
+    hasher.finalize().into()
+}
+
+/// Entries that fall into one of `buckets`
+pub fn entries_in_buckets(entries: Vec<Entry>, buckets: &[u16]) -> Vec<Entry> {
+    entries
+        .into_iter()
+        .filter(|entry| buckets.contains(&bucket_of(&entry.key)))
+        .collect()
+}
+
+/// Entries sent in one response
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Page {
+    /// Buckets the page covers, the last one only up to `next_key` if set
+    pub buckets: Vec<u16>,
+    pub entries: Vec<Entry>,
+    pub next_bucket: Option<u16>,
+    pub next_key: Option<String>,
+}
+
+/// Entries of `buckets`, in bucket and key order and skipping keys up to
+/// `after_key` in its bucket, as far as they fit in `budget` bytes. A bucket
+/// that does not fit is split between keys, only a single entry larger than
+/// `budget` is sent on its own.
+pub fn page(entries: Vec<Entry>, buckets: &[u16], after_key: Option<&str>, budget: usize) -> Page {
+    let mut by_bucket: BTreeMap<u16, Vec<Entry>> =
+        buckets.iter().map(|bucket| (*bucket, Vec::new())).collect();
+    for entry in entries {
+        if !is_after(&entry.key, after_key) {
+            continue;
+        }
+        if let Some(bucket) = by_bucket.get_mut(&bucket_of(&entry.key)) {
+            bucket.push(entry);
+        }
+    }
+
+    let mut page = Page {
+        buckets: Vec::new(),
+        entries: Vec::new(),
+        next_bucket: None,
+        next_key: None,
+    };
+    let mut size = 0;
+    'buckets: for (bucket, mut entries) in by_bucket {
+        entries.sort_by(|a, b| a.key.cmp(&b.key));
+        page.buckets.push(bucket);
+        for (index, entry) in entries.into_iter().enumerate() {
+            let entry_size = entry_size(&entry);
+            if !page.entries.is_empty() && size + entry_size > budget {
+                page.next_bucket = Some(bucket);
+                if index == 0 {
+                    page.buckets.pop();
+                } else {
+                    // バケットの途中で切れたら、送った最後のキーの次から続ける
+                    page.next_key = page.entries.last().map(|last| last.key.clone());
+                }
+                break 'buckets;
+            }
+            size += entry_size;
+            page.entries.push(entry);
+        }
+    }
+
+    page
+}
+
+// after_key と同じバケットでは、それより後のキーだけがページに入る
+fn is_after(key: &str, after_key: Option<&str>) -> bool {
+    match after_key {
+        Some(after) => bucket_of(after) != bucket_of(key) || key > after,
+        None => true,
+    }
+}
+
+// through_key と同じバケットでは、それまでのキーだけがページに入る
+fn is_through(key: &str, through_key: Option<&str>) -> bool {
+    match through_key {
+        Some(through) => bucket_of(through) != bucket_of(key) || key <= through,
+        None => true,
+    }
+}
+
+/// Split entries to push into messages of about `budget` bytes each
+pub fn split(entries: Vec<Entry>, budget: usize) -> Vec<Vec<Entry>> {
+    let mut batches = Vec::new();
+    let mut batch = Vec::new();
+    let mut size = 0;
+    for entry in entries {
+        let entry_size = entry_size(&entry);
+        if !batch.is_empty() && size + entry_size > budget {
+            batches.push(std::mem::take(&mut batch));
+            size = 0;
+        }
+        size += entry_size;
+        batch.push(entry);
+    }
+    if !batch.is_empty() {
+        batches.push(batch);
+    }
+
+    batches
+}
+
+// JSON にしたときの大きさ
+fn entry_size(entry: &Entry) -> usize {
+    serde_json::to_vec(entry).map_or(0, |json| json.len())
+}
+
+/// Local entries from `buckets` that the remote side does not already hold
+/// in identical form. `after_key` and `through_key` limit them to the keys a
+/// page of the remote side's entries covered.
+pub fn entries_to_push(
+    local: Vec<Entry>,
+    remote: &[Entry],
+    buckets: &[u16],
+    after_key: Option<&str>,
+    through_key: Option<&str>,
+) -> Vec<Entry> {
+    let remote: HashMap<&str, &Entry> = remote
+        .iter()
+        .map(|entry| (entry.key.as_str(), entry))
+        .collect();
+
+    entries_in_buckets(local, buckets)
+        .into_iter()
+        .filter(|entry| is_after(&entry.key, after_key) && is_through(&entry.key, through_key))
+        .filter(|entry| remote.get(entry.key.as_str()) != Some(&entry))
+        .collect()
+}
+
+/// Check that an entry of `namespace` received from a peer was signed by the
+/// origin in its timestamp, whose key is `public_key`. The serving peer may
+/// not be the author. Without a key the entry is only accepted when
+/// `strict_signatures` is off, as for gossip from such peers.
+pub fn verify_author(
+    namespace: &str,
+    entry: &Entry,
+    public_key: Option<&PublicKey>,
+    strict_signatures: bool,
+) -> Result<()> {
+    match public_key {
+        Some(public_key) if !crypto::verify_entry(public_key, namespace, entry)? => {
+            bail!("not signed by its origin {}", entry.timestamp.origin)
+        }
+        None if strict_signatures => bail!(
+            "no public key to verify its origin {}",
+            entry.timestamp.origin
+        ),
+        _ => {}
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::hlc::HlcTimestamp;
+    use crate::namespace::{NamespaceConfig, Namespaces};
+    use libp2p::identity::Keypair;
+    use std::collections::BTreeMap;
+
+    fn entry(key: &str, value: &str, wall_ms: u64) -> Entry {
+        Entry {
+            key: key.to_string(),
+            value: Some(Value::Text(value.to_string())),
+            timestamp: HlcTimestamp::new(wall_ms, 0, "peer"),
+            signature: None,
+        }
+    }
+
+    #[test]
+    fn test_identical_stores_have_same_root() {
+        let a = vec![entry("k1", "v1", 1), entry("k2", "v2", 2)];
+        let b = vec![entry("k2", "v2", 2), entry("k1", "v1", 1)];
+
+        let da = StoreDigest::from_entries(&a);
+        let db = StoreDigest::from_entries(&b);
+
+        assert_eq!(da.root, db.root);
+        assert!(da.differing_buckets(&db).is_empty());
+    }
+
+    #[test]
+    fn test_differing_bucket_is_detected() {
+        let a = vec![entry("k1", "v1", 1), entry("k2", "v2", 2)];
+        let b = vec![entry("k1", "v1", 1), entry("k2", "changed", 3)];
+
+        let da = StoreDigest::from_entries(&a);
+        let db = StoreDigest::from_entries(&b);
+
+        assert_eq!(da.differing_buckets(&db), vec![bucket_of("k2")]);
+    }
+
+    #[test]
+    fn test_entries_to_push_skips_identical_entries() {
+        let local = vec![entry("k1", "v1", 1), entry("k2", "v2", 5)];
+        let remote = vec![entry("k1", "v1", 1), entry("k2", "old", 2)];
+        let buckets = vec![bucket_of("k1"), bucket_of("k2")];
+
+        let push = entries_to_push(local, &remote, &buckets, None, None);
+        assert_eq!(push, vec![entry("k2", "v2", 5)]);
+    }
+
+    #[test]
+    fn test_text_and_bytes_hash_differently() {
+        let text = entry("k", "v", 1);
+        let bytes = Entry {
+            value: Some(Value::Bytes(b"v".to_vec())),
+            ..text.clone()
+        };
+
+        assert_ne!(entry_hash(&text), entry_hash(&bytes));
+    }
+
+    #[test]
+    fn test_signature_is_part_of_the_digest() {
+        let unsigned = entry("k", "v", 1);
+        let signed = Entry {
+            signature: Some(vec![1; 64]),
+            ..unsigned.clone()
+        };
+        let forged = Entry {
+            signature: Some(vec![2; 64]),
+            ..unsigned.clone()
+        };
+
+        let digest = StoreDigest::from_entries(std::slice::from_ref(&signed));
+        for copy in [unsigned, forged] {
+            assert_eq!(
+                digest.differing_buckets(&StoreDigest::from_entries(&[copy])),
+                vec![bucket_of("k")]
+            );
+        }
+        assert!(digest
+            .differing_buckets(&StoreDigest::from_entries(&[signed]))
+            .is_empty());
+    }
+
+    struct PagedReconciliation {
+        received: Vec<Entry>,
+        pushed: Vec<Entry>,
+        pages: usize,
+    }
+
+    // 応答側 local と要求側 remote の突き合わせを、続きの位置をたどって最後のページまで進める
+    fn page_through(local: &[Entry], remote: &[Entry], budget: usize) -> PagedReconciliation {
+        let digest = StoreDigest::from_entries(local);
+        let remote_digest = StoreDigest::from_entries(remote);
+        let mut from_bucket = 0;
+        let mut after_key: Option<String> = None;
+        let mut received = Vec::new();
+        let mut pushed = Vec::new();
+        let mut pages = 0;
+        loop {
+            let buckets: Vec<u16> = digest
+                .differing_buckets(&remote_digest)
+                .into_iter()
+                .filter(|bucket| *bucket >= from_bucket)
+                .collect();
+            if buckets.is_empty() {
+                break;
+            }
+
+            let page = page(local.to_vec(), &buckets, after_key.as_deref(), budget);
+            let size: usize = page.entries.iter().map(entry_size).sum();
+            assert!(size <= budget || page.entries.len() == 1);
+            assert!(page
+                .entries
+                .iter()
+                .all(|entry| page.buckets.contains(&bucket_of(&entry.key))));
+            pushed.extend(entries_to_push(
+                remote.to_vec(),
+                &page.entries,
+                &page.buckets,
+                after_key.as_deref(),
+                page.next_key.as_deref(),
+            ));
+            received.extend(page.entries);
+            pages += 1;
+
+            match page.next_bucket {
+                Some(next_bucket) => {
+                    from_bucket = next_bucket;
+                    after_key = page.next_key;
+                }
+                None => break,
+            }
+        }
+
+        PagedReconciliation {
+            received,
+            pushed,
+            pages,
+        }
+    }
+
+    fn sorted(mut entries: Vec<Entry>) -> Vec<Entry> {
+        entries.sort_by(|a, b| a.key.cmp(&b.key));
+        entries
+    }
+
+    #[test]
+    fn test_large_namespaces_are_paged() {
+        let value = "x".repeat(1000);
+        let entries: Vec<Entry> = (0..2000)
+            .map(|i| entry(&format!("key{i}"), &value, i))
+            .collect();
+        let total: usize = entries.iter().map(entry_size).sum();
+        let budget = 64 * 1024;
+        assert!(total > 10 * budget);
+
+        // 相手が何も持っていなければ全バケットが異なる
+        let PagedReconciliation {
+            received,
+            pushed,
+            pages,
+        } = page_through(&entries, &[], budget);
+        assert!(pages > 10);
+        assert!(pushed.is_empty());
+        assert_eq!(sorted(received), sorted(entries.clone()));
+
+        let batches = split(entries.clone(), budget);
+        assert!(batches.len() > 10);
+        assert!(batches
+            .iter()
+            .all(|batch| batch.iter().map(entry_size).sum::<usize>() <= budget));
+        assert_eq!(batches.concat(), entries);
+    }
+
+    #[test]
+    fn test_bucket_larger_than_a_page_is_split() {
+        let value = "x".repeat(1000);
+        let bucket = bucket_of("key0");
+        let keys: Vec<String> = (0..)
+            .map(|i| format!("key{i}"))
+            .filter(|key| bucket_of(key) == bucket)
+            .take(300)
+            .collect();
+        let budget = 32 * 1024;
+
+        // 1 つのバケットだけで予算の何倍もある
+        let local: Vec<Entry> = keys[..200]
+            .iter()
+            .map(|key| entry(key, &value, 2))
+            .collect();
+        let total: usize = local.iter().map(entry_size).sum();
+        assert!(total > 4 * budget);
+
+        // 相手は一部を古い値で持ち、こちらにないキーも持っている
+        let remote: Vec<Entry> = keys[150..].iter().map(|key| entry(key, "old", 1)).collect();
+
+        let PagedReconciliation {
+            received,
+            pushed,
+            pages,
+        } = page_through(&local, &remote, budget);
+        assert!(pages > 4);
+        assert_eq!(sorted(received), sorted(local.clone()));
+        // 相手にしかない・相手の方が違う値のものは、ページをまたいでもちょうど 1 回ずつ送り返す
+        assert_eq!(
+            sorted(pushed),
+            sorted(entries_to_push(remote, &local, &[bucket], None, None))
+        );
+    }
+
+    #[test]
+    fn test_request_serialization() {
+        let digest = StoreDigest::from_entries(&[entry("k", "v", 1)]);
+        let request = ReconcileRequest::Digests {
+            namespace: "config".to_string(),
+            digest: digest.clone(),
+            from_bucket: 0,
+            after_key: None,
+        };
+
+        let json = serde_json::to_vec(&request).unwrap();
+        match serde_json::from_slice(&json).unwrap() {
+            ReconcileRequest::Digests {
+                namespace,
+                digest: decoded,
+                ..
+            } => {
+                assert_eq!(namespace, "config");
+                assert_eq!(decoded, digest);
+            }
+            _ => panic!("Expected Digests request"),
+        }
+    }
+
+    #[test]
+    fn test_entries_must_be_signed_by_their_origin() {
+        let a = Keypair::generate_ed25519();
+        let b = Keypair::generate_ed25519();
+        let origin = a.public().to_peer_id().to_string();
+
+        let mut configs = BTreeMap::new();
+        configs.insert(
+            "config".to_string(),
+            NamespaceConfig {
+                writers: Some([origin.clone()].into()),
+                ..Default::default()
+            },
+        );
+        let namespaces = Namespaces::new(&configs).unwrap();
+        assert!(!namespaces.can_write("config", &b.public().to_peer_id()));
+
+        // B は書き込めないネームスペースに A を origin とするエントリを作って配る
+        let mut forged = Entry {
+            key: "k".to_string(),
+            value: Some(Value::Text("forged".to_string())),
+            timestamp: HlcTimestamp::new(1, 0, origin.clone()),
+            signature: None,
+        };
+        assert!(namespaces.can_write_origin("config", &forged.timestamp.origin));
+        assert!(verify_author("config", &forged, Some(&a.public()), true).is_err());
+        forged.signature = Some(crypto::sign_entry(&b, "config", &forged).unwrap());
+        assert!(verify_author("config", &forged, Some(&a.public()), true).is_err());
+
+        // A が署名したエントリは B から受け取っても適用できる
+        let mut entry = Entry {
+            value: Some(Value::Text("v".to_string())),
+            ..forged
+        };
+        entry.signature = Some(crypto::sign_entry(&a, "config", &entry).unwrap());
+        let response = ReconcileResponse::Entries {
+            namespace: "config".to_string(),
+            buckets: vec![bucket_of("k")],
+            entries: vec![entry],
+            after_key: None,
+            next_bucket: None,
+            next_key: None,
+        };
+        let entries = match serde_json::from_slice(&serde_json::to_vec(&response).unwrap()) {
+            Ok(ReconcileResponse::Entries { entries, .. }) => entries,
+            _ => panic!("Expected Entries response"),
+        };
+        assert!(verify_author("config", &entries[0], Some(&a.public()), true).is_ok());
+
+        // 鍵が分からない場合は strict_signatures が無効なときのみ受け付ける
+        assert!(verify_author("config", &entries[0], None, true).is_err());
+        assert!(verify_author("config", &entries[0], None, false).is_ok());
+    }
+}
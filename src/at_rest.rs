@@ -0,0 +1,662 @@
+use anyhow::{anyhow, bail, Context, Result};
+use argon2::Argon2;
+use chacha20poly1305::aead::rand_core::RngCore;
+use chacha20poly1305::aead::{Aead, AeadCore, KeyInit, OsRng, Payload};
+use chacha20poly1305::{ChaCha20Poly1305, Key, Nonce};
+use hkdf::Hkdf;
+use rusqlite::types::Value as SqlValue;
+use rusqlite::{params, Connection, OptionalExtension};
+use sha2::Sha256;
+use std::fs;
+use std::path::{Path, PathBuf};
+use tracing::info;
+
+/// File name of the at-rest key inside the data directory
+pub const KEY_FILE: &str = "storage.key";
+
+/// Environment variable holding the passphrase. When set it is used instead
+/// of the key file.
+pub const PASSPHRASE_ENV: &str = "P2P_SYNC_PASSPHRASE";
+
+/// Environment variable holding the passphrase `storage rekey` switches to
+pub const NEW_PASSPHRASE_ENV: &str = "P2P_SYNC_NEW_PASSPHRASE";
+
+/// Written by `storage rekey` once re-encrypted copies of every database
+/// exist: the new key, or empty when switching to a passphrase or decrypting
+pub const PENDING_KEY_FILE: &str = "storage.key.new";
+
+const KEY_LENGTH: usize = 32;
+const NONCE_LENGTH: usize = 12;
+const SALT_LENGTH: usize = 16;
+
+// at_rest.kdf
+const KDF_PASSPHRASE: &str = "argon2id";
+const KDF_KEY_FILE: &str = "key-file";
+
+const KEY_FILE_INFO: &[u8] = b"p2p-sync at-rest v1";
+const CHECK_AAD: &[u8] = b"p2p-sync at-rest check";
+const CHECK_VALUE: &[u8] = b"p2p-sync";
+
+// 暗号化前に付ける SQLite の型
+const TYPE_TEXT: u8 = 0;
+const TYPE_BLOB: u8 = 1;
+
+/// Secret the database keys are derived from. Each database has its own
+/// salt, so sync.db and whitelist.db are encrypted with different keys.
+#[derive(Clone)]
+pub enum Secret {
+    Passphrase(String),
+    /// Contents of the key file
+    Key([u8; KEY_LENGTH]),
+}
+
+/// The secret itself is never printed
+impl std::fmt::Debug for Secret {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(match self {
+            Secret::Passphrase(_) => "Secret::Passphrase",
+            Secret::Key(_) => "Secret::Key",
+        })
+    }
+}
+
+impl Secret {
+    pub fn generate() -> Self {
+        let mut key = [0u8; KEY_LENGTH];
+        OsRng.fill_bytes(&mut key);
+        Secret::Key(key)
+    }
+
+    fn kdf(&self) -> &'static str {
+        match self {
+            Secret::Passphrase(_) => KDF_PASSPHRASE,
+            Secret::Key(_) => KDF_KEY_FILE,
+        }
+    }
+
+    fn derive(&self, salt: &[u8]) -> Result<ChaCha20Poly1305> {
+        let mut key = [0u8; KEY_LENGTH];
+        match self {
+            Secret::Passphrase(passphrase) => Argon2::default()
+                .hash_password_into(passphrase.as_bytes(), salt, &mut key)
+                .map_err(|e| anyhow!("Failed to derive key from passphrase: {e}"))?,
+            Secret::Key(secret) => Hkdf::<Sha256>::new(Some(salt), secret)
+                .expand(KEY_FILE_INFO, &mut key)
+                .map_err(|_| anyhow!("Failed to derive key from key file"))?,
+        }
+        Ok(ChaCha20Poly1305::new(Key::from_slice(&key)))
+    }
+}
+
+pub fn key_path(data_dir: &Path) -> PathBuf {
+    data_dir.join(KEY_FILE)
+}
+
+/// The passphrase in `P2P_SYNC_PASSPHRASE`, or else the key file of
+/// `data_dir`, which is created on first use
+pub fn load_or_create_secret(data_dir: &Path) -> Result<Secret> {
+    if let Some(secret) = existing_secret(data_dir)? {
+        return Ok(secret);
+    }
+
+    let secret = Secret::generate();
+    save_key(&key_path(data_dir), &secret)?;
+    info!(
+        "Created at-rest key at {}, keep a copy of it: the data cannot be read without it",
+        key_path(data_dir).display()
+    );
+    Ok(secret)
+}
+
+/// Like `load_or_create_secret` but never creates a key file
+pub fn existing_secret(data_dir: &Path) -> Result<Option<Secret>> {
+    if let Some(passphrase) = passphrase_from_env(PASSPHRASE_ENV)? {
+        return Ok(Some(passphrase));
+    }
+
+    let path = key_path(data_dir);
+    if !path.exists() {
+        return Ok(None);
+    }
+    let bytes =
+        fs::read(&path).with_context(|| format!("Failed to read key file {}", path.display()))?;
+    let key = bytes
+        .try_into()
+        .map_err(|_| anyhow!("Key file {} must be {KEY_LENGTH} bytes", path.display()))?;
+    Ok(Some(Secret::Key(key)))
+}
+
+pub fn passphrase_from_env(var: &str) -> Result<Option<Secret>> {
+    match std::env::var(var) {
+        Ok(passphrase) if passphrase.is_empty() => bail!("{var} is set but empty"),
+        Ok(passphrase) => Ok(Some(Secret::Passphrase(passphrase))),
+        Err(_) => Ok(None),
+    }
+}
+
+/// Write a key file readable only by the owner
+pub fn save_key(path: &Path, secret: &Secret) -> Result<()> {
+    let Secret::Key(key) = secret else {
+        bail!("Only generated keys are written to a key file");
+    };
+    crate::identity::write_private(path, key)
+}
+
+pub fn pending_key_path(data_dir: &Path) -> PathBuf {
+    data_dir.join(PENDING_KEY_FILE)
+}
+
+/// Where `storage rekey` writes the re-encrypted copy of `database`
+pub fn rekey_copy_path(database: &Path) -> PathBuf {
+    database.with_extension("db.rekey")
+}
+
+/// Mark the re-encrypted copies of a `storage rekey` as complete. From here
+/// on `finish_rekey` moves them into place, even after a crash.
+pub fn save_pending_key(data_dir: &Path, new: Option<&Secret>) -> Result<()> {
+    let key: &[u8] = match new {
+        Some(Secret::Key(key)) => key,
+        _ => &[],
+    };
+    crate::identity::write_private(&pending_key_path(data_dir), key)?;
+    sync_dir(data_dir)
+}
+
+/// Complete a `storage rekey` whose copies were marked complete: replace
+/// `databases` with their copies and switch to the new key file, keeping the
+/// previous one as `storage.key.old`. Each step can be repeated, so this is
+/// run before the databases are opened to finish a rekey interrupted by a
+/// crash. Returns whether there was a rekey to complete.
+pub fn finish_rekey(data_dir: &Path, databases: &[PathBuf]) -> Result<bool> {
+    let pending = pending_key_path(data_dir);
+    if !pending.exists() {
+        return Ok(false);
+    }
+
+    for database in databases {
+        let copy = rekey_copy_path(database);
+        if copy.exists() {
+            fs::rename(&copy, database)?;
+        }
+    }
+    // 鍵ファイルを切り替える前に、置き換えたデータベースをディスクに残す
+    sync_dir(data_dir)?;
+
+    let path = key_path(data_dir);
+    if path.exists() {
+        fs::rename(&path, path.with_extension("key.old"))?;
+    }
+    if fs::metadata(&pending)?.len() > 0 {
+        fs::rename(&pending, &path)?;
+    } else {
+        fs::remove_file(&pending)?;
+    }
+    sync_dir(data_dir)?;
+
+    Ok(true)
+}
+
+/// Make renames in `dir` durable
+pub fn sync_dir(dir: &Path) -> Result<()> {
+    #[cfg(unix)]
+    fs::File::open(dir)?.sync_all()?;
+    #[cfg(not(unix))]
+    let _ = dir;
+    Ok(())
+}
+
+/// Rewrites every encrypted column of a database from one codec to another,
+/// called inside a transaction
+pub type Reencode = fn(&Connection, &Codec, &Codec) -> Result<()>;
+
+/// How column values of one database are stored: as is, or encrypted with
+/// ChaCha20-Poly1305. Encrypted values are bound to `aad` (usually the row's
+/// key) so they cannot be moved to another row.
+#[derive(Clone, Default)]
+pub struct Codec {
+    cipher: Option<ChaCha20Poly1305>,
+    header: Option<Header>,
+}
+
+#[derive(Clone)]
+struct Header {
+    kdf: &'static str,
+    salt: Vec<u8>,
+}
+
+impl Codec {
+    /// A codec that stores values as is
+    pub fn plaintext() -> Self {
+        Self::default()
+    }
+
+    /// A codec with a fresh salt
+    fn create(secret: &Secret) -> Result<Self> {
+        let mut salt = vec![0u8; SALT_LENGTH];
+        OsRng.fill_bytes(&mut salt);
+        Ok(Self {
+            cipher: Some(secret.derive(&salt)?),
+            header: Some(Header {
+                kdf: secret.kdf(),
+                salt,
+            }),
+        })
+    }
+
+    pub fn is_encrypted(&self) -> bool {
+        self.cipher.is_some()
+    }
+
+    /// Value to write to the database. `NULL` is kept so that optional
+    /// columns stay optional.
+    pub fn seal(&self, aad: &[u8], value: SqlValue) -> Result<SqlValue> {
+        let Some(cipher) = &self.cipher else {
+            return Ok(value);
+        };
+
+        let mut plaintext = match value {
+            SqlValue::Null => return Ok(SqlValue::Null),
+            SqlValue::Text(text) => {
+                let mut bytes = vec![TYPE_TEXT];
+                bytes.extend_from_slice(text.as_bytes());
+                bytes
+            }
+            SqlValue::Blob(data) => {
+                let mut bytes = vec![TYPE_BLOB];
+                bytes.extend_from_slice(&data);
+                bytes
+            }
+            other => bail!("Cannot encrypt {:?} values", other.data_type()),
+        };
+
+        let nonce = ChaCha20Poly1305::generate_nonce(&mut OsRng);
+        let ciphertext = cipher
+            .encrypt(
+                &nonce,
+                Payload {
+                    msg: &plaintext,
+                    aad,
+                },
+            )
+            .map_err(|_| anyhow!("Failed to encrypt value"))?;
+        plaintext.fill(0);
+
+        let mut sealed = nonce.to_vec();
+        sealed.extend_from_slice(&ciphertext);
+        Ok(SqlValue::Blob(sealed))
+    }
+
+    /// Inverse of `seal`. Values written before the database was encrypted
+    /// are never read back, they are converted when encryption is enabled.
+    /// With a key, anything but `NULL` or a sealed blob is an error.
+    pub fn open(&self, aad: &[u8], value: SqlValue) -> Result<SqlValue> {
+        let Some(cipher) = &self.cipher else {
+            return Ok(value);
+        };
+
+        let sealed = match value {
+            SqlValue::Blob(sealed) => sealed,
+            SqlValue::Null => return Ok(SqlValue::Null),
+            // ディスク上で書き換えられた平文の値は認証できないので受け付けない
+            other => bail!("Expected an encrypted value, found {}", other.data_type()),
+        };
+        if sealed.len() < NONCE_LENGTH {
+            bail!("Encrypted value is too short");
+        }
+        let (nonce, ciphertext) = sealed.split_at(NONCE_LENGTH);
+        let plaintext = cipher
+            .decrypt(
+                Nonce::from_slice(nonce),
+                Payload {
+                    msg: ciphertext,
+                    aad,
+                },
+            )
+            .map_err(|_| anyhow!("Failed to decrypt value"))?;
+
+        match plaintext.split_first() {
+            Some((&TYPE_TEXT, text)) => Ok(SqlValue::Text(String::from_utf8(text.to_vec())?)),
+            Some((&TYPE_BLOB, data)) => Ok(SqlValue::Blob(data.to_vec())),
+            _ => bail!("Invalid encrypted value"),
+        }
+    }
+
+    /// `open` for optional text columns
+    pub fn open_text(&self, aad: &[u8], value: SqlValue) -> Result<Option<String>> {
+        match self.open(aad, value)? {
+            SqlValue::Null => Ok(None),
+            SqlValue::Text(text) => Ok(Some(text)),
+            other => bail!("Expected text, found {}", other.data_type()),
+        }
+    }
+
+    /// `open` for optional blob columns
+    pub fn open_blob(&self, aad: &[u8], value: SqlValue) -> Result<Option<Vec<u8>>> {
+        match self.open(aad, value)? {
+            SqlValue::Null => Ok(None),
+            SqlValue::Blob(data) => Ok(Some(data)),
+            other => bail!("Expected a blob, found {}", other.data_type()),
+        }
+    }
+}
+
+/// Set up the codec of an opened database. With `secret` a plaintext
+/// database is encrypted in place; without, an encrypted database is an error.
+pub fn open(
+    conn: &Connection,
+    path: &Path,
+    secret: Option<&Secret>,
+    reencode: Reencode,
+) -> Result<Codec> {
+    let codec = unlock(conn, path, secret)?;
+
+    match secret {
+        Some(secret) if !codec.is_encrypted() => {
+            let encrypted = Codec::create(secret)?;
+            convert(conn, &codec, &encrypted, reencode)?;
+            info!("Encrypted existing data in {}", path.display());
+            Ok(encrypted)
+        }
+        _ => Ok(codec),
+    }
+}
+
+/// Re-encrypt a database with `new`, or decrypt it when `new` is `None`.
+/// `old` is needed if the database is encrypted.
+pub fn rekey(
+    conn: &Connection,
+    path: &Path,
+    old: Option<&Secret>,
+    new: Option<&Secret>,
+    reencode: Reencode,
+) -> Result<()> {
+    let from = unlock(conn, path, old)?;
+    let to = match new {
+        Some(secret) => Codec::create(secret)?,
+        None => Codec::plaintext(),
+    };
+    convert(conn, &from, &to, reencode)
+}
+
+fn unlock(conn: &Connection, path: &Path, secret: Option<&Secret>) -> Result<Codec> {
+    conn.execute(
+        "CREATE TABLE IF NOT EXISTS at_rest (
+            id INTEGER PRIMARY KEY CHECK (id = 1),
+            kdf TEXT NOT NULL,
+            salt BLOB NOT NULL,
+            check_value BLOB NOT NULL
+        )",
+        [],
+    )?;
+
+    let header = conn
+        .query_row(
+            "SELECT kdf, salt, check_value FROM at_rest WHERE id = 1",
+            [],
+            |row| {
+                Ok((
+                    row.get::<_, String>(0)?,
+                    row.get::<_, Vec<u8>>(1)?,
+                    row.get::<_, Vec<u8>>(2)?,
+                ))
+            },
+        )
+        .optional()?;
+
+    let Some((kdf, salt, check_value)) = header else {
+        return Ok(Codec::plaintext());
+    };
+
+    let Some(secret) = secret else {
+        bail!(
+            "{} is encrypted at rest: set `encrypt = true` in [storage] and provide its key file or {PASSPHRASE_ENV}",
+            path.display()
+        );
+    };
+    if kdf != secret.kdf() {
+        match kdf.as_str() {
+            KDF_PASSPHRASE => bail!(
+                "{} is encrypted with a passphrase: set {PASSPHRASE_ENV}",
+                path.display()
+            ),
+            KDF_KEY_FILE => bail!(
+                "{} is encrypted with a key file: unset {PASSPHRASE_ENV} and restore {KEY_FILE}",
+                path.display()
+            ),
+            _ => bail!("{} uses an unknown key derivation: {kdf}", path.display()),
+        }
+    }
+
+    let codec = Codec {
+        cipher: Some(secret.derive(&salt)?),
+        header: Some(Header {
+            kdf: secret.kdf(),
+            salt,
+        }),
+    };
+    if codec.open(CHECK_AAD, SqlValue::Blob(check_value)).is_err() {
+        bail!("Wrong at-rest key for {}", path.display());
+    }
+
+    Ok(codec)
+}
+
+fn convert(conn: &Connection, from: &Codec, to: &Codec, reencode: Reencode) -> Result<()> {
+    let tx = conn.unchecked_transaction()?;
+
+    reencode(conn, from, to)?;
+
+    conn.execute("DELETE FROM at_rest", [])?;
+    if let Some(header) = &to.header {
+        let check_value = to.seal(CHECK_AAD, SqlValue::Blob(CHECK_VALUE.to_vec()))?;
+        conn.execute(
+            "INSERT INTO at_rest (id, kdf, salt, check_value) VALUES (1, ?1, ?2, ?3)",
+            params![header.kdf, header.salt, check_value],
+        )?;
+    }
+
+    tx.commit()?;
+
+    // 置き換え前の値が空きページに残らないようにする
+    conn.execute("VACUUM", [])?;
+    Ok(())
+}
+
+/// Re-encode one column of every row of `table`, identifying rows by the
+/// text column `id`, which is also used as the associated data
+pub fn reencode_column(
+    conn: &Connection,
+    table: &str,
+    id: &str,
+    column: &str,
+    from: &Codec,
+    to: &Codec,
+) -> Result<()> {
+    let rows = conn
+        .prepare(&format!("SELECT {id}, {column} FROM {table}"))?
+        .query_map([], |row| {
+            Ok((row.get::<_, String>(0)?, row.get::<_, SqlValue>(1)?))
+        })?
+        .collect::<Result<Vec<_>, _>>()?;
+
+    for (row_id, value) in rows {
+        let aad = column_aad(table, column, &row_id);
+        let value = to.seal(&aad, from.open(&aad, value)?)?;
+        conn.execute(
+            &format!("UPDATE {table} SET {column} = ?1 WHERE {id} = ?2"),
+            params![value, row_id],
+        )?;
+    }
+
+    Ok(())
+}
+
+/// Associated data of a column value
+pub fn column_aad(table: &str, column: &str, row_id: &str) -> Vec<u8> {
+    format!("{table}\0{column}\0{row_id}").into_bytes()
+}
+
+/// Turn a codec error into an error `query_map` closures can return
+pub fn to_sql_error(column: usize, e: anyhow::Error) -> rusqlite::Error {
+    rusqlite::Error::FromSqlConversionFailure(column, rusqlite::types::Type::Blob, e.into())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tempfile::tempdir;
+
+    fn reencode_notes(conn: &Connection, from: &Codec, to: &Codec) -> Result<()> {
+        reencode_column(conn, "notes", "id", "body", from, to)
+    }
+
+    fn notes(dir: &Path) -> (Connection, PathBuf) {
+        let path = dir.join("notes.db");
+        let conn = Connection::open(&path).unwrap();
+        conn.execute(
+            "CREATE TABLE IF NOT EXISTS notes (id TEXT PRIMARY KEY, body TEXT)",
+            [],
+        )
+        .unwrap();
+        (conn, path)
+    }
+
+    fn raw_body(conn: &Connection) -> SqlValue {
+        conn.query_row("SELECT body FROM notes WHERE id = 'a'", [], |row| {
+            row.get(0)
+        })
+        .unwrap()
+    }
+
+    #[test]
+    fn test_seal_and_open() {
+        let codec = Codec::create(&Secret::generate()).unwrap();
+
+        for value in [
+            SqlValue::Text("hello".to_string()),
+            SqlValue::Blob(vec![0, 1, 2]),
+            SqlValue::Null,
+        ] {
+            let sealed = codec.seal(b"row", value.clone()).unwrap();
+            assert_eq!(codec.open(b"row", sealed).unwrap(), value);
+        }
+
+        let sealed = codec
+            .seal(b"row", SqlValue::from("hello".to_string()))
+            .unwrap();
+        assert!(matches!(sealed, SqlValue::Blob(_)));
+        // 別の行に移した値は復号できない
+        assert!(codec.open(b"other", sealed).is_err());
+        // 鍵があるときは暗号化されていない値を受け付けない
+        assert!(codec
+            .open(b"row", SqlValue::Text("planted".to_string()))
+            .is_err());
+        assert!(codec.open(b"row", SqlValue::Integer(1)).is_err());
+
+        let plaintext = Codec::plaintext();
+        assert_eq!(
+            plaintext.seal(b"row", SqlValue::from(1)).unwrap(),
+            SqlValue::Integer(1)
+        );
+    }
+
+    #[test]
+    fn test_encrypt_existing_database_and_rekey() {
+        let dir = tempdir().unwrap();
+        let (conn, path) = notes(dir.path());
+        conn.execute("INSERT INTO notes VALUES ('a', 'secret')", [])
+            .unwrap();
+
+        let key = Secret::generate();
+        let codec = open(&conn, &path, Some(&key), reencode_notes).unwrap();
+        assert!(codec.is_encrypted());
+        let sealed = raw_body(&conn);
+        assert!(matches!(sealed, SqlValue::Blob(_)));
+        assert_eq!(
+            codec
+                .open(&column_aad("notes", "body", "a"), sealed)
+                .unwrap(),
+            SqlValue::Text("secret".to_string())
+        );
+
+        // 鍵がない・違う・種類が違う場合は開けない
+        let error = open(&conn, &path, None, reencode_notes).err().unwrap();
+        assert!(error.to_string().contains("is encrypted at rest"));
+        let error = open(&conn, &path, Some(&Secret::generate()), reencode_notes)
+            .err()
+            .unwrap();
+        assert!(error.to_string().contains("Wrong at-rest key"));
+        let passphrase = Secret::Passphrase("correct horse".to_string());
+        assert!(open(&conn, &path, Some(&passphrase), reencode_notes).is_err());
+
+        rekey(&conn, &path, Some(&key), Some(&passphrase), reencode_notes).unwrap();
+        assert!(open(&conn, &path, Some(&key), reencode_notes).is_err());
+        assert!(open(&conn, &path, Some(&passphrase), reencode_notes)
+            .unwrap()
+            .is_encrypted());
+
+        rekey(&conn, &path, Some(&passphrase), None, reencode_notes).unwrap();
+        assert_eq!(raw_body(&conn), SqlValue::Text("secret".to_string()));
+        assert!(!open(&conn, &path, None, reencode_notes)
+            .unwrap()
+            .is_encrypted());
+    }
+
+    #[test]
+    fn test_key_file() {
+        let dir = tempdir().unwrap();
+        assert!(existing_secret(dir.path()).unwrap().is_none());
+
+        let Secret::Key(created) = load_or_create_secret(dir.path()).unwrap() else {
+            panic!("Expected a key file");
+        };
+        let Some(Secret::Key(loaded)) = existing_secret(dir.path()).unwrap() else {
+            panic!("Expected a key file");
+        };
+        assert_eq!(created, loaded);
+
+        fs::write(key_path(dir.path()), b"short").unwrap();
+        assert!(existing_secret(dir.path()).is_err());
+    }
+
+    #[test]
+    fn test_interrupted_rekey_is_finished() {
+        let dir = tempdir().unwrap();
+        let (conn, path) = notes(dir.path());
+        conn.execute("INSERT INTO notes VALUES ('a', 'secret')", [])
+            .unwrap();
+        let old = load_or_create_secret(dir.path()).unwrap();
+        open(&conn, &path, Some(&old), reencode_notes).unwrap();
+        drop(conn);
+        let databases = vec![path.clone(), dir.path().join("missing.db")];
+
+        // 印がなければ書きかけのコピーは使わない
+        let copy = rekey_copy_path(&path);
+        fs::copy(&path, &copy).unwrap();
+        assert!(!finish_rekey(dir.path(), &databases).unwrap());
+        assert!(copy.exists());
+
+        // コピーを書き換えて印を付けたところで止まった
+        let new = Secret::generate();
+        let conn = Connection::open(&copy).unwrap();
+        rekey(&conn, &copy, Some(&old), Some(&new), reencode_notes).unwrap();
+        drop(conn);
+        save_pending_key(dir.path(), Some(&new)).unwrap();
+
+        // 次の起動で鍵を読む前に完了させ、新しい鍵で開ける
+        assert!(finish_rekey(dir.path(), &databases).unwrap());
+        assert!(!copy.exists());
+        assert!(!pending_key_path(dir.path()).exists());
+        let secret = existing_secret(dir.path()).unwrap().unwrap();
+        let conn = Connection::open(&path).unwrap();
+        let codec = open(&conn, &path, Some(&secret), reencode_notes).unwrap();
+        assert_eq!(
+            codec
+                .open(&column_aad("notes", "body", "a"), raw_body(&conn))
+                .unwrap(),
+            SqlValue::Text("secret".to_string())
+        );
+        assert!(key_path(dir.path()).with_extension("key.old").exists());
+        assert!(!finish_rekey(dir.path(), &databases).unwrap());
+    }
+}
@@ -10,7 +10,7 @@ After=network.target
 
 [Service]
 Type=simple
-ExecStart=/usr/local/bin/p2p-sync
+ExecStart=/usr/local/bin/p2p-sync start
 Restart=always
 RestartSec=10
 User=%i
@@ -53,6 +53,7 @@ pub fn setup_autostart() -> Result<()> {
     <key>ProgramArguments</key>
     <array>
         <string>{}</string>
+        <string>start</string>
     </array>
     <key>RunAtLoad</key>
     <true/>
@@ -102,6 +103,7 @@ pub fn setup_autostart() -> Result<()> {
         r#"$WshShell = New-Object -comObject WScript.Shell
 $Shortcut = $WshShell.CreateShortcut("{}")
 $Shortcut.TargetPath = "{}"
+$Shortcut.Arguments = "start"
 $Shortcut.Save()"#,
         link_path.display(),
         exe_path.display()
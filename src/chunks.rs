@@ -0,0 +1,210 @@
+use anyhow::{bail, Result};
+use libp2p::request_response::{self, cbor, OutboundRequestId, ProtocolSupport};
+use libp2p::{PeerId, StreamProtocol};
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use std::collections::{HashMap, HashSet};
+
+/// Protocol name used to fetch content-addressed chunks from peers
+pub const PROTOCOL: &str = "/p2p-sync/chunks/1.0.0";
+
+/// Files and large values are split into chunks of this size before hashing
+pub const CHUNK_SIZE: usize = 256 * 1024;
+
+pub type Behaviour = cbor::Behaviour<ChunkRequest, ChunkResponse>;
+
+pub type Event = request_response::Event<ChunkRequest, ChunkResponse>;
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ChunkRequest {
+    /// Hex encoded SHA-256 of the chunk
+    pub hash: String,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub enum ChunkResponse {
+    Chunk(#[serde(with = "serde_bytes")] Vec<u8>),
+    NotFound,
+}
+
+/// Size and chunk hashes of a file or large value
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct ChunkManifest {
+    pub size: u64,
+    /// Hex encoded SHA-256 of each chunk, in order
+    pub chunks: Vec<String>,
+}
+
+impl ChunkManifest {
+    pub fn to_json(&self) -> Result<String> {
+        Ok(serde_json::to_string(self)?)
+    }
+
+    pub fn from_json(value: &str) -> Result<Self> {
+        Ok(serde_json::from_str(value)?)
+    }
+
+    /// Check a manifest received from a peer before fetching its chunks
+    pub fn validate(&self, max_size: usize) -> Result<()> {
+        if self.size > max_size as u64 {
+            bail!("Value too large: {} > {}", self.size, max_size);
+        }
+
+        let expected = self.size.div_ceil(CHUNK_SIZE as u64);
+        if self.chunks.len() as u64 != expected {
+            bail!(
+                "Manifest has {} chunks, expected {} for {} bytes",
+                self.chunks.len(),
+                expected,
+                self.size
+            );
+        }
+
+        if let Some(hash) = self.chunks.iter().find(|hash| !is_valid_hash(hash)) {
+            bail!("Invalid chunk hash: {}", hash);
+        }
+
+        Ok(())
+    }
+}
+
+pub fn new_behaviour() -> Behaviour {
+    request_response::Behaviour::new(
+        [(StreamProtocol::new(PROTOCOL), ProtocolSupport::Full)],
+        request_response::Config::default(),
+    )
+}
+
+/// Hex encoded SHA-256 of `data`
+pub fn hash(data: &[u8]) -> String {
+    hex::encode(Sha256::digest(data))
+}
+
+pub fn is_valid_hash(hash: &str) -> bool {
+    hash.len() == 64 && hash.bytes().all(|b| b.is_ascii_hexdigit())
+}
+
+struct ChunkFetch {
+    hash: String,
+    /// Peers to try if the current one does not have the chunk
+    fallback: Vec<PeerId>,
+}
+
+/// Chunk requests in flight. Each chunk is requested from one peer at a time
+/// and retried with the next connected peer if it is missing or corrupt.
+#[derive(Default)]
+pub struct ChunkFetches {
+    inflight: HashMap<OutboundRequestId, ChunkFetch>,
+}
+
+impl ChunkFetches {
+    /// Request every chunk in `hashes` that is not already in flight, asking
+    /// the peers in order
+    pub fn request(
+        &mut self,
+        behaviour: &mut Behaviour,
+        hashes: impl IntoIterator<Item = String>,
+        peers: &[PeerId],
+    ) {
+        let Some((first, fallback)) = peers.split_first() else {
+            return;
+        };
+
+        let inflight: HashSet<String> = self
+            .inflight
+            .values()
+            .map(|fetch| fetch.hash.clone())
+            .collect();
+
+        for hash in hashes {
+            if inflight.contains(&hash) {
+                continue;
+            }
+            let request_id = behaviour.send_request(first, ChunkRequest { hash: hash.clone() });
+            self.inflight.insert(
+                request_id,
+                ChunkFetch {
+                    hash,
+                    fallback: fallback.to_vec(),
+                },
+            );
+        }
+    }
+
+    /// Whether `request_id` was sent by this set of fetches
+    pub fn contains(&self, request_id: &OutboundRequestId) -> bool {
+        self.inflight.contains_key(request_id)
+    }
+
+    /// The chunk carried by a response, once it matches the requested hash.
+    /// Missing or corrupt chunks are requested from the next peer.
+    pub fn handle_response(
+        &mut self,
+        request_id: OutboundRequestId,
+        response: ChunkResponse,
+        behaviour: &mut Behaviour,
+    ) -> Option<Vec<u8>> {
+        let fetch = self.inflight.remove(&request_id)?;
+
+        match response {
+            ChunkResponse::Chunk(data) if hash(&data) == fetch.hash => return Some(data),
+            ChunkResponse::Chunk(_) => {
+                tracing::warn!("Received chunk does not match hash {}", fetch.hash);
+                self.retry(fetch, behaviour);
+            }
+            ChunkResponse::NotFound => self.retry(fetch, behaviour),
+        }
+
+        None
+    }
+
+    pub fn handle_failure(&mut self, request_id: OutboundRequestId, behaviour: &mut Behaviour) {
+        if let Some(fetch) = self.inflight.remove(&request_id) {
+            self.retry(fetch, behaviour);
+        }
+    }
+
+    fn retry(&mut self, mut fetch: ChunkFetch, behaviour: &mut Behaviour) {
+        if fetch.fallback.is_empty() {
+            // 次にピアが接続したときに再要求する
+            tracing::warn!("No connected peer has chunk {}", fetch.hash);
+            return;
+        }
+
+        let peer = fetch.fallback.remove(0);
+        let request_id = behaviour.send_request(
+            &peer,
+            ChunkRequest {
+                hash: fetch.hash.clone(),
+            },
+        );
+        self.inflight.insert(request_id, fetch);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_manifest_validation() {
+        let manifest = ChunkManifest {
+            size: CHUNK_SIZE as u64 + 1,
+            chunks: vec![hash(b"a"), hash(b"b")],
+        };
+        assert!(manifest.validate(CHUNK_SIZE * 2).is_ok());
+        assert!(manifest.validate(CHUNK_SIZE).is_err());
+
+        let missing_chunk = ChunkManifest {
+            chunks: vec![hash(b"a")],
+            ..manifest.clone()
+        };
+        assert!(missing_chunk.validate(CHUNK_SIZE * 2).is_err());
+
+        let bad_hash = ChunkManifest {
+            chunks: vec![hash(b"a"), "../escape".to_string()],
+            ..manifest
+        };
+        assert!(bad_hash.validate(CHUNK_SIZE * 2).is_err());
+    }
+}
@@ -1,14 +1,75 @@
-use anyhow::Result;
+use ::config::{Environment, File, FileFormat};
+use anyhow::{bail, Context, Result};
 use serde::{Deserialize, Serialize};
+use std::collections::{BTreeMap, BTreeSet, HashSet};
 use std::fs;
-use std::path::Path;
+use std::net::SocketAddr;
+use std::path::{Path, PathBuf};
 
-#[derive(Debug, Clone, Serialize, Deserialize)]
+/// Prefix of the environment variables overriding config keys
+pub const ENV_PREFIX: &str = "P2P_SYNC";
+/// Environment variable naming the config file, like `--config`
+pub const CONFIG_ENV: &str = "P2P_SYNC_CONFIG";
+
+// 設定キーとして読まない P2P_SYNC_* 変数（パスフレーズを設定に取り込まない）
+const RESERVED_ENV: &[&str] = &[
+    CONFIG_ENV,
+    crate::at_rest::PASSPHRASE_ENV,
+    crate::at_rest::NEW_PASSPHRASE_ENV,
+];
+
+// 環境変数ではカンマ区切りのリストとして読むキー
+const LIST_KEYS: &[&str] = &[
+    "bootstrap_peers",
+    "security.blocked_peers",
+    "security.allowed_peers",
+    "key_distribution.admin_peers",
+];
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
 pub struct Config {
     pub port: u16,
     pub data_dir: Option<String>,
     pub bootstrap_peers: Vec<String>,
     pub security: crate::security::SecurityConfig,
+    #[serde(default)]
+    pub storage: crate::storage::StorageConfig,
+    #[serde(default)]
+    pub http: HttpConfig,
+    #[serde(default)]
+    pub key_distribution: crate::key_distribution::KeyDistributionConfig,
+    #[serde(default)]
+    pub discovery: crate::discovery::DiscoveryConfig,
+    #[serde(default)]
+    pub metrics: crate::metrics::MetricsConfig,
+    #[serde(default)]
+    pub peer_scoring: crate::network::PeerScoringConfig,
+    #[serde(default)]
+    pub trust: crate::trust::TrustConfig,
+    /// Namespaces besides `default`, keyed by name
+    #[serde(default)]
+    pub namespaces: BTreeMap<String, crate::namespace::NamespaceConfig>,
+}
+
+/// Settings for the REST gateway (`[http]` section). The server is only
+/// available when built with the `http-api` feature.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct HttpConfig {
+    pub enabled: bool,
+    pub listen_addr: String,
+    /// Bearer token required on every request
+    pub token: Option<String>,
+}
+
+impl Default for HttpConfig {
+    fn default() -> Self {
+        Self {
+            enabled: false,
+            listen_addr: "127.0.0.1:8080".to_string(),
+            token: None,
+        }
+    }
 }
 
 impl Default for Config {
@@ -18,18 +79,452 @@ impl Default for Config {
             data_dir: None,
             bootstrap_peers: Vec::new(),
             security: crate::security::SecurityConfig::default(),
+            storage: crate::storage::StorageConfig::default(),
+            http: HttpConfig::default(),
+            key_distribution: crate::key_distribution::KeyDistributionConfig::default(),
+            discovery: crate::discovery::DiscoveryConfig::default(),
+            metrics: crate::metrics::MetricsConfig::default(),
+            peer_scoring: crate::network::PeerScoringConfig::default(),
+            trust: crate::trust::TrustConfig::default(),
+            namespaces: BTreeMap::new(),
+        }
+    }
+}
+
+impl Config {
+    /// Check values that deserialize fine but cannot be used, reporting
+    /// every problem with its key
+    pub fn validate(&self) -> Result<()> {
+        let mut errors = self.errors();
+        if errors.is_empty() {
+            return Ok(());
+        }
+        errors.sort();
+        bail!("Invalid configuration:\n  {}", errors.join("\n  "))
+    }
+
+    fn errors(&self) -> Vec<String> {
+        let mut errors = Vec::new();
+        let mut check = |ok: bool, key: &str, message: &str| {
+            if !ok {
+                errors.push(format!("{key}: {message}"));
+            }
+        };
+
+        let security = &self.security;
+        for (key, value) in [
+            (
+                "rate_limit_per_minute",
+                security.rate_limit_per_minute as usize,
+            ),
+            ("rate_limit_burst", security.rate_limit_burst as usize),
+            ("max_message_size", security.max_message_size),
+            ("max_key_length", security.max_key_length),
+            ("max_value_length", security.max_value_length),
+            ("max_object_size", security.max_object_size),
+            ("max_connections_per_ip", security.max_connections_per_ip),
+        ] {
+            check(
+                value > 0,
+                &format!("security.{key}"),
+                "must be greater than 0",
+            );
+        }
+        check(
+            !security.connection_timeout.is_zero(),
+            "security.connection_timeout",
+            "must be greater than 0",
+        );
+        check(
+            self.key_distribution.max_message_age_hours > 0,
+            "key_distribution.max_message_age_hours",
+            "must be greater than 0",
+        );
+        check(
+            self.key_distribution.auto_approve_min_recommendations != Some(0),
+            "key_distribution.auto_approve_min_recommendations",
+            "must be at least 1, or unset to disable automatic approval",
+        );
+        check(
+            self.trust.min_recommenders > 0,
+            "trust.min_recommenders",
+            "must be greater than 0",
+        );
+        check(
+            self.trust.min_weight.is_finite() && self.trust.min_weight >= 0.0,
+            "trust.min_weight",
+            "must be a number of at least 0",
+        );
+        for (peer, weight) in &self.trust.weights {
+            check(
+                weight.is_finite() && *weight >= 0.0,
+                &format!("trust.weights.{peer}"),
+                "must be a number of at least 0",
+            );
+        }
+
+        check(
+            self.http.listen_addr.parse::<SocketAddr>().is_ok(),
+            "http.listen_addr",
+            &format!("not a socket address: {:?}", self.http.listen_addr),
+        );
+        check(
+            !self.http.enabled || self.http.token.as_ref().is_some_and(|t| !t.is_empty()),
+            "http.token",
+            "must be set when the HTTP gateway is enabled",
+        );
+        check(
+            self.metrics.listen_addr.parse::<SocketAddr>().is_ok(),
+            "metrics.listen_addr",
+            &format!("not a socket address: {:?}", self.metrics.listen_addr),
+        );
+
+        for (i, addr) in self.bootstrap_peers.iter().enumerate() {
+            if let Err(e) = crate::discovery::parse_bootstrap_peer(addr) {
+                errors.push(format!("bootstrap_peers[{i}]: {e:#}"));
+            }
+        }
+        errors.extend(peer_id_errors(
+            "security.blocked_peers",
+            Some(&security.blocked_peers),
+        ));
+        errors.extend(peer_id_errors(
+            "security.allowed_peers",
+            security.allowed_peers.as_ref(),
+        ));
+        errors.extend(peer_id_errors(
+            "key_distribution.admin_peers",
+            Some(&self.key_distribution.admin_peers),
+        ));
+        let weighted: HashSet<String> = self.trust.weights.keys().cloned().collect();
+        errors.extend(peer_id_errors("trust.weights", Some(&weighted)));
+
+        for (name, namespace) in &self.namespaces {
+            let key = format!("namespaces.{name}");
+            if let Err(e) = crate::namespace::validate_name(name) {
+                errors.push(format!("{key}: {e}"));
+            }
+            errors.extend(peer_id_errors(
+                &format!("{key}.readers"),
+                namespace.readers.as_ref(),
+            ));
+            errors.extend(peer_id_errors(
+                &format!("{key}.writers"),
+                namespace.writers.as_ref(),
+            ));
+        }
+
+        if self.peer_scoring.enabled {
+            // トピックごとのパラメータも検査されるよう、仮のトピックを 1 つ渡す
+            let topic = crate::namespace::topic(crate::namespace::DEFAULT_NAMESPACE).hash();
+            if let Err(e) = self.peer_scoring.thresholds().validate() {
+                errors.push(format!("peer_scoring: {e}"));
+            }
+            if let Err(e) = self.peer_scoring.params(&[topic]).validate() {
+                errors.push(format!("peer_scoring: {e}"));
+            }
+        }
+
+        errors
+    }
+}
+
+fn peer_id_errors(key: &str, peers: Option<&HashSet<String>>) -> Vec<String> {
+    let mut invalid: Vec<_> = peers
+        .into_iter()
+        .flatten()
+        .filter(|peer| peer.parse::<libp2p::PeerId>().is_err())
+        .map(|peer| format!("{key}: not a PeerId: {peer:?}"))
+        .collect();
+    invalid.sort();
+    invalid
+}
+
+/// Values given on the command line, applied over the file and the
+/// environment
+#[derive(Debug, Clone, Default)]
+pub struct Overrides {
+    pub port: Option<u16>,
+    pub data_dir: Option<PathBuf>,
+    /// Replaces `bootstrap_peers` when not empty
+    pub bootstrap_peers: Vec<String>,
+    /// `key=value` pairs with dotted keys, values are read as TOML and fall
+    /// back to plain strings
+    pub set: Vec<String>,
+}
+
+impl Overrides {
+    fn to_toml(&self) -> Result<toml::Table> {
+        let mut table = toml::Table::new();
+        for item in &self.set {
+            let (key, value) = item
+                .split_once('=')
+                .with_context(|| format!("Expected key=value: {item:?}"))?;
+            insert_dotted(&mut table, key.trim(), parse_value(value.trim()))?;
         }
+        if let Some(port) = self.port {
+            table.insert("port".to_string(), toml::Value::Integer(port.into()));
+        }
+        if let Some(data_dir) = &self.data_dir {
+            table.insert(
+                "data_dir".to_string(),
+                toml::Value::String(data_dir.display().to_string()),
+            );
+        }
+        if !self.bootstrap_peers.is_empty() {
+            let peers = self
+                .bootstrap_peers
+                .iter()
+                .cloned()
+                .map(toml::Value::String);
+            table.insert(
+                "bootstrap_peers".to_string(),
+                toml::Value::Array(peers.collect()),
+            );
+        }
+        Ok(table)
     }
 }
 
+fn parse_value(value: &str) -> toml::Value {
+    toml::from_str::<toml::Table>(&format!("value = {value}"))
+        .ok()
+        .and_then(|mut table| table.remove("value"))
+        .unwrap_or_else(|| toml::Value::String(value.to_string()))
+}
+
+fn insert_dotted(table: &mut toml::Table, key: &str, value: toml::Value) -> Result<()> {
+    let (parents, last) = match key.rsplit_once('.') {
+        Some((parents, last)) => (parents.split('.').collect(), last),
+        None => (Vec::new(), key),
+    };
+    if last.is_empty() || parents.iter().any(|part: &&str| part.is_empty()) {
+        bail!("Invalid config key: {key:?}");
+    }
+
+    let mut table = table;
+    for part in parents {
+        table = match table
+            .entry(part)
+            .or_insert_with(|| toml::Value::Table(toml::Table::new()))
+        {
+            toml::Value::Table(child) => child,
+            _ => bail!("Config key {key:?} conflicts with another --set"),
+        };
+    }
+    table.insert(last.to_string(), value);
+    Ok(())
+}
+
+/// Builds the effective configuration from, in increasing priority, the
+/// defaults, the config file, `P2P_SYNC_*` environment variables and
+/// command line overrides
+#[derive(Debug, Clone)]
+pub struct ConfigLoader {
+    default_data_dir: PathBuf,
+    path: Option<PathBuf>,
+    env_path: Option<PathBuf>,
+    env: BTreeMap<String, String>,
+    overrides: Overrides,
+}
+
+/// Result of `ConfigLoader::load`
+#[derive(Debug, Clone)]
+pub struct LoadedConfig {
+    pub config: Config,
+    /// Config file that was read, or would be if it existed
+    pub path: PathBuf,
+    pub data_dir: PathBuf,
+    /// Keys given in the file, environment or overrides that no setting
+    /// reads, most likely typos
+    pub unknown_keys: Vec<String>,
+}
+
+impl LoadedConfig {
+    /// `Config::validate` that also rejects unknown keys
+    pub fn validate(&self) -> Result<()> {
+        let mut errors = self.config.errors();
+        errors.extend(
+            self.unknown_keys
+                .iter()
+                .map(|key| format!("{key}: unknown key")),
+        );
+        if errors.is_empty() {
+            return Ok(());
+        }
+        errors.sort();
+        bail!(
+            "Invalid configuration ({}):\n  {}",
+            self.path.display(),
+            errors.join("\n  ")
+        )
+    }
+}
+
+impl ConfigLoader {
+    /// Loader reading `<default_data_dir>/config.toml`, without environment
+    /// variables or overrides
+    pub fn new(default_data_dir: PathBuf) -> Self {
+        Self {
+            default_data_dir,
+            path: None,
+            env_path: None,
+            env: BTreeMap::new(),
+            overrides: Overrides::default(),
+        }
+    }
+
+    /// Read this file instead of `<data_dir>/config.toml`. Unlike the
+    /// default file it must exist.
+    pub fn with_path(mut self, path: Option<PathBuf>) -> Self {
+        self.path = path;
+        self
+    }
+
+    /// Use the `P2P_SYNC_*` variables in `vars`, except the passphrases
+    pub fn with_env(mut self, vars: impl IntoIterator<Item = (String, String)>) -> Self {
+        let prefix = format!("{ENV_PREFIX}_");
+        let mut vars: BTreeMap<_, _> = vars
+            .into_iter()
+            .filter(|(name, _)| name.starts_with(&prefix))
+            .collect();
+        self.env_path = vars.get(CONFIG_ENV).map(PathBuf::from);
+        vars.retain(|name, _| !RESERVED_ENV.contains(&name.as_str()));
+        self.env = vars;
+        self
+    }
+
+    pub fn with_overrides(mut self, overrides: Overrides) -> Self {
+        self.overrides = overrides;
+        self
+    }
+
+    /// Use `data_dir` instead of the configured one if given
+    pub fn with_data_dir(mut self, data_dir: Option<PathBuf>) -> Self {
+        if data_dir.is_some() {
+            self.overrides.data_dir = data_dir;
+        }
+        self
+    }
+
+    /// Config file to read: `--config`, `P2P_SYNC_CONFIG`, or `config.toml`
+    /// in the data directory given by `--data-dir`, `P2P_SYNC_DATA_DIR` or
+    /// the platform default
+    pub fn config_path(&self) -> (PathBuf, bool) {
+        if let Some(path) = &self.path {
+            return (path.clone(), true);
+        }
+        if let Some(path) = &self.env_path {
+            return (path.clone(), true);
+        }
+        (self.data_dir_hint().join("config.toml"), false)
+    }
+
+    fn data_dir_hint(&self) -> PathBuf {
+        self.overrides
+            .data_dir
+            .clone()
+            .or_else(|| {
+                self.env
+                    .get(&format!("{ENV_PREFIX}_DATA_DIR"))
+                    .map(PathBuf::from)
+            })
+            .unwrap_or_else(|| self.default_data_dir.clone())
+    }
+
+    pub fn load(&self) -> Result<LoadedConfig> {
+        let (path, required) = self.config_path();
+        if required && !path.exists() {
+            bail!("Config file not found: {}", path.display());
+        }
+
+        let mut environment = Environment::with_prefix(ENV_PREFIX)
+            .prefix_separator("_")
+            .separator("__")
+            .try_parsing(true)
+            .list_separator(",")
+            .source(Some(self.env.clone().into_iter().collect()));
+        for key in LIST_KEYS {
+            environment = environment.with_list_parse_key(key);
+        }
+
+        let defaults = toml::to_string(&Config::default())?;
+        let overrides = toml::to_string(&self.overrides.to_toml()?)?;
+        let raw = ::config::Config::builder()
+            .add_source(File::from_str(&defaults, FileFormat::Toml))
+            .add_source(
+                File::from(path.as_path())
+                    .format(FileFormat::Toml)
+                    .required(false),
+            )
+            .add_source(environment)
+            .add_source(File::from_str(&overrides, FileFormat::Toml))
+            .build()
+            .with_context(|| format!("Failed to read {}", path.display()))?;
+
+        let config: Config = raw
+            .clone()
+            .try_deserialize()
+            .context("Invalid configuration")?;
+
+        // 実際に読まれたキーと比べて、どの設定にも対応しないキーを探す
+        let mut unknown_keys = BTreeSet::new();
+        let given: toml::Value = raw.try_deserialize()?;
+        let effective = toml::Value::try_from(&config)?;
+        collect_unknown_keys("", &given, &effective, &mut unknown_keys);
+
+        let data_dir = match &config.data_dir {
+            Some(data_dir) => PathBuf::from(data_dir),
+            None => self.data_dir_hint(),
+        };
+
+        Ok(LoadedConfig {
+            config,
+            path,
+            data_dir,
+            unknown_keys: unknown_keys.into_iter().collect(),
+        })
+    }
+}
+
+fn collect_unknown_keys(
+    prefix: &str,
+    given: &toml::Value,
+    effective: &toml::Value,
+    unknown: &mut BTreeSet<String>,
+) {
+    let (toml::Value::Table(given), toml::Value::Table(effective)) = (given, effective) else {
+        return;
+    };
+    for (key, value) in given {
+        let path = match prefix {
+            "" => key.clone(),
+            _ => format!("{prefix}.{key}"),
+        };
+        match effective.get(key) {
+            Some(known) => collect_unknown_keys(&path, value, known, unknown),
+            // 空のテーブルは設定値を持たない
+            None if value.as_table().is_some_and(|t| t.is_empty()) => {}
+            None => {
+                unknown.insert(path);
+            }
+        }
+    }
+}
+
+/// Config in `path`, or the defaults if it does not exist. Environment
+/// variables and command line overrides are not applied, use
+/// `ConfigLoader` for those.
+#[allow(dead_code)] // バイナリでは使わないがライブラリの API として残す
 pub fn load_config(path: &Path) -> Result<Config> {
-    if path.exists() {
-        let content = fs::read_to_string(path)?;
-        let config: Config = toml::from_str(&content)?;
-        Ok(config)
-    } else {
-        Ok(Config::default())
+    if !path.exists() {
+        return Ok(Config::default());
     }
+    let data_dir = path.parent().map(Path::to_path_buf).unwrap_or_default();
+    let loaded = ConfigLoader::new(data_dir)
+        .with_path(Some(path.to_path_buf()))
+        .load()?;
+    Ok(loaded.config)
 }
 
 pub fn save_config(path: &Path, config: &Config) -> Result<()> {
@@ -37,3 +532,159 @@ pub fn save_config(path: &Path, config: &Config) -> Result<()> {
     fs::write(path, content)?;
     Ok(())
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tempfile::tempdir;
+
+    fn env(vars: &[(&str, &str)]) -> Vec<(String, String)> {
+        vars.iter()
+            .map(|(k, v)| (k.to_string(), v.to_string()))
+            .collect()
+    }
+
+    #[test]
+    fn test_layers() {
+        let dir = tempdir().unwrap();
+        fs::write(
+            dir.path().join("config.toml"),
+            "port = 5000\nbootstrap_peers = [\"/ip4/10.0.0.1/tcp/4001\"]\n\n[security]\nrate_limit_per_minute = 120\n",
+        )
+        .unwrap();
+
+        let loaded = ConfigLoader::new(dir.path().to_path_buf())
+            .with_env(env(&[
+                ("P2P_SYNC_PORT", "6000"),
+                ("P2P_SYNC_SECURITY__RATE_LIMIT_BURST", "20"),
+                (
+                    "P2P_SYNC_BOOTSTRAP_PEERS",
+                    "/ip4/10.0.0.2/tcp/4001,/ip4/10.0.0.3/tcp/4001",
+                ),
+                ("HOME", "/root"),
+            ]))
+            .with_overrides(Overrides {
+                port: Some(7000),
+                set: vec!["metrics.enabled=true".to_string()],
+                ..Default::default()
+            })
+            .load()
+            .unwrap();
+
+        let config = &loaded.config;
+        assert_eq!(config.port, 7000);
+        assert_eq!(config.security.rate_limit_per_minute, 120);
+        assert_eq!(config.security.rate_limit_burst, 20);
+        assert_eq!(config.bootstrap_peers.len(), 2);
+        assert!(config.metrics.enabled);
+        // ファイルにも環境変数にもないキーはデフォルト値
+        assert_eq!(config.security.max_key_length, 256);
+        assert_eq!(loaded.data_dir, dir.path());
+        assert!(loaded.unknown_keys.is_empty());
+        assert!(loaded.validate().is_ok());
+    }
+
+    #[test]
+    fn test_reserved_env() {
+        let dir = tempdir().unwrap();
+        let loaded = ConfigLoader::new(dir.path().to_path_buf())
+            .with_env(env(&[
+                ("P2P_SYNC_PASSPHRASE", "secret"),
+                ("P2P_SYNC_NEW_PASSPHRASE", "secret"),
+                ("P2P_SYNC_DATA_DIR", "/tmp/p2p-sync-test"),
+            ]))
+            .load()
+            .unwrap();
+
+        assert!(loaded.unknown_keys.is_empty());
+        assert_eq!(loaded.data_dir, Path::new("/tmp/p2p-sync-test"));
+        assert!(!toml::to_string(&loaded.config).unwrap().contains("secret"));
+    }
+
+    #[test]
+    fn test_set_values() {
+        let table = Overrides {
+            set: vec![
+                "http.token=abc".to_string(),
+                "http.enabled = true".to_string(),
+                "security.blocked_peers=[\"a\", \"b\"]".to_string(),
+                "namespaces.team.encrypt=true".to_string(),
+            ],
+            ..Default::default()
+        }
+        .to_toml()
+        .unwrap();
+
+        assert_eq!(table["http"]["token"].as_str(), Some("abc"));
+        assert_eq!(table["http"]["enabled"].as_bool(), Some(true));
+        assert_eq!(
+            table["security"]["blocked_peers"].as_array().unwrap().len(),
+            2
+        );
+        assert_eq!(table["namespaces"]["team"]["encrypt"].as_bool(), Some(true));
+
+        for invalid in ["port", "=1", "http..token=x"] {
+            let overrides = Overrides {
+                set: vec![invalid.to_string()],
+                ..Default::default()
+            };
+            assert!(overrides.to_toml().is_err(), "{invalid}");
+        }
+    }
+
+    #[test]
+    fn test_invalid_values() {
+        let dir = tempdir().unwrap();
+        let loader = ConfigLoader::new(dir.path().to_path_buf());
+
+        // 型が合わない値はキーと出どころ付きで失敗する
+        let error = loader
+            .clone()
+            .with_env(env(&[("P2P_SYNC_PORT", "not-a-port")]))
+            .load()
+            .unwrap_err();
+        assert!(format!("{error:#}").contains("port"));
+
+        let loaded = loader
+            .with_overrides(Overrides {
+                set: vec![
+                    "security.rate_limit_per_minute=0".to_string(),
+                    "security.blocked_peers=[\"not-a-peer\"]".to_string(),
+                    "key_distribution.admin_peers=[\"admin\"]".to_string(),
+                    "trust.min_recommenders=0".to_string(),
+                    "trust.weights.admin=-1.0".to_string(),
+                    "http.enabled=true".to_string(),
+                    "metrics.listen_addr=localhost".to_string(),
+                    "security.rate_limt_burst=5".to_string(),
+                ],
+                ..Default::default()
+            })
+            .load()
+            .unwrap();
+
+        assert_eq!(loaded.unknown_keys, vec!["security.rate_limt_burst"]);
+        let message = loaded.validate().unwrap_err().to_string();
+        for expected in [
+            "security.rate_limit_per_minute: must be greater than 0",
+            "security.blocked_peers: not a PeerId: \"not-a-peer\"",
+            "key_distribution.admin_peers: not a PeerId: \"admin\"",
+            "trust.min_recommenders: must be greater than 0",
+            "trust.weights: not a PeerId: \"admin\"",
+            "trust.weights.admin: must be a number of at least 0",
+            "http.token: must be set",
+            "metrics.listen_addr: not a socket address",
+            "security.rate_limt_burst: unknown key",
+        ] {
+            assert!(message.contains(expected), "{expected} in {message}");
+        }
+        assert!(Config::default().validate().is_ok());
+    }
+
+    #[test]
+    fn test_explicit_path_must_exist() {
+        let dir = tempdir().unwrap();
+        let loader = ConfigLoader::new(dir.path().to_path_buf())
+            .with_path(Some(dir.path().join("missing.toml")));
+        assert!(loader.load().is_err());
+    }
+}
@@ -5,13 +5,15 @@ use std::net::IpAddr;
 use std::sync::Arc;
 use tokio::sync::RwLock;
 
-use crate::security::AccessControl;
+use crate::metrics::{Metrics, ReasonLabels};
+use crate::security::{AccessControl, SecurityConfig};
 
 type ActiveConnections = Arc<RwLock<HashMap<PeerId, IpAddr>>>;
 
 pub struct ConnectionManager {
     access_control: Arc<AccessControl>,
     active_connections: ActiveConnections,
+    metrics: Metrics,
 }
 
 impl ConnectionManager {
@@ -20,25 +22,44 @@ impl ConnectionManager {
         Self {
             access_control: Arc::new(access_control),
             active_connections: Arc::new(RwLock::new(HashMap::new())),
+            metrics: Metrics::default(),
         }
     }
 
+    /// Count accepted and rejected connections in `metrics`
+    pub fn with_metrics(mut self, metrics: Metrics) -> Self {
+        self.metrics = metrics;
+        self
+    }
+
     pub async fn handle_incoming_connection(
         &self,
         peer_id: PeerId,
         remote_addr: IpAddr,
     ) -> Result<()> {
+        // ピア許可チェック（拒否したピアが IP の接続枠を消費しないよう先に行う）
+        if let Err(e) = self.access_control.check_peer_allowed(&peer_id).await {
+            self.reject("access_denied");
+            return Err(e);
+        }
+
         // IP制限チェック
-        self.access_control
+        if let Err(e) = self
+            .access_control
             .check_connection_limit(&remote_addr)
-            .await?;
-
-        // ピア許可チェック
-        self.access_control.check_peer_allowed(&peer_id).await?;
+            .await
+        {
+            self.reject("ip_limit");
+            return Err(e);
+        }
 
         // 接続を記録
         let mut connections = self.active_connections.write().await;
         connections.insert(peer_id, remote_addr);
+        self.metrics.connections_accepted.inc();
+        self.metrics
+            .active_connections
+            .set(connections.len() as i64);
 
         tracing::info!(
             "Connection accepted from peer: {} ({})",
@@ -48,14 +69,31 @@ impl ConnectionManager {
         Ok(())
     }
 
+    /// Whether `peer_id` would pass access control, used to keep rejected
+    /// peers out of discovery
+    pub async fn is_peer_allowed(&self, peer_id: &PeerId) -> bool {
+        self.access_control
+            .check_peer_allowed(peer_id)
+            .await
+            .is_ok()
+    }
+
     pub async fn handle_connection_closed(&self, peer_id: &PeerId) {
         let mut connections = self.active_connections.write().await;
         if let Some(ip) = connections.remove(peer_id) {
+            self.metrics
+                .active_connections
+                .set(connections.len() as i64);
             self.access_control.release_connection(&ip).await;
             tracing::info!("Connection closed for peer: {} ({})", peer_id, ip);
         }
     }
 
+    /// Apply a reloaded `SecurityConfig` to the following connections
+    pub async fn set_security_config(&self, config: SecurityConfig) {
+        self.access_control.set_config(config).await;
+    }
+
     pub async fn get_active_connections(&self) -> HashMap<PeerId, IpAddr> {
         self.active_connections.read().await.clone()
     }
@@ -63,11 +101,17 @@ impl ConnectionManager {
     pub async fn get_connection_count(&self) -> usize {
         self.active_connections.read().await.len()
     }
+
+    fn reject(&self, reason: &'static str) {
+        self.metrics
+            .connections_rejected
+            .get_or_create(&ReasonLabels { reason })
+            .inc();
+    }
 }
 #[cfg(test)]
 mod tests {
     use super::*;
-    use crate::security::SecurityConfig;
     use std::net::Ipv4Addr;
 
     fn create_test_connection_manager() -> ConnectionManager {
@@ -223,4 +267,62 @@ mod tests {
         // All connections should be closed
         assert_eq!(manager.get_connection_count().await, 0);
     }
+
+    #[tokio::test]
+    async fn test_rejected_peer_does_not_use_ip_slot() {
+        let blocked = create_test_peer_id();
+        let security_config = SecurityConfig {
+            max_connections_per_ip: 1,
+            blocked_peers: [blocked.to_string()].into_iter().collect(),
+            ..Default::default()
+        };
+        let manager = ConnectionManager::new(AccessControl::new(security_config));
+        let remote_addr = IpAddr::V4(Ipv4Addr::new(192, 168, 1, 1));
+
+        assert!(!manager.is_peer_allowed(&blocked).await);
+        assert!(manager
+            .handle_incoming_connection(blocked, remote_addr)
+            .await
+            .is_err());
+        assert_eq!(manager.get_connection_count().await, 0);
+
+        let peer = create_test_peer_id();
+        assert!(manager.is_peer_allowed(&peer).await);
+        assert!(manager
+            .handle_incoming_connection(peer, remote_addr)
+            .await
+            .is_ok());
+    }
+
+    #[tokio::test]
+    async fn test_connection_metrics() {
+        let metrics = Metrics::default();
+        let security_config = SecurityConfig {
+            max_connections_per_ip: 1,
+            ..Default::default()
+        };
+        let manager = ConnectionManager::new(AccessControl::new(security_config))
+            .with_metrics(metrics.clone());
+        let remote_addr = IpAddr::V4(Ipv4Addr::new(192, 168, 1, 1));
+
+        let peer = create_test_peer_id();
+        manager
+            .handle_incoming_connection(peer, remote_addr)
+            .await
+            .unwrap();
+        assert!(manager
+            .handle_incoming_connection(create_test_peer_id(), remote_addr)
+            .await
+            .is_err());
+        assert_eq!(metrics.connections_accepted.get(), 1);
+        assert_eq!(metrics.active_connections.get(), 1);
+        let ip_limit = ReasonLabels { reason: "ip_limit" };
+        assert_eq!(
+            metrics.connections_rejected.get_or_create(&ip_limit).get(),
+            1
+        );
+
+        manager.handle_connection_closed(&peer).await;
+        assert_eq!(metrics.active_connections.get(), 0);
+    }
 }
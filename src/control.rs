@@ -0,0 +1,687 @@
+use anyhow::{Context, Result};
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+use std::fmt;
+use std::path::{Path, PathBuf};
+use tokio::sync::broadcast::error::RecvError;
+use tokio::sync::{mpsc, oneshot};
+
+use crate::crypto::SignedData;
+use crate::hlc::HlcTimestamp;
+use crate::namespace::DEFAULT_NAMESPACE;
+use crate::security::Rejection;
+use crate::trust::Recommendation;
+use crate::watch::{ChangeEvent, ChangeFeed};
+use crate::whitelist::{PendingRequest, Revocation, WhitelistEntry};
+
+/// File name of the control socket inside the data directory
+pub const SOCKET_FILE: &str = "control.sock";
+
+/// Requests accepted on the control socket. Each request is one JSON object
+/// per line, e.g. `{"command":"put","key":"k","value":"v"}`.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(tag = "command", rename_all = "kebab-case")]
+pub enum ControlRequest {
+    Put {
+        key: String,
+        value: String,
+        /// Defaults to the `default` namespace
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        namespace: Option<String>,
+    },
+    Get {
+        key: String,
+        /// Defaults to the `default` namespace
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        namespace: Option<String>,
+        /// Read the value the key had at this time instead of the current one
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        at: Option<DateTime<Utc>>,
+    },
+    /// Store binary data. Values larger than `max_value_length` are chunked.
+    PutBytes {
+        key: String,
+        /// Base64 encoded data
+        value: String,
+        /// Defaults to the `default` namespace
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        namespace: Option<String>,
+    },
+    /// Fetch a value as bytes, including binary values
+    GetBytes {
+        key: String,
+        /// Defaults to the `default` namespace
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        namespace: Option<String>,
+    },
+    Delete {
+        key: String,
+        /// Defaults to the `default` namespace
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        namespace: Option<String>,
+    },
+    /// Versions of a key kept in the history
+    History {
+        key: String,
+        /// Defaults to the `default` namespace
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        namespace: Option<String>,
+    },
+    /// Publish a version from the history again as a new write
+    Rollback {
+        key: String,
+        version: u64,
+        /// Defaults to the `default` namespace
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        namespace: Option<String>,
+    },
+    List {
+        /// Only return keys starting with this prefix
+        #[serde(default)]
+        prefix: Option<String>,
+        /// Defaults to the `default` namespace
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        namespace: Option<String>,
+    },
+    Status,
+    Peers,
+    Info,
+    WhitelistAdd {
+        peer_id: String,
+        name: Option<String>,
+        expires_in_hours: Option<u64>,
+        /// Base64 or hex encoded protobuf public key
+        public_key: Option<String>,
+    },
+    WhitelistRemove {
+        peer_id: String,
+    },
+    WhitelistList,
+    WhitelistCheck {
+        peer_id: String,
+    },
+    /// Whitelist requests waiting for approval
+    WhitelistPending,
+    WhitelistApprove {
+        peer_id: String,
+    },
+    WhitelistReject {
+        peer_id: String,
+    },
+    /// Peers revoked by an admin peer
+    WhitelistRevoked,
+    /// Trust recommendations received, of one peer or of all peers
+    WhitelistRecommendations {
+        #[serde(default)]
+        peer_id: Option<String>,
+    },
+    AnnounceKey,
+    RequestKeys,
+    RequestWhitelist {
+        name: Option<String>,
+    },
+    RecommendPeer {
+        peer_id: String,
+        name: Option<String>,
+    },
+    /// Withdraw a recommendation made by this node
+    RevokeRecommendation {
+        peer_id: String,
+    },
+    /// Revoke a compromised peer on every node, only from an admin peer
+    RevokePeer {
+        peer_id: String,
+        reason: Option<String>,
+    },
+    Cleanup,
+    ReloadCache,
+    /// Re-read the config file and whitelist.db, applying `[security]` and
+    /// disconnecting peers that are no longer allowed
+    Reload,
+    /// Stream a `Change` response for every matching change until the client
+    /// disconnects
+    Watch {
+        #[serde(default)]
+        prefix: Option<String>,
+        /// Only stream changes to this namespace, all namespaces if `None`
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        namespace: Option<String>,
+    },
+}
+
+impl ControlRequest {
+    /// Namespace a key-value request applies to
+    pub fn namespace(&self) -> &str {
+        match self {
+            ControlRequest::Put { namespace, .. }
+            | ControlRequest::Get { namespace, .. }
+            | ControlRequest::PutBytes { namespace, .. }
+            | ControlRequest::GetBytes { namespace, .. }
+            | ControlRequest::Delete { namespace, .. }
+            | ControlRequest::History { namespace, .. }
+            | ControlRequest::Rollback { namespace, .. }
+            | ControlRequest::List { namespace, .. } => {
+                namespace.as_deref().unwrap_or(DEFAULT_NAMESPACE)
+            }
+            _ => DEFAULT_NAMESPACE,
+        }
+    }
+}
+
+/// A connected peer as reported by `status` and `peers`
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct PeerConnection {
+    pub peer_id: String,
+    pub address: String,
+}
+
+/// A version of a key as reported by `history`
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct KeyVersion {
+    pub version: u64,
+    /// Binary values are shown as their size, `None` marks a delete
+    pub value: Option<String>,
+    pub timestamp: HlcTimestamp,
+}
+
+/// Responses written back on the control socket, one JSON object per line
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(tag = "result", rename_all = "kebab-case")]
+pub enum ControlResponse {
+    Done {
+        message: String,
+    },
+    Value {
+        key: String,
+        value: Option<String>,
+    },
+    Bytes {
+        key: String,
+        /// Base64 encoded data
+        value: Option<String>,
+    },
+    Items {
+        items: Vec<(String, String)>,
+    },
+    History {
+        key: String,
+        versions: Vec<KeyVersion>,
+    },
+    Peers {
+        peers: Vec<PeerConnection>,
+    },
+    Info {
+        peer_id: String,
+        listen_addrs: Vec<String>,
+        connections: usize,
+    },
+    Whitelist {
+        entries: Vec<WhitelistEntry>,
+    },
+    Whitelisted {
+        peer_id: String,
+        whitelisted: bool,
+        /// Whitelisted or trusted through recommendations
+        #[serde(default)]
+        trusted: bool,
+    },
+    PendingRequests {
+        requests: Vec<PendingRequest>,
+    },
+    Revocations {
+        revocations: Vec<SignedData<Revocation>>,
+    },
+    Recommendations {
+        recommendations: Vec<Recommendation>,
+    },
+    Change(ChangeEvent),
+    Error {
+        message: String,
+        #[serde(default)]
+        kind: ErrorKind,
+    },
+}
+
+impl ControlResponse {
+    /// Failure of the node itself rather than of the request
+    pub fn error(message: impl Into<String>) -> Self {
+        ControlResponse::Error {
+            message: message.into(),
+            kind: ErrorKind::Internal,
+        }
+    }
+
+    /// Response for a failed request, classified by the error it failed with
+    pub fn from_error(error: &anyhow::Error) -> Self {
+        let kind = if error.downcast_ref::<Rejection>().is_some() {
+            ErrorKind::Invalid
+        } else if let Some(error) = error.downcast_ref::<RequestError>() {
+            error.kind
+        } else {
+            ErrorKind::Internal
+        };
+
+        ControlResponse::Error {
+            message: format!("{error:#}"),
+            kind,
+        }
+    }
+}
+
+/// Why a request failed, so clients such as the HTTP gateway can tell bad
+/// input and refusals from failures of the node
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum ErrorKind {
+    /// The request or its key/value is malformed or over a limit
+    Invalid,
+    /// The node may not perform the request, e.g. a namespace ACL
+    Forbidden,
+    /// The namespace or version does not exist
+    NotFound,
+    #[default]
+    Internal,
+}
+
+/// A request error of a kind other than `ErrorKind::Internal`
+#[derive(Debug)]
+pub struct RequestError {
+    kind: ErrorKind,
+    message: String,
+}
+
+impl RequestError {
+    pub fn invalid(message: impl Into<String>) -> Self {
+        Self {
+            kind: ErrorKind::Invalid,
+            message: message.into(),
+        }
+    }
+
+    pub fn forbidden(message: impl Into<String>) -> Self {
+        Self {
+            kind: ErrorKind::Forbidden,
+            message: message.into(),
+        }
+    }
+
+    pub fn not_found(message: impl Into<String>) -> Self {
+        Self {
+            kind: ErrorKind::NotFound,
+            message: message.into(),
+        }
+    }
+}
+
+impl fmt::Display for RequestError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(&self.message)
+    }
+}
+
+impl std::error::Error for RequestError {}
+
+/// A request forwarded from a control connection to the node's event loop
+pub struct ControlCommand {
+    pub request: ControlRequest,
+    pub reply: oneshot::Sender<ControlResponse>,
+}
+
+pub fn socket_path(data_dir: &Path) -> PathBuf {
+    data_dir.join(SOCKET_FILE)
+}
+
+/// Listen on `path` and forward every request to `commands`. `watch`
+/// requests are served directly from `changes`.
+///
+/// The socket is only accessible by the owner of the node process.
+#[cfg(unix)]
+pub fn spawn_server(
+    path: &Path,
+    commands: mpsc::Sender<ControlCommand>,
+    changes: ChangeFeed,
+) -> Result<tokio::task::JoinHandle<()>> {
+    use std::os::unix::fs::PermissionsExt;
+    use tokio::net::UnixListener;
+
+    if path.exists() {
+        // 前回のプロセスが残したソケットを使っているノードがいないか確認する
+        if std::os::unix::net::UnixStream::connect(path).is_ok() {
+            anyhow::bail!("Another node is already listening on {}", path.display());
+        }
+        std::fs::remove_file(path)?;
+    }
+
+    let listener = UnixListener::bind(path)
+        .with_context(|| format!("Failed to bind control socket {}", path.display()))?;
+    std::fs::set_permissions(path, std::fs::Permissions::from_mode(0o600))?;
+
+    Ok(tokio::spawn(async move {
+        loop {
+            match listener.accept().await {
+                Ok((stream, _)) => {
+                    let commands = commands.clone();
+                    let changes = changes.clone();
+                    tokio::spawn(async move {
+                        if let Err(e) = serve_connection(stream, commands, changes).await {
+                            tracing::warn!("Control connection failed: {}", e);
+                        }
+                    });
+                }
+                Err(e) => {
+                    tracing::warn!("Failed to accept control connection: {}", e);
+                }
+            }
+        }
+    }))
+}
+
+#[cfg(unix)]
+async fn serve_connection(
+    stream: tokio::net::UnixStream,
+    commands: mpsc::Sender<ControlCommand>,
+    changes: ChangeFeed,
+) -> Result<()> {
+    use tokio::io::{AsyncBufReadExt, BufReader};
+
+    let (reader, mut writer) = stream.into_split();
+    let mut lines = BufReader::new(reader).lines();
+
+    while let Some(line) = lines.next_line().await? {
+        if line.trim().is_empty() {
+            continue;
+        }
+
+        let response = match serde_json::from_str::<ControlRequest>(&line) {
+            Ok(ControlRequest::Watch { prefix, namespace }) => {
+                let mut subscription = changes.subscribe(namespace, prefix);
+                loop {
+                    tokio::select! {
+                        event = subscription.recv() => {
+                            let response = match event {
+                                Ok(event) => ControlResponse::Change(event),
+                                Err(RecvError::Lagged(missed)) => {
+                                    ControlResponse::error(format!("Missed {missed} changes"))
+                                }
+                                Err(RecvError::Closed) => return Ok(()),
+                            };
+                            write_response(&mut writer, &response).await?;
+                        }
+                        // クライアントが切断したら購読を終了する
+                        line = lines.next_line() => {
+                            if line?.is_none() {
+                                return Ok(());
+                            }
+                        }
+                    }
+                }
+            }
+            Ok(request) => dispatch(&commands, request).await,
+            Err(e) => ControlResponse::Error {
+                message: format!("Invalid request: {e}"),
+                kind: ErrorKind::Invalid,
+            },
+        };
+
+        write_response(&mut writer, &response).await?;
+    }
+
+    Ok(())
+}
+
+#[cfg(unix)]
+async fn write_response(
+    writer: &mut tokio::net::unix::OwnedWriteHalf,
+    response: &ControlResponse,
+) -> Result<()> {
+    use tokio::io::AsyncWriteExt;
+
+    let mut json = serde_json::to_vec(response)?;
+    json.push(b'\n');
+    writer.write_all(&json).await?;
+    Ok(())
+}
+
+/// Hand a request to the event loop and wait for its response
+pub async fn dispatch(
+    commands: &mpsc::Sender<ControlCommand>,
+    request: ControlRequest,
+) -> ControlResponse {
+    let (reply, response) = oneshot::channel();
+
+    if commands
+        .send(ControlCommand { request, reply })
+        .await
+        .is_err()
+    {
+        return ControlResponse::error("Node is shutting down");
+    }
+
+    response
+        .await
+        .unwrap_or_else(|_| ControlResponse::error("Node dropped the request"))
+}
+
+/// Send one request to a running node and wait for the response
+#[cfg(unix)]
+pub async fn send_request(path: &Path, request: &ControlRequest) -> Result<ControlResponse> {
+    use tokio::io::{AsyncBufReadExt, AsyncWriteExt, BufReader};
+    use tokio::net::UnixStream;
+
+    let stream = UnixStream::connect(path).await.with_context(|| {
+        format!(
+            "Failed to connect to {} (is `p2p-sync start` running?)",
+            path.display()
+        )
+    })?;
+    let (reader, mut writer) = stream.into_split();
+
+    let mut json = serde_json::to_vec(request)?;
+    json.push(b'\n');
+    writer.write_all(&json).await?;
+
+    let line = BufReader::new(reader)
+        .lines()
+        .next_line()
+        .await?
+        .context("Node closed the control connection")?;
+
+    Ok(serde_json::from_str(&line)?)
+}
+
+/// Subscribe to changes on a running node
+#[cfg(unix)]
+pub async fn watch(
+    path: &Path,
+    namespace: Option<String>,
+    prefix: Option<String>,
+) -> Result<WatchStream> {
+    use tokio::io::{AsyncBufReadExt, AsyncWriteExt, BufReader};
+    use tokio::net::UnixStream;
+
+    let stream = UnixStream::connect(path).await.with_context(|| {
+        format!(
+            "Failed to connect to {} (is `p2p-sync start` running?)",
+            path.display()
+        )
+    })?;
+    let (reader, mut writer) = stream.into_split();
+
+    let mut json = serde_json::to_vec(&ControlRequest::Watch { prefix, namespace })?;
+    json.push(b'\n');
+    writer.write_all(&json).await?;
+
+    Ok(WatchStream {
+        lines: BufReader::new(reader).lines(),
+        _writer: writer,
+    })
+}
+
+/// Responses streamed by a `watch` request
+#[cfg(unix)]
+pub struct WatchStream {
+    lines: tokio::io::Lines<tokio::io::BufReader<tokio::net::unix::OwnedReadHalf>>,
+    // 書き込み側を閉じるとサーバーは購読を終了する
+    _writer: tokio::net::unix::OwnedWriteHalf,
+}
+
+#[cfg(unix)]
+impl WatchStream {
+    /// Next response, or `None` when the node closed the connection
+    pub async fn next(&mut self) -> Result<Option<ControlResponse>> {
+        match self.lines.next_line().await? {
+            Some(line) => Ok(Some(serde_json::from_str(&line)?)),
+            None => Ok(None),
+        }
+    }
+}
+
+#[cfg(all(test, unix))]
+mod tests {
+    use super::*;
+    use tempfile::tempdir;
+
+    #[test]
+    fn test_request_wire_format() {
+        let request: ControlRequest =
+            serde_json::from_str(r#"{"command":"put","key":"k","value":"v"}"#).unwrap();
+        assert!(
+            matches!(request, ControlRequest::Put { key, value, namespace: None } if key == "k" && value == "v")
+        );
+
+        let json = serde_json::to_string(&ControlRequest::WhitelistList).unwrap();
+        assert_eq!(json, r#"{"command":"whitelist-list"}"#);
+
+        // prefix は省略可能
+        let request: ControlRequest = serde_json::from_str(r#"{"command":"list"}"#).unwrap();
+        assert!(matches!(
+            request,
+            ControlRequest::List {
+                prefix: None,
+                namespace: None
+            }
+        ));
+        assert_eq!(request.namespace(), DEFAULT_NAMESPACE);
+
+        let request: ControlRequest =
+            serde_json::from_str(r#"{"command":"get","key":"k","namespace":"secrets"}"#).unwrap();
+        assert_eq!(request.namespace(), "secrets");
+    }
+
+    #[test]
+    fn test_error_kinds() {
+        let kind = |error: anyhow::Error| match ControlResponse::from_error(&error) {
+            ControlResponse::Error { kind, .. } => kind,
+            other => panic!("unexpected response: {other:?}"),
+        };
+        assert_eq!(kind(Rejection::EmptyKey.into()), ErrorKind::Invalid);
+        assert_eq!(
+            kind(RequestError::forbidden("not a writer").into()),
+            ErrorKind::Forbidden
+        );
+        assert_eq!(
+            kind(anyhow::Error::from(RequestError::not_found("missing")).context("lookup")),
+            ErrorKind::NotFound
+        );
+        assert_eq!(kind(anyhow::anyhow!("disk full")), ErrorKind::Internal);
+
+        // kind のない古い応答は内部エラーとして扱う
+        let response: ControlResponse =
+            serde_json::from_str(r#"{"result":"error","message":"failed"}"#).unwrap();
+        assert!(matches!(
+            response,
+            ControlResponse::Error {
+                kind: ErrorKind::Internal,
+                ..
+            }
+        ));
+    }
+
+    #[tokio::test]
+    async fn test_round_trip_through_socket() {
+        let dir = tempdir().unwrap();
+        let path = socket_path(dir.path());
+        let (tx, mut rx) = mpsc::channel(4);
+
+        let server = spawn_server(&path, tx, ChangeFeed::new()).unwrap();
+
+        // Stand-in for the node event loop
+        tokio::spawn(async move {
+            while let Some(command) = rx.recv().await {
+                let response = match command.request {
+                    ControlRequest::Get { key, .. } => ControlResponse::Value {
+                        key,
+                        value: Some("value".to_string()),
+                    },
+                    _ => ControlResponse::error("unexpected"),
+                };
+                let _ = command.reply.send(response);
+            }
+        });
+
+        let response = send_request(
+            &path,
+            &ControlRequest::Get {
+                key: "key".to_string(),
+                namespace: None,
+                at: None,
+            },
+        )
+        .await
+        .unwrap();
+
+        match response {
+            ControlResponse::Value { key, value } => {
+                assert_eq!(key, "key");
+                assert_eq!(value, Some("value".to_string()));
+            }
+            other => panic!("Unexpected response: {other:?}"),
+        }
+
+        server.abort();
+    }
+
+    #[tokio::test]
+    async fn test_second_server_is_rejected() {
+        let dir = tempdir().unwrap();
+        let path = socket_path(dir.path());
+        let (tx, _rx) = mpsc::channel(1);
+
+        let server = spawn_server(&path, tx.clone(), ChangeFeed::new()).unwrap();
+        assert!(spawn_server(&path, tx, ChangeFeed::new()).is_err());
+
+        server.abort();
+    }
+
+    #[tokio::test]
+    async fn test_watch_streams_changes() {
+        let dir = tempdir().unwrap();
+        let path = socket_path(dir.path());
+        let (tx, _rx) = mpsc::channel(1);
+        let storage = crate::storage::Storage::open(dir.path().join("test.db"), None).unwrap();
+
+        let server = spawn_server(&path, tx, storage.changes()).unwrap();
+        let mut stream = watch(&path, None, Some("app/".to_string())).await.unwrap();
+
+        // サーバーが購読を開始するまで書き込みを繰り返す
+        let event = loop {
+            storage.put(DEFAULT_NAMESPACE, "other", "ignored").unwrap();
+            storage.put(DEFAULT_NAMESPACE, "app/name", "value").unwrap();
+            if let Ok(response) =
+                tokio::time::timeout(std::time::Duration::from_millis(50), stream.next()).await
+            {
+                break response.unwrap().unwrap();
+            }
+        };
+
+        match event {
+            ControlResponse::Change(event) => {
+                assert_eq!(event.key, "app/name");
+                assert_eq!(event.new, Some("value".to_string()));
+            }
+            other => panic!("Unexpected response: {other:?}"),
+        }
+
+        server.abort();
+    }
+}
@@ -1,8 +1,14 @@
 use anyhow::Result;
-use libp2p::identity::Keypair;
+use base64::{engine::general_purpose::STANDARD as BASE64, Engine};
+use libp2p::identity::{Keypair, PublicKey};
+use libp2p::PeerId;
 use serde::{Deserialize, Serialize};
 use sha2::{Digest, Sha256};
 
+use crate::chunks::ChunkManifest;
+use crate::hlc::HlcTimestamp;
+use crate::storage::{Entry, Value};
+
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct SignedData<T: Serialize> {
     pub data: T,
@@ -52,11 +58,63 @@ impl<T: Serialize> SignedData<T> {
     }
 }
 
+/// Public key embedded in a PeerId. Ed25519 and secp256k1 keys are short
+/// enough to be inlined, PeerIds of RSA keys only hold a hash of the key.
+pub fn public_key_from_peer_id(peer_id: &PeerId) -> Option<PublicKey> {
+    // identity マルチハッシュ（コード 0）の場合のみダイジェストが鍵そのもの
+    let multihash = peer_id.as_ref();
+    if multihash.code() != 0 {
+        return None;
+    }
+    PublicKey::try_decode_protobuf(multihash.digest()).ok()
+}
+
+// 変更の作成者が署名する内容（保存される行とネームスペース）
+#[derive(Serialize)]
+struct EntryContent<'a> {
+    namespace: &'a str,
+    key: &'a str,
+    value: Option<&'a Value>,
+    timestamp: &'a HlcTimestamp,
+}
+
+impl<'a> EntryContent<'a> {
+    fn new(namespace: &'a str, entry: &'a Entry) -> Self {
+        Self {
+            namespace,
+            key: &entry.key,
+            value: entry.value.as_ref(),
+            timestamp: &entry.timestamp,
+        }
+    }
+
+    fn hash(&self) -> Result<Vec<u8>> {
+        Ok(Sha256::digest(bincode::serialize(self)?).to_vec())
+    }
+}
+
+/// Signature of the author of `entry` over the row and its namespace. It is
+/// stored with the row so the entry can be verified wherever it travels.
+pub fn sign_entry(keypair: &Keypair, namespace: &str, entry: &Entry) -> Result<Vec<u8>> {
+    Ok(keypair.sign(&EntryContent::new(namespace, entry).hash()?)?)
+}
+
+/// Whether `entry.signature` was made by `public_key` for this row of
+/// `namespace`. Unsigned entries do not verify.
+pub fn verify_entry(public_key: &PublicKey, namespace: &str, entry: &Entry) -> Result<bool> {
+    match &entry.signature {
+        Some(signature) => {
+            Ok(public_key.verify(&EntryContent::new(namespace, entry).hash()?, signature))
+        }
+        None => Ok(false),
+    }
+}
+
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct SignedSyncMessage {
     pub key: String,
     pub value: Option<String>,
-    pub timestamp: chrono::DateTime<chrono::Utc>,
+    pub timestamp: crate::hlc::HlcTimestamp,
     pub operation: SyncOperation,
 }
 
@@ -64,6 +122,10 @@ pub struct SignedSyncMessage {
 pub enum SyncOperation {
     Put,
     Delete,
+    /// `value` is the base64 encoded data
+    PutBytes,
+    /// `value` is the JSON encoded chunk manifest
+    PutChunked,
 }
 
 impl From<crate::sync::SyncMessage> for SignedSyncMessage {
@@ -85,6 +147,26 @@ impl From<crate::sync::SyncMessage> for SignedSyncMessage {
                 timestamp,
                 operation: SyncOperation::Delete,
             },
+            crate::sync::SyncMessage::PutBytes {
+                key,
+                value,
+                timestamp,
+            } => Self {
+                key,
+                value: Some(BASE64.encode(value)),
+                timestamp,
+                operation: SyncOperation::PutBytes,
+            },
+            crate::sync::SyncMessage::PutChunked {
+                key,
+                manifest,
+                timestamp,
+            } => Self {
+                key,
+                value: manifest.to_json().ok(),
+                timestamp,
+                operation: SyncOperation::PutChunked,
+            },
         }
     }
 }
@@ -101,6 +183,22 @@ impl From<SignedSyncMessage> for crate::sync::SyncMessage {
                 key: msg.key,
                 timestamp: msg.timestamp,
             },
+            SyncOperation::PutBytes => crate::sync::SyncMessage::PutBytes {
+                key: msg.key,
+                value: msg
+                    .value
+                    .and_then(|value| BASE64.decode(value).ok())
+                    .unwrap_or_default(),
+                timestamp: msg.timestamp,
+            },
+            SyncOperation::PutChunked => crate::sync::SyncMessage::PutChunked {
+                key: msg.key,
+                manifest: msg
+                    .value
+                    .and_then(|value| ChunkManifest::from_json(&value).ok())
+                    .unwrap_or_default(),
+                timestamp: msg.timestamp,
+            },
         }
     }
 }
@@ -146,6 +244,43 @@ mod tests {
         assert!(signed.verify_with_public_key(&public_key).unwrap());
     }
 
+    #[test]
+    fn test_public_key_from_peer_id() {
+        let keypair = identity::Keypair::generate_ed25519();
+        let peer_id = keypair.public().to_peer_id();
+        assert_eq!(public_key_from_peer_id(&peer_id), Some(keypair.public()));
+
+        // 公開鍵としてデコードできない PeerId
+        assert_eq!(public_key_from_peer_id(&PeerId::random()), None);
+    }
+
+    #[test]
+    fn test_sign_and_verify_entry() {
+        let keypair = identity::Keypair::generate_ed25519();
+        let origin = keypair.public().to_peer_id().to_string();
+        let mut entry = Entry {
+            key: "k".to_string(),
+            value: Some(Value::Text("v".to_string())),
+            timestamp: HlcTimestamp::new(1, 0, origin),
+            signature: None,
+        };
+        assert!(!verify_entry(&keypair.public(), "config", &entry).unwrap());
+
+        entry.signature = Some(sign_entry(&keypair, "config", &entry).unwrap());
+        assert!(verify_entry(&keypair.public(), "config", &entry).unwrap());
+
+        // 別のネームスペースや値には使えない
+        assert!(!verify_entry(&keypair.public(), "default", &entry).unwrap());
+        let tombstone = Entry {
+            value: None,
+            ..entry.clone()
+        };
+        assert!(!verify_entry(&keypair.public(), "config", &tombstone).unwrap());
+
+        let other = identity::Keypair::generate_ed25519();
+        assert!(!verify_entry(&other.public(), "config", &entry).unwrap());
+    }
+
     #[test]
     fn test_tampered_data() {
         let keypair = identity::Keypair::generate_ed25519();
@@ -165,13 +300,13 @@ mod tests {
 
     #[test]
     fn test_sync_message_conversion() {
+        use crate::hlc::HybridClock;
         use crate::sync::SyncMessage;
-        use chrono::Utc;
 
         let put_msg = SyncMessage::Put {
             key: "test_key".to_string(),
             value: "test_value".to_string(),
-            timestamp: Utc::now(),
+            timestamp: HybridClock::new("peer").now(),
         };
 
         let signed_msg: SignedSyncMessage = put_msg.clone().into();
@@ -188,4 +323,27 @@ mod tests {
             _ => panic!("Expected Put message"),
         }
     }
+
+    #[test]
+    fn test_binary_sync_message_conversion() {
+        use crate::hlc::HybridClock;
+        use crate::sync::SyncMessage;
+
+        let put_msg = SyncMessage::PutBytes {
+            key: "blob".to_string(),
+            value: vec![0, 255, 128],
+            timestamp: HybridClock::new("peer").now(),
+        };
+
+        let signed_msg: SignedSyncMessage = put_msg.into();
+        assert!(matches!(signed_msg.operation, SyncOperation::PutBytes));
+
+        match SyncMessage::from(signed_msg) {
+            SyncMessage::PutBytes { key, value, .. } => {
+                assert_eq!(key, "blob");
+                assert_eq!(value, vec![0, 255, 128]);
+            }
+            _ => panic!("Expected PutBytes message"),
+        }
+    }
 }
@@ -0,0 +1,117 @@
+use anyhow::{Context, Result};
+use libp2p::multiaddr::Protocol;
+use libp2p::{kad, Multiaddr, PeerId};
+use serde::{Deserialize, Serialize};
+use std::time::Duration;
+
+/// Peer discovery settings loaded from the `[discovery]` section of config.toml
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct DiscoveryConfig {
+    /// Interval between Kademlia bootstraps, 0 disables them
+    pub bootstrap_interval_secs: u64,
+    /// Interval between random walks (lookups of a random PeerId) that find
+    /// peers outside the routing table, 0 disables them
+    pub random_walk_interval_secs: u64,
+}
+
+impl Default for DiscoveryConfig {
+    fn default() -> Self {
+        Self {
+            bootstrap_interval_secs: 5 * 60,
+            random_walk_interval_secs: 60,
+        }
+    }
+}
+
+impl DiscoveryConfig {
+    pub fn random_walk_interval(&self) -> Option<Duration> {
+        (self.random_walk_interval_secs > 0)
+            .then(|| Duration::from_secs(self.random_walk_interval_secs))
+    }
+}
+
+pub type Kademlia = kad::Behaviour<kad::store::MemoryStore>;
+
+pub fn new_kad_behaviour(local_peer_id: PeerId, config: &DiscoveryConfig) -> Kademlia {
+    let mut kad_config = kad::Config::new(kad::PROTOCOL_NAME);
+    kad_config.set_periodic_bootstrap_interval(
+        (config.bootstrap_interval_secs > 0)
+            .then(|| Duration::from_secs(config.bootstrap_interval_secs)),
+    );
+
+    let mut kad = kad::Behaviour::with_config(
+        local_peer_id,
+        kad::store::MemoryStore::new(local_peer_id),
+        kad_config,
+    );
+    // 外部アドレスが確認できない環境でも他のノードのルーティングテーブルに載るようにする
+    kad.set_mode(Some(kad::Mode::Server));
+    kad
+}
+
+/// An entry of `Config.bootstrap_peers`
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct BootstrapPeer {
+    /// Taken from a trailing `/p2p/` component if there is one
+    pub peer_id: Option<PeerId>,
+    pub addr: Multiaddr,
+}
+
+pub fn parse_bootstrap_peer(addr: &str) -> Result<BootstrapPeer> {
+    let mut addr: Multiaddr = addr
+        .parse()
+        .with_context(|| format!("Invalid bootstrap address: {addr}"))?;
+
+    let peer_id = match addr.iter().last() {
+        Some(Protocol::P2p(peer_id)) => {
+            addr.pop();
+            Some(peer_id)
+        }
+        _ => None,
+    };
+
+    Ok(BootstrapPeer { peer_id, addr })
+}
+
+/// Look up a random PeerId to discover peers beyond the current routing table
+pub fn random_walk(kad: &mut Kademlia) {
+    kad.get_closest_peers(PeerId::random());
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_bootstrap_peer() {
+        let peer_id = PeerId::random();
+
+        let parsed =
+            parse_bootstrap_peer(&format!("/ip4/203.0.113.1/tcp/4001/p2p/{peer_id}")).unwrap();
+        assert_eq!(parsed.peer_id, Some(peer_id));
+        assert_eq!(parsed.addr, "/ip4/203.0.113.1/tcp/4001".parse().unwrap());
+
+        let parsed = parse_bootstrap_peer("/dns4/sync.example.com/tcp/4001").unwrap();
+        assert_eq!(parsed.peer_id, None);
+        assert_eq!(
+            parsed.addr,
+            "/dns4/sync.example.com/tcp/4001".parse().unwrap()
+        );
+
+        assert!(parse_bootstrap_peer("203.0.113.1:4001").is_err());
+    }
+
+    #[test]
+    fn test_intervals_can_be_disabled() {
+        let config = DiscoveryConfig {
+            bootstrap_interval_secs: 0,
+            random_walk_interval_secs: 0,
+        };
+        assert_eq!(config.random_walk_interval(), None);
+        assert_eq!(
+            DiscoveryConfig::default().random_walk_interval(),
+            Some(Duration::from_secs(60))
+        );
+    }
+}
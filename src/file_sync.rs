@@ -0,0 +1,682 @@
+use anyhow::{Context, Result};
+use libp2p::request_response::OutboundRequestId;
+use libp2p::PeerId;
+use notify::{RecursiveMode, Watcher};
+use std::collections::{HashMap, HashSet};
+use std::fs;
+use std::io::{BufWriter, Read, Write};
+use std::path::{Component, Path, PathBuf};
+use tokio::sync::mpsc;
+
+use crate::chunks::{
+    self, Behaviour, ChunkFetches, ChunkManifest, ChunkRequest, ChunkResponse, CHUNK_SIZE,
+};
+use crate::namespace::DEFAULT_NAMESPACE;
+use crate::security::SecurityConfig;
+use crate::storage::Storage;
+use crate::watch::{ChangeEvent, ChangeSubscription};
+
+/// Storage keys holding file manifests are `file:<relative path>`
+pub const KEY_PREFIX: &str = "file:";
+
+/// Suffix of the temporary files written while a file is assembled
+const TEMP_SUFFIX: &str = ".p2p-sync.tmp";
+
+/// Content-addressed cache of file chunks, used to serve peers
+pub struct ChunkStore {
+    dir: PathBuf,
+}
+
+impl ChunkStore {
+    pub fn new(dir: PathBuf) -> Result<Self> {
+        fs::create_dir_all(&dir)?;
+        Ok(Self { dir })
+    }
+
+    pub fn contains(&self, hash: &str) -> bool {
+        self.path(hash).is_some_and(|path| path.exists())
+    }
+
+    pub fn get(&self, hash: &str) -> Result<Option<Vec<u8>>> {
+        match self.path(hash) {
+            Some(path) if path.exists() => Ok(Some(fs::read(path)?)),
+            _ => Ok(None),
+        }
+    }
+
+    /// Store a chunk, returning its hash
+    pub fn insert(&self, data: &[u8]) -> Result<String> {
+        let hash = chunks::hash(data);
+        let path = self.path(&hash).expect("hex digest is a valid hash");
+
+        if !path.exists() {
+            fs::create_dir_all(path.parent().expect("chunk path has a parent"))?;
+            let tmp = path.with_extension("tmp");
+            fs::write(&tmp, data)?;
+            fs::rename(&tmp, &path)?;
+        }
+
+        Ok(hash)
+    }
+
+    fn path(&self, hash: &str) -> Option<PathBuf> {
+        // ハッシュ以外の文字列でキャッシュ外のパスを指せないようにする
+        if !chunks::is_valid_hash(hash) {
+            return None;
+        }
+        Some(self.dir.join(&hash[..2]).join(hash))
+    }
+}
+
+/// Split the file at `path` into chunks stored in `chunks`
+pub fn index_file(path: &Path, chunks: &ChunkStore) -> Result<ChunkManifest> {
+    let mut file = fs::File::open(path)?;
+    let mut manifest = ChunkManifest {
+        size: 0,
+        chunks: Vec::new(),
+    };
+    let mut buffer = vec![0; CHUNK_SIZE];
+
+    loop {
+        let mut filled = 0;
+        while filled < CHUNK_SIZE {
+            let read = file.read(&mut buffer[filled..])?;
+            if read == 0 {
+                break;
+            }
+            filled += read;
+        }
+        if filled == 0 {
+            break;
+        }
+
+        manifest.chunks.push(chunks.insert(&buffer[..filled])?);
+        manifest.size += filled as u64;
+    }
+
+    Ok(manifest)
+}
+
+/// Write the file described by `manifest` from cached chunks. The file is
+/// replaced atomically so readers never see a partial file.
+pub fn assemble_file(path: &Path, manifest: &ChunkManifest, chunks: &ChunkStore) -> Result<()> {
+    let file_name = path
+        .file_name()
+        .and_then(|name| name.to_str())
+        .context("Invalid file name")?;
+    let tmp = path.with_file_name(format!(".{file_name}{TEMP_SUFFIX}"));
+
+    if let Some(parent) = path.parent() {
+        fs::create_dir_all(parent)?;
+    }
+
+    // マニフェストの size はピアが決めるので、事前に確保せずチャンクごとに書き出す
+    let mut file = BufWriter::new(fs::File::create(&tmp)?);
+    for hash in &manifest.chunks {
+        let chunk = chunks
+            .get(hash)?
+            .with_context(|| format!("Missing chunk {hash}"))?;
+        file.write_all(&chunk)?;
+    }
+    file.into_inner().map_err(|e| e.into_error())?.sync_all()?;
+
+    fs::rename(&tmp, path)?;
+    Ok(())
+}
+
+/// Storage key for a file below `root`
+pub fn key_for_path(root: &Path, path: &Path) -> Option<String> {
+    let relative = path.strip_prefix(root).ok()?;
+    let mut parts = Vec::new();
+    for component in relative.components() {
+        match component {
+            Component::Normal(part) => parts.push(part.to_str()?),
+            _ => return None,
+        }
+    }
+
+    (!parts.is_empty()).then(|| format!("{KEY_PREFIX}{}", parts.join("/")))
+}
+
+/// Local path for a storage key, rejecting keys that would escape `root`
+pub fn path_for_key(root: &Path, key: &str) -> Option<PathBuf> {
+    let relative = key.strip_prefix(KEY_PREFIX)?;
+    let mut path = root.to_path_buf();
+    for part in relative.split('/') {
+        if part.is_empty() || part == "." || part == ".." || part.contains('\\') {
+            return None;
+        }
+        path.push(part);
+    }
+
+    Some(path)
+}
+
+fn is_temp_file(path: &Path) -> bool {
+    path.file_name()
+        .and_then(|name| name.to_str())
+        .is_some_and(|name| name.ends_with(TEMP_SUFFIX))
+}
+
+/// A write to apply to `Storage` after a local file changed
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum LocalChange {
+    Put { key: String, manifest: String },
+    Delete { key: String },
+}
+
+pub enum SyncEvent {
+    /// Paths below the root that changed on disk
+    Local(Vec<PathBuf>),
+    /// A file manifest changed in storage
+    Remote(ChangeEvent),
+}
+
+/// Mirrors a directory tree through file manifests in `Storage`
+pub struct DirectorySync {
+    root: PathBuf,
+    chunks: ChunkStore,
+    local_origin: String,
+    _watcher: notify::RecommendedWatcher,
+    fs_events: mpsc::UnboundedReceiver<notify::Result<notify::Event>>,
+    changes: ChangeSubscription,
+    /// Remote files waiting for chunks, by key
+    pending: HashMap<String, ChunkManifest>,
+    fetches: ChunkFetches,
+    max_object_size: usize,
+}
+
+impl DirectorySync {
+    pub fn new(
+        root: &Path,
+        data_dir: &Path,
+        storage: &Storage,
+        local_origin: String,
+    ) -> Result<Self> {
+        fs::create_dir_all(root)?;
+        let root = fs::canonicalize(root)?;
+
+        let (tx, fs_events) = mpsc::unbounded_channel();
+        let mut watcher = notify::recommended_watcher(move |event| {
+            let _ = tx.send(event);
+        })?;
+        watcher
+            .watch(&root, RecursiveMode::Recursive)
+            .with_context(|| format!("Failed to watch {}", root.display()))?;
+
+        Ok(Self {
+            chunks: ChunkStore::new(data_dir.join("chunks"))?,
+            changes: storage.changes().subscribe(
+                Some(DEFAULT_NAMESPACE.to_string()),
+                Some(KEY_PREFIX.to_string()),
+            ),
+            root,
+            local_origin,
+            _watcher: watcher,
+            fs_events,
+            pending: HashMap::new(),
+            fetches: ChunkFetches::default(),
+            max_object_size: SecurityConfig::default().max_object_size,
+        })
+    }
+
+    /// Refuse remote files larger than `max_object_size`
+    /// (`security.max_object_size`)
+    pub fn with_max_object_size(mut self, max_object_size: usize) -> Self {
+        self.max_object_size = max_object_size;
+        self
+    }
+
+    pub fn root(&self) -> &Path {
+        &self.root
+    }
+
+    pub async fn next_event(&mut self) -> SyncEvent {
+        use tokio::sync::broadcast::error::RecvError;
+
+        loop {
+            tokio::select! {
+                Some(event) = self.fs_events.recv() => {
+                    let mut paths = HashSet::new();
+                    let mut collect = |event: notify::Result<notify::Event>| match event {
+                        Ok(event) if !event.kind.is_access() => paths.extend(event.paths),
+                        Ok(_) => {}
+                        Err(e) => tracing::warn!("File watcher error: {}", e),
+                    };
+
+                    // 連続したイベントはまとめて処理する
+                    collect(event);
+                    while let Ok(event) = self.fs_events.try_recv() {
+                        collect(event);
+                    }
+
+                    if !paths.is_empty() {
+                        return SyncEvent::Local(paths.into_iter().collect());
+                    }
+                }
+                change = self.changes.recv() => match change {
+                    Ok(event) if event.origin_peer != self.local_origin => {
+                        return SyncEvent::Remote(event);
+                    }
+                    Ok(_) => {}
+                    Err(RecvError::Lagged(missed)) => {
+                        tracing::warn!("Missed {} file changes, rescanning {}", missed, self.root.display());
+                        return SyncEvent::Local(vec![self.root.clone()]);
+                    }
+                    Err(RecvError::Closed) => std::future::pending::<()>().await,
+                },
+            }
+        }
+    }
+
+    /// Compare every file below the root with storage. Files that exist only
+    /// in storage are fetched from peers rather than deleted, since they may
+    /// have been added while this node was offline.
+    pub fn initial_scan(&mut self, storage: &Storage) -> Result<Vec<LocalChange>> {
+        let changes = self.local_changes(std::slice::from_ref(&self.root), storage)?;
+
+        for (key, value) in storage.list(DEFAULT_NAMESPACE)? {
+            if !key.starts_with(KEY_PREFIX) {
+                continue;
+            }
+            let Some(path) = path_for_key(&self.root, &key) else {
+                continue;
+            };
+            if !path.exists() {
+                if let Ok(manifest) = ChunkManifest::from_json(&value) {
+                    self.pending.insert(key, manifest);
+                }
+            }
+        }
+
+        self.complete_pending(storage)?;
+        Ok(changes)
+    }
+
+    /// Storage writes that bring the manifests for `paths` in line with disk
+    pub fn local_changes(&self, paths: &[PathBuf], storage: &Storage) -> Result<Vec<LocalChange>> {
+        let mut changes = Vec::new();
+
+        for path in paths {
+            if is_temp_file(path) {
+                continue;
+            }
+
+            match fs::symlink_metadata(path) {
+                Ok(metadata) if metadata.is_dir() => {
+                    for file in walk_files(path)? {
+                        self.index_change(&file, storage, &mut changes)?;
+                    }
+                }
+                Ok(metadata) if metadata.is_file() => {
+                    self.index_change(path, storage, &mut changes)?;
+                }
+                Ok(_) => {} // シンボリックリンクなどは同期しない
+                Err(e) if e.kind() == std::io::ErrorKind::NotFound => {
+                    let Some(key) = key_for_path(&self.root, path) else {
+                        continue;
+                    };
+                    // ディレクトリが削除された場合は配下のファイルも削除する
+                    let dir_prefix = format!("{key}/");
+                    for (existing, _) in storage.list(DEFAULT_NAMESPACE)? {
+                        if existing == key || existing.starts_with(&dir_prefix) {
+                            changes.push(LocalChange::Delete { key: existing });
+                        }
+                    }
+                }
+                Err(e) => return Err(e.into()),
+            }
+        }
+
+        Ok(changes)
+    }
+
+    fn index_change(
+        &self,
+        path: &Path,
+        storage: &Storage,
+        changes: &mut Vec<LocalChange>,
+    ) -> Result<()> {
+        if is_temp_file(path) {
+            return Ok(());
+        }
+        let Some(key) = key_for_path(&self.root, path) else {
+            return Ok(());
+        };
+
+        let manifest = match index_file(path, &self.chunks) {
+            Ok(manifest) => manifest,
+            // 書き込み途中で削除された場合など。次のイベントで処理される
+            Err(e) => {
+                tracing::debug!("Skipping {}: {}", path.display(), e);
+                return Ok(());
+            }
+        };
+
+        let current = storage
+            .get(DEFAULT_NAMESPACE, &key)?
+            .and_then(|value| ChunkManifest::from_json(&value).ok());
+        if current.as_ref() != Some(&manifest) {
+            changes.push(LocalChange::Put {
+                key,
+                manifest: manifest.to_json()?,
+            });
+        }
+
+        Ok(())
+    }
+
+    /// Bring the local file in line with a manifest written by a peer
+    pub fn apply_remote(&mut self, event: &ChangeEvent, storage: &Storage) -> Result<()> {
+        let Some(path) = path_for_key(&self.root, &event.key) else {
+            tracing::warn!("Ignoring file change with unsafe path: {}", event.key);
+            return Ok(());
+        };
+
+        match &event.new {
+            Some(value) => {
+                let manifest = ChunkManifest::from_json(value)?;
+                if let Err(e) = manifest.validate(self.max_object_size) {
+                    tracing::warn!("Ignoring invalid manifest for {}: {}", event.key, e);
+                    return Ok(());
+                }
+                self.pending.insert(event.key.clone(), manifest);
+                self.complete_pending(storage)?;
+            }
+            None => {
+                self.pending.remove(&event.key);
+                if path.is_file() {
+                    fs::remove_file(&path)?;
+                    tracing::info!("Removed {}", path.display());
+                }
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Request chunks of pending files that are neither cached nor in flight
+    pub fn request_missing(&mut self, behaviour: &mut Behaviour, peers: &[PeerId]) {
+        let missing: HashSet<String> = self
+            .pending
+            .values()
+            .flat_map(|manifest| manifest.chunks.iter())
+            .filter(|hash| !self.chunks.contains(hash))
+            .cloned()
+            .collect();
+
+        self.fetches.request(behaviour, missing, peers);
+    }
+
+    /// Answer a chunk request from a peer
+    pub fn serve(&self, request: &ChunkRequest) -> Result<ChunkResponse> {
+        Ok(match self.chunks.get(&request.hash)? {
+            Some(data) => ChunkResponse::Chunk(data),
+            None => ChunkResponse::NotFound,
+        })
+    }
+
+    pub fn handle_response(
+        &mut self,
+        request_id: OutboundRequestId,
+        response: ChunkResponse,
+        behaviour: &mut Behaviour,
+        storage: &Storage,
+    ) -> Result<()> {
+        if let Some(data) = self
+            .fetches
+            .handle_response(request_id, response, behaviour)
+        {
+            self.chunks.insert(&data)?;
+            self.complete_pending(storage)?;
+        }
+
+        Ok(())
+    }
+
+    pub fn handle_failure(&mut self, request_id: OutboundRequestId, behaviour: &mut Behaviour) {
+        self.fetches.handle_failure(request_id, behaviour);
+    }
+
+    /// Write every pending file whose chunks are all cached
+    fn complete_pending(&mut self, storage: &Storage) -> Result<()> {
+        let ready: Vec<String> = self
+            .pending
+            .iter()
+            .filter(|(_, manifest)| {
+                manifest
+                    .chunks
+                    .iter()
+                    .all(|hash| self.chunks.contains(hash))
+            })
+            .map(|(key, _)| key.clone())
+            .collect();
+
+        for key in ready {
+            let Some(manifest) = self.pending.remove(&key) else {
+                continue;
+            };
+
+            // 取得中により新しい版が書き込まれていたら古い版は書き出さない
+            let current = storage
+                .get(DEFAULT_NAMESPACE, &key)?
+                .and_then(|value| ChunkManifest::from_json(&value).ok());
+            if current.as_ref() != Some(&manifest) {
+                continue;
+            }
+
+            let Some(path) = path_for_key(&self.root, &key) else {
+                continue;
+            };
+            let unchanged =
+                path.is_file() && index_file(&path, &self.chunks).ok().as_ref() == Some(&manifest);
+            if !unchanged {
+                assemble_file(&path, &manifest, &self.chunks)?;
+                tracing::info!("Synced {}", path.display());
+            }
+        }
+
+        Ok(())
+    }
+}
+
+fn walk_files(dir: &Path) -> Result<Vec<PathBuf>> {
+    let mut files = Vec::new();
+    let mut stack = vec![dir.to_path_buf()];
+
+    while let Some(dir) = stack.pop() {
+        for entry in fs::read_dir(&dir)? {
+            let entry = entry?;
+            let file_type = entry.file_type()?;
+            if file_type.is_dir() {
+                stack.push(entry.path());
+            } else if file_type.is_file() {
+                files.push(entry.path());
+            }
+        }
+    }
+
+    Ok(files)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::hlc::HlcTimestamp;
+    use tempfile::tempdir;
+
+    fn apply(storage: &Storage, changes: Vec<LocalChange>) {
+        for change in changes {
+            match change {
+                LocalChange::Put { key, manifest } => {
+                    storage.put(DEFAULT_NAMESPACE, &key, &manifest).unwrap();
+                }
+                LocalChange::Delete { key } => {
+                    storage.delete(DEFAULT_NAMESPACE, &key).unwrap();
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn test_index_and_assemble_round_trip() {
+        let dir = tempdir().unwrap();
+        let chunks = ChunkStore::new(dir.path().join("chunks")).unwrap();
+
+        let data: Vec<u8> = (0..CHUNK_SIZE * 2 + 100).map(|i| (i % 251) as u8).collect();
+        let source = dir.path().join("source.bin");
+        fs::write(&source, &data).unwrap();
+
+        let manifest = index_file(&source, &chunks).unwrap();
+        assert_eq!(manifest.size, data.len() as u64);
+        assert_eq!(manifest.chunks.len(), 3);
+
+        let target = dir.path().join("nested/target.bin");
+        assemble_file(&target, &manifest, &chunks).unwrap();
+        assert_eq!(fs::read(&target).unwrap(), data);
+    }
+
+    #[test]
+    fn test_chunk_store_rejects_invalid_hash() {
+        let dir = tempdir().unwrap();
+        let chunks = ChunkStore::new(dir.path().join("chunks")).unwrap();
+
+        let hash = chunks.insert(b"data").unwrap();
+        assert_eq!(chunks.get(&hash).unwrap(), Some(b"data".to_vec()));
+        assert_eq!(chunks.get("../../etc/passwd").unwrap(), None);
+        assert!(!chunks.contains(&"0".repeat(63)));
+    }
+
+    #[test]
+    fn test_key_path_mapping() {
+        let root = Path::new("/sync");
+
+        let key = key_for_path(root, Path::new("/sync/docs/a.txt")).unwrap();
+        assert_eq!(key, "file:docs/a.txt");
+        assert_eq!(
+            path_for_key(root, &key).unwrap(),
+            PathBuf::from("/sync/docs/a.txt")
+        );
+
+        assert_eq!(key_for_path(root, Path::new("/elsewhere/a.txt")), None);
+        assert_eq!(key_for_path(root, root), None);
+        assert_eq!(path_for_key(root, "file:../escape"), None);
+        assert_eq!(path_for_key(root, "file:a//b"), None);
+        assert_eq!(path_for_key(root, "other:a"), None);
+    }
+
+    #[tokio::test]
+    async fn test_local_changes_create_and_delete() {
+        let dir = tempdir().unwrap();
+        let root = dir.path().join("root");
+        let storage = Storage::open(dir.path().join("sync.db"), None).unwrap();
+        let sync = DirectorySync::new(&root, dir.path(), &storage, "local".to_string()).unwrap();
+        let root = sync.root().to_path_buf();
+
+        fs::create_dir_all(root.join("docs")).unwrap();
+        fs::write(root.join("docs/a.txt"), b"hello").unwrap();
+        fs::write(root.join(format!(".b.txt{TEMP_SUFFIX}")), b"partial").unwrap();
+
+        let changes = sync.local_changes(&[root.join("docs")], &storage).unwrap();
+        assert_eq!(changes.len(), 1);
+        assert!(matches!(&changes[0], LocalChange::Put { key, .. } if key == "file:docs/a.txt"));
+        apply(&storage, changes);
+
+        // Unchanged content produces no write
+        let changes = sync
+            .local_changes(&[root.join("docs/a.txt")], &storage)
+            .unwrap();
+        assert!(changes.is_empty());
+
+        // Removing the directory deletes the files below it
+        fs::remove_dir_all(root.join("docs")).unwrap();
+        let changes = sync.local_changes(&[root.join("docs")], &storage).unwrap();
+        assert_eq!(
+            changes,
+            vec![LocalChange::Delete {
+                key: "file:docs/a.txt".to_string()
+            }]
+        );
+    }
+
+    #[tokio::test]
+    async fn test_remote_change_is_written_when_chunks_are_cached() {
+        let dir = tempdir().unwrap();
+        let storage = Storage::open(dir.path().join("sync.db"), None).unwrap();
+        let mut sync = DirectorySync::new(
+            &dir.path().join("root"),
+            dir.path(),
+            &storage,
+            "local".to_string(),
+        )
+        .unwrap();
+
+        let manifest = ChunkManifest {
+            size: 5,
+            chunks: vec![sync.chunks.insert(b"hello").unwrap()],
+        };
+        let value = manifest.to_json().unwrap();
+        let timestamp = HlcTimestamp::new(1, 0, "remote");
+        storage
+            .put_with_timestamp(
+                DEFAULT_NAMESPACE,
+                "file:remote.txt",
+                &value,
+                &timestamp,
+                None,
+            )
+            .unwrap();
+
+        let event = ChangeEvent {
+            namespace: DEFAULT_NAMESPACE.to_string(),
+            key: "file:remote.txt".to_string(),
+            old: None,
+            new: Some(value),
+            origin_peer: "remote".to_string(),
+            timestamp: timestamp.clone(),
+        };
+        sync.apply_remote(&event, &storage).unwrap();
+
+        let path = sync.root().join("remote.txt");
+        assert_eq!(fs::read(&path).unwrap(), b"hello");
+
+        // A remote delete removes the file again
+        let event = ChangeEvent {
+            new: None,
+            old: event.new,
+            ..event
+        };
+        sync.apply_remote(&event, &storage).unwrap();
+        assert!(!path.exists());
+    }
+
+    #[tokio::test]
+    async fn test_invalid_remote_manifest_is_ignored() {
+        let dir = tempdir().unwrap();
+        let storage = Storage::open(dir.path().join("sync.db"), None).unwrap();
+        let mut sync = DirectorySync::new(
+            &dir.path().join("root"),
+            dir.path(),
+            &storage,
+            "local".to_string(),
+        )
+        .unwrap()
+        .with_max_object_size(1024);
+
+        // 上限を超える size は確保も取得もしない
+        let manifest = ChunkManifest {
+            size: u64::MAX,
+            chunks: vec![chunks::hash(b"hello")],
+        };
+        let event = ChangeEvent {
+            namespace: DEFAULT_NAMESPACE.to_string(),
+            key: "file:huge.bin".to_string(),
+            old: None,
+            new: Some(manifest.to_json().unwrap()),
+            origin_peer: "remote".to_string(),
+            timestamp: HlcTimestamp::new(1, 0, "remote"),
+        };
+        sync.apply_remote(&event, &storage).unwrap();
+        assert!(sync.pending.is_empty());
+    }
+}
@@ -0,0 +1,290 @@
+use anyhow::{anyhow, bail, Result};
+use chacha20poly1305::aead::{Aead, AeadCore, KeyInit, OsRng, Payload};
+use chacha20poly1305::{ChaCha20Poly1305, Key, Nonce};
+use curve25519_dalek::edwards::CompressedEdwardsY;
+use hkdf::Hkdf;
+use libp2p::identity::{Keypair, PublicKey};
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256, Sha512};
+use x25519_dalek::{EphemeralSecret, StaticSecret};
+
+const KEY_LENGTH: usize = 32;
+const NONCE_LENGTH: usize = 12;
+
+const WRAP_INFO: &[u8] = b"p2p-sync group key wrap v1";
+
+/// Symmetric key shared by the readers of an encrypted namespace. A new key
+/// with a higher epoch is created when the key is rotated, older keys are kept
+/// to decrypt messages sent before the rotation.
+#[derive(Clone, PartialEq, Eq)]
+pub struct GroupKey {
+    epoch: u64,
+    key: [u8; KEY_LENGTH],
+}
+
+/// The key itself is never printed
+impl std::fmt::Debug for GroupKey {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("GroupKey")
+            .field("epoch", &self.epoch)
+            .field("id", &self.id())
+            .finish()
+    }
+}
+
+/// A value encrypted with a group key
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct EncryptedValue {
+    /// `GroupKey::id` of the key used
+    pub key_id: String,
+    #[serde(with = "serde_bytes")]
+    pub nonce: Vec<u8>,
+    #[serde(with = "serde_bytes")]
+    pub ciphertext: Vec<u8>,
+}
+
+/// A group key encrypted for one recipient. The wrapping key is derived from
+/// an X25519 exchange between a one-time key and the recipient's Ed25519
+/// identity key converted to X25519.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct WrappedKey {
+    /// PeerId of the recipient
+    pub recipient: String,
+    #[serde(with = "serde_bytes")]
+    pub ephemeral_public: Vec<u8>,
+    #[serde(with = "serde_bytes")]
+    pub nonce: Vec<u8>,
+    #[serde(with = "serde_bytes")]
+    pub ciphertext: Vec<u8>,
+}
+
+impl GroupKey {
+    pub fn generate(epoch: u64) -> Self {
+        Self {
+            epoch,
+            key: ChaCha20Poly1305::generate_key(&mut OsRng).into(),
+        }
+    }
+
+    pub fn from_bytes(epoch: u64, bytes: &[u8]) -> Result<Self> {
+        let key = bytes
+            .try_into()
+            .map_err(|_| anyhow!("Group key must be {KEY_LENGTH} bytes"))?;
+        Ok(Self { epoch, key })
+    }
+
+    pub fn epoch(&self) -> u64 {
+        self.epoch
+    }
+
+    pub fn as_bytes(&self) -> &[u8] {
+        &self.key
+    }
+
+    /// Short fingerprint identifying the key in messages
+    pub fn id(&self) -> String {
+        hex::encode(&Sha256::digest(self.key)[..8])
+    }
+
+    /// Encrypt `plaintext` for `namespace`. The namespace is authenticated so
+    /// a ciphertext cannot be moved to another namespace.
+    pub fn encrypt(&self, namespace: &str, plaintext: &[u8]) -> Result<EncryptedValue> {
+        let nonce = ChaCha20Poly1305::generate_nonce(&mut OsRng);
+        let ciphertext = self
+            .cipher()
+            .encrypt(
+                &nonce,
+                Payload {
+                    msg: plaintext,
+                    aad: namespace.as_bytes(),
+                },
+            )
+            .map_err(|_| anyhow!("Failed to encrypt value"))?;
+
+        Ok(EncryptedValue {
+            key_id: self.id(),
+            nonce: nonce.to_vec(),
+            ciphertext,
+        })
+    }
+
+    pub fn decrypt(&self, namespace: &str, value: &EncryptedValue) -> Result<Vec<u8>> {
+        if value.key_id != self.id() {
+            bail!("Value was encrypted with key {}", value.key_id);
+        }
+        self.cipher()
+            .decrypt(
+                nonce(&value.nonce)?,
+                Payload {
+                    msg: &value.ciphertext,
+                    aad: namespace.as_bytes(),
+                },
+            )
+            .map_err(|_| anyhow!("Failed to decrypt value with key {}", value.key_id))
+    }
+
+    /// Encrypt this key for `recipient`, which must be an Ed25519 key
+    pub fn wrap_for(&self, namespace: &str, recipient: &PublicKey) -> Result<WrappedKey> {
+        let recipient_id = recipient.to_peer_id().to_string();
+        let recipient_public = x25519_public(recipient)?;
+
+        let ephemeral = EphemeralSecret::random_from_rng(OsRng);
+        let ephemeral_public = x25519_dalek::PublicKey::from(&ephemeral);
+        let shared = ephemeral.diffie_hellman(&recipient_public);
+        if !shared.was_contributory() {
+            bail!("Public key of {recipient_id} cannot be used for key exchange");
+        }
+
+        let cipher = wrapping_cipher(
+            shared.as_bytes(),
+            ephemeral_public.as_bytes(),
+            &recipient_id,
+        )?;
+        let nonce = ChaCha20Poly1305::generate_nonce(&mut OsRng);
+        let ciphertext = cipher
+            .encrypt(
+                &nonce,
+                Payload {
+                    msg: &self.key,
+                    aad: &wrap_aad(namespace, self.epoch),
+                },
+            )
+            .map_err(|_| anyhow!("Failed to wrap group key"))?;
+
+        Ok(WrappedKey {
+            recipient: recipient_id,
+            ephemeral_public: ephemeral_public.as_bytes().to_vec(),
+            nonce: nonce.to_vec(),
+            ciphertext,
+        })
+    }
+
+    /// Decrypt a key wrapped for the owner of `keypair`
+    pub fn unwrap(
+        namespace: &str,
+        epoch: u64,
+        wrapped: &WrappedKey,
+        keypair: &Keypair,
+    ) -> Result<Self> {
+        let ephemeral_public: [u8; 32] = wrapped
+            .ephemeral_public
+            .as_slice()
+            .try_into()
+            .map_err(|_| anyhow!("Invalid ephemeral public key"))?;
+
+        let shared = x25519_secret(keypair)?
+            .diffie_hellman(&x25519_dalek::PublicKey::from(ephemeral_public));
+        let cipher = wrapping_cipher(shared.as_bytes(), &ephemeral_public, &wrapped.recipient)?;
+        let key = cipher
+            .decrypt(
+                nonce(&wrapped.nonce)?,
+                Payload {
+                    msg: &wrapped.ciphertext,
+                    aad: &wrap_aad(namespace, epoch),
+                },
+            )
+            .map_err(|_| anyhow!("Failed to unwrap group key"))?;
+
+        Self::from_bytes(epoch, &key)
+    }
+
+    fn cipher(&self) -> ChaCha20Poly1305 {
+        ChaCha20Poly1305::new(Key::from_slice(&self.key))
+    }
+}
+
+fn nonce(bytes: &[u8]) -> Result<&Nonce> {
+    if bytes.len() != NONCE_LENGTH {
+        bail!("Nonce must be {NONCE_LENGTH} bytes");
+    }
+    Ok(Nonce::from_slice(bytes))
+}
+
+fn wrap_aad(namespace: &str, epoch: u64) -> Vec<u8> {
+    let mut aad = namespace.as_bytes().to_vec();
+    aad.extend_from_slice(&epoch.to_be_bytes());
+    aad
+}
+
+fn wrapping_cipher(
+    shared: &[u8],
+    ephemeral_public: &[u8],
+    recipient: &str,
+) -> Result<ChaCha20Poly1305> {
+    let mut info = WRAP_INFO.to_vec();
+    info.extend_from_slice(ephemeral_public);
+    info.extend_from_slice(recipient.as_bytes());
+
+    let mut key = [0u8; KEY_LENGTH];
+    Hkdf::<Sha256>::new(None, shared)
+        .expand(&info, &mut key)
+        .map_err(|_| anyhow!("Failed to derive wrapping key"))?;
+    Ok(ChaCha20Poly1305::new(Key::from_slice(&key)))
+}
+
+/// Ed25519 公開鍵を X25519（Montgomery 形式）に変換する
+fn x25519_public(public_key: &PublicKey) -> Result<x25519_dalek::PublicKey> {
+    let ed25519 = public_key
+        .clone()
+        .try_into_ed25519()
+        .map_err(|_| anyhow!("Only Ed25519 keys can receive group keys"))?;
+    let point = CompressedEdwardsY(ed25519.to_bytes())
+        .decompress()
+        .ok_or_else(|| anyhow!("Invalid Ed25519 public key"))?;
+    Ok(x25519_dalek::PublicKey::from(
+        point.to_montgomery().to_bytes(),
+    ))
+}
+
+/// Ed25519 の秘密鍵から対応する X25519 秘密鍵を導出する（RFC 8032 の鍵展開と同じ）
+fn x25519_secret(keypair: &Keypair) -> Result<StaticSecret> {
+    let ed25519 = keypair
+        .clone()
+        .try_into_ed25519()
+        .map_err(|_| anyhow!("Only Ed25519 keys can receive group keys"))?;
+    let hash = Sha512::digest(ed25519.secret().as_ref());
+    let mut scalar = [0u8; 32];
+    scalar.copy_from_slice(&hash[..32]);
+    Ok(StaticSecret::from(scalar))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_encrypt_round_trip() {
+        let key = GroupKey::generate(1);
+        let encrypted = key.encrypt("secrets", b"hello").unwrap();
+
+        assert_eq!(encrypted.key_id, key.id());
+        assert_ne!(encrypted.ciphertext, b"hello");
+        assert_eq!(key.decrypt("secrets", &encrypted).unwrap(), b"hello");
+
+        // 別のネームスペースや別の鍵では復号できない
+        assert!(key.decrypt("config", &encrypted).is_err());
+        assert!(GroupKey::generate(1)
+            .decrypt("secrets", &encrypted)
+            .is_err());
+    }
+
+    #[test]
+    fn test_wrap_for_recipient() {
+        let recipient = Keypair::generate_ed25519();
+        let other = Keypair::generate_ed25519();
+        let key = GroupKey::generate(3);
+
+        let wrapped = key.wrap_for("secrets", &recipient.public()).unwrap();
+        assert_eq!(
+            wrapped.recipient,
+            recipient.public().to_peer_id().to_string()
+        );
+
+        let unwrapped = GroupKey::unwrap("secrets", 3, &wrapped, &recipient).unwrap();
+        assert_eq!(unwrapped, key);
+
+        assert!(GroupKey::unwrap("secrets", 3, &wrapped, &other).is_err());
+        // エポックを書き換えた鍵は受け付けない
+        assert!(GroupKey::unwrap("secrets", 4, &wrapped, &recipient).is_err());
+    }
+}
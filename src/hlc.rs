@@ -0,0 +1,245 @@
+use anyhow::{bail, Result};
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+use std::cmp::Ordering;
+use std::fmt;
+use std::sync::Mutex;
+
+/// Hybrid logical clock timestamp.
+///
+/// Timestamps are totally ordered by wall time, then logical counter, then the
+/// PeerId of the origin node, so every replica picks the same winner for
+/// concurrent writes regardless of the order in which they arrive.
+#[derive(Debug, Clone, PartialEq, Eq, Hash, Serialize, Deserialize)]
+pub struct HlcTimestamp {
+    /// Physical component in milliseconds since the Unix epoch
+    pub wall_ms: u64,
+    /// Counter for events that share the same physical component
+    pub logical: u32,
+    /// PeerId of the node that issued the timestamp
+    pub origin: String,
+}
+
+impl HlcTimestamp {
+    pub fn new(wall_ms: u64, logical: u32, origin: impl Into<String>) -> Self {
+        Self {
+            wall_ms,
+            logical,
+            origin: origin.into(),
+        }
+    }
+
+    /// Wall-clock component as a `DateTime`
+    pub fn to_datetime(&self) -> DateTime<Utc> {
+        DateTime::from_timestamp_millis(self.wall_ms as i64).unwrap_or_default()
+    }
+}
+
+impl Ord for HlcTimestamp {
+    fn cmp(&self, other: &Self) -> Ordering {
+        self.wall_ms
+            .cmp(&other.wall_ms)
+            .then(self.logical.cmp(&other.logical))
+            .then_with(|| self.origin.cmp(&other.origin))
+    }
+}
+
+impl PartialOrd for HlcTimestamp {
+    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl fmt::Display for HlcTimestamp {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{}+{}@{}",
+            self.to_datetime().to_rfc3339(),
+            self.logical,
+            self.origin
+        )
+    }
+}
+
+/// How far ahead of local physical time a remote timestamp may be by default
+pub const DEFAULT_MAX_SKEW_MS: u64 = 60_000;
+
+/// Issues monotonically increasing `HlcTimestamp`s for one node
+pub struct HybridClock {
+    origin: String,
+    max_skew_ms: u64,
+    // (wall_ms, logical) of the last issued or observed timestamp
+    last: Mutex<(u64, u32)>,
+}
+
+impl HybridClock {
+    pub fn new(origin: impl Into<String>) -> Self {
+        Self {
+            origin: origin.into(),
+            max_skew_ms: DEFAULT_MAX_SKEW_MS,
+            last: Mutex::new((0, 0)),
+        }
+    }
+
+    /// PeerId stamped on local timestamps
+    pub fn origin(&self) -> &str {
+        &self.origin
+    }
+
+    /// Refuse remote timestamps more than `max_skew_ms` ahead of physical time
+    pub fn with_max_skew(mut self, max_skew_ms: u64) -> Self {
+        self.max_skew_ms = max_skew_ms;
+        self
+    }
+
+    /// Fail if `remote` is too far ahead of local physical time to be
+    /// observed. Accepting it would move the clock forward for good and let
+    /// its writes win every later conflict.
+    pub fn check(&self, remote: &HlcTimestamp) -> Result<()> {
+        self.check_at(remote, physical_now())
+    }
+
+    /// Timestamp for a local event
+    pub fn now(&self) -> HlcTimestamp {
+        self.tick(physical_now())
+    }
+
+    /// Merge a timestamp received from another node so that later local
+    /// events are ordered after it. Callers must `check` it first.
+    pub fn observe(&self, remote: &HlcTimestamp) {
+        self.merge(remote, physical_now());
+    }
+
+    fn check_at(&self, remote: &HlcTimestamp, physical: u64) -> Result<()> {
+        let limit = physical.saturating_add(self.max_skew_ms);
+        if remote.wall_ms > limit {
+            bail!(
+                "timestamp of {} is {} ms ahead of local time (max {} ms)",
+                remote.origin,
+                remote.wall_ms - physical,
+                self.max_skew_ms
+            );
+        }
+        Ok(())
+    }
+
+    fn tick(&self, physical: u64) -> HlcTimestamp {
+        let mut last = self.last.lock().unwrap_or_else(|e| e.into_inner());
+
+        *last = if physical > last.0 {
+            (physical, 0)
+        } else {
+            successor(*last)
+        };
+
+        HlcTimestamp::new(last.0, last.1, self.origin.clone())
+    }
+
+    fn merge(&self, remote: &HlcTimestamp, physical: u64) {
+        let mut last = self.last.lock().unwrap_or_else(|e| e.into_inner());
+
+        let wall = physical.max(last.0).max(remote.wall_ms);
+        *last = if wall == last.0 && wall == remote.wall_ms {
+            successor((wall, last.1.max(remote.logical)))
+        } else if wall == last.0 {
+            successor(*last)
+        } else if wall == remote.wall_ms {
+            successor((wall, remote.logical))
+        } else {
+            (wall, 0)
+        };
+    }
+}
+
+// 論理カウンタが溢れたら物理時刻を 1ms 進めて繰り上げる
+fn successor((wall, logical): (u64, u32)) -> (u64, u32) {
+    match logical.checked_add(1) {
+        Some(logical) => (wall, logical),
+        None => (wall.saturating_add(1), 0),
+    }
+}
+
+fn physical_now() -> u64 {
+    Utc::now().timestamp_millis().max(0) as u64
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_ordering_uses_origin_as_tie_breaker() {
+        let a = HlcTimestamp::new(1000, 0, "peer-a");
+        let b = HlcTimestamp::new(1000, 0, "peer-b");
+        let later = HlcTimestamp::new(1000, 1, "peer-a");
+
+        assert!(a < b);
+        assert!(b < later);
+        assert!(HlcTimestamp::new(999, 5, "peer-z") < a);
+    }
+
+    #[test]
+    fn test_clock_is_monotonic_within_same_millisecond() {
+        let clock = HybridClock::new("peer");
+
+        let first = clock.tick(1000);
+        let second = clock.tick(1000);
+        let third = clock.tick(999); // 物理時計が戻っても単調増加
+
+        assert!(first < second);
+        assert!(second < third);
+        assert_eq!(third.wall_ms, 1000);
+    }
+
+    #[test]
+    fn test_observe_moves_clock_past_remote() {
+        let clock = HybridClock::new("local");
+        let remote = HlcTimestamp::new(5000, 3, "remote");
+
+        // Local physical time lags behind the remote clock
+        clock.merge(&remote, 1000);
+        let next = clock.tick(1000);
+
+        assert!(next > remote);
+        assert_eq!(next.wall_ms, 5000);
+        assert_eq!(next.logical, 5);
+    }
+
+    #[test]
+    fn test_logical_overflow_carries_into_wall_time() {
+        let clock = HybridClock::new("local");
+        let remote = HlcTimestamp::new(5000, u32::MAX, "remote");
+
+        clock.merge(&remote, 1000);
+        let next = clock.tick(1000);
+
+        assert!(next > remote);
+        assert_eq!((next.wall_ms, next.logical), (5001, 1));
+    }
+
+    #[test]
+    fn test_check_rejects_far_future_timestamps() {
+        let clock = HybridClock::new("local").with_max_skew(1000);
+
+        assert!(clock
+            .check_at(&HlcTimestamp::new(1900, 0, "remote"), 1000)
+            .is_ok());
+        assert!(clock
+            .check_at(&HlcTimestamp::new(2001, 0, "remote"), 1000)
+            .is_err());
+        assert!(clock
+            .check_at(&HlcTimestamp::new(u64::MAX, 0, "remote"), 1000)
+            .is_err());
+    }
+
+    #[test]
+    fn test_now_tracks_wall_clock() {
+        let clock = HybridClock::new("peer");
+        let before = Utc::now().timestamp_millis() as u64;
+        let ts = clock.now();
+
+        assert!(ts.wall_ms >= before);
+        assert_eq!(ts.origin, "peer");
+    }
+}
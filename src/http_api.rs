@@ -0,0 +1,625 @@
+use anyhow::{Context, Result};
+use axum::body::Bytes;
+use axum::extract::{DefaultBodyLimit, Path, Query, Request, State};
+use axum::http::{header, StatusCode};
+use axum::middleware::{self, Next};
+use axum::response::sse::{Event, KeepAlive, Sse};
+use axum::response::{IntoResponse, Response};
+use axum::routing::get;
+use axum::{Json, Router};
+use base64::{engine::general_purpose::STANDARD as BASE64, Engine};
+use serde::{Deserialize, Serialize};
+use tokio::sync::broadcast::error::RecvError;
+use tokio::sync::{mpsc, watch};
+
+use crate::config::HttpConfig;
+use crate::control::{self, ControlCommand, ControlRequest, ControlResponse, ErrorKind};
+use crate::security::{validate_value, Rejection, SecurityConfig};
+use crate::watch::ChangeFeed;
+
+#[derive(Clone)]
+struct AppState {
+    commands: mpsc::Sender<ControlCommand>,
+    changes: ChangeFeed,
+    token: String,
+    /// Limits of the node, replaced when its config is reloaded
+    security: watch::Receiver<SecurityConfig>,
+}
+
+impl AppState {
+    fn check_key(&self, key: &str) -> Result<(), Rejection> {
+        self.security.borrow().check_key(key)
+    }
+
+    fn check_value(&self, value: &str) -> Result<(), Rejection> {
+        validate_value(value, self.security.borrow().max_value_length)
+    }
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct KeyValue {
+    pub key: String,
+    pub value: String,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct KeyValueList {
+    pub items: Vec<KeyValue>,
+}
+
+/// `?namespace=` selects the namespace, the default one when absent
+#[derive(Debug, Deserialize)]
+struct NamespaceQuery {
+    namespace: Option<String>,
+}
+
+#[derive(Debug, Deserialize)]
+struct PrefixQuery {
+    namespace: Option<String>,
+    prefix: Option<String>,
+}
+
+/// Start the REST gateway. Requests are handed to the node's event loop over
+/// `commands`, so writes are validated, signed and published exactly like
+/// commands typed at the prompt. The upload size limit follows
+/// `max_object_size` at startup.
+pub async fn spawn_server(
+    config: &HttpConfig,
+    commands: mpsc::Sender<ControlCommand>,
+    changes: ChangeFeed,
+    security: watch::Receiver<SecurityConfig>,
+) -> Result<tokio::task::JoinHandle<()>> {
+    let token = config
+        .token
+        .clone()
+        .filter(|token| !token.is_empty())
+        .context("http.token must be set when the HTTP gateway is enabled")?;
+
+    let listener = tokio::net::TcpListener::bind(&config.listen_addr)
+        .await
+        .with_context(|| format!("Failed to bind HTTP gateway to {}", config.listen_addr))?;
+    tracing::info!("HTTP gateway listening on {}", listener.local_addr()?);
+
+    let app = router(commands, changes, token, security);
+
+    Ok(tokio::spawn(async move {
+        if let Err(e) = axum::serve(listener, app).await {
+            tracing::warn!("HTTP gateway stopped: {}", e);
+        }
+    }))
+}
+
+fn router(
+    commands: mpsc::Sender<ControlCommand>,
+    changes: ChangeFeed,
+    token: String,
+    security: watch::Receiver<SecurityConfig>,
+) -> Router {
+    let blob_limit = security.borrow().max_object_size;
+    let state = AppState {
+        commands,
+        changes,
+        token,
+        security,
+    };
+
+    Router::new()
+        .route("/kv", get(list_keys))
+        // キーには '/' を含められるのでワイルドカードで受ける
+        .route("/kv/{*key}", get(get_key).put(put_key).delete(delete_key))
+        // バイナリ値は JSON を介さずにそのまま送受信する
+        .route(
+            "/blob/{*key}",
+            get(get_blob)
+                .put(put_blob)
+                .layer(DefaultBodyLimit::max(blob_limit)),
+        )
+        .route("/watch", get(watch))
+        .layer(middleware::from_fn_with_state(state.clone(), require_token))
+        .with_state(state)
+}
+
+async fn require_token(State(state): State<AppState>, request: Request, next: Next) -> Response {
+    let authorized = request
+        .headers()
+        .get(header::AUTHORIZATION)
+        .and_then(|value| value.to_str().ok())
+        .and_then(|value| value.strip_prefix("Bearer "))
+        .is_some_and(|token| constant_time_eq(token.as_bytes(), state.token.as_bytes()));
+
+    if !authorized {
+        return error(StatusCode::UNAUTHORIZED, "Missing or invalid bearer token");
+    }
+
+    next.run(request).await
+}
+
+async fn get_key(
+    State(state): State<AppState>,
+    Path(key): Path<String>,
+    Query(query): Query<NamespaceQuery>,
+) -> Response {
+    if let Err(e) = state.check_key(&key) {
+        return error(StatusCode::BAD_REQUEST, e);
+    }
+
+    let request = ControlRequest::Get {
+        namespace: query.namespace,
+        key,
+        at: None,
+    };
+    match control::dispatch(&state.commands, request).await {
+        ControlResponse::Value {
+            key,
+            value: Some(value),
+        } => Json(KeyValue { key, value }).into_response(),
+        ControlResponse::Value { key, value: None } => {
+            error(StatusCode::NOT_FOUND, format!("{key} not found"))
+        }
+        other => unexpected(other),
+    }
+}
+
+async fn put_key(
+    State(state): State<AppState>,
+    Path(key): Path<String>,
+    Query(query): Query<NamespaceQuery>,
+    value: String,
+) -> Response {
+    if let Err(e) = state.check_key(&key) {
+        return error(StatusCode::BAD_REQUEST, e);
+    }
+    if let Err(e) = state.check_value(&value) {
+        return error(StatusCode::BAD_REQUEST, e);
+    }
+
+    let request = ControlRequest::Put {
+        namespace: query.namespace,
+        key,
+        value,
+    };
+    match control::dispatch(&state.commands, request).await {
+        ControlResponse::Done { .. } => StatusCode::NO_CONTENT.into_response(),
+        other => unexpected(other),
+    }
+}
+
+async fn get_blob(
+    State(state): State<AppState>,
+    Path(key): Path<String>,
+    Query(query): Query<NamespaceQuery>,
+) -> Response {
+    if let Err(e) = state.check_key(&key) {
+        return error(StatusCode::BAD_REQUEST, e);
+    }
+
+    let request = ControlRequest::GetBytes {
+        namespace: query.namespace,
+        key,
+    };
+    match control::dispatch(&state.commands, request).await {
+        ControlResponse::Bytes {
+            value: Some(value), ..
+        } => match BASE64.decode(value) {
+            Ok(data) => {
+                ([(header::CONTENT_TYPE, "application/octet-stream")], data).into_response()
+            }
+            Err(e) => error(StatusCode::INTERNAL_SERVER_ERROR, e),
+        },
+        ControlResponse::Bytes { key, value: None } => {
+            error(StatusCode::NOT_FOUND, format!("{key} not found"))
+        }
+        other => unexpected(other),
+    }
+}
+
+async fn put_blob(
+    State(state): State<AppState>,
+    Path(key): Path<String>,
+    Query(query): Query<NamespaceQuery>,
+    data: Bytes,
+) -> Response {
+    if let Err(e) = state.check_key(&key) {
+        return error(StatusCode::BAD_REQUEST, e);
+    }
+
+    let request = ControlRequest::PutBytes {
+        namespace: query.namespace,
+        key,
+        value: BASE64.encode(data),
+    };
+    match control::dispatch(&state.commands, request).await {
+        ControlResponse::Done { .. } => StatusCode::NO_CONTENT.into_response(),
+        other => unexpected(other),
+    }
+}
+
+async fn delete_key(
+    State(state): State<AppState>,
+    Path(key): Path<String>,
+    Query(query): Query<NamespaceQuery>,
+) -> Response {
+    if let Err(e) = state.check_key(&key) {
+        return error(StatusCode::BAD_REQUEST, e);
+    }
+
+    let request = ControlRequest::Delete {
+        namespace: query.namespace,
+        key,
+    };
+    match control::dispatch(&state.commands, request).await {
+        ControlResponse::Done { .. } => StatusCode::NO_CONTENT.into_response(),
+        other => unexpected(other),
+    }
+}
+
+async fn list_keys(State(state): State<AppState>, Query(query): Query<PrefixQuery>) -> Response {
+    let request = ControlRequest::List {
+        namespace: query.namespace,
+        prefix: query.prefix,
+    };
+
+    match control::dispatch(&state.commands, request).await {
+        ControlResponse::Items { items } => Json(KeyValueList {
+            items: items
+                .into_iter()
+                .map(|(key, value)| KeyValue { key, value })
+                .collect(),
+        })
+        .into_response(),
+        other => unexpected(other),
+    }
+}
+
+/// Server-sent events stream with one `change` event per matching change
+async fn watch(State(state): State<AppState>, Query(query): Query<PrefixQuery>) -> Response {
+    let subscription = state.changes.subscribe(query.namespace, query.prefix);
+
+    let events = futures::stream::unfold(subscription, |mut subscription| async move {
+        let event = match subscription.recv().await {
+            Ok(change) => Event::default().event("change").json_data(change),
+            Err(RecvError::Lagged(missed)) => {
+                Ok(Event::default().event("lagged").data(missed.to_string()))
+            }
+            Err(RecvError::Closed) => return None,
+        };
+        Some((event, subscription))
+    });
+
+    Sse::new(events)
+        .keep_alive(KeepAlive::default())
+        .into_response()
+}
+
+fn error(status: StatusCode, message: impl ToString) -> Response {
+    (
+        status,
+        Json(serde_json::json!({ "error": message.to_string() })),
+    )
+        .into_response()
+}
+
+fn unexpected(response: ControlResponse) -> Response {
+    match response {
+        ControlResponse::Error { message, kind } => {
+            let status = match kind {
+                ErrorKind::Invalid => StatusCode::BAD_REQUEST,
+                ErrorKind::Forbidden => StatusCode::FORBIDDEN,
+                ErrorKind::NotFound => StatusCode::NOT_FOUND,
+                ErrorKind::Internal => StatusCode::INTERNAL_SERVER_ERROR,
+            };
+            error(status, message)
+        }
+        other => error(
+            StatusCode::INTERNAL_SERVER_ERROR,
+            format!("Unexpected response from node: {other:?}"),
+        ),
+    }
+}
+
+/// Compare tokens without leaking the position of the first mismatch
+fn constant_time_eq(a: &[u8], b: &[u8]) -> bool {
+    a.len() == b.len() && a.iter().zip(b).fold(0u8, |acc, (x, y)| acc | (x ^ y)) == 0
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::hlc::HlcTimestamp;
+    use crate::watch::ChangeEvent;
+    use axum::body::Body;
+    use futures::StreamExt;
+    use std::collections::BTreeMap;
+    use tower::ServiceExt;
+
+    const TOKEN: &str = "secret";
+
+    /// Router backed by an in-memory stand-in for the node event loop
+    fn test_router() -> Router {
+        test_router_with_changes(ChangeFeed::new())
+    }
+
+    fn test_router_with_changes(changes: ChangeFeed) -> Router {
+        let (tx, mut rx) = mpsc::channel::<ControlCommand>(8);
+
+        tokio::spawn(async move {
+            let mut store = BTreeMap::new();
+            while let Some(command) = rx.recv().await {
+                let namespace = command.request.namespace().to_string();
+                let response = match command.request {
+                    _ if namespace == "invalid" => {
+                        ControlResponse::from_error(&Rejection::EmptyKey.into())
+                    }
+                    _ if namespace == "read-only" => ControlResponse::from_error(
+                        &control::RequestError::forbidden("not a writer").into(),
+                    ),
+                    _ if namespace == "unknown" => ControlResponse::from_error(
+                        &control::RequestError::not_found("Unknown namespace").into(),
+                    ),
+                    ControlRequest::Put { key, value, .. } => {
+                        store.insert((namespace, key), value);
+                        ControlResponse::Done {
+                            message: "ok".to_string(),
+                        }
+                    }
+                    ControlRequest::Get { key, .. } => ControlResponse::Value {
+                        value: store.get(&(namespace, key.clone())).cloned(),
+                        key,
+                    },
+                    ControlRequest::PutBytes { key, value, .. } => {
+                        store.insert((namespace, key), value);
+                        ControlResponse::Done {
+                            message: "ok".to_string(),
+                        }
+                    }
+                    ControlRequest::GetBytes { key, .. } => ControlResponse::Bytes {
+                        value: store.get(&(namespace, key.clone())).cloned(),
+                        key,
+                    },
+                    ControlRequest::Delete { key, .. } => {
+                        store.remove(&(namespace, key));
+                        ControlResponse::Done {
+                            message: "ok".to_string(),
+                        }
+                    }
+                    ControlRequest::List { prefix, .. } => ControlResponse::Items {
+                        items: store
+                            .iter()
+                            .filter(|((ns, key), _)| {
+                                *ns == namespace
+                                    && prefix
+                                        .as_deref()
+                                        .map_or(true, |prefix| key.starts_with(prefix))
+                            })
+                            .map(|((_, key), value)| (key.clone(), value.clone()))
+                            .collect(),
+                    },
+                    _ => ControlResponse::error("unsupported"),
+                };
+                let _ = command.reply.send(response);
+            }
+        });
+
+        let (_, security) = watch::channel(SecurityConfig::default());
+        router(tx, changes, TOKEN.to_string(), security)
+    }
+
+    fn request(method: &str, uri: &str, body: &str) -> Request {
+        Request::builder()
+            .method(method)
+            .uri(uri)
+            .header(header::AUTHORIZATION, format!("Bearer {TOKEN}"))
+            .body(Body::from(body.to_string()))
+            .unwrap()
+    }
+
+    async fn body_json<T: serde::de::DeserializeOwned>(response: Response) -> T {
+        let bytes = axum::body::to_bytes(response.into_body(), usize::MAX)
+            .await
+            .unwrap();
+        serde_json::from_slice(&bytes).unwrap()
+    }
+
+    #[tokio::test]
+    async fn test_requests_without_token_are_rejected() {
+        let app = test_router();
+
+        let response = app
+            .clone()
+            .oneshot(Request::get("/kv/key").body(Body::empty()).unwrap())
+            .await
+            .unwrap();
+        assert_eq!(response.status(), StatusCode::UNAUTHORIZED);
+
+        let response = app
+            .oneshot(
+                Request::get("/kv/key")
+                    .header(header::AUTHORIZATION, "Bearer wrong")
+                    .body(Body::empty())
+                    .unwrap(),
+            )
+            .await
+            .unwrap();
+        assert_eq!(response.status(), StatusCode::UNAUTHORIZED);
+    }
+
+    #[tokio::test]
+    async fn test_put_get_delete() {
+        let app = test_router();
+
+        let response = app
+            .clone()
+            .oneshot(request("PUT", "/kv/config/name", "value"))
+            .await
+            .unwrap();
+        assert_eq!(response.status(), StatusCode::NO_CONTENT);
+
+        let response = app
+            .clone()
+            .oneshot(request("GET", "/kv/config/name", ""))
+            .await
+            .unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+        let kv: KeyValue = body_json(response).await;
+        assert_eq!(kv.key, "config/name");
+        assert_eq!(kv.value, "value");
+
+        let response = app
+            .clone()
+            .oneshot(request("DELETE", "/kv/config/name", ""))
+            .await
+            .unwrap();
+        assert_eq!(response.status(), StatusCode::NO_CONTENT);
+
+        let response = app
+            .oneshot(request("GET", "/kv/config/name", ""))
+            .await
+            .unwrap();
+        assert_eq!(response.status(), StatusCode::NOT_FOUND);
+    }
+
+    #[tokio::test]
+    async fn test_blob_round_trip() {
+        let app = test_router();
+        let data = vec![0u8, 159, 146, 150, 255];
+
+        let response = app
+            .clone()
+            .oneshot(
+                Request::put("/blob/images/logo")
+                    .header(header::AUTHORIZATION, format!("Bearer {TOKEN}"))
+                    .body(Body::from(data.clone()))
+                    .unwrap(),
+            )
+            .await
+            .unwrap();
+        assert_eq!(response.status(), StatusCode::NO_CONTENT);
+
+        let response = app
+            .oneshot(request("GET", "/blob/images/logo", ""))
+            .await
+            .unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+        assert_eq!(
+            response.headers()[header::CONTENT_TYPE],
+            "application/octet-stream"
+        );
+        let body = axum::body::to_bytes(response.into_body(), usize::MAX)
+            .await
+            .unwrap();
+        assert_eq!(body.to_vec(), data);
+    }
+
+    #[tokio::test]
+    async fn test_list_with_prefix() {
+        let app = test_router();
+
+        for key in ["app/a", "app/b", "other"] {
+            app.clone()
+                .oneshot(request("PUT", &format!("/kv/{key}"), "v"))
+                .await
+                .unwrap();
+        }
+
+        let response = app
+            .oneshot(request("GET", "/kv?prefix=app/", ""))
+            .await
+            .unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+
+        let list: KeyValueList = body_json(response).await;
+        let keys: Vec<_> = list.items.into_iter().map(|kv| kv.key).collect();
+        assert_eq!(keys, vec!["app/a", "app/b"]);
+    }
+
+    #[tokio::test]
+    async fn test_namespace_query() {
+        let app = test_router();
+
+        app.clone()
+            .oneshot(request("PUT", "/kv/token?namespace=secrets", "s3cret"))
+            .await
+            .unwrap();
+
+        let response = app
+            .clone()
+            .oneshot(request("GET", "/kv/token", ""))
+            .await
+            .unwrap();
+        assert_eq!(response.status(), StatusCode::NOT_FOUND);
+
+        let response = app
+            .oneshot(request("GET", "/kv?namespace=secrets", ""))
+            .await
+            .unwrap();
+        let list: KeyValueList = body_json(response).await;
+        assert_eq!(list.items.len(), 1);
+        assert_eq!(list.items[0].value, "s3cret");
+    }
+
+    #[tokio::test]
+    async fn test_invalid_key_is_rejected() {
+        let app = test_router();
+
+        let response = app
+            .oneshot(request("PUT", "/kv/a/../b", "v"))
+            .await
+            .unwrap();
+        assert_eq!(response.status(), StatusCode::BAD_REQUEST);
+    }
+
+    #[tokio::test]
+    async fn test_node_errors_map_to_status_codes() {
+        let app = test_router();
+
+        let response = app
+            .clone()
+            .oneshot(request("PUT", "/kv/key?namespace=read-only", "v"))
+            .await
+            .unwrap();
+        assert_eq!(response.status(), StatusCode::FORBIDDEN);
+
+        let response = app
+            .clone()
+            .oneshot(request("GET", "/kv/key?namespace=unknown", ""))
+            .await
+            .unwrap();
+        assert_eq!(response.status(), StatusCode::NOT_FOUND);
+
+        let response = app
+            .oneshot(request("PUT", "/kv/key?namespace=invalid", "v"))
+            .await
+            .unwrap();
+        assert_eq!(response.status(), StatusCode::BAD_REQUEST);
+    }
+
+    #[tokio::test]
+    async fn test_watch_streams_changes() {
+        let changes = ChangeFeed::new();
+        let app = test_router_with_changes(changes.clone());
+
+        let response = app
+            .oneshot(request("GET", "/watch?prefix=app/", ""))
+            .await
+            .unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+
+        for key in ["other", "app/name"] {
+            changes.publish(ChangeEvent {
+                namespace: "default".to_string(),
+                key: key.to_string(),
+                old: None,
+                new: Some("value".to_string()),
+                origin_peer: "peer".to_string(),
+                timestamp: HlcTimestamp::new(1, 0, "peer"),
+            });
+        }
+
+        let mut body = response.into_body().into_data_stream();
+        let frame = body.next().await.unwrap().unwrap();
+        let frame = String::from_utf8(frame.to_vec()).unwrap();
+
+        assert!(frame.starts_with("event: change\n"));
+        assert!(frame.contains(r#""key":"app/name""#));
+    }
+}
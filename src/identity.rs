@@ -0,0 +1,293 @@
+use anyhow::{Context, Result};
+use base64::Engine;
+use libp2p::identity::{Keypair, PublicKey};
+use std::fs;
+use std::path::{Path, PathBuf};
+use tracing::{info, warn};
+
+/// File name of the node identity inside the data directory
+pub const IDENTITY_FILE: &str = "identity.key";
+
+/// Encodings accepted by `load_public_key_from_file`
+#[derive(Debug, Clone, Copy, PartialEq, Eq, clap::ValueEnum)]
+pub enum PublicKeyFormat {
+    /// Raw protobuf bytes
+    Raw,
+    /// Base64-encoded protobuf
+    Base64,
+    /// Hex-encoded protobuf
+    Hex,
+}
+
+pub fn identity_path(data_dir: &Path) -> PathBuf {
+    data_dir.join(IDENTITY_FILE)
+}
+
+/// Load the node keypair from `data_dir`, creating it on first start
+pub fn load_or_create(data_dir: &Path) -> Result<Keypair> {
+    let path = identity_path(data_dir);
+
+    if path.exists() {
+        return load(&path);
+    }
+
+    let keypair = Keypair::generate_ed25519();
+    save(&path, &keypair)?;
+    info!(
+        "Created new node identity {} at {}",
+        keypair.public().to_peer_id(),
+        path.display()
+    );
+
+    Ok(keypair)
+}
+
+pub fn load(path: &Path) -> Result<Keypair> {
+    check_permissions(path);
+
+    let bytes =
+        fs::read(path).with_context(|| format!("Failed to read identity {}", path.display()))?;
+    let keypair = Keypair::from_protobuf_encoding(&bytes)
+        .with_context(|| format!("Invalid identity file {}", path.display()))?;
+
+    Ok(keypair)
+}
+
+/// Write the keypair so that only the owner can read it
+pub fn save(path: &Path, keypair: &Keypair) -> Result<()> {
+    let bytes = keypair.to_protobuf_encoding()?;
+
+    // 一時ファイルに書き込んでから置き換える（途中で壊れた鍵を残さないため）
+    let tmp_path = path.with_extension("key.tmp");
+    write_private(&tmp_path, &bytes)?;
+    fs::rename(&tmp_path, path)?;
+
+    Ok(())
+}
+
+/// Replace the identity with a freshly generated keypair.
+///
+/// The previous key is kept next to the new one as `identity.key.old`.
+pub fn rotate(data_dir: &Path) -> Result<Keypair> {
+    let path = identity_path(data_dir);
+
+    if path.exists() {
+        let backup = path.with_extension("key.old");
+        fs::copy(&path, &backup)?;
+        set_private_permissions(&backup)?;
+    }
+
+    let keypair = Keypair::generate_ed25519();
+    save(&path, &keypair)?;
+
+    Ok(keypair)
+}
+
+pub fn encode_public_key(public_key: &PublicKey, format: PublicKeyFormat) -> Vec<u8> {
+    let bytes = public_key.encode_protobuf();
+
+    match format {
+        PublicKeyFormat::Raw => bytes,
+        PublicKeyFormat::Base64 => base64::engine::general_purpose::STANDARD
+            .encode(bytes)
+            .into_bytes(),
+        PublicKeyFormat::Hex => hex::encode(bytes).into_bytes(),
+    }
+}
+
+/// Read a public key written as raw protobuf, base64 or hex
+pub fn load_public_key_from_file(path: &str) -> Result<PublicKey> {
+    let data = fs::read(path)?;
+
+    // Try different formats
+    // First try raw protobuf
+    if let Ok(pk) = PublicKey::try_decode_protobuf(&data) {
+        return Ok(pk);
+    }
+
+    decode_public_key(&String::from_utf8_lossy(&data))
+        .map_err(|_| anyhow::anyhow!("Unable to parse public key from file: {}", path))
+}
+
+/// Decode a base64 or hex encoded protobuf public key
+pub fn decode_public_key(text: &str) -> Result<PublicKey> {
+    let text = text.trim();
+
+    // Try base64 decode then protobuf
+    if let Ok(decoded) = base64::engine::general_purpose::STANDARD.decode(text) {
+        if let Ok(pk) = PublicKey::try_decode_protobuf(&decoded) {
+            return Ok(pk);
+        }
+    }
+
+    // Try as hex string
+    if let Ok(decoded) = hex::decode(text) {
+        if let Ok(pk) = PublicKey::try_decode_protobuf(&decoded) {
+            return Ok(pk);
+        }
+    }
+
+    anyhow::bail!("Unable to parse public key");
+}
+
+#[cfg(unix)]
+pub(crate) fn write_private(path: &Path, bytes: &[u8]) -> Result<()> {
+    use std::io::Write;
+    use std::os::unix::fs::OpenOptionsExt;
+
+    let mut file = fs::OpenOptions::new()
+        .write(true)
+        .create(true)
+        .truncate(true)
+        .mode(0o600)
+        .open(path)?;
+    file.write_all(bytes)?;
+    file.sync_all()?;
+
+    // 既存ファイルの場合 mode は適用されないので明示的に設定する
+    set_private_permissions(path)
+}
+
+#[cfg(not(unix))]
+pub(crate) fn write_private(path: &Path, bytes: &[u8]) -> Result<()> {
+    fs::write(path, bytes)?;
+    set_private_permissions(path)
+}
+
+#[cfg(unix)]
+fn set_private_permissions(path: &Path) -> Result<()> {
+    use std::os::unix::fs::PermissionsExt;
+
+    fs::set_permissions(path, fs::Permissions::from_mode(0o600))?;
+    Ok(())
+}
+
+#[cfg(not(unix))]
+fn set_private_permissions(path: &Path) -> Result<()> {
+    let mut permissions = fs::metadata(path)?.permissions();
+    permissions.set_readonly(true);
+    fs::set_permissions(path, permissions)?;
+    Ok(())
+}
+
+#[cfg(unix)]
+fn check_permissions(path: &Path) {
+    use std::os::unix::fs::PermissionsExt;
+
+    if let Ok(metadata) = fs::metadata(path) {
+        let mode = metadata.permissions().mode();
+        if mode & 0o077 != 0 {
+            warn!(
+                "Identity file {} is accessible by other users (mode {:o}), run: chmod 600 {}",
+                path.display(),
+                mode & 0o777,
+                path.display()
+            );
+        }
+    }
+}
+
+#[cfg(not(unix))]
+fn check_permissions(_path: &Path) {}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tempfile::tempdir;
+
+    #[test]
+    fn test_identity_persists_across_loads() {
+        let dir = tempdir().unwrap();
+
+        let first = load_or_create(dir.path()).unwrap();
+        let second = load_or_create(dir.path()).unwrap();
+
+        assert_eq!(first.public().to_peer_id(), second.public().to_peer_id());
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn test_identity_file_is_private() {
+        use std::os::unix::fs::PermissionsExt;
+
+        let dir = tempdir().unwrap();
+        load_or_create(dir.path()).unwrap();
+
+        let mode = fs::metadata(identity_path(dir.path()))
+            .unwrap()
+            .permissions()
+            .mode();
+        assert_eq!(mode & 0o777, 0o600);
+    }
+
+    #[test]
+    fn test_rotate_changes_peer_id() {
+        let dir = tempdir().unwrap();
+
+        let original = load_or_create(dir.path()).unwrap();
+        let rotated = rotate(dir.path()).unwrap();
+        let reloaded = load_or_create(dir.path()).unwrap();
+
+        assert_ne!(
+            original.public().to_peer_id(),
+            rotated.public().to_peer_id()
+        );
+        assert_eq!(
+            rotated.public().to_peer_id(),
+            reloaded.public().to_peer_id()
+        );
+
+        let backup = load(&identity_path(dir.path()).with_extension("key.old")).unwrap();
+        assert_eq!(backup.public().to_peer_id(), original.public().to_peer_id());
+    }
+
+    #[test]
+    fn test_corrupt_identity_is_rejected() {
+        let dir = tempdir().unwrap();
+        fs::write(identity_path(dir.path()), b"not a key").unwrap();
+
+        assert!(load_or_create(dir.path()).is_err());
+    }
+
+    #[test]
+    fn test_encode_public_key_formats() {
+        let keypair = Keypair::generate_ed25519();
+        let public_key = keypair.public();
+        let raw = public_key.encode_protobuf();
+
+        assert_eq!(encode_public_key(&public_key, PublicKeyFormat::Raw), raw);
+
+        let b64 = encode_public_key(&public_key, PublicKeyFormat::Base64);
+        let decoded = base64::engine::general_purpose::STANDARD
+            .decode(&b64)
+            .unwrap();
+        assert_eq!(decoded, raw);
+
+        let hex_str = encode_public_key(&public_key, PublicKeyFormat::Hex);
+        assert_eq!(hex::decode(hex_str).unwrap(), raw);
+    }
+
+    #[test]
+    fn test_exported_key_round_trips() {
+        let dir = tempdir().unwrap();
+        let keypair = load_or_create(dir.path()).unwrap();
+        let peer_id = keypair.public().to_peer_id();
+
+        for format in [
+            PublicKeyFormat::Raw,
+            PublicKeyFormat::Base64,
+            PublicKeyFormat::Hex,
+        ] {
+            let path = dir.path().join(format!("{format:?}.pub"));
+            let mut bytes = encode_public_key(&keypair.public(), format);
+            if format != PublicKeyFormat::Raw {
+                // stdout へのリダイレクトで付く改行も受け付ける
+                bytes.push(b'\n');
+            }
+            fs::write(&path, bytes).unwrap();
+
+            let loaded = load_public_key_from_file(path.to_str().unwrap()).unwrap();
+            assert_eq!(loaded.to_peer_id(), peer_id);
+        }
+    }
+}
@@ -2,17 +2,26 @@ use anyhow::Result;
 use chrono::{DateTime, Utc};
 use libp2p::PeerId;
 use serde::{Deserialize, Serialize};
-use std::collections::HashMap;
+use std::collections::{HashMap, HashSet};
 use std::sync::Arc;
 use tokio::sync::RwLock;
 use tracing::{info, warn};
 
 use crate::crypto::SignedData;
-use crate::whitelist::PeerWhitelist;
+use crate::group_key::{EncryptedValue, GroupKey, WrappedKey};
+use crate::namespace::Namespaces;
+use crate::whitelist::{PeerWhitelist, Revocation};
 
 // Type aliases to reduce complexity
 type PendingRequests = Arc<RwLock<HashMap<PeerId, DateTime<Utc>>>>;
 type ProcessedMessages = Arc<RwLock<HashMap<String, DateTime<Utc>>>>;
+type PendingGroupKeyRequests = Arc<RwLock<HashMap<(String, String), DateTime<Utc>>>>;
+
+/// How long to wait for a group key before asking again
+const GROUP_KEY_REQUEST_INTERVAL_SECS: i64 = 60;
+
+/// How long to wait before relaying stored revocations to the same peer again
+const REVOCATION_RELAY_INTERVAL_SECS: i64 = 600;
 
 /// Key distribution messages that are exchanged between peers
 #[derive(Debug, Clone, Serialize, Deserialize)]
@@ -49,19 +58,64 @@ pub enum KeyDistributionMessage {
         name: Option<String>, // Optional name for the recommended peer
         timestamp: DateTime<Utc>,
     },
+    /// Withdraw a `TrustRecommendation` made earlier by the sender
+    RecommendationRevoked {
+        recommender: String,
+        recommended: String,
+        timestamp: DateTime<Utc>,
+    },
+    /// Ask members of an encrypted namespace for its group key
+    GroupKeyRequest {
+        requestor: String,
+        /// Protobuf-encoded public key of the requestor, used to wrap the key
+        /// when the member has not stored it
+        public_key: Vec<u8>,
+        namespace: String,
+        /// Key a received value was encrypted with, `None` for the current one
+        key_id: Option<String>,
+        timestamp: DateTime<Utc>,
+    },
+    /// Group key of an encrypted namespace, wrapped for each recipient with
+    /// their public key
+    GroupKeyGrant {
+        namespace: String,
+        epoch: u64,
+        key_id: String,
+        keys: Vec<WrappedKey>,
+        /// Peers the member that rotated the key wrapped it for. Other
+        /// members only pass it on to them and to peers they whitelist later.
+        /// `None` for keys that were never rotated.
+        #[serde(default)]
+        recipients: Option<Vec<String>>,
+        timestamp: DateTime<Utc>,
+    },
+    /// Revoke a compromised peer on every node. `revocation` is signed by an
+    /// admin peer, so any peer can relay it to nodes that missed it.
+    Revocation {
+        revocation: SignedData<Revocation>,
+        timestamp: DateTime<Utc>,
+    },
 }
 
-/// Configuration for key distribution behavior
-#[derive(Debug, Clone)]
+/// Configuration for key distribution behavior (`[key_distribution]` section)
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(default)]
 pub struct KeyDistributionConfig {
     /// Whether to automatically share keys with whitelisted peers
     pub auto_share_keys: bool,
     /// Whether to automatically request missing keys
     pub auto_request_keys: bool,
-    /// Whether to accept whitelist requests from unknown peers
+    /// Whether to queue whitelist requests from unknown peers for approval
     pub accept_whitelist_requests: bool,
     /// Maximum age for key distribution messages (in hours)
     pub max_message_age_hours: u64,
+    /// Maximum number of whitelist requests waiting for approval
+    pub max_pending_requests: usize,
+    /// Approve a request without an administrator once this many whitelisted
+    /// peers have recommended the requester
+    pub auto_approve_min_recommendations: Option<u32>,
+    /// Peers whose revocations are applied
+    pub admin_peers: HashSet<String>,
 }
 
 impl Default for KeyDistributionConfig {
@@ -69,8 +123,12 @@ impl Default for KeyDistributionConfig {
         Self {
             auto_share_keys: true,
             auto_request_keys: true,
-            accept_whitelist_requests: false, // Conservative default
+            // リクエストはキューに入るだけで、承認されるまでアクセスは許可されない
+            accept_whitelist_requests: true,
             max_message_age_hours: 24,
+            max_pending_requests: 100,
+            auto_approve_min_recommendations: None, // Conservative default
+            admin_peers: HashSet::new(),
         }
     }
 }
@@ -85,6 +143,12 @@ pub struct KeyDistributionManager {
     pending_requests: PendingRequests,
     /// Track recently processed messages to avoid replay attacks
     processed_messages: ProcessedMessages,
+    /// Namespaces whose group keys this node manages
+    namespaces: Namespaces,
+    /// Group keys already asked for, by namespace and key id
+    pending_group_key_requests: PendingGroupKeyRequests,
+    /// Peers stored revocations were last relayed to
+    relayed_revocations: PendingRequests,
 }
 
 impl KeyDistributionManager {
@@ -102,9 +166,18 @@ impl KeyDistributionManager {
             local_peer_id,
             pending_requests: Arc::new(RwLock::new(HashMap::new())),
             processed_messages: Arc::new(RwLock::new(HashMap::new())),
+            namespaces: Namespaces::default(),
+            pending_group_key_requests: Arc::new(RwLock::new(HashMap::new())),
+            relayed_revocations: Arc::new(RwLock::new(HashMap::new())),
         }
     }
 
+    /// Manage group keys of the encrypted ones among `namespaces`
+    pub fn with_namespaces(mut self, namespaces: Namespaces) -> Self {
+        self.namespaces = namespaces;
+        self
+    }
+
     /// Handle incoming key distribution message
     pub async fn handle_message(
         &self,
@@ -119,6 +192,10 @@ impl KeyDistributionManager {
             KeyDistributionMessage::KeyAnnouncement { timestamp, .. } => *timestamp,
             KeyDistributionMessage::WhitelistRequest { timestamp, .. } => *timestamp,
             KeyDistributionMessage::TrustRecommendation { timestamp, .. } => *timestamp,
+            KeyDistributionMessage::RecommendationRevoked { timestamp, .. } => *timestamp,
+            KeyDistributionMessage::GroupKeyRequest { timestamp, .. } => *timestamp,
+            KeyDistributionMessage::GroupKeyGrant { timestamp, .. } => *timestamp,
+            KeyDistributionMessage::Revocation { timestamp, .. } => *timestamp,
         };
 
         if Utc::now() - message_time > max_age {
@@ -180,9 +257,60 @@ impl KeyDistributionManager {
                 name,
                 ..
             } => {
-                self.handle_trust_recommendation(recommender, recommended, name, sender_peer_id)
+                self.handle_trust_recommendation(
+                    recommender,
+                    recommended,
+                    name,
+                    message_time,
+                    sender_peer_id,
+                )
+                .await
+            }
+            KeyDistributionMessage::RecommendationRevoked {
+                recommender,
+                recommended,
+                ..
+            } => {
+                self.handle_recommendation_revoked(recommender, recommended, sender_peer_id)
                     .await
             }
+            KeyDistributionMessage::GroupKeyRequest {
+                requestor,
+                public_key,
+                namespace,
+                key_id,
+                ..
+            } => {
+                self.handle_group_key_request(
+                    requestor,
+                    public_key,
+                    namespace,
+                    key_id,
+                    sender_peer_id,
+                )
+                .await
+            }
+            KeyDistributionMessage::GroupKeyGrant {
+                namespace,
+                epoch,
+                key_id,
+                keys,
+                recipients,
+                ..
+            } => {
+                self.handle_group_key_grant(
+                    namespace,
+                    epoch,
+                    key_id,
+                    keys,
+                    recipients,
+                    sender_peer_id,
+                )
+                .await
+            }
+            KeyDistributionMessage::Revocation { revocation, .. } => {
+                self.handle_revocation(revocation, sender_peer_id).await
+            }
         }
     }
 
@@ -392,6 +520,11 @@ impl KeyDistributionManager {
 
         let requested_peer_id = peer_id.parse::<PeerId>()?;
 
+        if self.whitelist.is_revoked(&requested_peer_id).await? {
+            warn!("Whitelist request from revoked peer: {}", requested_peer_id);
+            return Ok(None);
+        }
+
         // Verify the sender is requesting for themselves
         if sender_peer_id != requested_peer_id {
             warn!(
@@ -418,19 +551,78 @@ impl KeyDistributionManager {
             sender_peer_id, name
         );
 
-        // Note: This is a security-sensitive operation that might require manual approval
-        // For now, we just log it. In a production system, this might trigger notifications
-        // or require administrator approval.
+        if self.whitelist.is_whitelisted(&requested_peer_id).await?
+            && self
+                .whitelist
+                .get_public_key(&requested_peer_id)
+                .await?
+                .is_some()
+        {
+            info!("Peer {} is already whitelisted", requested_peer_id);
+            return Ok(None);
+        }
+
+        // 管理者の承認待ちキューに保存する
+        if !self
+            .whitelist
+            .add_pending_request(
+                &requested_peer_id,
+                name,
+                &public_key_obj,
+                self.config.max_pending_requests,
+            )
+            .await?
+        {
+            warn!(
+                "Pending whitelist request queue is full, dropping request from: {}",
+                requested_peer_id
+            );
+            return Ok(None);
+        }
+
+        if !self.try_auto_approve(&requested_peer_id).await? {
+            info!(
+                "Whitelist request from {} is pending approval",
+                requested_peer_id
+            );
+        }
 
         Ok(None)
     }
 
+    /// Approve a pending request if the auto-approval rule is met. Returns
+    /// true if the peer was whitelisted.
+    async fn try_auto_approve(&self, peer_id: &PeerId) -> Result<bool> {
+        let Some(min_recommendations) = self.config.auto_approve_min_recommendations else {
+            return Ok(false);
+        };
+        if self.whitelist.get_pending_request(peer_id).await?.is_none() {
+            return Ok(false);
+        }
+
+        let recommendations = self
+            .whitelist
+            .count_whitelisted_recommenders(peer_id)
+            .await?;
+        if recommendations < min_recommendations {
+            return Ok(false);
+        }
+
+        self.whitelist.approve_request(peer_id).await?;
+        info!(
+            "Auto-approved whitelist request from {} ({} recommendations)",
+            peer_id, recommendations
+        );
+        Ok(true)
+    }
+
     /// Handle a trust recommendation from another peer
     async fn handle_trust_recommendation(
         &self,
         recommender: String,
         recommended: String,
         name: Option<String>,
+        timestamp: DateTime<Utc>,
         sender_peer_id: PeerId,
     ) -> Result<Option<KeyDistributionMessage>> {
         let recommender_peer_id = recommender.parse::<PeerId>()?;
@@ -445,15 +637,39 @@ impl KeyDistributionManager {
             return Ok(None);
         }
 
-        // Verify the recommender is whitelisted
-        if !self.whitelist.is_whitelisted(&recommender_peer_id).await? {
+        // Verify the recommender is trusted
+        if !self
+            .whitelist
+            .is_trusted_by_chain(&recommender_peer_id)
+            .await?
+        {
             warn!(
-                "Trust recommendation from non-whitelisted peer: {}",
+                "Trust recommendation from untrusted peer: {}",
                 recommender_peer_id
             );
             return Ok(None);
         }
 
+        // 取り消しより前に出された推薦が後から届いても有効にしない
+        let withdrawn = self
+            .whitelist
+            .list_recommendations(Some(&recommended_peer_id))
+            .await?
+            .into_iter()
+            .any(|recommendation| {
+                recommendation.recommender == recommender
+                    && recommendation
+                        .revoked_at
+                        .is_some_and(|revoked_at| revoked_at > timestamp)
+            });
+        if withdrawn {
+            info!(
+                "Ignoring trust recommendation of {} withdrawn by {}",
+                recommended_peer_id, recommender_peer_id
+            );
+            return Ok(None);
+        }
+
         // Don't allow self-recommendation
         if recommender_peer_id == recommended_peer_id {
             warn!(
@@ -474,6 +690,8 @@ impl KeyDistributionManager {
                     "Added trust recommendation: {} recommended by {}",
                     recommended_peer_id, recommender_peer_id
                 );
+                // 推薦が揃った時点で保留中のリクエストを承認する
+                self.try_auto_approve(&recommended_peer_id).await?;
             }
             Err(e) => {
                 warn!("Failed to add trust recommendation: {}", e);
@@ -483,6 +701,441 @@ impl KeyDistributionManager {
         Ok(None)
     }
 
+    /// Handle the withdrawal of a trust recommendation by its recommender
+    async fn handle_recommendation_revoked(
+        &self,
+        recommender: String,
+        recommended: String,
+        sender_peer_id: PeerId,
+    ) -> Result<Option<KeyDistributionMessage>> {
+        let recommender_peer_id = recommender.parse::<PeerId>()?;
+        let recommended_peer_id = recommended.parse::<PeerId>()?;
+
+        // Only the recommender can withdraw its recommendation
+        if sender_peer_id != recommender_peer_id {
+            warn!(
+                "Recommendation revocation sender mismatch: {} != {}",
+                sender_peer_id, recommender_peer_id
+            );
+            return Ok(None);
+        }
+
+        if self
+            .whitelist
+            .revoke_recommendation(&recommended_peer_id, &recommender_peer_id)
+            .await?
+        {
+            info!(
+                "Trust recommendation of {} withdrawn by {}",
+                recommended_peer_id, recommender_peer_id
+            );
+        }
+
+        Ok(None)
+    }
+
+    /// Handle a revocation signed by an admin peer, whoever relayed it
+    async fn handle_revocation(
+        &self,
+        revocation: SignedData<Revocation>,
+        sender_peer_id: PeerId,
+    ) -> Result<Option<KeyDistributionMessage>> {
+        let issuer = revocation.signer.parse::<PeerId>()?;
+        let revoked_peer_id = revocation.data.peer_id.parse::<PeerId>()?;
+
+        if !self.config.admin_peers.contains(&issuer.to_string()) {
+            warn!(
+                "Ignoring revocation of {} from non-admin peer: {}",
+                revoked_peer_id, issuer
+            );
+            return Ok(None);
+        }
+
+        // 中継されてくるので送信者ではなく管理者の鍵で署名を確かめる
+        let valid = match self.whitelist.verification_key(&issuer).await? {
+            Some(public_key) => revocation.verify_with_public_key(&public_key)?,
+            None => false,
+        };
+        if !valid {
+            warn!(
+                "Invalid signature on revocation of {} relayed by {}",
+                revoked_peer_id, sender_peer_id
+            );
+            return Ok(None);
+        }
+
+        if revoked_peer_id == self.local_peer_id {
+            warn!("This node has been revoked by admin peer {}", issuer);
+            return Ok(None);
+        }
+
+        if self.whitelist.revoke_peer(&revocation).await? {
+            info!(
+                "Revoked peer {} on behalf of admin peer {} (reason: {:?})",
+                revoked_peer_id, issuer, revocation.data.reason
+            );
+        }
+
+        Ok(None)
+    }
+
+    /// Revoke `peer_id` locally and create the revocation to gossip. Only
+    /// admin peers can do so, other nodes would ignore it.
+    pub async fn revoke_peer(
+        &self,
+        peer_id: &PeerId,
+        reason: Option<String>,
+    ) -> Result<KeyDistributionMessage> {
+        if !self
+            .config
+            .admin_peers
+            .contains(&self.local_peer_id.to_string())
+        {
+            anyhow::bail!(
+                "This node is not an admin peer (add {} to key_distribution.admin_peers)",
+                self.local_peer_id
+            );
+        }
+        if *peer_id == self.local_peer_id {
+            anyhow::bail!("An admin peer cannot revoke itself");
+        }
+
+        let revocation = SignedData::new(
+            Revocation {
+                peer_id: peer_id.to_string(),
+                reason,
+                revoked_at: Utc::now(),
+            },
+            &self.local_keypair,
+        )?;
+        if !self.whitelist.revoke_peer(&revocation).await? {
+            anyhow::bail!("Peer {} has already been revoked", peer_id);
+        }
+        info!("Revoked peer {}", peer_id);
+
+        Ok(KeyDistributionMessage::Revocation {
+            revocation,
+            timestamp: Utc::now(),
+        })
+    }
+
+    /// Stored revocations to send to `peer`, which may have been offline when
+    /// they were issued. Empty if they were sent to it recently, so peers
+    /// that reconnect or resubscribe often do not receive them every time.
+    pub async fn relay_revocations(&self, peer: &PeerId) -> Result<Vec<SignedData<Revocation>>> {
+        let now = Utc::now();
+        {
+            let mut relayed = self.relayed_revocations.write().await;
+            if let Some(relayed_at) = relayed.get(peer) {
+                if now - *relayed_at < chrono::Duration::seconds(REVOCATION_RELAY_INTERVAL_SECS) {
+                    return Ok(Vec::new());
+                }
+            }
+            relayed.insert(*peer, now);
+        }
+
+        self.whitelist.list_revocations().await
+    }
+
+    /// Apply revocations relayed directly by `sender_peer_id`
+    pub async fn apply_revocations(
+        &self,
+        revocations: Vec<SignedData<Revocation>>,
+        sender_peer_id: PeerId,
+    ) -> Result<()> {
+        for revocation in revocations {
+            self.handle_revocation(revocation, sender_peer_id).await?;
+        }
+        Ok(())
+    }
+
+    /// Members hold a namespace's group key and may hand it out: they must be
+    /// both readers and writers, so a writer cannot slip in a key of its own
+    fn is_group_key_member(&self, namespace: &str, peer_id: &PeerId) -> bool {
+        self.namespaces.is_encrypted(namespace)
+            && self.namespaces.can_read(namespace, peer_id)
+            && self.namespaces.can_write(namespace, peer_id)
+    }
+
+    /// Handle a group key request by wrapping the key for the requestor
+    async fn handle_group_key_request(
+        &self,
+        requestor: String,
+        public_key: Vec<u8>,
+        namespace: String,
+        key_id: Option<String>,
+        sender_peer_id: PeerId,
+    ) -> Result<Option<KeyDistributionMessage>> {
+        let requestor_peer_id = requestor.parse::<PeerId>()?;
+
+        if sender_peer_id != requestor_peer_id {
+            warn!(
+                "Group key request sender mismatch: {} != {}",
+                sender_peer_id, requestor_peer_id
+            );
+            return Ok(None);
+        }
+
+        // 自分が配布できる立場でなければ他のメンバーに任せる
+        if !self.is_group_key_member(&namespace, &self.local_peer_id) {
+            return Ok(None);
+        }
+
+        if !self.whitelist.is_whitelisted(&requestor_peer_id).await?
+            || !self.namespaces.can_read(&namespace, &requestor_peer_id)
+        {
+            warn!(
+                "Group key of {} requested by non-reader: {}",
+                namespace, requestor_peer_id
+            );
+            return Ok(None);
+        }
+
+        // 保存済みの鍵を優先し、なければ PeerId と一致する申請内の鍵を使う
+        let public_key = match self.whitelist.get_public_key(&requestor_peer_id).await? {
+            Some(public_key) => public_key,
+            None => match libp2p::identity::PublicKey::try_decode_protobuf(&public_key) {
+                Ok(public_key) if public_key.to_peer_id() == requestor_peer_id => public_key,
+                _ => {
+                    warn!(
+                        "Invalid public key in group key request from {}",
+                        requestor_peer_id
+                    );
+                    return Ok(None);
+                }
+            },
+        };
+
+        let key = match &key_id {
+            Some(key_id) => self.whitelist.get_group_key(&namespace, key_id).await?,
+            None => None,
+        };
+        let key = match key {
+            Some(key) => key,
+            None => match self.whitelist.current_group_key(&namespace).await? {
+                Some(key) => key,
+                None => return Ok(None),
+            },
+        };
+
+        // ローテーションした鍵は、ローテーションしたメンバーが配った相手にだけ渡す
+        // （別のノードで削除されたピアがまだここでホワイトリストに残っていても新しい鍵は渡らない）
+        if !self
+            .whitelist
+            .may_receive_group_key(&namespace, &key.id(), &requestor_peer_id)
+            .await?
+        {
+            warn!(
+                "Group key {} of {} was not granted to {} when it was rotated",
+                key.id(),
+                namespace,
+                requestor_peer_id
+            );
+            return Ok(None);
+        }
+
+        info!(
+            "Sending group key {} of {} to {}",
+            key.id(),
+            namespace,
+            requestor_peer_id
+        );
+        Ok(Some(KeyDistributionMessage::GroupKeyGrant {
+            keys: vec![key.wrap_for(&namespace, &public_key)?],
+            recipients: self
+                .whitelist
+                .group_key_recipients(&namespace, &key.id())
+                .await?,
+            namespace,
+            epoch: key.epoch(),
+            key_id: key.id(),
+            timestamp: Utc::now(),
+        }))
+    }
+
+    /// Handle a group key grant, storing the key if it was wrapped for us
+    async fn handle_group_key_grant(
+        &self,
+        namespace: String,
+        epoch: u64,
+        key_id: String,
+        keys: Vec<WrappedKey>,
+        recipients: Option<Vec<String>>,
+        sender_peer_id: PeerId,
+    ) -> Result<Option<KeyDistributionMessage>> {
+        let local_peer_id = self.local_peer_id.to_string();
+        let Some(wrapped) = keys.iter().find(|key| key.recipient == local_peer_id) else {
+            return Ok(None);
+        };
+
+        if !self.whitelist.is_whitelisted(&sender_peer_id).await?
+            || !self.is_group_key_member(&namespace, &sender_peer_id)
+        {
+            warn!(
+                "Ignoring group key of {} from non-member: {}",
+                namespace, sender_peer_id
+            );
+            return Ok(None);
+        }
+
+        let key = GroupKey::unwrap(&namespace, epoch, wrapped, &self.local_keypair)?;
+        if key.id() != key_id {
+            warn!(
+                "Group key from {} does not match its id {}",
+                sender_peer_id, key_id
+            );
+            return Ok(None);
+        }
+
+        if self.whitelist.add_group_key(&namespace, &key).await? {
+            info!(
+                "Received group key {} (epoch {}) of {} from {}",
+                key_id, epoch, namespace, sender_peer_id
+            );
+        }
+        if let Some(recipients) = recipients {
+            self.whitelist
+                .restrict_group_key(&namespace, &key_id, &recipients)
+                .await?;
+        }
+        self.pending_group_key_requests
+            .write()
+            .await
+            .retain(|(pending_namespace, _), _| *pending_namespace != namespace);
+
+        Ok(None)
+    }
+
+    /// The key to encrypt new values of `namespace` with. The first member to
+    /// write creates one, other peers ask for it when they cannot decrypt.
+    pub async fn group_key_for_writing(&self, namespace: &str) -> Result<GroupKey> {
+        if let Some(key) = self.whitelist.current_group_key(namespace).await? {
+            return Ok(key);
+        }
+        if !self.is_group_key_member(namespace, &self.local_peer_id) {
+            anyhow::bail!("No group key for namespace {namespace} has been received yet");
+        }
+
+        let key = GroupKey::generate(1);
+        self.whitelist.add_group_key(namespace, &key).await?;
+        info!("Created group key {} of {}", key.id(), namespace);
+        Ok(key)
+    }
+
+    /// Decrypt a value of `namespace`. `None` means the key is unknown and
+    /// should be requested with `request_group_key`.
+    pub async fn decrypt(
+        &self,
+        namespace: &str,
+        value: &EncryptedValue,
+    ) -> Result<Option<Vec<u8>>> {
+        match self
+            .whitelist
+            .get_group_key(namespace, &value.key_id)
+            .await?
+        {
+            Some(key) => Ok(Some(key.decrypt(namespace, value)?)),
+            None => Ok(None),
+        }
+    }
+
+    /// Ask members for a missing group key, at most once a minute per key
+    pub async fn request_group_key(
+        &self,
+        namespace: &str,
+        key_id: Option<String>,
+    ) -> Option<KeyDistributionMessage> {
+        let pending_key = (namespace.to_string(), key_id.clone().unwrap_or_default());
+        {
+            let mut pending = self.pending_group_key_requests.write().await;
+            if let Some(&requested_at) = pending.get(&pending_key) {
+                if Utc::now() - requested_at
+                    < chrono::Duration::seconds(GROUP_KEY_REQUEST_INTERVAL_SECS)
+                {
+                    return None;
+                }
+            }
+            pending.insert(pending_key, Utc::now());
+        }
+
+        info!("Requesting group key of {}", namespace);
+        Some(KeyDistributionMessage::GroupKeyRequest {
+            requestor: self.local_peer_id.to_string(),
+            public_key: self.local_keypair.public().encode_protobuf(),
+            namespace: namespace.to_string(),
+            key_id,
+            timestamp: Utc::now(),
+        })
+    }
+
+    /// Replace the group key of every encrypted namespace this node manages
+    /// and wrap the new key for the remaining whitelisted readers that could
+    /// receive the current one. Called whenever a peer is removed from the
+    /// whitelist so it cannot read new values.
+    pub async fn rotate_group_keys(&self) -> Result<Vec<KeyDistributionMessage>> {
+        let mut grants = Vec::new();
+
+        for namespace in self.namespaces.encrypted() {
+            if !self.is_group_key_member(namespace, &self.local_peer_id) {
+                continue;
+            }
+            let Some(current) = self.whitelist.current_group_key(namespace).await? else {
+                continue;
+            };
+
+            let key = GroupKey::generate(current.epoch() + 1);
+            self.whitelist.add_group_key(namespace, &key).await?;
+
+            let mut keys = Vec::new();
+            for entry in self.whitelist.list_peers().await? {
+                let peer_id = entry.peer_id.parse::<PeerId>()?;
+                // 前の鍵を受け取れないピアは、ここで削除されていなくても新しい鍵の対象外
+                if peer_id == self.local_peer_id
+                    || !self.namespaces.can_read(namespace, &peer_id)
+                    || !self.whitelist.is_whitelisted(&peer_id).await?
+                    || !self
+                        .whitelist
+                        .may_receive_group_key(namespace, &current.id(), &peer_id)
+                        .await?
+                {
+                    continue;
+                }
+                // 公開鍵を保存していないピアも、PeerId に鍵が含まれていれば受け取れる
+                match self.whitelist.verification_key(&peer_id).await? {
+                    Some(public_key) => keys.push(key.wrap_for(namespace, &public_key)?),
+                    None => warn!(
+                        "Cannot send rotated group key of {} to {}: public key unknown",
+                        namespace, peer_id
+                    ),
+                }
+            }
+
+            let mut recipients: Vec<String> =
+                keys.iter().map(|key| key.recipient.clone()).collect();
+            recipients.push(self.local_peer_id.to_string());
+            self.whitelist
+                .restrict_group_key(namespace, &key.id(), &recipients)
+                .await?;
+
+            info!(
+                "Rotated group key of {} to epoch {} for {} readers",
+                namespace,
+                key.epoch(),
+                keys.len()
+            );
+            grants.push(KeyDistributionMessage::GroupKeyGrant {
+                namespace: namespace.to_string(),
+                epoch: key.epoch(),
+                key_id: key.id(),
+                keys,
+                recipients: Some(recipients),
+                timestamp: Utc::now(),
+            });
+        }
+
+        Ok(grants)
+    }
+
     /// Request missing public keys for whitelisted peers
     pub async fn request_missing_keys(&self) -> Result<Vec<KeyDistributionMessage>> {
         if !self.config.auto_request_keys {
@@ -567,6 +1220,11 @@ impl KeyDistributionManager {
             processed.retain(|_, &mut timestamp| timestamp > cutoff);
         }
 
+        {
+            let mut relayed = self.relayed_revocations.write().await;
+            relayed.retain(|_, &mut timestamp| timestamp > cutoff);
+        }
+
         Ok(())
     }
 }
@@ -581,7 +1239,7 @@ mod tests {
         let dir = tempdir().unwrap();
         let db_path = dir.path().join("whitelist.db");
         #[allow(clippy::arc_with_non_send_sync)]
-        let whitelist = Arc::new(PeerWhitelist::new(&db_path).unwrap());
+        let whitelist = Arc::new(PeerWhitelist::open(&db_path, None).unwrap());
 
         let keypair = libp2p::identity::Keypair::generate_ed25519();
         let config = KeyDistributionConfig::default();
@@ -594,7 +1252,7 @@ mod tests {
         let dir = tempdir().unwrap();
         let db_path = dir.path().join("whitelist.db");
         #[allow(clippy::arc_with_non_send_sync)]
-        let whitelist = Arc::new(PeerWhitelist::new(&db_path).unwrap());
+        let whitelist = Arc::new(PeerWhitelist::open(&db_path, None).unwrap());
 
         let keypair = libp2p::identity::Keypair::generate_ed25519();
         let config = KeyDistributionConfig::default();
@@ -620,7 +1278,7 @@ mod tests {
         let dir = tempdir().unwrap();
         let db_path = dir.path().join("whitelist.db");
         #[allow(clippy::arc_with_non_send_sync)]
-        let whitelist = Arc::new(PeerWhitelist::new(&db_path).unwrap());
+        let whitelist = Arc::new(PeerWhitelist::open(&db_path, None).unwrap());
 
         // Add a peer without public key
         let peer_id = PeerId::random();
@@ -643,4 +1301,657 @@ mod tests {
             _ => panic!("Expected KeyRequest"),
         }
     }
+
+    fn signed(
+        message: KeyDistributionMessage,
+        keypair: &libp2p::identity::Keypair,
+    ) -> SignedData<KeyDistributionMessage> {
+        SignedData::new(message, keypair).unwrap()
+    }
+
+    #[tokio::test]
+    async fn test_whitelist_request_is_queued() {
+        let dir = tempdir().unwrap();
+        #[allow(clippy::arc_with_non_send_sync)]
+        let whitelist =
+            Arc::new(PeerWhitelist::open(&dir.path().join("whitelist.db"), None).unwrap());
+
+        let manager = KeyDistributionManager::new(
+            whitelist.clone(),
+            KeyDistributionConfig::default(),
+            libp2p::identity::Keypair::generate_ed25519(),
+        );
+
+        let requester = libp2p::identity::Keypair::generate_ed25519();
+        let requester_id = requester.public().to_peer_id();
+        let request = KeyDistributionManager::new(
+            whitelist.clone(),
+            KeyDistributionConfig::default(),
+            requester.clone(),
+        )
+        .create_whitelist_request(Some("Laptop".to_string()));
+
+        manager
+            .handle_message(signed(request, &requester), requester_id)
+            .await
+            .unwrap();
+
+        assert!(!whitelist.is_whitelisted(&requester_id).await.unwrap());
+        let pending = whitelist.list_pending_requests().await.unwrap();
+        assert_eq!(pending.len(), 1);
+        assert_eq!(pending[0].peer_id, requester_id.to_string());
+        assert_eq!(pending[0].name.as_deref(), Some("Laptop"));
+    }
+
+    #[tokio::test]
+    async fn test_auto_approve_after_recommendations() {
+        let dir = tempdir().unwrap();
+        #[allow(clippy::arc_with_non_send_sync)]
+        let whitelist =
+            Arc::new(PeerWhitelist::open(&dir.path().join("whitelist.db"), None).unwrap());
+
+        let recommender = libp2p::identity::Keypair::generate_ed25519();
+        let recommender_id = recommender.public().to_peer_id();
+        whitelist
+            .add_peer(&recommender_id, None, Some(&recommender.public()), None)
+            .await
+            .unwrap();
+
+        let config = KeyDistributionConfig {
+            auto_approve_min_recommendations: Some(1),
+            ..Default::default()
+        };
+        let manager = KeyDistributionManager::new(
+            whitelist.clone(),
+            config,
+            libp2p::identity::Keypair::generate_ed25519(),
+        );
+
+        let requester = libp2p::identity::Keypair::generate_ed25519();
+        let requester_id = requester.public().to_peer_id();
+        let request = KeyDistributionMessage::WhitelistRequest {
+            peer_id: requester_id.to_string(),
+            public_key: requester.public().encode_protobuf(),
+            name: None,
+            timestamp: Utc::now(),
+        };
+        manager
+            .handle_message(signed(request, &requester), requester_id)
+            .await
+            .unwrap();
+
+        // 推薦がまだ無いので保留のまま
+        assert_eq!(whitelist.list_pending_requests().await.unwrap().len(), 1);
+
+        let recommendation = KeyDistributionMessage::TrustRecommendation {
+            recommender: recommender_id.to_string(),
+            recommended: requester_id.to_string(),
+            name: None,
+            timestamp: Utc::now(),
+        };
+        manager
+            .handle_message(signed(recommendation, &recommender), recommender_id)
+            .await
+            .unwrap();
+
+        assert!(whitelist.list_pending_requests().await.unwrap().is_empty());
+        assert!(whitelist.is_whitelisted(&requester_id).await.unwrap());
+        assert_eq!(
+            whitelist.get_public_key(&requester_id).await.unwrap(),
+            Some(requester.public())
+        );
+    }
+
+    #[tokio::test]
+    async fn test_recommendation_revoked() {
+        let dir = tempdir().unwrap();
+        #[allow(clippy::arc_with_non_send_sync)]
+        let whitelist =
+            Arc::new(PeerWhitelist::open(&dir.path().join("whitelist.db"), None).unwrap());
+
+        let recommender = libp2p::identity::Keypair::generate_ed25519();
+        let recommender_id = recommender.public().to_peer_id();
+        let other = libp2p::identity::Keypair::generate_ed25519();
+        let recommended = PeerId::random();
+        for keypair in [&recommender, &other] {
+            whitelist
+                .add_peer(&keypair.public().to_peer_id(), None, None, None)
+                .await
+                .unwrap();
+        }
+        let manager = KeyDistributionManager::new(
+            whitelist.clone(),
+            KeyDistributionConfig::default(),
+            libp2p::identity::Keypair::generate_ed25519(),
+        );
+
+        let recommended_at = Utc::now();
+        let recommendation = KeyDistributionMessage::TrustRecommendation {
+            recommender: recommender_id.to_string(),
+            recommended: recommended.to_string(),
+            name: None,
+            timestamp: recommended_at,
+        };
+        manager
+            .handle_message(signed(recommendation, &recommender), recommender_id)
+            .await
+            .unwrap();
+        assert!(whitelist.is_trusted_by_chain(&recommended).await.unwrap());
+
+        let revoked = KeyDistributionMessage::RecommendationRevoked {
+            recommender: recommender_id.to_string(),
+            recommended: recommended.to_string(),
+            timestamp: Utc::now(),
+        };
+        // 推薦者以外は取り消せない
+        manager
+            .handle_message(signed(revoked.clone(), &other), other.public().to_peer_id())
+            .await
+            .unwrap();
+        assert!(whitelist.is_trusted_by_chain(&recommended).await.unwrap());
+
+        manager
+            .handle_message(signed(revoked, &recommender), recommender_id)
+            .await
+            .unwrap();
+        assert!(!whitelist.is_trusted_by_chain(&recommended).await.unwrap());
+
+        // 取り消し前の推薦が遅れて届いても信頼は戻らない
+        let delayed = KeyDistributionMessage::TrustRecommendation {
+            recommender: recommender_id.to_string(),
+            recommended: recommended.to_string(),
+            name: Some("late".to_string()),
+            timestamp: recommended_at,
+        };
+        manager
+            .handle_message(signed(delayed, &recommender), recommender_id)
+            .await
+            .unwrap();
+        assert!(!whitelist.is_trusted_by_chain(&recommended).await.unwrap());
+    }
+
+    #[tokio::test]
+    async fn test_revocation_from_admin() {
+        let dir = tempdir().unwrap();
+        #[allow(clippy::arc_with_non_send_sync)]
+        let whitelist =
+            Arc::new(PeerWhitelist::open(&dir.path().join("whitelist.db"), None).unwrap());
+
+        let admin = libp2p::identity::Keypair::generate_ed25519();
+        let relay = libp2p::identity::Keypair::generate_ed25519();
+        let compromised = PeerId::random();
+        whitelist
+            .add_peer(&compromised, None, None, None)
+            .await
+            .unwrap();
+
+        let config = KeyDistributionConfig {
+            admin_peers: [admin.public().to_peer_id().to_string()]
+                .into_iter()
+                .collect(),
+            ..Default::default()
+        };
+        let manager = KeyDistributionManager::new(whitelist.clone(), config, relay.clone());
+
+        // 管理者でないピアの失効は無視する
+        let forged = KeyDistributionMessage::Revocation {
+            revocation: SignedData::new(
+                Revocation {
+                    peer_id: compromised.to_string(),
+                    reason: None,
+                    revoked_at: Utc::now(),
+                },
+                &relay,
+            )
+            .unwrap(),
+            timestamp: Utc::now(),
+        };
+        manager
+            .handle_message(signed(forged, &relay), relay.public().to_peer_id())
+            .await
+            .unwrap();
+        assert!(!whitelist.is_revoked(&compromised).await.unwrap());
+
+        // 管理者の署名があれば、鍵が保存されていなくても中継されたものを受け入れる
+        let dir = tempdir().unwrap();
+        #[allow(clippy::arc_with_non_send_sync)]
+        let admin_whitelist =
+            Arc::new(PeerWhitelist::open(&dir.path().join("whitelist.db"), None).unwrap());
+        let admin_manager = KeyDistributionManager::new(
+            admin_whitelist.clone(),
+            KeyDistributionConfig {
+                admin_peers: [admin.public().to_peer_id().to_string()]
+                    .into_iter()
+                    .collect(),
+                ..Default::default()
+            },
+            admin.clone(),
+        );
+        admin_manager
+            .revoke_peer(&compromised, Some("Leaked key".to_string()))
+            .await
+            .unwrap();
+        assert!(admin_whitelist.is_revoked(&compromised).await.unwrap());
+
+        let relayed = admin_manager
+            .relay_revocations(&relay.public().to_peer_id())
+            .await
+            .unwrap();
+        assert_eq!(relayed.len(), 1);
+        manager
+            .apply_revocations(relayed, admin.public().to_peer_id())
+            .await
+            .unwrap();
+        assert!(whitelist.is_revoked(&compromised).await.unwrap());
+        assert!(!whitelist.is_whitelisted(&compromised).await.unwrap());
+    }
+
+    #[tokio::test]
+    async fn test_revocations_are_relayed_once_per_peer() {
+        let dir = tempdir().unwrap();
+        #[allow(clippy::arc_with_non_send_sync)]
+        let whitelist =
+            Arc::new(PeerWhitelist::open(&dir.path().join("whitelist.db"), None).unwrap());
+        let admin = libp2p::identity::Keypair::generate_ed25519();
+        let manager = KeyDistributionManager::new(
+            whitelist,
+            KeyDistributionConfig {
+                admin_peers: [admin.public().to_peer_id().to_string()]
+                    .into_iter()
+                    .collect(),
+                ..Default::default()
+            },
+            admin,
+        );
+        manager.revoke_peer(&PeerId::random(), None).await.unwrap();
+
+        // 購読のたびに送り直さず、同じピアには間隔を空けるまで送らない
+        let subscriber = PeerId::random();
+        assert_eq!(
+            manager.relay_revocations(&subscriber).await.unwrap().len(),
+            1
+        );
+        assert!(manager
+            .relay_revocations(&subscriber)
+            .await
+            .unwrap()
+            .is_empty());
+        assert_eq!(
+            manager
+                .relay_revocations(&PeerId::random())
+                .await
+                .unwrap()
+                .len(),
+            1
+        );
+
+        // 間隔が過ぎれば再び送る
+        manager.relayed_revocations.write().await.insert(
+            subscriber,
+            Utc::now() - chrono::Duration::seconds(REVOCATION_RELAY_INTERVAL_SECS + 1),
+        );
+        assert_eq!(
+            manager.relay_revocations(&subscriber).await.unwrap().len(),
+            1
+        );
+    }
+
+    #[tokio::test]
+    async fn test_group_key_request_and_rotation() {
+        use crate::namespace::NamespaceConfig;
+        use libp2p::identity::Keypair;
+
+        let dir = tempdir().unwrap();
+        let member = Keypair::generate_ed25519();
+        let reader = Keypair::generate_ed25519();
+        let other_reader = Keypair::generate_ed25519();
+        let outsider = Keypair::generate_ed25519();
+        let [member_id, reader_id, other_reader_id, outsider_id] =
+            [&member, &reader, &other_reader, &outsider].map(|key| key.public().to_peer_id());
+
+        let mut configs = std::collections::BTreeMap::new();
+        configs.insert(
+            "secrets".to_string(),
+            NamespaceConfig {
+                readers: Some(
+                    [member_id, reader_id, other_reader_id]
+                        .map(|id| id.to_string())
+                        .into(),
+                ),
+                writers: Some([member_id.to_string()].into()),
+                encrypt: true,
+            },
+        );
+        let namespaces = Namespaces::new(&configs).unwrap();
+
+        let manager = |name: &str, keypair: &Keypair, peers: &[&Keypair]| {
+            #[allow(clippy::arc_with_non_send_sync)]
+            let whitelist = Arc::new(PeerWhitelist::open(&dir.path().join(name), None).unwrap());
+            let manager = KeyDistributionManager::new(
+                whitelist.clone(),
+                KeyDistributionConfig::default(),
+                keypair.clone(),
+            )
+            .with_namespaces(namespaces.clone());
+            let peers: Vec<_> = peers.iter().map(|key| key.public()).collect();
+            async move {
+                for public_key in peers {
+                    whitelist
+                        .add_peer(&public_key.to_peer_id(), None, Some(&public_key), None)
+                        .await
+                        .unwrap();
+                }
+                (manager, whitelist)
+            }
+        };
+        let (member_manager, member_whitelist) =
+            manager("member.db", &member, &[&other_reader, &outsider]).await;
+        // 公開鍵を保存していないピアには要求に含まれる鍵で渡す
+        member_whitelist
+            .add_peer(&reader_id, None, None, None)
+            .await
+            .unwrap();
+        let (reader_manager, _) = manager("reader.db", &reader, &[&member]).await;
+        let (outsider_manager, _) = manager("outsider.db", &outsider, &[&member]).await;
+
+        // 読者でも書き込み権限がなければ鍵を作れない
+        assert!(reader_manager
+            .group_key_for_writing("secrets")
+            .await
+            .is_err());
+        let key = member_manager
+            .group_key_for_writing("secrets")
+            .await
+            .unwrap();
+        let encrypted = key.encrypt("secrets", b"value").unwrap();
+        assert_eq!(
+            reader_manager.decrypt("secrets", &encrypted).await.unwrap(),
+            None
+        );
+
+        let request = reader_manager
+            .request_group_key("secrets", Some(key.id()))
+            .await
+            .unwrap();
+        // 同じ鍵は続けて要求しない
+        assert!(reader_manager
+            .request_group_key("secrets", Some(key.id()))
+            .await
+            .is_none());
+
+        let grant = member_manager
+            .handle_message(signed(request, &reader), reader_id)
+            .await
+            .unwrap()
+            .unwrap();
+        reader_manager
+            .handle_message(signed(grant, &member), member_id)
+            .await
+            .unwrap();
+        assert_eq!(
+            reader_manager.decrypt("secrets", &encrypted).await.unwrap(),
+            Some(b"value".to_vec())
+        );
+
+        // 読者でないピアには渡さない
+        let request = outsider_manager
+            .request_group_key("secrets", None)
+            .await
+            .unwrap();
+        assert!(member_manager
+            .handle_message(signed(request, &outsider), outsider_id)
+            .await
+            .unwrap()
+            .is_none());
+
+        member_whitelist.remove_peer(&reader_id).await.unwrap();
+        let grants = member_manager.rotate_group_keys().await.unwrap();
+        assert_eq!(grants.len(), 1);
+        match &grants[0] {
+            KeyDistributionMessage::GroupKeyGrant { epoch, keys, .. } => {
+                assert_eq!(*epoch, 2);
+                let recipients: Vec<_> = keys.iter().map(|key| key.recipient.clone()).collect();
+                assert_eq!(recipients, vec![other_reader_id.to_string()]);
+            }
+            _ => panic!("Expected GroupKeyGrant"),
+        }
+        assert_eq!(
+            member_manager
+                .group_key_for_writing("secrets")
+                .await
+                .unwrap()
+                .epoch(),
+            2
+        );
+    }
+
+    #[tokio::test]
+    async fn test_rotated_key_is_only_granted_to_its_recipients() {
+        use crate::namespace::NamespaceConfig;
+        use libp2p::identity::Keypair;
+
+        let dir = tempdir().unwrap();
+        let [rotating, member, removed, late] = [(); 4].map(|_| Keypair::generate_ed25519());
+        let [rotating_id, member_id, removed_id, late_id] =
+            [&rotating, &member, &removed, &late].map(|key| key.public().to_peer_id());
+
+        let mut configs = std::collections::BTreeMap::new();
+        configs.insert(
+            "secrets".to_string(),
+            NamespaceConfig {
+                readers: Some(
+                    [rotating_id, member_id, removed_id, late_id]
+                        .map(|id| id.to_string())
+                        .into(),
+                ),
+                writers: Some([rotating_id, member_id].map(|id| id.to_string()).into()),
+                encrypt: true,
+            },
+        );
+        let namespaces = Namespaces::new(&configs).unwrap();
+
+        let manager = |name: &str, keypair: &Keypair, peers: &[&Keypair]| {
+            #[allow(clippy::arc_with_non_send_sync)]
+            let whitelist = Arc::new(PeerWhitelist::open(&dir.path().join(name), None).unwrap());
+            let manager = KeyDistributionManager::new(
+                whitelist.clone(),
+                KeyDistributionConfig::default(),
+                keypair.clone(),
+            )
+            .with_namespaces(namespaces.clone());
+            let peers: Vec<_> = peers.iter().map(|key| key.public()).collect();
+            async move {
+                for public_key in peers {
+                    whitelist
+                        .add_peer(&public_key.to_peer_id(), None, Some(&public_key), None)
+                        .await
+                        .unwrap();
+                }
+                (manager, whitelist)
+            }
+        };
+        let (rotating_manager, rotating_whitelist) =
+            manager("rotating.db", &rotating, &[&member, &removed]).await;
+        // removed はこのメンバーのホワイトリストからはまだ削除されていない
+        let (member_manager, member_whitelist) =
+            manager("member.db", &member, &[&rotating, &removed]).await;
+
+        rotating_manager
+            .group_key_for_writing("secrets")
+            .await
+            .unwrap();
+        rotating_whitelist.remove_peer(&removed_id).await.unwrap();
+        let grant = rotating_manager
+            .rotate_group_keys()
+            .await
+            .unwrap()
+            .remove(0);
+        member_manager
+            .handle_message(signed(grant, &rotating), rotating_id)
+            .await
+            .unwrap();
+        let key = member_manager
+            .group_key_for_writing("secrets")
+            .await
+            .unwrap();
+        assert_eq!(key.epoch(), 2);
+
+        let request = |keypair: &Keypair| KeyDistributionMessage::GroupKeyRequest {
+            requestor: keypair.public().to_peer_id().to_string(),
+            public_key: keypair.public().encode_protobuf(),
+            namespace: "secrets".to_string(),
+            key_id: None,
+            timestamp: Utc::now(),
+        };
+        assert!(member_manager
+            .handle_message(signed(request(&removed), &removed), removed_id)
+            .await
+            .unwrap()
+            .is_none());
+
+        // ローテーション後にホワイトリストへ追加したピアには渡す
+        member_whitelist
+            .add_peer(&late_id, None, Some(&late.public()), None)
+            .await
+            .unwrap();
+        match member_manager
+            .handle_message(signed(request(&late), &late), late_id)
+            .await
+            .unwrap()
+        {
+            Some(KeyDistributionMessage::GroupKeyGrant {
+                key_id, recipients, ..
+            }) => {
+                assert_eq!(key_id, key.id());
+                let recipients = recipients.unwrap();
+                assert!(recipients.contains(&member_id.to_string()));
+                assert!(!recipients.contains(&removed_id.to_string()));
+            }
+            other => panic!("Expected GroupKeyGrant, got {other:?}"),
+        }
+
+        // removed を残したメンバーが次にローテーションしても removed には渡らない
+        member_whitelist.remove_peer(&late_id).await.unwrap();
+        match &member_manager.rotate_group_keys().await.unwrap()[0] {
+            KeyDistributionMessage::GroupKeyGrant { keys, .. } => {
+                let recipients: Vec<_> = keys.iter().map(|key| key.recipient.clone()).collect();
+                assert_eq!(recipients, vec![rotating_id.to_string()]);
+            }
+            _ => panic!("Expected GroupKeyGrant"),
+        }
+    }
+
+    #[tokio::test]
+    async fn test_rotated_key_reaches_readers_without_stored_key() {
+        use crate::namespace::NamespaceConfig;
+        use libp2p::identity::Keypair;
+
+        let dir = tempdir().unwrap();
+        let [rotating, keyless, removed] = [(); 3].map(|_| Keypair::generate_ed25519());
+        let [rotating_id, keyless_id, removed_id] =
+            [&rotating, &keyless, &removed].map(|key| key.public().to_peer_id());
+
+        let mut configs = std::collections::BTreeMap::new();
+        configs.insert(
+            "secrets".to_string(),
+            NamespaceConfig {
+                readers: Some(
+                    [rotating_id, keyless_id, removed_id]
+                        .map(|id| id.to_string())
+                        .into(),
+                ),
+                writers: Some([rotating_id.to_string()].into()),
+                encrypt: true,
+            },
+        );
+        let namespaces = Namespaces::new(&configs).unwrap();
+
+        #[allow(clippy::arc_with_non_send_sync)]
+        let rotating_whitelist =
+            Arc::new(PeerWhitelist::open(&dir.path().join("rotating.db"), None).unwrap());
+        let rotating_manager = KeyDistributionManager::new(
+            rotating_whitelist.clone(),
+            KeyDistributionConfig::default(),
+            rotating.clone(),
+        )
+        .with_namespaces(namespaces.clone());
+        #[allow(clippy::arc_with_non_send_sync)]
+        let keyless_whitelist =
+            Arc::new(PeerWhitelist::open(&dir.path().join("keyless.db"), None).unwrap());
+        let keyless_manager = KeyDistributionManager::new(
+            keyless_whitelist.clone(),
+            KeyDistributionConfig::default(),
+            keyless.clone(),
+        )
+        .with_namespaces(namespaces);
+        keyless_whitelist
+            .add_peer(&rotating_id, None, Some(&rotating.public()), None)
+            .await
+            .unwrap();
+
+        // PeerId だけでホワイトリストに登録し、公開鍵は保存しない
+        for peer_id in [keyless_id, removed_id] {
+            rotating_whitelist
+                .add_peer(&peer_id, None, None, None)
+                .await
+                .unwrap();
+        }
+        rotating_manager
+            .group_key_for_writing("secrets")
+            .await
+            .unwrap();
+        rotating_whitelist.remove_peer(&removed_id).await.unwrap();
+
+        let grant = rotating_manager
+            .rotate_group_keys()
+            .await
+            .unwrap()
+            .remove(0);
+        match &grant {
+            KeyDistributionMessage::GroupKeyGrant {
+                keys, recipients, ..
+            } => {
+                let wrapped: Vec<_> = keys.iter().map(|key| key.recipient.clone()).collect();
+                assert_eq!(wrapped, vec![keyless_id.to_string()]);
+                assert!(recipients
+                    .as_ref()
+                    .unwrap()
+                    .contains(&keyless_id.to_string()));
+            }
+            _ => panic!("Expected GroupKeyGrant"),
+        }
+        keyless_manager
+            .handle_message(signed(grant, &rotating), rotating_id)
+            .await
+            .unwrap();
+
+        let key = rotating_manager
+            .group_key_for_writing("secrets")
+            .await
+            .unwrap();
+        let encrypted = key.encrypt("secrets", b"value").unwrap();
+        assert_eq!(
+            keyless_manager
+                .decrypt("secrets", &encrypted)
+                .await
+                .unwrap(),
+            Some(b"value".to_vec())
+        );
+
+        // 後から要求しても新しい鍵を受け取れる
+        let request = KeyDistributionMessage::GroupKeyRequest {
+            requestor: keyless_id.to_string(),
+            public_key: keyless.public().encode_protobuf(),
+            namespace: "secrets".to_string(),
+            key_id: Some(key.id()),
+            timestamp: Utc::now(),
+        };
+        assert!(matches!(
+            rotating_manager
+                .handle_message(signed(request, &keyless), keyless_id)
+                .await
+                .unwrap(),
+            Some(KeyDistributionMessage::GroupKeyGrant { .. })
+        ));
+    }
 }
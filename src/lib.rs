@@ -1,8 +1,24 @@
+pub mod anti_entropy;
+pub mod at_rest;
+pub mod chunks;
 pub mod config;
+pub mod control;
 pub mod crypto;
+pub mod discovery;
+pub mod file_sync;
+pub mod group_key;
+pub mod hlc;
+#[cfg(feature = "http-api")]
+pub mod http_api;
+pub mod identity;
 pub mod key_distribution;
+pub mod metrics;
+pub mod namespace;
 pub mod network;
+pub mod reload;
 pub mod security;
 pub mod storage;
 pub mod sync;
+pub mod trust;
+pub mod watch;
 pub mod whitelist;
@@ -1,42 +1,77 @@
 use anyhow::Result;
 use clap::{Parser, Subcommand};
 use futures::StreamExt;
-use libp2p::{gossipsub, identify, kad, mdns, noise, tcp, yamux, Multiaddr, SwarmBuilder};
+use libp2p::{
+    allow_block_list, gossipsub, gossipsub::MessageAcceptance, identify, kad, mdns, noise, tcp,
+    yamux, Multiaddr, SwarmBuilder,
+};
 use std::collections::hash_map::DefaultHasher;
 use std::hash::{Hash, Hasher};
 use std::io::Write;
-use std::path::PathBuf;
+use std::path::{Path, PathBuf};
 use std::sync::Arc;
 use std::time::Duration;
 use tokio::io::AsyncBufReadExt;
 use tracing::info;
 
+mod anti_entropy;
+mod at_rest;
 mod autostart;
+mod chunks;
 mod config;
 mod connection_manager;
+mod control;
 mod crypto;
+mod discovery;
+mod file_sync;
+mod group_key;
+mod hlc;
+#[cfg(feature = "http-api")]
+mod http_api;
+mod identity;
 mod key_distribution;
+mod metrics;
+mod namespace;
 mod network;
+mod reload;
 mod security;
 mod storage;
 mod sync;
+mod trust;
+mod watch;
 mod whitelist;
 
+use anti_entropy::{ReconcileRequest, ReconcileResponse, StoreDigest};
+use base64::{engine::general_purpose::STANDARD as BASE64, Engine};
+use chunks::ChunkFetches;
+use config::{ConfigLoader, LoadedConfig, Overrides};
 use connection_manager::ConnectionManager;
+use control::{ControlRequest, ControlResponse, KeyVersion, PeerConnection, RequestError};
 use crypto::SignedData;
-use key_distribution::{KeyDistributionConfig, KeyDistributionManager, KeyDistributionMessage};
+use file_sync::{DirectorySync, LocalChange, SyncEvent};
+use identity::{load_public_key_from_file, PublicKeyFormat};
+use key_distribution::{KeyDistributionManager, KeyDistributionMessage};
+use metrics::Metrics;
+use namespace::{Namespaces, DEFAULT_NAMESPACE};
 use network::P2PSyncBehaviour;
-use security::{
-    sanitize_input, validate_key, validate_value, AccessControl, RateLimiter, SecurityConfig,
-};
-use storage::Storage;
-use sync::{P2PMessage, SyncMessage};
+use reload::{ConfigChanges, Reload, ReloadWatcher};
+use security::{AccessControl, RateLimiter, SecurityConfig};
+use storage::{Entry, Sequence, Storage, Value};
+use sync::{P2PMessage, SignedChange, SyncMessage};
 use whitelist::PeerWhitelist;
 
 #[derive(Parser)]
 #[command(name = "p2p-sync")]
 #[command(about = "P2P synchronization system", long_about = None)]
 struct Cli {
+    /// Config file (defaults to P2P_SYNC_CONFIG, or else <data_dir>/config.toml)
+    #[arg(long, global = true)]
+    config: Option<PathBuf>,
+
+    /// Override a config key, e.g. --set security.rate_limit_per_minute=120
+    #[arg(long = "set", value_name = "KEY=VALUE", global = true)]
+    set: Vec<String>,
+
     #[command(subcommand)]
     command: Commands,
 }
@@ -44,20 +79,57 @@ struct Cli {
 #[derive(Subcommand)]
 enum Commands {
     Start {
-        #[arg(short, long, default_value_t = 0)]
-        port: u16,
+        /// Listen port, 0 picks a free one (defaults to `port` of the config)
+        #[arg(short, long)]
+        port: Option<u16>,
 
         #[arg(short, long)]
         dial: Option<Multiaddr>,
 
-        #[arg(short, long)]
+        /// Bootstrap peer, replacing `bootstrap_peers` of the config (repeatable)
+        #[arg(short, long = "bootstrap", value_name = "ADDR")]
+        bootstrap: Vec<String>,
+
+        #[arg(long)]
         data_dir: Option<PathBuf>,
+
+        /// Mirror this directory tree between peers
+        #[arg(short, long)]
+        watch: Option<PathBuf>,
     },
 
     Install,
 
     #[command(subcommand)]
     Whitelist(WhitelistCommands),
+
+    #[command(subcommand)]
+    Identity(IdentityCommands),
+
+    #[command(subcommand)]
+    Storage(StorageCommands),
+
+    /// Inspect the configuration merged from the file, P2P_SYNC_* variables
+    /// and command line flags
+    #[command(subcommand)]
+    Config(ConfigCommands),
+
+    /// Send a command to a running node over its control socket
+    Ctl {
+        #[arg(long)]
+        data_dir: Option<PathBuf>,
+
+        /// Control socket path (defaults to <data_dir>/control.sock)
+        #[arg(long)]
+        socket: Option<PathBuf>,
+
+        /// Print the raw JSON response
+        #[arg(long)]
+        json: bool,
+
+        #[command(subcommand)]
+        command: CtlCommands,
+    },
 }
 
 #[derive(Subcommand)]
@@ -86,6 +158,378 @@ enum WhitelistCommands {
         peer_id: String,
         public_key_file: String,
     },
+
+    /// List trust recommendations, including revoked and expired ones
+    Recommendations {
+        /// Only list recommendations of this peer
+        peer_id: Option<String>,
+    },
+
+    /// List whitelist requests received from other peers
+    Pending,
+
+    /// Whitelist a peer with the public key from its request
+    Approve {
+        peer_id: String,
+    },
+
+    /// Discard a peer's whitelist request
+    Reject {
+        peer_id: String,
+    },
+
+    /// List peers revoked by an admin peer
+    Revoked,
+}
+
+#[derive(Subcommand)]
+enum CtlCommands {
+    /// Add or update a key-value pair
+    #[command(alias = "add")]
+    Put {
+        key: String,
+        #[arg(required_unless_present = "file")]
+        value: Option<String>,
+        /// Store the contents of a file as a binary value
+        #[arg(short, long, conflicts_with = "value")]
+        file: Option<PathBuf>,
+        /// Namespace of the key (defaults to "default")
+        #[arg(short = 'N', long)]
+        namespace: Option<String>,
+    },
+
+    /// Retrieve value for a key
+    Get {
+        key: String,
+        /// Write the value to a file instead of printing it (works for binary values)
+        #[arg(short, long, conflicts_with = "at")]
+        output: Option<PathBuf>,
+        /// Namespace of the key (defaults to "default")
+        #[arg(short = 'N', long)]
+        namespace: Option<String>,
+        /// Show the value the key had at this time (RFC 3339, e.g. 2025-01-04T12:00:00Z)
+        #[arg(long)]
+        at: Option<chrono::DateTime<chrono::Utc>>,
+    },
+
+    /// Show the versions of a key kept in the history
+    History {
+        key: String,
+        /// Namespace of the key (defaults to "default")
+        #[arg(short = 'N', long)]
+        namespace: Option<String>,
+    },
+
+    /// Restore a version from `history`, published as a new write
+    Rollback {
+        key: String,
+        version: u64,
+        /// Namespace of the key (defaults to "default")
+        #[arg(short = 'N', long)]
+        namespace: Option<String>,
+    },
+
+    /// Delete a key-value pair
+    Delete {
+        key: String,
+        /// Namespace of the key (defaults to "default")
+        #[arg(short = 'N', long)]
+        namespace: Option<String>,
+    },
+
+    /// List all stored items
+    List {
+        /// Only list keys starting with this prefix
+        #[arg(short, long)]
+        prefix: Option<String>,
+        /// Namespace of the key (defaults to "default")
+        #[arg(short = 'N', long)]
+        namespace: Option<String>,
+    },
+
+    /// Show connection status
+    Status,
+
+    /// Show connected peers
+    Peers,
+
+    /// Show node information
+    Info,
+
+    #[command(subcommand)]
+    Whitelist(CtlWhitelistCommands),
+
+    /// Announce the node's public key to all peers
+    AnnounceKey,
+
+    /// Request missing public keys
+    RequestKeys,
+
+    /// Request to be added to peer whitelists
+    RequestWhitelist {
+        #[arg(short, long)]
+        name: Option<String>,
+    },
+
+    /// Recommend a peer to the network
+    RecommendPeer {
+        peer_id: String,
+        #[arg(short, long)]
+        name: Option<String>,
+    },
+
+    /// Withdraw a recommendation made by this node
+    RevokeRecommendation { peer_id: String },
+
+    /// Revoke a compromised peer on every node (admin peers only)
+    RevokePeer {
+        peer_id: String,
+        #[arg(short, long)]
+        reason: Option<String>,
+    },
+
+    /// Clean up old key distribution data
+    Cleanup,
+
+    /// Reload whitelist cache from database
+    ReloadCache,
+
+    /// Re-read config.toml and the whitelist (also done on change or SIGHUP)
+    Reload,
+
+    /// Print changes as they are applied, until interrupted
+    Watch {
+        /// Only show keys starting with this prefix
+        #[arg(short, long)]
+        prefix: Option<String>,
+        /// Only show changes to this namespace (defaults to all namespaces)
+        #[arg(short = 'N', long)]
+        namespace: Option<String>,
+    },
+}
+
+#[derive(Subcommand)]
+enum CtlWhitelistCommands {
+    Add {
+        peer_id: String,
+        #[arg(short, long)]
+        name: Option<String>,
+        #[arg(short, long)]
+        expires_in_hours: Option<u64>,
+        #[arg(short = 'k', long)]
+        public_key_file: Option<String>,
+    },
+
+    Remove {
+        peer_id: String,
+    },
+
+    List,
+
+    Check {
+        peer_id: String,
+    },
+
+    /// List whitelist requests received from other peers
+    Pending,
+
+    /// Whitelist a peer with the public key from its request
+    Approve {
+        peer_id: String,
+    },
+
+    /// Discard a peer's whitelist request
+    Reject {
+        peer_id: String,
+    },
+
+    /// List peers revoked by an admin peer
+    Revoked,
+
+    /// List trust recommendations, including revoked and expired ones
+    Recommendations {
+        /// Only list recommendations of this peer
+        peer_id: Option<String>,
+    },
+}
+
+impl CtlCommands {
+    fn into_request(self) -> Result<ControlRequest> {
+        let request = match self {
+            CtlCommands::Put {
+                key,
+                file: Some(path),
+                namespace,
+                ..
+            } => ControlRequest::PutBytes {
+                key,
+                value: BASE64.encode(std::fs::read(&path)?),
+                namespace,
+            },
+            CtlCommands::Put {
+                key,
+                value,
+                namespace,
+                ..
+            } => ControlRequest::Put {
+                key,
+                value: value.unwrap_or_default(),
+                namespace,
+            },
+            CtlCommands::Get {
+                key,
+                output: Some(_),
+                namespace,
+                ..
+            } => ControlRequest::GetBytes { key, namespace },
+            CtlCommands::Get {
+                key, namespace, at, ..
+            } => ControlRequest::Get { key, namespace, at },
+            CtlCommands::History { key, namespace } => ControlRequest::History { key, namespace },
+            CtlCommands::Rollback {
+                key,
+                version,
+                namespace,
+            } => ControlRequest::Rollback {
+                key,
+                version,
+                namespace,
+            },
+            CtlCommands::Delete { key, namespace } => ControlRequest::Delete { key, namespace },
+            CtlCommands::List { prefix, namespace } => ControlRequest::List { prefix, namespace },
+            CtlCommands::Status => ControlRequest::Status,
+            CtlCommands::Peers => ControlRequest::Peers,
+            CtlCommands::Info => ControlRequest::Info,
+            CtlCommands::Whitelist(CtlWhitelistCommands::Add {
+                peer_id,
+                name,
+                expires_in_hours,
+                public_key_file,
+            }) => {
+                // 鍵ファイルはクライアント側で読み込み、base64 で送る
+                let public_key = public_key_file
+                    .map(|path| load_public_key_from_file(&path))
+                    .transpose()?
+                    .map(|pk| {
+                        String::from_utf8_lossy(&identity::encode_public_key(
+                            &pk,
+                            PublicKeyFormat::Base64,
+                        ))
+                        .into_owned()
+                    });
+
+                ControlRequest::WhitelistAdd {
+                    peer_id,
+                    name,
+                    expires_in_hours,
+                    public_key,
+                }
+            }
+            CtlCommands::Whitelist(CtlWhitelistCommands::Remove { peer_id }) => {
+                ControlRequest::WhitelistRemove { peer_id }
+            }
+            CtlCommands::Whitelist(CtlWhitelistCommands::List) => ControlRequest::WhitelistList,
+            CtlCommands::Whitelist(CtlWhitelistCommands::Check { peer_id }) => {
+                ControlRequest::WhitelistCheck { peer_id }
+            }
+            CtlCommands::Whitelist(CtlWhitelistCommands::Pending) => {
+                ControlRequest::WhitelistPending
+            }
+            CtlCommands::Whitelist(CtlWhitelistCommands::Approve { peer_id }) => {
+                ControlRequest::WhitelistApprove { peer_id }
+            }
+            CtlCommands::Whitelist(CtlWhitelistCommands::Reject { peer_id }) => {
+                ControlRequest::WhitelistReject { peer_id }
+            }
+            CtlCommands::Whitelist(CtlWhitelistCommands::Revoked) => {
+                ControlRequest::WhitelistRevoked
+            }
+            CtlCommands::Whitelist(CtlWhitelistCommands::Recommendations { peer_id }) => {
+                ControlRequest::WhitelistRecommendations { peer_id }
+            }
+            CtlCommands::AnnounceKey => ControlRequest::AnnounceKey,
+            CtlCommands::RequestKeys => ControlRequest::RequestKeys,
+            CtlCommands::RequestWhitelist { name } => ControlRequest::RequestWhitelist { name },
+            CtlCommands::RecommendPeer { peer_id, name } => {
+                ControlRequest::RecommendPeer { peer_id, name }
+            }
+            CtlCommands::RevokeRecommendation { peer_id } => {
+                ControlRequest::RevokeRecommendation { peer_id }
+            }
+            CtlCommands::RevokePeer { peer_id, reason } => {
+                ControlRequest::RevokePeer { peer_id, reason }
+            }
+            CtlCommands::Cleanup => ControlRequest::Cleanup,
+            CtlCommands::ReloadCache => ControlRequest::ReloadCache,
+            CtlCommands::Reload => ControlRequest::Reload,
+            CtlCommands::Watch { prefix, namespace } => ControlRequest::Watch { prefix, namespace },
+        };
+
+        Ok(request)
+    }
+}
+
+#[derive(Subcommand)]
+enum IdentityCommands {
+    /// Print the PeerId and public key of this node
+    Show {
+        #[arg(short, long)]
+        data_dir: Option<PathBuf>,
+    },
+
+    /// Write the public key in a format accepted by `whitelist add -k`
+    ExportPublic {
+        #[arg(short, long)]
+        output: Option<PathBuf>,
+        #[arg(short, long, value_enum, default_value_t = PublicKeyFormat::Base64)]
+        format: PublicKeyFormat,
+        #[arg(short, long)]
+        data_dir: Option<PathBuf>,
+    },
+
+    /// Generate a new keypair, keeping the old one as identity.key.old
+    Rotate {
+        #[arg(short, long)]
+        data_dir: Option<PathBuf>,
+    },
+}
+
+#[derive(Subcommand)]
+enum ConfigCommands {
+    /// Print the effective configuration
+    Show {
+        #[arg(short, long)]
+        data_dir: Option<PathBuf>,
+    },
+
+    /// Report every invalid value and unknown key of the effective configuration
+    Validate {
+        #[arg(short, long)]
+        data_dir: Option<PathBuf>,
+    },
+
+    /// Write the default configuration to the config file
+    Init {
+        #[arg(short, long)]
+        data_dir: Option<PathBuf>,
+        /// Replace an existing file
+        #[arg(long)]
+        force: bool,
+    },
+}
+
+#[derive(Subcommand)]
+enum StorageCommands {
+    /// Re-encrypt sync.db and whitelist.db with a new key: the passphrase in
+    /// P2P_SYNC_NEW_PASSPHRASE, or else a newly generated key file. The node
+    /// must be stopped.
+    Rekey {
+        #[arg(short, long)]
+        data_dir: Option<PathBuf>,
+        /// Store the data in plaintext instead
+        #[arg(long)]
+        decrypt: bool,
+    },
 }
 
 #[tokio::main]
@@ -94,19 +538,57 @@ async fn main() -> Result<()> {
 
     let cli = Cli::parse();
 
+    // 設定はデフォルト < ファイル < P2P_SYNC_* 環境変数 < コマンドライン の順に重ねる
+    let loader = ConfigLoader::new(default_data_dir())
+        .with_path(cli.config)
+        .with_env(std::env::vars())
+        .with_overrides(Overrides {
+            set: cli.set.clone(),
+            ..Default::default()
+        });
+
     match cli.command {
         Commands::Start {
             port,
             dial,
+            bootstrap,
             data_dir,
+            watch,
         } => {
-            start_node(port, dial, data_dir).await?;
+            let loader = loader.with_overrides(Overrides {
+                port,
+                data_dir,
+                bootstrap_peers: bootstrap,
+                set: cli.set,
+            });
+            start_node(loader, dial, watch).await?;
         }
         Commands::Install => {
             install_service()?;
         }
         Commands::Whitelist(cmd) => {
-            handle_whitelist_command(cmd).await?;
+            handle_whitelist_command(cmd, loader.load()?).await?;
+        }
+        Commands::Identity(cmd) => {
+            handle_identity_command(cmd, &loader)?;
+        }
+        Commands::Storage(cmd) => {
+            handle_storage_command(cmd, &loader)?;
+        }
+        Commands::Config(cmd) => {
+            handle_config_command(cmd, loader)?;
+        }
+        Commands::Ctl {
+            data_dir,
+            socket,
+            json,
+            command,
+        } => {
+            let socket = match socket {
+                Some(socket) => socket,
+                None => control::socket_path(&resolve_data_dir(&loader, data_dir)?),
+            };
+            handle_ctl_command(socket, json, command).await?;
         }
     }
 
@@ -114,52 +596,74 @@ async fn main() -> Result<()> {
 }
 
 async fn start_node(
-    port: u16,
+    loader: ConfigLoader,
     dial_addr: Option<Multiaddr>,
-    data_dir: Option<PathBuf>,
+    watch_dir: Option<PathBuf>,
 ) -> Result<()> {
-    let data_dir = data_dir.unwrap_or_else(|| {
-        dirs::data_dir()
-            .unwrap_or_else(|| PathBuf::from("."))
-            .join("p2p-sync")
-    });
+    let loaded = loader.load()?;
+    for key in &loaded.unknown_keys {
+        tracing::warn!("Ignoring unknown config key: {}", key);
+    }
+    loaded.config.validate()?;
+    let LoadedConfig {
+        mut config,
+        path: config_path,
+        data_dir,
+        ..
+    } = loaded;
+    let port = config.port;
 
     std::fs::create_dir_all(&data_dir)?;
-    let storage = Storage::new(data_dir.join("sync.db"))?;
 
-    // 設定の読み込み
-    let config_path = data_dir.join("config.toml");
-    let config = config::load_config(&config_path)?;
+    // Load the persistent node identity (created on first start)
+    let local_key = identity::load_or_create(&data_dir)?;
+    let local_peer_id = libp2p::PeerId::from(local_key.public());
 
-    // デフォルト設定を保存
+    // デフォルト設定を保存（環境変数やフラグで上書きした値は書き込まない）
     if !config_path.exists() {
-        config::save_config(&config_path, &config)?;
+        if let Some(parent) = config_path.parent() {
+            std::fs::create_dir_all(parent)?;
+        }
+        config::save_config(&config_path, &config::Config::default())?;
         info!("Created default config at: {}", config_path.display());
     }
 
-    let rate_limiter = RateLimiter::new(config.security.clone());
+    // 拒否数やメッシュサイズなどの計測値（[metrics] 有効時に HTTP で公開する）
+    let metrics = Metrics::default();
+
+    let secret = storage_secret(&data_dir, &config)?;
+    let storage = Storage::open(data_dir.join("sync.db"), secret.as_ref())?
+        .with_max_clock_skew(config.storage.max_clock_skew_secs)
+        .with_keypair(local_key.clone())
+        .with_metrics(metrics.clone())?;
+
+    let rate_limiter = RateLimiter::new(config.security.clone()).with_metrics(metrics.clone());
+    let namespaces = Namespaces::new(&config.namespaces)?;
 
     // ホワイトリストの初期化
     let whitelist_path = data_dir.join("whitelist.db");
     #[allow(clippy::arc_with_non_send_sync)]
-    let whitelist = Arc::new(PeerWhitelist::new(&whitelist_path)?);
+    let whitelist = Arc::new(
+        PeerWhitelist::open(&whitelist_path, secret.as_ref())?.with_trust(config.trust.clone()),
+    );
 
     // ホワイトリストを含むアクセス制御の初期化
-    let access_control = AccessControl::with_whitelist(config.security.clone(), whitelist.clone());
-    let connection_manager = ConnectionManager::new(access_control);
-
-    // Generate keypair for this node
-    let local_key = libp2p::identity::Keypair::generate_ed25519();
-    let local_peer_id = libp2p::PeerId::from(local_key.public());
+    let access_control = AccessControl::with_whitelist(config.security.clone(), whitelist.clone())
+        .with_metrics(metrics.clone());
+    let connection_manager = ConnectionManager::new(access_control).with_metrics(metrics.clone());
 
     // Initialize key distribution manager
-    let key_dist_config = KeyDistributionConfig::default();
+    let key_dist_config = config.key_distribution.clone();
     #[allow(clippy::arc_with_non_send_sync)]
-    let key_dist_manager = Arc::new(KeyDistributionManager::new(
-        whitelist.clone(),
-        key_dist_config,
-        local_key.clone(),
-    ));
+    let key_dist_manager = Arc::new(
+        KeyDistributionManager::new(whitelist.clone(), key_dist_config, local_key.clone())
+            .with_namespaces(namespaces.clone()),
+    );
+
+    let topic_hashes: Vec<_> = namespaces
+        .names()
+        .map(|name| namespace::topic(name).hash())
+        .collect();
 
     let mut swarm = SwarmBuilder::with_existing_identity(local_key.clone())
         .with_tokio()
@@ -169,6 +673,7 @@ async fn start_node(
             yamux::Config::default,
         )?
         .with_quic()
+        .with_dns()?
         .with_behaviour(|key| {
             let message_id_fn = |message: &gossipsub::Message| {
                 let mut s = DefaultHasher::new();
@@ -176,42 +681,64 @@ async fn start_node(
                 gossipsub::MessageId::from(s.finish().to_string())
             };
 
+            // 受信したメッセージは handle_gossipsub_message の検証結果を報告するまで転送しない
             let gossipsub_config = gossipsub::ConfigBuilder::default()
                 .heartbeat_interval(Duration::from_secs(10))
                 .validation_mode(gossipsub::ValidationMode::Strict)
+                .validate_messages()
                 .message_id_fn(message_id_fn)
+                .max_transmit_size(config.security.gossipsub_max_transmit_size())
                 .build()
                 .expect("Valid config");
 
-            let gossipsub = gossipsub::Behaviour::new(
+            let mut gossipsub = gossipsub::Behaviour::new(
                 gossipsub::MessageAuthenticity::Signed(key.clone()),
                 gossipsub_config,
             )
             .expect("Correct configuration");
+            config.peer_scoring.apply(&mut gossipsub, &topic_hashes)?;
 
             let mdns =
                 mdns::tokio::Behaviour::new(mdns::Config::default(), key.public().to_peer_id())?;
-            let kad = kad::Behaviour::new(
-                key.public().to_peer_id(),
-                kad::store::MemoryStore::new(key.public().to_peer_id()),
-            );
+            let kad = discovery::new_kad_behaviour(key.public().to_peer_id(), &config.discovery);
             let identify = identify::Behaviour::new(identify::Config::new(
                 "/p2p-sync/0.1.0".to_string(),
                 key.public(),
             ));
 
             Ok(P2PSyncBehaviour {
+                blocked: allow_block_list::Behaviour::default(),
                 gossipsub,
                 mdns,
                 kad,
                 identify,
+                reconcile: anti_entropy::new_behaviour(),
+                chunks: chunks::new_behaviour(),
             })
         })?
         .with_swarm_config(|c| c.with_idle_connection_timeout(Duration::from_secs(60)))
         .build();
 
-    let topic = gossipsub::IdentTopic::new("p2p-sync");
-    swarm.behaviour_mut().gossipsub.subscribe(&topic)?;
+    // ブロックされたピアとの接続は確立時点で拒否する
+    for peer_id in config.security.blocked_peer_ids() {
+        swarm.behaviour_mut().blocked.block_peer(peer_id);
+    }
+    // 管理者に失効させられたピアも同様に拒否する
+    for revocation in whitelist.list_revocations().await? {
+        swarm
+            .behaviour_mut()
+            .blocked
+            .block_peer(revocation.data.peer_id.parse()?);
+    }
+
+    // 鍵配布などの制御メッセージは default ネームスペースのトピックで送る
+    let topic = namespace::topic(DEFAULT_NAMESPACE);
+    for name in namespaces.names() {
+        swarm
+            .behaviour_mut()
+            .gossipsub
+            .subscribe(&namespace::topic(name))?;
+    }
 
     swarm.listen_on(format!("/ip4/0.0.0.0/tcp/{port}").parse()?)?;
     swarm.listen_on(format!("/ip4/0.0.0.0/udp/{port}/quic-v1").parse()?)?;
@@ -220,8 +747,67 @@ async fn start_node(
         swarm.dial(addr)?;
     }
 
+    // 設定されたブートストラップピアに接続し、Kademlia のルーティングテーブルに登録する
+    add_bootstrap_peers(&mut swarm, &config.bootstrap_peers);
+
     info!("Local peer id: {:?}", swarm.local_peer_id());
 
+    // ディレクトリ同期（--watch 指定時のみ）
+    let mut dir_sync = match watch_dir {
+        Some(dir) => {
+            let mut dir_sync =
+                DirectorySync::new(&dir, &data_dir, &storage, local_peer_id.to_string())?
+                    .with_max_object_size(config.security.max_object_size);
+            let changes = dir_sync.initial_scan(&storage)?;
+            apply_local_file_changes(
+                &mut swarm,
+                &storage,
+                &namespaces,
+                &config.security,
+                &key_dist_manager,
+                changes,
+            )
+            .await;
+            info!("Watching {} for changes", dir_sync.root().display());
+            Some(dir_sync)
+        }
+        None => None,
+    };
+
+    // 大きな値のチャンク取得状況
+    let mut value_fetches = ChunkFetches::default();
+
+    // ローカル制御ソケット（ヘッドレス運用時は `p2p-sync ctl` から操作する）
+    let (control_tx, mut control_rx) = tokio::sync::mpsc::channel(32);
+
+    // 再読み込みした [security] を REST ゲートウェイにも反映する
+    let (security_tx, _security_rx) = tokio::sync::watch::channel(config.security.clone());
+
+    // REST ゲートウェイ（http-api フィーチャー有効時のみ）
+    if config.http.enabled {
+        #[cfg(feature = "http-api")]
+        http_api::spawn_server(
+            &config.http,
+            control_tx.clone(),
+            storage.changes(),
+            security_tx.subscribe(),
+        )
+        .await?;
+        #[cfg(not(feature = "http-api"))]
+        tracing::warn!("[http] is enabled but this binary was built without the http-api feature");
+    }
+    if config.metrics.enabled {
+        metrics::spawn_server(&config.metrics, &metrics).await?;
+    }
+    #[cfg(unix)]
+    {
+        let socket_path = control::socket_path(&data_dir);
+        control::spawn_server(&socket_path, control_tx, storage.changes())?;
+        info!("Control socket listening on {}", socket_path.display());
+    }
+    #[cfg(not(unix))]
+    drop(control_tx);
+
     // 初期プロンプトを表示
     println!("\n=== P2P Sync System Started ===");
     println!("Local Peer ID: {local_peer_id}");
@@ -232,22 +818,197 @@ async fn start_node(
     std::io::stdout().flush()?;
 
     let mut stdin = tokio::io::BufReader::new(tokio::io::stdin()).lines();
+    let mut stdin_open = true;
+
+    // 削除済みキーの tombstone を定期的にガベージコレクションする
+    let tombstone_retention =
+        chrono::Duration::hours(config.storage.tombstone_retention_hours as i64);
+    let history_retention = (config.storage.history_retention_hours > 0)
+        .then(|| chrono::Duration::hours(config.storage.history_retention_hours as i64));
+    let mut gc_interval = tokio::time::interval(Duration::from_secs(60 * 60));
+
+    // DHT のランダムウォークで mDNS の届かないピアを探す
+    let random_walk_period = config.discovery.random_walk_interval();
+    let mut random_walk_interval =
+        tokio::time::interval(random_walk_period.unwrap_or(Duration::from_secs(60)));
+
+    // メッシュの大きさはハートビートごとに変わるので定期的に反映する
+    let mut mesh_interval = tokio::time::interval(Duration::from_secs(10));
+
+    // 設定ファイルと別プロセスからのホワイトリスト変更を、再起動せずに反映する
+    let mut reload_watcher = ReloadWatcher::new(&config_path, &whitelist_path)?;
 
     loop {
         tokio::select! {
-            line = stdin.next_line() => {
-                if let Ok(Some(line)) = line {
-                    handle_input(&mut swarm, &storage, &topic, line, &config.security, &connection_manager, &local_key, &key_dist_manager, &whitelist).await?;
-                    // 次のプロンプトを表示
-                    print!("> ");
-                    std::io::stdout().flush()?;
+            _ = mesh_interval.tick() => {
+                let mesh_peers = swarm.behaviour().gossipsub.all_mesh_peers().count();
+                metrics.mesh_peers.set(mesh_peers as i64);
+            }
+            _ = gc_interval.tick() => {
+                match storage.purge_tombstones(chrono::Utc::now() - tombstone_retention) {
+                    Ok(0) => {}
+                    Ok(purged) => info!("Purged {} expired tombstones", purged),
+                    Err(e) => tracing::warn!("Failed to purge tombstones: {}", e),
+                }
+                if let Some(retention) = history_retention {
+                    match storage.purge_history(chrono::Utc::now() - retention) {
+                        Ok(0) => {}
+                        Ok(purged) => info!("Purged {} old versions from the history", purged),
+                        Err(e) => tracing::warn!("Failed to purge history: {}", e),
+                    }
+                }
+                match storage.purge_chunks() {
+                    Ok(0) => {}
+                    Ok(purged) => info!("Purged {} unreferenced value chunks", purged),
+                    Err(e) => tracing::warn!("Failed to purge value chunks: {}", e),
+                }
+            }
+            _ = random_walk_interval.tick(), if random_walk_period.is_some() => {
+                discovery::random_walk(&mut swarm.behaviour_mut().kad);
+            }
+            reload = reload_watcher.next() => {
+                if let Err(e) = reload_node(&mut swarm, reload, &loader, &mut config, &rate_limiter, &connection_manager, &whitelist, &key_dist_manager, &topic, &security_tx).await {
+                    tracing::warn!("Failed to reload: {:#}", e);
+                }
+            }
+            line = stdin.next_line(), if stdin_open => {
+                match line {
+                    Ok(Some(line)) => {
+                        let result = match line.trim() {
+                            "reload" => reload_node(&mut swarm, Reload::ALL, &loader, &mut config, &rate_limiter, &connection_manager, &whitelist, &key_dist_manager, &topic, &security_tx)
+                                .await
+                                .map(|message| println!("✓ {message}")),
+                            _ => handle_input(&mut swarm, &storage, &topic, &namespaces, line, &config.security, &connection_manager, &local_key, &key_dist_manager, &whitelist).await,
+                        };
+                        if let Err(e) = result {
+                            println!("✗ {e:#}");
+                        }
+                        // 次のプロンプトを表示
+                        print!("> ");
+                        std::io::stdout().flush()?;
+                    }
+                    // stdin が閉じられた（デーモンとして起動された）場合は制御ソケットのみで動作する
+                    _ => {
+                        stdin_open = false;
+                        info!("stdin closed, running headless");
+                    }
+                }
+            }
+            Some(command) = control_rx.recv() => {
+                let response = match command.request {
+                    ControlRequest::Reload => reload_node(&mut swarm, Reload::ALL, &loader, &mut config, &rate_limiter, &connection_manager, &whitelist, &key_dist_manager, &topic, &security_tx)
+                        .await
+                        .map(|message| ControlResponse::Done { message }),
+                    _ => execute_request(&mut swarm, &storage, &topic, &namespaces, &command.request, &config.security, &connection_manager, &local_key, &key_dist_manager, &whitelist).await,
+                }
+                .unwrap_or_else(|e| ControlResponse::from_error(&e));
+                let _ = command.reply.send(response);
+            }
+            event = next_sync_event(&mut dir_sync), if dir_sync.is_some() => {
+                if let Some(dir_sync) = dir_sync.as_mut() {
+                    handle_sync_event(&mut swarm, &storage, &namespaces, &config.security, &key_dist_manager, dir_sync, event).await;
                 }
             }
             event = swarm.select_next_some() => {
-                handle_swarm_event(&mut swarm, &storage, &topic, event, &rate_limiter, &connection_manager, &whitelist, &key_dist_manager).await?;
+                // 失敗はピアから届いた内容が原因のことがあるので、各処理の中で記録してノードは止めない
+                handle_swarm_event(&mut swarm, &storage, &topic, &namespaces, event, &config.security, &rate_limiter, &connection_manager, &whitelist, &key_dist_manager, &mut dir_sync, &mut value_fetches, &metrics).await;
+            }
+        }
+    }
+}
+
+/// Apply a changed config file or whitelist to the running node. `[security]`
+/// is swapped in place, blocked peers are closed through the swarm's block
+/// list and connected peers that no longer pass access control are
+/// disconnected. An invalid config leaves the running one untouched.
+#[allow(clippy::too_many_arguments)]
+async fn reload_node(
+    swarm: &mut libp2p::Swarm<P2PSyncBehaviour>,
+    reload: Reload,
+    loader: &ConfigLoader,
+    config: &mut config::Config,
+    rate_limiter: &RateLimiter,
+    connection_manager: &ConnectionManager,
+    whitelist: &PeerWhitelist,
+    key_dist_manager: &KeyDistributionManager,
+    topic: &gossipsub::IdentTopic,
+    security_tx: &tokio::sync::watch::Sender<SecurityConfig>,
+) -> Result<String> {
+    let mut reloaded = Vec::new();
+
+    if reload.config {
+        let loaded = loader.load()?;
+        loaded.config.validate()?;
+        for key in &loaded.unknown_keys {
+            tracing::warn!("Ignoring unknown config key: {}", key);
+        }
+
+        let changes = ConfigChanges::new(config, &loaded.config);
+        for setting in &changes.restart_required {
+            tracing::warn!("Changes to {} take effect after a restart", setting);
+        }
+        if changes.security {
+            let security = loaded.config.security;
+            rate_limiter.set_config(security.clone()).await;
+            connection_manager
+                .set_security_config(security.clone())
+                .await;
+            security_tx.send_replace(security.clone());
+            config.security = security;
+
+            for peer_id in changes.unblocked {
+                // 失効させられたピアはブロックリストから外さない
+                if !whitelist.is_revoked(&peer_id).await? {
+                    swarm.behaviour_mut().blocked.unblock_peer(peer_id);
+                }
             }
+            // ブロックリストに加えたピアとの接続はその場で閉じられる
+            for peer_id in changes.blocked {
+                swarm.behaviour_mut().blocked.block_peer(peer_id);
+                info!("Blocked peer: {peer_id}");
+            }
+            reloaded.push("security settings");
+        }
+    }
+
+    let mut rotated = 0;
+    if reload.whitelist {
+        // whitelist remove などで別のプロセスから削除されたピアにも新しい鍵を渡さない
+        let removed = whitelist.reload_cache().await?;
+        if !removed.is_empty() {
+            info!("Peers removed from the whitelist: {:?}", removed);
+            rotated = rotate_group_keys(swarm, topic, &config.security, key_dist_manager).await?;
         }
+        reloaded.push("whitelist");
     }
+
+    // 新しいブロックリストやホワイトリストで許可されなくなったピアを切断する
+    let mut disconnected = 0;
+    for peer_id in connection_manager
+        .get_active_connections()
+        .await
+        .into_keys()
+    {
+        if !connection_manager.is_peer_allowed(&peer_id).await {
+            disconnect_rejected_peer(swarm, &peer_id);
+            disconnected += 1;
+        }
+    }
+
+    let mut message = match (reloaded.is_empty(), disconnected) {
+        (true, 0) => "Nothing changed".to_string(),
+        (true, n) => format!("Disconnected {n} peers"),
+        (false, 0) => format!("Reloaded {}", reloaded.join(" and ")),
+        (false, n) => format!(
+            "Reloaded {}, disconnected {n} peers",
+            reloaded.join(" and ")
+        ),
+    };
+    if rotated > 0 {
+        message.push_str(&format!(", rotated {rotated} group key(s)"));
+    }
+    info!("{}", message);
+    Ok(message)
 }
 
 #[allow(clippy::too_many_arguments)]
@@ -255,6 +1016,7 @@ async fn handle_input(
     swarm: &mut libp2p::Swarm<P2PSyncBehaviour>,
     storage: &Storage,
     topic: &gossipsub::IdentTopic,
+    namespaces: &Namespaces,
     input: String,
     security_config: &SecurityConfig,
     connection_manager: &ConnectionManager,
@@ -263,115 +1025,74 @@ async fn handle_input(
     whitelist: &Arc<PeerWhitelist>,
 ) -> Result<()> {
     let parts: Vec<&str> = input.split_whitespace().collect();
+    let mut output = None;
 
-    match parts.as_slice() {
-        ["add", key, value] => {
-            // 入力のサニタイズ
-            let sanitized_key = sanitize_input(key);
-            let sanitized_value = sanitize_input(value);
-
-            // 入力検証
-            validate_key(&sanitized_key, security_config.max_key_length)?;
-            validate_value(&sanitized_value, security_config.max_value_length)?;
-
-            let timestamp = chrono::Utc::now();
-            let msg = SyncMessage::Put {
-                key: sanitized_key.clone(),
-                value: sanitized_value.clone(),
-                timestamp,
-            };
-
-            storage.put(&sanitized_key, &sanitized_value)?;
-
-            // Convert to P2P message and sign
-            let p2p_msg = P2PMessage::Sync(msg);
-            let signed_data = SignedData::new(p2p_msg, local_key)?;
-
-            let json = serde_json::to_vec(&signed_data)?;
-
-            // メッセージサイズチェック
-            if json.len() > security_config.max_message_size {
-                anyhow::bail!("Message too large: {} bytes", json.len());
+    let request = match parts.as_slice() {
+        ["add", key, value] => ControlRequest::Put {
+            key: key.to_string(),
+            value: value.to_string(),
+            namespace: None,
+        },
+        ["get", key] => ControlRequest::Get {
+            key: key.to_string(),
+            namespace: None,
+            at: None,
+        },
+        ["get", key, "--at", at] => ControlRequest::Get {
+            key: key.to_string(),
+            namespace: None,
+            at: Some(
+                at.parse()
+                    .map_err(|e| anyhow::anyhow!("Invalid time {at}: {e}"))?,
+            ),
+        },
+        ["history", key] => ControlRequest::History {
+            key: key.to_string(),
+            namespace: None,
+        },
+        ["rollback", key, version] => ControlRequest::Rollback {
+            key: key.to_string(),
+            version: version
+                .parse()
+                .map_err(|e| anyhow::anyhow!("Invalid version {version}: {e}"))?,
+            namespace: None,
+        },
+        ["put-file", key, path] => ControlRequest::PutBytes {
+            key: key.to_string(),
+            value: BASE64.encode(std::fs::read(path)?),
+            namespace: None,
+        },
+        ["get-file", key, path] => {
+            output = Some(PathBuf::from(path));
+            ControlRequest::GetBytes {
+                key: key.to_string(),
+                namespace: None,
             }
-
-            swarm
-                .behaviour_mut()
-                .gossipsub
-                .publish(topic.clone(), json)?;
-
-            println!("✓ Added: {sanitized_key} = {sanitized_value}");
-            info!("Published: {} = {}", sanitized_key, sanitized_value);
         }
-        ["get", key] => match storage.get(key)? {
-            Some(value) => {
-                println!("✓ {key} = {value}");
-                info!("{} = {}", key, value);
-            }
-            None => {
-                println!("✗ {key} not found");
-                info!("{} not found", key);
-            }
+        ["list"] => ControlRequest::List {
+            prefix: None,
+            namespace: None,
+        },
+        ["list", prefix] => ControlRequest::List {
+            prefix: Some(prefix.to_string()),
+            namespace: None,
+        },
+        ["status"] => ControlRequest::Status,
+        ["delete", key] => ControlRequest::Delete {
+            key: key.to_string(),
+            namespace: None,
         },
-        ["list"] => {
-            let items = storage.list()?;
-            if items.is_empty() {
-                println!("No items stored");
-            } else {
-                println!("Stored items ({}):", items.len());
-                for (key, value) in items {
-                    println!("  {key} = {value}");
-                }
-            }
-        }
-        ["status"] => {
-            let connection_count = connection_manager.get_connection_count().await;
-            let active_connections = connection_manager.get_active_connections().await;
-            println!("=== P2P Status ===");
-            println!("Active connections: {connection_count}");
-            if connection_count > 0 {
-                println!("Connected peers:");
-                for (peer_id, ip) in active_connections {
-                    println!("  {peer_id} <- {ip}");
-                }
-            } else {
-                println!("No active connections - waiting for peers...");
-            }
-            info!("Status checked - {} active connections", connection_count);
-        }
-        ["delete", key] => {
-            let timestamp = chrono::Utc::now();
-            let msg = SyncMessage::Delete {
-                key: key.to_string(),
-                timestamp,
-            };
-
-            storage.delete_with_timestamp(key, timestamp)?;
-
-            // Convert to P2P message and sign
-            let p2p_msg = P2PMessage::Sync(msg);
-            let signed_data = SignedData::new(p2p_msg, local_key)?;
-
-            let json = serde_json::to_vec(&signed_data)?;
-
-            // メッセージサイズチェック
-            if json.len() > security_config.max_message_size {
-                anyhow::bail!("Message too large: {} bytes", json.len());
-            }
-
-            swarm
-                .behaviour_mut()
-                .gossipsub
-                .publish(topic.clone(), json)?;
-
-            println!("✓ Deleted: {key}");
-            info!("Deleted: {}", key);
-        }
         ["help"] | ["h"] => {
             println!("Available commands:");
             println!("  add <key> <value>  - Add or update a key-value pair");
             println!("  get <key>          - Retrieve value for a key");
+            println!("  get <key> --at <time> - Value the key had at an RFC 3339 time");
+            println!("  history <key>      - Show the versions of a key");
+            println!("  rollback <key> <version> - Publish an earlier version again");
+            println!("  put-file <key> <path> - Store a file as a binary value");
+            println!("  get-file <key> <path> - Write a value to a file");
             println!("  delete <key>       - Delete a key-value pair");
-            println!("  list               - List all stored items");
+            println!("  list [prefix]      - List stored items (optionally by key prefix)");
             println!("  status             - Show connection status");
             println!("  peers              - Show connected peers");
             println!("  info               - Show node information");
@@ -383,6 +1104,10 @@ async fn handle_input(
             println!("  p2p-sync whitelist list");
             println!("  p2p-sync whitelist check <peer_id>");
             println!("  p2p-sync whitelist add-key <peer_id> <public_key_file>");
+            println!("  p2p-sync whitelist pending");
+            println!("  p2p-sync whitelist approve|reject <peer_id>");
+            println!("  p2p-sync whitelist revoked");
+            println!("  p2p-sync whitelist recommendations [peer_id]");
             println!();
             println!("Key Distribution (interactive commands):");
             println!("  announce-key       - Announce your public key to all peers");
@@ -391,56 +1116,437 @@ async fn handle_input(
             println!();
             println!("Trust Management:");
             println!("  recommend-peer <peer_id> - Recommend a peer to the network");
+            println!("  revoke-recommendation <peer_id> - Withdraw your recommendation of a peer");
+            println!(
+                "  revoke-peer <peer_id>    - Revoke a compromised peer on every node (admin only)"
+            );
             println!();
             println!("Maintenance:");
             println!("  cleanup - Clean up old key distribution data");
             println!("  reload-cache - Reload whitelist cache from database");
+            println!("  reload - Re-read config.toml and the whitelist");
+            println!();
+            println!("All commands are also available from another terminal via `p2p-sync ctl`.");
+            return Ok(());
         }
-        ["peers"] => {
-            let active_connections = connection_manager.get_active_connections().await;
-            if active_connections.is_empty() {
-                println!("No connected peers");
-            } else {
-                println!("Connected peers ({}):", active_connections.len());
-                for (peer_id, ip) in active_connections {
-                    println!("  {peer_id} from {ip}");
-                }
+        ["peers"] => ControlRequest::Peers,
+        ["info"] => ControlRequest::Info,
+        ["announce-key"] => ControlRequest::AnnounceKey,
+        ["request-keys"] => ControlRequest::RequestKeys,
+        ["request-whitelist"] => {
+            print!("Enter your name (optional): ");
+            std::io::stdout().flush()?;
+
+            ControlRequest::RequestWhitelist {
+                name: read_optional_line()?,
             }
         }
-        ["info"] => {
-            let local_peer_id = swarm.local_peer_id();
-            let listeners: Vec<_> = swarm.listeners().collect();
-            println!("=== Node Information ===");
-            println!("Local Peer ID: {local_peer_id}");
-            println!("Listening on:");
-            for addr in listeners {
-                println!("  {addr}");
+        ["recommend-peer", peer_id] => {
+            // Parse peer ID
+            if peer_id.parse::<libp2p::PeerId>().is_err() {
+                println!("✗ Invalid peer ID format");
+                return Ok(());
+            }
+
+            print!("Enter optional name for this peer: ");
+            std::io::stdout().flush()?;
+
+            ControlRequest::RecommendPeer {
+                peer_id: peer_id.to_string(),
+                name: read_optional_line()?,
+            }
+        }
+        ["revoke-recommendation", peer_id] => {
+            if peer_id.parse::<libp2p::PeerId>().is_err() {
+                println!("✗ Invalid peer ID format");
+                return Ok(());
+            }
+
+            ControlRequest::RevokeRecommendation {
+                peer_id: peer_id.to_string(),
             }
-            let connection_count = connection_manager.get_connection_count().await;
-            println!("Active connections: {connection_count}");
         }
-        ["announce-key"] => {
+        ["revoke-peer", peer_id] => {
+            if peer_id.parse::<libp2p::PeerId>().is_err() {
+                println!("✗ Invalid peer ID format");
+                return Ok(());
+            }
+
+            print!("Enter optional reason for the revocation: ");
+            std::io::stdout().flush()?;
+
+            ControlRequest::RevokePeer {
+                peer_id: peer_id.to_string(),
+                reason: read_optional_line()?,
+            }
+        }
+        ["cleanup"] => ControlRequest::Cleanup,
+        ["reload-cache"] => ControlRequest::ReloadCache,
+        ["verify-signature"] => {
+            // Create a test signed message to demonstrate signature verification
+            let test_msg = P2PMessage::Sync {
+                change: SignedChange {
+                    message: SyncMessage::Put {
+                        key: "test".to_string(),
+                        value: "verification".to_string(),
+                        timestamp: hlc::HybridClock::new(swarm.local_peer_id().to_string()).now(),
+                    },
+                    signature: Vec::new(),
+                },
+                seq: 0,
+            };
+
+            match SignedData::new(test_msg, local_key) {
+                Ok(signed_data) => match signed_data.verify(local_key) {
+                    Ok(true) => {
+                        println!("✓ Signature verification functionality working correctly")
+                    }
+                    Ok(false) => println!("✗ Signature verification failed"),
+                    Err(e) => println!("✗ Signature verification error: {e}"),
+                },
+                Err(e) => println!("✗ Failed to create signed data: {e}"),
+            }
+            return Ok(());
+        }
+        ["test-access-control"] => {
+            let test_config = SecurityConfig::default();
+            let _test_access_control = AccessControl::new(test_config);
+            println!("✓ Access control test completed");
+            return Ok(());
+        }
+        _ => {
+            println!("Unknown command: '{}'", input.trim());
+            println!("Available commands: add, get, delete, list, status, peers, info, help");
+            println!("Key distribution: announce-key, request-keys, request-whitelist");
+            println!(
+                "Trust management: recommend-peer <peer_id>, revoke-recommendation <peer_id>, revoke-peer <peer_id>"
+            );
+            println!("Maintenance: cleanup, reload-cache, reload");
+            println!("Type 'help' for detailed usage information.");
+            return Ok(());
+        }
+    };
+
+    let response = execute_request(
+        swarm,
+        storage,
+        topic,
+        namespaces,
+        &request,
+        security_config,
+        connection_manager,
+        local_key,
+        key_dist_manager,
+        whitelist,
+    )
+    .await?;
+    let response = match &output {
+        Some(path) => save_bytes(path, response)?,
+        None => response,
+    };
+    print_response(&request, response);
+
+    Ok(())
+}
+
+/// Read one line from stdin, treating an empty answer as `None`
+fn read_optional_line() -> Result<Option<String>> {
+    let mut input = String::new();
+    std::io::stdin().read_line(&mut input)?;
+
+    let input = input.trim();
+    Ok((!input.is_empty()).then(|| input.to_string()))
+}
+
+/// Run a command against the node. Shared by the interactive prompt and the
+/// control socket.
+#[allow(clippy::too_many_arguments)]
+async fn execute_request(
+    swarm: &mut libp2p::Swarm<P2PSyncBehaviour>,
+    storage: &Storage,
+    topic: &gossipsub::IdentTopic,
+    namespaces: &Namespaces,
+    request: &ControlRequest,
+    security_config: &SecurityConfig,
+    connection_manager: &ConnectionManager,
+    local_key: &libp2p::identity::Keypair,
+    key_dist_manager: &Arc<KeyDistributionManager>,
+    whitelist: &Arc<PeerWhitelist>,
+) -> Result<ControlResponse> {
+    // 参加していないネームスペースへの読み書きはエラーにする
+    let namespace = request.namespace();
+    namespaces.get(namespace)?;
+
+    let response = match request {
+        ControlRequest::Put { key, value, .. } => {
+            // 長すぎるキーや値は切り詰めずに store_and_publish の検証でエラーにする
+            store_and_publish(
+                swarm,
+                storage,
+                namespaces,
+                security_config,
+                key_dist_manager,
+                namespace,
+                key,
+                Some(Value::Text(value.clone())),
+            )
+            .await?;
+
+            // 暗号化ネームスペースの値をログに残さないよう、値は出力しない
+            info!("Published: {} ({} bytes)", key, value.len());
+            ControlResponse::Done {
+                message: format!("Added: {key} = {value}"),
+            }
+        }
+        ControlRequest::PutBytes { key, value, .. } => {
+            let data = BASE64
+                .decode(value)
+                .map_err(|e| RequestError::invalid(format!("Value is not valid base64: {e}")))?;
+            let size = data.len();
+
+            store_and_publish(
+                swarm,
+                storage,
+                namespaces,
+                security_config,
+                key_dist_manager,
+                namespace,
+                key,
+                Some(Value::Bytes(data)),
+            )
+            .await?;
+
+            info!("Published: {} ({} bytes)", key, size);
+            ControlResponse::Done {
+                message: format!("Added: {key} ({size} bytes)"),
+            }
+        }
+        ControlRequest::GetBytes { key, .. } => ControlResponse::Bytes {
+            key: key.clone(),
+            value: storage
+                .get_bytes(namespace, key)?
+                .map(|data| BASE64.encode(data)),
+        },
+        ControlRequest::Get {
+            key, at: Some(at), ..
+        } => {
+            // 過去の値はバイナリも含めて一覧と同じ形式で返す
+            let value = storage
+                .get_value_at(namespace, key, *at)?
+                .map(|value| value.to_string());
+            ControlResponse::Value {
+                key: key.clone(),
+                value,
+            }
+        }
+        ControlRequest::History { key, .. } => ControlResponse::History {
+            key: key.clone(),
+            versions: storage
+                .history(namespace, key)?
+                .into_iter()
+                .map(|version| KeyVersion {
+                    version: version.version,
+                    value: version.value.map(|value| value.to_string()),
+                    timestamp: version.timestamp,
+                })
+                .collect(),
+        },
+        ControlRequest::Rollback { key, version, .. } => {
+            let Some(old) = storage.version(namespace, key, *version)? else {
+                return Err(RequestError::not_found(format!(
+                    "{key} has no version {version} in the history"
+                ))
+                .into());
+            };
+
+            // 過去の値を新しい書き込みとして署名・配信し、全ノードで最新の値にする
+            store_and_publish(
+                swarm,
+                storage,
+                namespaces,
+                security_config,
+                key_dist_manager,
+                namespace,
+                key,
+                old.value,
+            )
+            .await?;
+
+            info!("Rolled back {} to version {}", key, version);
+            ControlResponse::Done {
+                message: format!("Rolled back {key} to version {version}"),
+            }
+        }
+        ControlRequest::Get { key, .. } => {
+            let value = storage.get(namespace, key)?;
+            match &value {
+                Some(value) => info!("{} ({} bytes)", key, value.len()),
+                None => info!("{} not found", key),
+            }
+            ControlResponse::Value {
+                key: key.clone(),
+                value,
+            }
+        }
+        ControlRequest::List { prefix, .. } => {
+            let mut items = storage.list(namespace)?;
+            if let Some(prefix) = prefix {
+                items.retain(|(key, _)| key.starts_with(prefix.as_str()));
+            }
+            ControlResponse::Items { items }
+        }
+        ControlRequest::Status | ControlRequest::Peers => {
+            let peers = connection_manager
+                .get_active_connections()
+                .await
+                .into_iter()
+                .map(|(peer_id, ip)| PeerConnection {
+                    peer_id: peer_id.to_string(),
+                    address: ip.to_string(),
+                })
+                .collect::<Vec<_>>();
+            if matches!(request, ControlRequest::Status) {
+                info!("Status checked - {} active connections", peers.len());
+            }
+            ControlResponse::Peers { peers }
+        }
+        ControlRequest::Delete { key, .. } => {
+            store_and_publish(
+                swarm,
+                storage,
+                namespaces,
+                security_config,
+                key_dist_manager,
+                namespace,
+                key,
+                None,
+            )
+            .await?;
+
+            info!("Deleted: {}", key);
+            ControlResponse::Done {
+                message: format!("Deleted: {key}"),
+            }
+        }
+        ControlRequest::Info => ControlResponse::Info {
+            peer_id: swarm.local_peer_id().to_string(),
+            listen_addrs: swarm.listeners().map(|addr| addr.to_string()).collect(),
+            connections: connection_manager.get_connection_count().await,
+        },
+        ControlRequest::WhitelistAdd {
+            peer_id,
+            name,
+            expires_in_hours,
+            public_key,
+        } => {
+            let peer_id = peer_id.parse::<libp2p::PeerId>()?;
+            let expires_at = expires_in_hours
+                .map(|hours| chrono::Utc::now() + chrono::Duration::hours(hours as i64));
+            let public_key = public_key
+                .as_deref()
+                .map(identity::decode_public_key)
+                .transpose()?;
+
+            whitelist
+                .add_peer(&peer_id, name.clone(), public_key.as_ref(), expires_at)
+                .await?;
+
+            info!("Added peer {} to whitelist", peer_id);
+            ControlResponse::Done {
+                message: if public_key.is_some() {
+                    format!("Added peer {peer_id} to whitelist with public key")
+                } else {
+                    format!("Added peer {peer_id} to whitelist (no public key)")
+                },
+            }
+        }
+        ControlRequest::WhitelistRemove { peer_id } => {
+            let peer_id = peer_id.parse::<libp2p::PeerId>()?;
+            whitelist.remove_peer(&peer_id).await?;
+            info!("Removed peer {} from whitelist", peer_id);
+
+            // 削除したピアが今後の値を読めないようにグループ鍵を入れ替える
+            let rotated =
+                rotate_group_keys(swarm, topic, security_config, key_dist_manager).await?;
+
+            ControlResponse::Done {
+                message: if rotated > 0 {
+                    format!(
+                        "Removed peer {peer_id} from whitelist and rotated {rotated} group key(s)"
+                    )
+                } else {
+                    format!("Removed peer {peer_id} from whitelist")
+                },
+            }
+        }
+        ControlRequest::WhitelistList => ControlResponse::Whitelist {
+            entries: whitelist.list_peers().await?,
+        },
+        ControlRequest::WhitelistCheck { peer_id } => {
+            let peer_id = peer_id.parse::<libp2p::PeerId>()?;
+            ControlResponse::Whitelisted {
+                whitelisted: whitelist.is_whitelisted(&peer_id).await?,
+                trusted: whitelist.is_trusted_by_chain(&peer_id).await?,
+                peer_id: peer_id.to_string(),
+            }
+        }
+        ControlRequest::WhitelistPending => ControlResponse::PendingRequests {
+            requests: whitelist.list_pending_requests().await?,
+        },
+        ControlRequest::WhitelistApprove { peer_id } => {
+            let peer_id = peer_id.parse::<libp2p::PeerId>()?;
+            whitelist.approve_request(&peer_id).await?;
+
+            info!("Approved whitelist request from {}", peer_id);
+            ControlResponse::Done {
+                message: format!("Approved whitelist request from {peer_id}"),
+            }
+        }
+        ControlRequest::WhitelistReject { peer_id } => {
+            let peer_id = peer_id.parse::<libp2p::PeerId>()?;
+            whitelist.reject_request(&peer_id).await?;
+
+            info!("Rejected whitelist request from {}", peer_id);
+            ControlResponse::Done {
+                message: format!("Rejected whitelist request from {peer_id}"),
+            }
+        }
+        ControlRequest::WhitelistRecommendations { peer_id } => {
+            let peer_id = peer_id
+                .as_deref()
+                .map(|peer_id| peer_id.parse::<libp2p::PeerId>())
+                .transpose()?;
+            ControlResponse::Recommendations {
+                recommendations: whitelist.list_recommendations(peer_id.as_ref()).await?,
+            }
+        }
+        ControlRequest::WhitelistRevoked => ControlResponse::Revocations {
+            revocations: whitelist.list_revocations().await?,
+        },
+        ControlRequest::AnnounceKey => {
             let announcement = key_dist_manager.create_key_announcement();
             let p2p_msg = P2PMessage::KeyDistribution(announcement);
             let signed_data = SignedData::new(p2p_msg, local_key)?;
 
             let json = serde_json::to_vec(&signed_data)?;
-            if json.len() > security_config.max_message_size {
-                anyhow::bail!("Message too large: {} bytes", json.len());
-            }
+            security_config.check_message_size(json.len())?;
 
             swarm
                 .behaviour_mut()
                 .gossipsub
                 .publish(topic.clone(), json)?;
-            println!("✓ Announced public key to all peers");
             info!("Published key announcement");
+            ControlResponse::Done {
+                message: "Announced public key to all peers".to_string(),
+            }
         }
-        ["request-keys"] => {
+        ControlRequest::RequestKeys => {
             let requests = key_dist_manager.request_missing_keys().await?;
 
             if requests.is_empty() {
-                println!("No missing keys to request");
+                ControlResponse::Done {
+                    message: "No missing keys to request".to_string(),
+                }
             } else {
                 let num_requests = requests.len();
                 for request in requests {
@@ -448,151 +1554,496 @@ async fn handle_input(
                     let signed_data = SignedData::new(p2p_msg, local_key)?;
 
                     let json = serde_json::to_vec(&signed_data)?;
-                    if json.len() <= security_config.max_message_size {
+                    if security_config.check_message_size(json.len()).is_ok() {
                         swarm
                             .behaviour_mut()
                             .gossipsub
                             .publish(topic.clone(), json)?;
                     }
                 }
-                println!("✓ Requested {num_requests} missing public key(s)");
                 info!("Published {} key requests", num_requests);
+                ControlResponse::Done {
+                    message: format!("Requested {num_requests} missing public key(s)"),
+                }
             }
         }
-        ["request-whitelist"] => {
-            print!("Enter your name (optional): ");
-            std::io::stdout().flush()?;
-
-            let mut name_input = String::new();
-            if std::io::stdin().read_line(&mut name_input).is_ok() {
-                let name = name_input.trim();
-                let name = if name.is_empty() {
-                    None
-                } else {
-                    Some(name.to_string())
-                };
-
-                let request = key_dist_manager.create_whitelist_request(name);
-                let p2p_msg = P2PMessage::KeyDistribution(request);
-                let signed_data = SignedData::new(p2p_msg, local_key)?;
+        ControlRequest::RequestWhitelist { name } => {
+            let request = key_dist_manager.create_whitelist_request(name.clone());
+            let p2p_msg = P2PMessage::KeyDistribution(request);
+            let signed_data = SignedData::new(p2p_msg, local_key)?;
 
-                let json = serde_json::to_vec(&signed_data)?;
-                if json.len() > security_config.max_message_size {
-                    anyhow::bail!("Message too large: {} bytes", json.len());
-                }
+            let json = serde_json::to_vec(&signed_data)?;
+            security_config.check_message_size(json.len())?;
 
-                swarm
-                    .behaviour_mut()
-                    .gossipsub
-                    .publish(topic.clone(), json)?;
-                println!("✓ Sent whitelist request to all peers");
-                info!("Published whitelist request");
+            swarm
+                .behaviour_mut()
+                .gossipsub
+                .publish(topic.clone(), json)?;
+            info!("Published whitelist request");
+            ControlResponse::Done {
+                message: "Sent whitelist request to all peers".to_string(),
             }
         }
-        ["recommend-peer", peer_id] => {
-            // Parse peer ID
-            let peer_id = match peer_id.parse::<libp2p::PeerId>() {
-                Ok(id) => id,
-                Err(_) => {
-                    println!("✗ Invalid peer ID format");
-                    return Ok(());
-                }
+        ControlRequest::RecommendPeer { peer_id, name } => {
+            let peer_id = peer_id.parse::<libp2p::PeerId>()?;
+
+            let recommendation = KeyDistributionMessage::TrustRecommendation {
+                recommender: swarm.local_peer_id().to_string(),
+                recommended: peer_id.to_string(),
+                name: name.clone(),
+                timestamp: chrono::Utc::now(),
             };
 
-            print!("Enter optional name for this peer: ");
-            std::io::stdout().flush()?;
+            let p2p_msg = P2PMessage::KeyDistribution(recommendation);
+            let signed_data = SignedData::new(p2p_msg, local_key)?;
 
-            let mut name_input = String::new();
-            if std::io::stdin().read_line(&mut name_input).is_ok() {
-                let name = name_input.trim();
-                let name = if name.is_empty() {
-                    None
-                } else {
-                    Some(name.to_string())
-                };
+            let json = serde_json::to_vec(&signed_data)?;
+            security_config.check_message_size(json.len())?;
 
-                let recommendation = KeyDistributionMessage::TrustRecommendation {
-                    recommender: swarm.local_peer_id().to_string(),
-                    recommended: peer_id.to_string(),
-                    name: name.clone(),
-                    timestamp: chrono::Utc::now(),
-                };
+            swarm
+                .behaviour_mut()
+                .gossipsub
+                .publish(topic.clone(), json)?;
+            info!("Published trust recommendation for {}", peer_id);
+            ControlResponse::Done {
+                message: format!("Recommended peer {peer_id} to the network"),
+            }
+        }
+        ControlRequest::RevokeRecommendation { peer_id } => {
+            let peer_id = peer_id.parse::<libp2p::PeerId>()?;
 
-                let p2p_msg = P2PMessage::KeyDistribution(recommendation);
-                let signed_data = SignedData::new(p2p_msg, local_key)?;
+            let revoked = KeyDistributionMessage::RecommendationRevoked {
+                recommender: swarm.local_peer_id().to_string(),
+                recommended: peer_id.to_string(),
+                timestamp: chrono::Utc::now(),
+            };
+            publish_key_distribution(swarm, topic, security_config, local_key, revoked)?;
+            info!(
+                "Published withdrawal of trust recommendation for {}",
+                peer_id
+            );
+            ControlResponse::Done {
+                message: format!("Withdrew recommendation of peer {peer_id}"),
+            }
+        }
+        ControlRequest::RevokePeer { peer_id, reason } => {
+            let peer_id = peer_id.parse::<libp2p::PeerId>()?;
+            let revocation = key_dist_manager
+                .revoke_peer(&peer_id, reason.clone())
+                .await?;
 
-                let json = serde_json::to_vec(&signed_data)?;
-                if json.len() > security_config.max_message_size {
-                    anyhow::bail!("Message too large: {} bytes", json.len());
-                }
+            // ブロックリストに加えると接続もその場で閉じられる
+            swarm.behaviour_mut().blocked.block_peer(peer_id);
+            // 接続していないピアには、購読してきた時点で保存済みの失効を中継する
+            publish_key_distribution(swarm, topic, security_config, local_key, revocation)?;
 
-                swarm
-                    .behaviour_mut()
-                    .gossipsub
-                    .publish(topic.clone(), json)?;
-                println!("✓ Recommended peer {peer_id} to the network");
-                info!("Published trust recommendation for {}", peer_id);
+            let rotated =
+                rotate_group_keys(swarm, topic, security_config, key_dist_manager).await?;
+
+            ControlResponse::Done {
+                message: if rotated > 0 {
+                    format!("Revoked peer {peer_id} and rotated {rotated} group key(s)")
+                } else {
+                    format!("Revoked peer {peer_id} on all nodes")
+                },
             }
         }
-        ["cleanup"] => {
+        ControlRequest::Cleanup => {
             key_dist_manager.cleanup().await?;
-            println!("✓ Cleaned up old key distribution data");
             info!("Performed key distribution cleanup");
+            ControlResponse::Done {
+                message: "Cleaned up old key distribution data".to_string(),
+            }
         }
-        ["reload-cache"] => {
-            whitelist.reload_cache().await?;
-            println!("✓ Reloaded whitelist cache");
+        ControlRequest::ReloadCache => {
+            let removed = whitelist.reload_cache().await?;
             info!("Reloaded whitelist cache");
+            let rotated = if removed.is_empty() {
+                0
+            } else {
+                rotate_group_keys(swarm, topic, security_config, key_dist_manager).await?
+            };
+            ControlResponse::Done {
+                message: if rotated > 0 {
+                    format!("Reloaded whitelist cache and rotated {rotated} group key(s)")
+                } else {
+                    "Reloaded whitelist cache".to_string()
+                },
+            }
         }
-        ["verify-signature"] => {
-            // Create a test signed message to demonstrate signature verification
-            let test_msg = P2PMessage::Sync(SyncMessage::Put {
-                key: "test".to_string(),
-                value: "verification".to_string(),
-                timestamp: chrono::Utc::now(),
-            });
+        // 設定を書き換えるためイベントループで直接処理する
+        ControlRequest::Reload => anyhow::bail!("reload is handled by the node event loop"),
+        // 変更の購読は制御ソケット側でストリームとして処理する
+        ControlRequest::Watch { .. } => {
+            anyhow::bail!("watch is only available as a stream on the control socket")
+        }
+    };
 
-            match SignedData::new(test_msg, local_key) {
-                Ok(signed_data) => match signed_data.verify(local_key) {
-                    Ok(true) => {
-                        println!("✓ Signature verification functionality working correctly")
+    Ok(response)
+}
+
+/// Print a response in the format used by the interactive prompt
+fn print_response(request: &ControlRequest, response: ControlResponse) {
+    match response {
+        ControlResponse::Done { message } => println!("✓ {message}"),
+        ControlResponse::Value {
+            key,
+            value: Some(value),
+        } => println!("✓ {key} = {value}"),
+        ControlResponse::Value { key, value: None }
+        | ControlResponse::Bytes { key, value: None } => println!("✗ {key} not found"),
+        ControlResponse::Bytes {
+            key,
+            value: Some(value),
+        } => println!(
+            "✓ {key} = <binary, {} bytes>",
+            BASE64.decode(value).map_or(0, |data| data.len())
+        ),
+        ControlResponse::History { key, versions } => {
+            if versions.is_empty() {
+                println!("No history for {key}");
+            } else {
+                println!("History of {key} ({}):", versions.len());
+                for version in versions {
+                    let value = version.value.as_deref().unwrap_or("<deleted>");
+                    println!(
+                        "  {}  {}  {} by {}",
+                        version.version,
+                        version.timestamp.to_datetime().to_rfc3339(),
+                        value,
+                        version.timestamp.origin
+                    );
+                }
+            }
+        }
+        ControlResponse::Items { items } => {
+            if items.is_empty() {
+                println!("No items stored");
+            } else {
+                println!("Stored items ({}):", items.len());
+                for (key, value) in items {
+                    println!("  {key} = {value}");
+                }
+            }
+        }
+        ControlResponse::Peers { peers } => {
+            if matches!(request, ControlRequest::Status) {
+                println!("=== P2P Status ===");
+                println!("Active connections: {}", peers.len());
+                if peers.is_empty() {
+                    println!("No active connections - waiting for peers...");
+                } else {
+                    println!("Connected peers:");
+                    for peer in peers {
+                        println!("  {} <- {}", peer.peer_id, peer.address);
                     }
-                    Ok(false) => println!("✗ Signature verification failed"),
-                    Err(e) => println!("✗ Signature verification error: {e}"),
-                },
-                Err(e) => println!("✗ Failed to create signed data: {e}"),
+                }
+            } else if peers.is_empty() {
+                println!("No connected peers");
+            } else {
+                println!("Connected peers ({}):", peers.len());
+                for peer in peers {
+                    println!("  {} from {}", peer.peer_id, peer.address);
+                }
             }
         }
-        ["test-access-control"] => {
-            let test_config = SecurityConfig::default();
-            let _test_access_control = AccessControl::new(test_config);
-            println!("✓ Access control test completed");
+        ControlResponse::Info {
+            peer_id,
+            listen_addrs,
+            connections,
+        } => {
+            println!("=== Node Information ===");
+            println!("Local Peer ID: {peer_id}");
+            println!("Listening on:");
+            for addr in listen_addrs {
+                println!("  {addr}");
+            }
+            println!("Active connections: {connections}");
         }
-        _ => {
-            println!("Unknown command: '{}'", input.trim());
-            println!("Available commands: add, get, delete, list, status, peers, info, help");
-            println!("Key distribution: announce-key, request-keys, request-whitelist");
-            println!("Trust management: recommend-peer <peer_id>");
-            println!("Maintenance: cleanup, reload-cache");
-            println!("Type 'help' for detailed usage information.");
+        ControlResponse::Whitelist { entries } => print_whitelist(entries),
+        ControlResponse::PendingRequests { requests } => print_pending_requests(requests),
+        ControlResponse::Revocations { revocations } => print_revocations(revocations),
+        ControlResponse::Recommendations { recommendations } => {
+            print_recommendations(recommendations)
+        }
+        ControlResponse::Whitelisted {
+            peer_id,
+            whitelisted,
+            trusted,
+        } => print_whitelisted(&peer_id, whitelisted, trusted),
+        ControlResponse::Change(event) => {
+            // default 以外のネームスペースはキーの前に付けて表示する
+            let key = if event.namespace == DEFAULT_NAMESPACE {
+                event.key
+            } else {
+                format!("{}:{}", event.namespace, event.key)
+            };
+            match (event.old, event.new) {
+                (_, None) => println!("- {key}  ({})", event.timestamp),
+                (None, Some(new)) => println!("+ {key} = {new}  ({})", event.timestamp),
+                (Some(old), Some(new)) => {
+                    println!("~ {key} = {new} (was {old})  ({})", event.timestamp)
+                }
+            }
+        }
+        ControlResponse::Error { message, .. } => println!("✗ {message}"),
+    }
+}
+
+/// Validate a local write, apply it to storage, then sign and publish it on
+/// the namespace's topic, encrypted if the namespace is. `None` deletes the key.
+#[allow(clippy::too_many_arguments)]
+async fn store_and_publish(
+    swarm: &mut libp2p::Swarm<P2PSyncBehaviour>,
+    storage: &Storage,
+    namespaces: &Namespaces,
+    security_config: &SecurityConfig,
+    key_dist_manager: &KeyDistributionManager,
+    namespace: &str,
+    key: &str,
+    value: Option<Value>,
+) -> Result<()> {
+    // 他のノードに拒否される書き込みはローカルでも受け付けない
+    if !namespaces.can_write(namespace, swarm.local_peer_id()) {
+        return Err(RequestError::forbidden(format!(
+            "This node is not a writer of namespace {namespace}"
+        ))
+        .into());
+    }
+
+    // 入力検証
+    let value = match value {
+        Some(Value::Bytes(data)) => {
+            security_config.check_object_size(data.len())?;
+            // 大きなバイナリ値はチャンクに分割し、マニフェストのみを配信する
+            Some(storage.encode_bytes(data, security_config.max_value_length)?)
         }
+        other => other,
+    };
+    security_config.check_entry(key, value.as_ref())?;
+
+    // 鍵が手に入らない場合は保存前に失敗させる
+    let group_key = if namespaces.is_encrypted(namespace) {
+        Some(key_dist_manager.group_key_for_writing(namespace).await?)
+    } else {
+        None
+    };
+
+    // 保存された行には自分の署名が付き、突き合わせでも作成者を検証できる
+    let entry = storage.local_entry(namespace, key, value)?;
+    let change = SignedChange::from_entry(entry.clone())?;
+
+    // Convert to P2P message and sign
+    let message = |seq| -> Result<Vec<u8>> {
+        let p2p_msg = match &group_key {
+            Some(group_key) => P2PMessage::EncryptedSync {
+                namespace: namespace.to_string(),
+                value: group_key.encrypt(namespace, &serde_json::to_vec(&change)?)?,
+                seq,
+            },
+            None => P2PMessage::sync(namespace, change.clone(), seq),
+        };
+        let signed_data = SignedData::new(p2p_msg, key_dist_manager.local_keypair())?;
+        Ok(serde_json::to_vec(&signed_data)?)
+    };
+
+    // メッセージサイズチェック。通し番号の桁数で大きさが変わるので最大の番号で確かめ、
+    // 配信できない変更は保存も番号の消費もしない（受信側に欠番が見えると突き合わせが始まる）
+    security_config.check_message_size(message(u64::MAX)?.len())?;
+
+    storage.apply_entry(namespace, &entry)?;
+    // 番号は配信の直前に割り当てる。配信に失敗しても番号は戻さない。次の変更で受信側が
+    // 欠番に気づいて突き合わせで取りに来るので、保存済みの値はそこで届く
+    let seq = storage.next_sequence(namespace)?;
+    publish_sync_message(swarm, &namespace::topic(namespace), message(seq)?).map_err(|e| {
+        e.context("Stored locally, peers will receive the change through reconciliation")
+    })
+}
+
+/// Replace the group keys of the encrypted namespaces after a peer left the
+/// whitelist and publish the new keys, returning how many were rotated
+async fn rotate_group_keys(
+    swarm: &mut libp2p::Swarm<P2PSyncBehaviour>,
+    topic: &gossipsub::IdentTopic,
+    security_config: &SecurityConfig,
+    key_dist_manager: &KeyDistributionManager,
+) -> Result<usize> {
+    let grants = key_dist_manager.rotate_group_keys().await?;
+    let rotated = grants.len();
+    for grant in grants {
+        publish_key_distribution(
+            swarm,
+            topic,
+            security_config,
+            key_dist_manager.local_keypair(),
+            grant,
+        )?;
+    }
+
+    Ok(rotated)
+}
+
+/// Sign a key distribution message and publish it on the control topic
+fn publish_key_distribution(
+    swarm: &mut libp2p::Swarm<P2PSyncBehaviour>,
+    topic: &gossipsub::IdentTopic,
+    security_config: &SecurityConfig,
+    local_key: &libp2p::identity::Keypair,
+    message: KeyDistributionMessage,
+) -> Result<()> {
+    let signed_data = SignedData::new(P2PMessage::KeyDistribution(message), local_key)?;
+    let json = serde_json::to_vec(&signed_data)?;
+    security_config.check_message_size(json.len())?;
+
+    match swarm.behaviour_mut().gossipsub.publish(topic.clone(), json) {
+        // 鍵を持たないピアは後で復号できない値を受け取った時点で要求してくる
+        Ok(_) | Err(gossipsub::PublishError::NoPeersSubscribedToTopic) => Ok(()),
+        Err(e) => Err(e.into()),
+    }
+}
+
+async fn next_sync_event(dir_sync: &mut Option<DirectorySync>) -> SyncEvent {
+    match dir_sync {
+        Some(dir_sync) => dir_sync.next_event().await,
+        None => std::future::pending().await,
+    }
+}
+
+async fn handle_sync_event(
+    swarm: &mut libp2p::Swarm<P2PSyncBehaviour>,
+    storage: &Storage,
+    namespaces: &Namespaces,
+    security_config: &SecurityConfig,
+    key_dist_manager: &KeyDistributionManager,
+    dir_sync: &mut DirectorySync,
+    event: SyncEvent,
+) {
+    use tracing::warn;
+
+    match event {
+        SyncEvent::Local(paths) => match dir_sync.local_changes(&paths, storage) {
+            Ok(changes) => {
+                apply_local_file_changes(
+                    swarm,
+                    storage,
+                    namespaces,
+                    security_config,
+                    key_dist_manager,
+                    changes,
+                )
+                .await
+            }
+            Err(e) => warn!("Failed to scan changed files: {}", e),
+        },
+        SyncEvent::Remote(event) => {
+            if let Err(e) = dir_sync.apply_remote(&event, storage) {
+                warn!("Failed to apply file change {}: {}", event.key, e);
+            }
+
+            // 変更元のピアを優先してチャンクを取得する
+            let origin = event.origin_peer.parse::<libp2p::PeerId>().ok();
+            let peers = connected_peers_preferring(swarm, origin.as_ref());
+            dir_sync.request_missing(&mut swarm.behaviour_mut().chunks, &peers);
+        }
+    }
+}
+
+async fn apply_local_file_changes(
+    swarm: &mut libp2p::Swarm<P2PSyncBehaviour>,
+    storage: &Storage,
+    namespaces: &Namespaces,
+    security_config: &SecurityConfig,
+    key_dist_manager: &KeyDistributionManager,
+    changes: Vec<LocalChange>,
+) {
+    for change in changes {
+        let (key, value) = match &change {
+            LocalChange::Put { key, manifest } => (key, Some(Value::Text(manifest.clone()))),
+            LocalChange::Delete { key } => (key, None),
+        };
+
+        match store_and_publish(
+            swarm,
+            storage,
+            namespaces,
+            security_config,
+            key_dist_manager,
+            DEFAULT_NAMESPACE,
+            key,
+            value,
+        )
+        .await
+        {
+            Ok(()) => info!("Published file change: {:?}", change),
+            Err(e) => tracing::warn!("Failed to sync {}: {}", key, e),
+        }
+    }
+}
+
+/// Connected peers, with `preferred` first if it is connected
+fn connected_peers_preferring(
+    swarm: &libp2p::Swarm<P2PSyncBehaviour>,
+    preferred: Option<&libp2p::PeerId>,
+) -> Vec<libp2p::PeerId> {
+    let mut peers: Vec<libp2p::PeerId> = swarm.connected_peers().cloned().collect();
+    if let Some(index) = peers.iter().position(|peer| Some(peer) == preferred) {
+        peers.swap(0, index);
+    }
+    peers
+}
+
+/// Request chunks of large values that are stored without their data,
+/// asking `preferred` (usually the peer that sent the value) first
+fn request_value_chunks(
+    swarm: &mut libp2p::Swarm<P2PSyncBehaviour>,
+    storage: &Storage,
+    value_fetches: &mut ChunkFetches,
+    preferred: Option<&libp2p::PeerId>,
+) -> Result<()> {
+    let missing = storage.missing_chunks()?;
+    if !missing.is_empty() {
+        let peers = connected_peers_preferring(swarm, preferred);
+        value_fetches.request(&mut swarm.behaviour_mut().chunks, missing, &peers);
     }
 
     Ok(())
 }
 
+/// Publish a data change. Without subscribed peers the change stays local and
+/// is delivered by reconciliation once a peer connects.
+fn publish_sync_message(
+    swarm: &mut libp2p::Swarm<P2PSyncBehaviour>,
+    topic: &gossipsub::IdentTopic,
+    json: Vec<u8>,
+) -> Result<()> {
+    match swarm.behaviour_mut().gossipsub.publish(topic.clone(), json) {
+        Ok(_) => Ok(()),
+        Err(gossipsub::PublishError::NoPeersSubscribedToTopic) => {
+            info!("No peers subscribed yet, change will be synced on reconnect");
+            Ok(())
+        }
+        Err(e) => Err(e.into()),
+    }
+}
+
+#[allow(clippy::too_many_arguments)]
 async fn handle_swarm_event(
     swarm: &mut libp2p::Swarm<P2PSyncBehaviour>,
     storage: &Storage,
     topic: &gossipsub::IdentTopic,
+    namespaces: &Namespaces,
     event: libp2p::swarm::SwarmEvent<
         <P2PSyncBehaviour as libp2p::swarm::NetworkBehaviour>::ToSwarm,
     >,
+    security_config: &SecurityConfig,
     rate_limiter: &RateLimiter,
     connection_manager: &ConnectionManager,
     whitelist: &Arc<PeerWhitelist>,
     key_dist_manager: &Arc<KeyDistributionManager>,
-) -> Result<()> {
+    dir_sync: &mut Option<DirectorySync>,
+    value_fetches: &mut ChunkFetches,
+    metrics: &Metrics,
+) {
     use libp2p::swarm::SwarmEvent;
     use tracing::warn;
 
@@ -602,13 +2053,18 @@ async fn handle_swarm_event(
                 swarm,
                 storage,
                 topic,
+                namespaces,
                 behaviour_event,
+                security_config,
                 rate_limiter,
                 connection_manager,
                 whitelist,
                 key_dist_manager,
+                dir_sync,
+                value_fetches,
+                metrics,
             )
-            .await?;
+            .await;
         }
         SwarmEvent::NewListenAddr { address, .. } => {
             info!("Local node is listening on {address}");
@@ -619,11 +2075,21 @@ async fn handle_swarm_event(
             // Actual connection tracking happens in ConnectionEstablished
         }
         SwarmEvent::ConnectionEstablished {
-            peer_id, endpoint, ..
+            peer_id,
+            endpoint,
+            num_established,
+            ..
         } => {
             info!("Connection established with peer: {peer_id}");
+            let mut accepted = true;
             // Extract IP address from endpoint and handle connection
-            if let Some(ip) =
+            // 同じピアとの2本目以降の接続（相互ダイヤルや TCP と QUIC）は最初の判定に従う
+            if num_established.get() > 1 {
+                accepted = connection_manager
+                    .get_active_connections()
+                    .await
+                    .contains_key(&peer_id);
+            } else if let Some(ip) =
                 endpoint
                     .get_remote_address()
                     .iter()
@@ -638,241 +2104,1130 @@ async fn handle_swarm_event(
                     .await
                 {
                     tracing::warn!("Failed to handle incoming connection: {}", e);
+                    accepted = false;
+                }
+            }
+
+            // 拒否したピアは接続を閉じ、gossipsub のメッシュや DHT にも残さない
+            if !accepted {
+                disconnect_rejected_peer(swarm, &peer_id);
+                return;
+            }
+
+            // 最初の接続時にストアの差分を突き合わせる（オフライン中の更新を取り込む）
+            if num_established.get() == 1 {
+                // 相手が読み取れるネームスペースごとに突き合わせる
+                for name in namespaces.names() {
+                    if namespaces.can_read(name, &peer_id) {
+                        if let Err(e) =
+                            start_reconciliation(swarm, storage, &peer_id, name, 0, None)
+                        {
+                            warn!(
+                                "Failed to start reconciliation of {} with {}: {:#}",
+                                name, peer_id, e
+                            );
+                            metrics.event_failed("reconcile");
+                        }
+                    }
+                }
+                info!("Started state reconciliation with {peer_id}");
+
+                // 以前取得できなかったチャンクを新しいピアに要求する
+                if let Some(dir_sync) = dir_sync.as_mut() {
+                    dir_sync.request_missing(&mut swarm.behaviour_mut().chunks, &[peer_id]);
+                }
+                match storage.missing_chunks() {
+                    Ok(missing) => value_fetches.request(
+                        &mut swarm.behaviour_mut().chunks,
+                        missing,
+                        &[peer_id],
+                    ),
+                    Err(e) => {
+                        warn!("Failed to list missing chunks: {:#}", e);
+                        metrics.event_failed("chunks");
+                    }
                 }
             }
         }
-        SwarmEvent::ConnectionClosed { peer_id, cause, .. } => {
+        SwarmEvent::ConnectionClosed {
+            peer_id,
+            cause,
+            num_established,
+            ..
+        } => {
             warn!("Connection closed with peer {peer_id}: {cause:?}");
-            connection_manager.handle_connection_closed(&peer_id).await;
+            if num_established == 0 {
+                connection_manager.handle_connection_closed(&peer_id).await;
+            }
         }
         _ => {}
     }
-
-    Ok(())
 }
 
+#[allow(clippy::too_many_arguments)]
 async fn handle_behaviour_event(
     swarm: &mut libp2p::Swarm<P2PSyncBehaviour>,
     storage: &Storage,
     topic: &gossipsub::IdentTopic,
+    namespaces: &Namespaces,
     event: <P2PSyncBehaviour as libp2p::swarm::NetworkBehaviour>::ToSwarm,
+    security_config: &SecurityConfig,
     rate_limiter: &RateLimiter,
     connection_manager: &ConnectionManager,
     whitelist: &Arc<PeerWhitelist>,
     key_dist_manager: &Arc<KeyDistributionManager>,
-) -> Result<()> {
+    dir_sync: &mut Option<DirectorySync>,
+    value_fetches: &mut ChunkFetches,
+    metrics: &Metrics,
+) {
     match event {
+        network::P2PSyncBehaviourEvent::Blocked(event) => match event {},
         network::P2PSyncBehaviourEvent::Mdns(mdns_event) => {
-            handle_mdns_event(swarm, mdns_event).await?;
+            handle_mdns_event(swarm, mdns_event, connection_manager).await;
         }
         network::P2PSyncBehaviourEvent::Gossipsub(gossipsub_event) => {
             handle_gossipsub_event(
                 storage,
+                namespaces,
                 gossipsub_event,
+                security_config,
                 rate_limiter,
                 connection_manager,
                 whitelist,
                 key_dist_manager,
                 swarm,
                 topic,
+                value_fetches,
+                metrics,
             )
-            .await?;
+            .await;
         }
         network::P2PSyncBehaviourEvent::Kad(kad_event) => {
-            info!("Kademlia event: {kad_event:?}");
+            handle_kad_event(swarm, kad_event, connection_manager).await;
         }
         network::P2PSyncBehaviourEvent::Identify(identify_event) => {
-            info!("Identify event: {identify_event:?}");
+            handle_identify_event(swarm, identify_event);
+        }
+        network::P2PSyncBehaviourEvent::Reconcile(reconcile_event) => {
+            if let Err(e) = handle_reconcile_event(
+                swarm,
+                storage,
+                namespaces,
+                reconcile_event,
+                security_config,
+                whitelist,
+                key_dist_manager,
+                value_fetches,
+            )
+            .await
+            {
+                tracing::warn!("Failed to process reconciliation event: {:#}", e);
+                metrics.event_failed("reconcile");
+            }
+        }
+        network::P2PSyncBehaviourEvent::Chunks(chunk_event) => {
+            if let Err(e) = handle_chunk_event(
+                swarm,
+                storage,
+                namespaces,
+                chunk_event,
+                whitelist,
+                dir_sync,
+                value_fetches,
+            )
+            .await
+            {
+                tracing::warn!("Failed to process chunk transfer event: {:#}", e);
+                metrics.event_failed("chunks");
+            }
+        }
+    }
+}
+
+async fn handle_reconcile_event(
+    swarm: &mut libp2p::Swarm<P2PSyncBehaviour>,
+    storage: &Storage,
+    namespaces: &Namespaces,
+    event: anti_entropy::Event,
+    security_config: &SecurityConfig,
+    whitelist: &Arc<PeerWhitelist>,
+    key_dist_manager: &KeyDistributionManager,
+    value_fetches: &mut ChunkFetches,
+) -> Result<()> {
+    use libp2p::request_response::{Event, Message};
+    use tracing::warn;
+
+    match event {
+        Event::Message { peer, message, .. } => {
+            // 直接の相手がホワイトリスト（または推薦）で信頼されている場合のみ同期する
+            if !whitelist.is_trusted_by_chain(&peer).await? {
+                warn!(
+                    "Ignoring reconciliation with non-whitelisted peer: {}",
+                    peer
+                );
+                return Ok(());
+            }
+
+            match message {
+                Message::Request {
+                    request, channel, ..
+                } => {
+                    let response = match request {
+                        ReconcileRequest::Digests { namespace, .. }
+                            if !namespaces.can_read(&namespace, &peer) =>
+                        {
+                            info!(
+                                "Declining reconciliation of namespace {} with {}",
+                                namespace, peer
+                            );
+                            ReconcileResponse::Denied
+                        }
+                        ReconcileRequest::Digests {
+                            namespace,
+                            digest: remote,
+                            from_bucket,
+                            after_key,
+                        } => {
+                            let entries = storage.entries(&namespace)?;
+                            let local = StoreDigest::from_entries(&entries);
+                            let buckets: Vec<u16> = local
+                                .differing_buckets(&remote)
+                                .into_iter()
+                                .filter(|bucket| *bucket >= from_bucket)
+                                .collect();
+
+                            if buckets.is_empty() {
+                                ReconcileResponse::InSync
+                            } else {
+                                // 1 回の応答に収まらない分は next_bucket から要求し直してもらう
+                                let page = anti_entropy::page(
+                                    entries,
+                                    &buckets,
+                                    after_key.as_deref(),
+                                    anti_entropy::PAGE_SIZE,
+                                );
+                                info!(
+                                    "Sending {} entries of namespace {} from {} of {} differing buckets to {}",
+                                    page.entries.len(),
+                                    namespace,
+                                    page.buckets.len(),
+                                    buckets.len(),
+                                    peer
+                                );
+                                ReconcileResponse::Entries {
+                                    namespace,
+                                    buckets: page.buckets,
+                                    entries: page.entries,
+                                    after_key,
+                                    next_bucket: page.next_bucket,
+                                    next_key: page.next_key,
+                                }
+                            }
+                        }
+                        ReconcileRequest::Entries { namespace, entries } => {
+                            let applied = apply_reconciled_entries(
+                                storage,
+                                namespaces,
+                                &namespace,
+                                &entries,
+                                &peer,
+                                security_config,
+                                whitelist,
+                            )
+                            .await?;
+                            info!("Applied {} reconciled entries from {}", applied, peer);
+                            request_value_chunks(swarm, storage, value_fetches, Some(&peer))?;
+                            ReconcileResponse::Ack
+                        }
+                        ReconcileRequest::Revocations { revocations } => {
+                            // 署名は中継者ではなく管理者の鍵で確かめる
+                            key_dist_manager
+                                .apply_revocations(revocations, peer)
+                                .await?;
+                            ReconcileResponse::Ack
+                        }
+                    };
+
+                    if swarm
+                        .behaviour_mut()
+                        .reconcile
+                        .send_response(channel, response)
+                        .is_err()
+                    {
+                        warn!("Failed to send reconciliation response to {}", peer);
+                    }
+                }
+                Message::Response { response, .. } => match response {
+                    ReconcileResponse::InSync => {
+                        info!("Store already in sync with {}", peer);
+                    }
+                    ReconcileResponse::Entries {
+                        namespace,
+                        buckets,
+                        entries,
+                        after_key,
+                        next_bucket,
+                        next_key,
+                    } => {
+                        let applied = apply_reconciled_entries(
+                            storage,
+                            namespaces,
+                            &namespace,
+                            &entries,
+                            &peer,
+                            security_config,
+                            whitelist,
+                        )
+                        .await?;
+                        info!("Applied {} reconciled entries from {}", applied, peer);
+                        request_value_chunks(swarm, storage, value_fetches, Some(&peer))?;
+
+                        // マージ後の自分の状態のうち相手が持っていないものを送り返す
+                        if namespaces.can_read(&namespace, &peer) {
+                            let push = anti_entropy::entries_to_push(
+                                storage.entries(&namespace)?,
+                                &entries,
+                                &buckets,
+                                after_key.as_deref(),
+                                next_key.as_deref(),
+                            );
+                            if !push.is_empty() {
+                                info!("Pushing {} entries to {}", push.len(), peer);
+                            }
+                            for entries in anti_entropy::split(push, anti_entropy::PAGE_SIZE) {
+                                swarm.behaviour_mut().reconcile.send_request(
+                                    &peer,
+                                    ReconcileRequest::Entries {
+                                        namespace: namespace.clone(),
+                                        entries,
+                                    },
+                                );
+                            }
+                        }
+
+                        // 残りはマージ後のダイジェストで続きから突き合わせる
+                        if let Some(next_bucket) = next_bucket {
+                            start_reconciliation(
+                                swarm,
+                                storage,
+                                &peer,
+                                &namespace,
+                                next_bucket,
+                                next_key,
+                            )?;
+                        }
+                    }
+                    ReconcileResponse::Ack => {
+                        info!("Reconciliation with {} complete", peer);
+                    }
+                    ReconcileResponse::Denied => {
+                        info!("Peer {} declined reconciliation of a namespace", peer);
+                    }
+                },
+            }
+        }
+        Event::OutboundFailure { peer, error, .. } => {
+            warn!("Reconciliation request to {} failed: {}", peer, error);
+        }
+        Event::InboundFailure { peer, error, .. } => {
+            warn!("Reconciliation request from {} failed: {}", peer, error);
+        }
+        Event::ResponseSent { .. } => {}
+    }
+
+    Ok(())
+}
+
+async fn handle_chunk_event(
+    swarm: &mut libp2p::Swarm<P2PSyncBehaviour>,
+    storage: &Storage,
+    namespaces: &Namespaces,
+    event: chunks::Event,
+    whitelist: &Arc<PeerWhitelist>,
+    dir_sync: &mut Option<DirectorySync>,
+    value_fetches: &mut ChunkFetches,
+) -> Result<()> {
+    use libp2p::request_response::{Event, Message};
+    use tracing::warn;
+
+    match event {
+        Event::Message { peer, message, .. } => match message {
+            Message::Request {
+                request, channel, ..
+            } => {
+                // チャンクは信頼されたピアにのみ渡す
+                let response = if !whitelist.is_trusted_by_chain(&peer).await? {
+                    warn!("Refusing chunk request from non-whitelisted peer: {}", peer);
+                    chunks::ChunkResponse::NotFound
+                } else if let Some(data) = storage.get_chunk(&request.hash)? {
+                    // 相手が読み取れるネームスペースの値のチャンクだけを渡す
+                    let readable = storage
+                        .chunk_namespaces(&request.hash)?
+                        .iter()
+                        .any(|namespace| namespaces.can_read(namespace, &peer));
+                    if readable {
+                        chunks::ChunkResponse::Chunk(data)
+                    } else {
+                        warn!("Refusing chunk {} to {}: not a reader", request.hash, peer);
+                        chunks::ChunkResponse::NotFound
+                    }
+                } else {
+                    match dir_sync.as_ref() {
+                        // ディレクトリ同期のファイルは default ネームスペースに属する
+                        Some(dir_sync) if namespaces.can_read(DEFAULT_NAMESPACE, &peer) => {
+                            dir_sync.serve(&request)?
+                        }
+                        _ => chunks::ChunkResponse::NotFound,
+                    }
+                };
+
+                if swarm
+                    .behaviour_mut()
+                    .chunks
+                    .send_response(channel, response)
+                    .is_err()
+                {
+                    warn!("Failed to send chunk to {}", peer);
+                }
+            }
+            Message::Response {
+                request_id,
+                response,
+            } => {
+                if value_fetches.contains(&request_id) {
+                    if let Some(data) = value_fetches.handle_response(
+                        request_id,
+                        response,
+                        &mut swarm.behaviour_mut().chunks,
+                    ) {
+                        storage.insert_chunk(&data)?;
+                    }
+                } else if let Some(dir_sync) = dir_sync.as_mut() {
+                    dir_sync.handle_response(
+                        request_id,
+                        response,
+                        &mut swarm.behaviour_mut().chunks,
+                        storage,
+                    )?;
+                }
+            }
+        },
+        Event::OutboundFailure {
+            peer,
+            request_id,
+            error,
+            ..
+        } => {
+            warn!("Chunk request to {} failed: {}", peer, error);
+            if value_fetches.contains(&request_id) {
+                value_fetches.handle_failure(request_id, &mut swarm.behaviour_mut().chunks);
+            } else if let Some(dir_sync) = dir_sync.as_mut() {
+                dir_sync.handle_failure(request_id, &mut swarm.behaviour_mut().chunks);
+            }
+        }
+        Event::InboundFailure { peer, error, .. } => {
+            warn!("Chunk request from {} failed: {}", peer, error);
         }
+        Event::ResponseSent { .. } => {}
     }
 
     Ok(())
 }
 
+/// Send the digest of `namespace` to `peer`, which replies with the entries
+/// that differ in buckets from `from_bucket` on, skipping keys of that bucket
+/// up to `after_key`
+fn start_reconciliation(
+    swarm: &mut libp2p::Swarm<P2PSyncBehaviour>,
+    storage: &Storage,
+    peer: &libp2p::PeerId,
+    namespace: &str,
+    from_bucket: u16,
+    after_key: Option<String>,
+) -> Result<()> {
+    let digest = StoreDigest::from_entries(&storage.entries(namespace)?);
+    swarm.behaviour_mut().reconcile.send_request(
+        peer,
+        ReconcileRequest::Digests {
+            namespace: namespace.to_string(),
+            digest,
+            from_bucket,
+            after_key,
+        },
+    );
+    Ok(())
+}
+
+/// Validate and merge entries of `namespace` received during reconciliation.
+/// Only entries written and signed by the namespace's writers are applied,
+/// whichever peer served them.
+async fn apply_reconciled_entries(
+    storage: &Storage,
+    namespaces: &Namespaces,
+    namespace: &str,
+    entries: &[Entry],
+    peer: &libp2p::PeerId,
+    security_config: &SecurityConfig,
+    whitelist: &PeerWhitelist,
+) -> Result<usize> {
+    use tracing::warn;
+
+    let mut applied = 0;
+    for entry in entries {
+        if !namespaces.can_write_origin(namespace, &entry.timestamp.origin) {
+            warn!(
+                "Rejected entry {} of namespace {} from peer {}: {} is not a writer",
+                entry.key, namespace, peer, entry.timestamp.origin
+            );
+            continue;
+        }
+        // origin は相手が申告したものなので、origin の鍵による署名で確かめる
+        let public_key = match entry.timestamp.origin.parse::<libp2p::PeerId>() {
+            Ok(origin) => whitelist.verification_key(&origin).await?,
+            Err(_) => None,
+        };
+        if let Err(reason) = anti_entropy::verify_author(
+            namespace,
+            entry,
+            public_key.as_ref(),
+            security_config.strict_signatures,
+        ) {
+            warn!(
+                "Rejected entry {} of namespace {} from peer {}: {}",
+                entry.key, namespace, peer, reason
+            );
+            continue;
+        }
+        // 入力検証
+        if let Err(reason) = security_config.check_entry(&entry.key, entry.value.as_ref()) {
+            warn!(
+                "Rejected entry {} from peer {}: {}",
+                entry.key, peer, reason
+            );
+            continue;
+        }
+        if let Err(reason) = storage.check_timestamp(&entry.timestamp) {
+            warn!(
+                "Rejected entry {} of namespace {} from peer {}: {}",
+                entry.key, namespace, peer, reason
+            );
+            continue;
+        }
+
+        // 作成者の鍵で確かめた行は、同じ書き込みの署名なし・不正な署名の複製を置き換える
+        match public_key {
+            Some(_) => storage.apply_verified_entry(namespace, entry)?,
+            None => storage.apply_entry(namespace, entry)?,
+        }
+        applied += 1;
+    }
+
+    Ok(applied)
+}
+
+fn disconnect_rejected_peer(swarm: &mut libp2p::Swarm<P2PSyncBehaviour>, peer_id: &libp2p::PeerId) {
+    swarm.behaviour_mut().kad.remove_peer(peer_id);
+    let _ = swarm.disconnect_peer_id(*peer_id);
+    info!("Disconnected rejected peer: {peer_id}");
+}
+
 async fn handle_mdns_event(
     swarm: &mut libp2p::Swarm<P2PSyncBehaviour>,
     event: mdns::Event,
-) -> Result<()> {
+    connection_manager: &ConnectionManager,
+) {
     match event {
         mdns::Event::Discovered(list) => {
             for (peer_id, addr) in list {
+                // 接続しても拒否されるピアには接続しない
+                if !connection_manager.is_peer_allowed(&peer_id).await {
+                    tracing::debug!("Ignoring mDNS peer rejected by access control: {peer_id}");
+                    continue;
+                }
                 info!("mDNS discovered a new peer: {peer_id}");
-                swarm.behaviour_mut().gossipsub.add_explicit_peer(&peer_id);
-                swarm.behaviour_mut().kad.add_address(&peer_id, addr);
+                swarm
+                    .behaviour_mut()
+                    .kad
+                    .add_address(&peer_id, addr.clone());
+                dial_discovered_peer(swarm, peer_id, vec![addr]);
             }
         }
         mdns::Event::Expired(list) => {
             for (peer_id, _) in list {
                 info!("mDNS discover peer expired: {peer_id}");
-                swarm
-                    .behaviour_mut()
-                    .gossipsub
-                    .remove_explicit_peer(&peer_id);
             }
         }
     }
+}
 
-    Ok(())
+/// Connect to a discovered peer as a regular gossipsub peer, so that it can
+/// join the mesh and is subject to peer scoring. Known addresses of the peer
+/// are used besides `addrs`.
+fn dial_discovered_peer(
+    swarm: &mut libp2p::Swarm<P2PSyncBehaviour>,
+    peer_id: libp2p::PeerId,
+    addrs: Vec<Multiaddr>,
+) {
+    use libp2p::swarm::dial_opts::{DialOpts, PeerCondition};
+
+    let opts = DialOpts::peer_id(peer_id)
+        .condition(PeerCondition::DisconnectedAndNotDialing)
+        .addresses(addrs)
+        .build();
+    if let Err(e) = swarm.dial(opts) {
+        tracing::debug!("Not dialing discovered peer {peer_id}: {e}");
+    }
+}
+
+fn add_bootstrap_peers(swarm: &mut libp2p::Swarm<P2PSyncBehaviour>, bootstrap_peers: &[String]) {
+    use tracing::warn;
+
+    if bootstrap_peers.is_empty() {
+        return;
+    }
+
+    for addr in bootstrap_peers {
+        let discovery::BootstrapPeer { peer_id, addr } = match discovery::parse_bootstrap_peer(addr)
+        {
+            Ok(peer) => peer,
+            Err(e) => {
+                warn!("{:#}", e);
+                continue;
+            }
+        };
+
+        // PeerId が無いアドレスは接続後に identify で DHT に登録される
+        if let Some(peer_id) = peer_id {
+            swarm
+                .behaviour_mut()
+                .kad
+                .add_address(&peer_id, addr.clone());
+        }
+        if let Err(e) = swarm.dial(addr.clone()) {
+            warn!("Failed to dial bootstrap peer {}: {}", addr, e);
+        }
+    }
+
+    if let Err(e) = swarm.behaviour_mut().kad.bootstrap() {
+        info!("Kademlia bootstrap deferred: {}", e);
+    }
+}
+
+async fn handle_kad_event(
+    swarm: &mut libp2p::Swarm<P2PSyncBehaviour>,
+    event: kad::Event,
+    connection_manager: &ConnectionManager,
+) {
+    match event {
+        kad::Event::RoutingUpdated {
+            peer, is_new_peer, ..
+        } => {
+            if is_new_peer && connection_manager.is_peer_allowed(&peer).await {
+                // mDNS と同様に DHT で見つけたピアにも接続し、gossipsub のメッシュに参加させる
+                info!("Kademlia discovered a new peer: {peer}");
+                dial_discovered_peer(swarm, peer, Vec::new());
+            }
+        }
+        kad::Event::OutboundQueryProgressed {
+            result: kad::QueryResult::GetClosestPeers(Ok(ok)),
+            ..
+        } => {
+            for peer in ok.peers {
+                for addr in peer.addrs {
+                    swarm.behaviour_mut().kad.add_address(&peer.peer_id, addr);
+                }
+            }
+        }
+        kad::Event::OutboundQueryProgressed {
+            result: kad::QueryResult::Bootstrap(result),
+            ..
+        } => match result {
+            Ok(ok) if ok.num_remaining == 0 => info!("Kademlia bootstrap complete"),
+            Ok(_) => {}
+            Err(e) => tracing::warn!("Kademlia bootstrap failed: {e:?}"),
+        },
+        other => tracing::debug!("Kademlia event: {other:?}"),
+    }
 }
 
+fn handle_identify_event(swarm: &mut libp2p::Swarm<P2PSyncBehaviour>, event: identify::Event) {
+    match event {
+        identify::Event::Received { peer_id, info, .. } => {
+            // DHT に参加しているピアのアドレスをルーティングテーブルに登録する
+            if info.protocols.contains(&kad::PROTOCOL_NAME) {
+                for addr in info.listen_addrs {
+                    swarm.behaviour_mut().kad.add_address(&peer_id, addr);
+                }
+            }
+        }
+        other => tracing::debug!("Identify event: {other:?}"),
+    }
+}
+
+#[allow(clippy::too_many_arguments)]
 async fn handle_gossipsub_event(
     storage: &Storage,
+    namespaces: &Namespaces,
     event: gossipsub::Event,
+    security_config: &SecurityConfig,
+    rate_limiter: &RateLimiter,
+    connection_manager: &ConnectionManager,
+    whitelist: &Arc<PeerWhitelist>,
+    key_dist_manager: &Arc<KeyDistributionManager>,
+    swarm: &mut libp2p::Swarm<P2PSyncBehaviour>,
+    topic: &gossipsub::IdentTopic,
+    value_fetches: &mut ChunkFetches,
+    metrics: &Metrics,
+) {
+    match event {
+        gossipsub::Event::Message {
+            propagation_source: peer_id,
+            message_id,
+            message,
+        } => {
+            let result = handle_gossipsub_message(
+                storage,
+                namespaces,
+                peer_id,
+                message,
+                security_config,
+                rate_limiter,
+                connection_manager,
+                whitelist,
+                key_dist_manager,
+                swarm,
+                topic,
+                value_fetches,
+                metrics,
+            )
+            .await;
+
+            // 検証結果を gossipsub に伝える（処理に失敗したメッセージは転送せず、送信元も罰しない）。
+            // 失敗はピアから届いた内容が原因のことがあるので、ノードは止めない
+            let acceptance = match result {
+                Ok(acceptance) => acceptance,
+                Err(e) => {
+                    tracing::warn!("Failed to process message from {}: {:#}", peer_id, e);
+                    metrics.event_failed("gossip");
+                    MessageAcceptance::Ignore
+                }
+            };
+            swarm
+                .behaviour_mut()
+                .gossipsub
+                .report_message_validation_result(&message_id, &peer_id, acceptance);
+        }
+        gossipsub::Event::Subscribed {
+            peer_id,
+            topic: subscribed,
+        } => {
+            info!("Peer {peer_id} subscribed to topic: {subscribed}");
+
+            // オフラインだった間に出された失効を受け取れるよう、保存済みの失効を
+            // 購読したピアにだけ直接送る（全員に gossip すると購読のたびに増幅される）
+            if subscribed == topic.hash() {
+                match key_dist_manager.relay_revocations(&peer_id).await {
+                    Ok(revocations) if !revocations.is_empty() => {
+                        info!("Relaying {} revocations to {}", revocations.len(), peer_id);
+                        swarm
+                            .behaviour_mut()
+                            .reconcile
+                            .send_request(&peer_id, ReconcileRequest::Revocations { revocations });
+                    }
+                    Ok(_) => {}
+                    Err(e) => {
+                        tracing::warn!("Failed to load revocations to relay: {:#}", e);
+                    }
+                }
+            }
+        }
+        gossipsub::Event::Unsubscribed { peer_id, topic } => {
+            info!("Peer {peer_id} unsubscribed from topic: {topic}");
+        }
+        _ => {}
+    }
+}
+
+/// Check and apply one gossipsub message. The result is reported to gossipsub:
+/// only accepted messages are forwarded, rejected ones lower the score of the
+/// peer that sent them. Checks that depend on this node's own whitelist or
+/// ACLs ignore the message instead, so honest relays are not penalised.
+#[allow(clippy::too_many_arguments)]
+async fn handle_gossipsub_message(
+    storage: &Storage,
+    namespaces: &Namespaces,
+    peer_id: libp2p::PeerId,
+    message: gossipsub::Message,
+    security_config: &SecurityConfig,
     rate_limiter: &RateLimiter,
     connection_manager: &ConnectionManager,
     whitelist: &Arc<PeerWhitelist>,
     key_dist_manager: &Arc<KeyDistributionManager>,
     swarm: &mut libp2p::Swarm<P2PSyncBehaviour>,
     topic: &gossipsub::IdentTopic,
-) -> Result<()> {
+    value_fetches: &mut ChunkFetches,
+    metrics: &Metrics,
+) -> Result<MessageAcceptance> {
     use tracing::warn;
 
-    match event {
-        gossipsub::Event::Message {
-            propagation_source: peer_id,
-            message,
+    // レート制限チェック
+    if let Err(e) = rate_limiter.check_rate_limit(&peer_id).await {
+        warn!("Rate limit exceeded for peer {}: {}", peer_id, e);
+        metrics.reject_gossip("rate_limited");
+        return Ok(MessageAcceptance::Ignore);
+    }
+
+    // 接続状況チェック（未承認の接続からはホワイトリスト申請のみ受け付ける）
+    let active_connections = connection_manager.get_active_connections().await;
+    let from_accepted_peer = active_connections.contains_key(&peer_id);
+
+    // メッセージサイズチェック
+    if let Err(reason) = security_config.check_message_size(message.data.len()) {
+        warn!("Rejected message from peer {}: {}", peer_id, reason);
+        metrics.reject_gossip("too_large");
+        return Ok(MessageAcceptance::Reject);
+    }
+
+    // Parse signed P2P message
+    let signed_data: SignedData<P2PMessage> = match serde_json::from_slice(&message.data) {
+        Ok(m) => m,
+        Err(e) => {
+            warn!("Invalid signed message from peer {}: {}", peer_id, e);
+            metrics.reject_gossip("malformed");
+            return Ok(MessageAcceptance::Reject);
+        }
+    };
+
+    // Verify sender's signature
+    let signer_peer_id = match signed_data.signer.parse::<libp2p::PeerId>() {
+        Ok(id) => id,
+        Err(e) => {
+            warn!("Invalid signer peer ID from {}: {}", peer_id, e);
+            metrics.reject_gossip("invalid_signer");
+            return Ok(MessageAcceptance::Reject);
+        }
+    };
+
+    // gossipsub の source は送信元の鍵で署名されているため、署名者の偽装を防げる
+    if message.source != Some(signer_peer_id) {
+        warn!(
+            "Message signer {} does not match gossipsub source {:?} (from {})",
+            signer_peer_id, message.source, peer_id
+        );
+        metrics.reject_gossip("source_mismatch");
+        return Ok(MessageAcceptance::Reject);
+    }
+
+    // ホワイトリスト申請は未知のピアからも受け付け、申請内の公開鍵で署名を検証する
+    let request_key = match &signed_data.data {
+        P2PMessage::KeyDistribution(KeyDistributionMessage::WhitelistRequest {
+            public_key,
             ..
+        }) => Some(libp2p::identity::PublicKey::try_decode_protobuf(public_key).ok()),
+        _ => None,
+    };
+
+    if let Some(request_key) = request_key {
+        let valid = match request_key {
+            Some(key) => {
+                key.to_peer_id() == signer_peer_id && signed_data.verify_with_public_key(&key)?
+            }
+            None => false,
+        };
+        if !valid {
+            warn!("Invalid whitelist request from peer: {}", signer_peer_id);
+            metrics.reject_gossip("invalid_whitelist_request");
+            return Ok(MessageAcceptance::Reject);
+        }
+    } else if !from_accepted_peer {
+        warn!("Message from unknown peer: {}", peer_id);
+        metrics.reject_gossip("unknown_peer");
+        return Ok(MessageAcceptance::Ignore);
+    } else if !whitelist.is_trusted_by_chain(&signer_peer_id).await? {
+        // Signer must be whitelisted or trusted through recommendations
+        warn!("Message from non-whitelisted peer: {}", signer_peer_id);
+        metrics.reject_gossip("not_whitelisted");
+        return Ok(MessageAcceptance::Ignore);
+    } else if let Some(public_key) = whitelist.verification_key(&signer_peer_id).await? {
+        // Verify with the stored key or the key embedded in the PeerId
+        if !signed_data.verify_with_public_key(&public_key)? {
+            warn!("Invalid signature from peer: {}", signer_peer_id);
+            metrics.reject_gossip("invalid_signature");
+            return Ok(MessageAcceptance::Reject);
+        }
+        tracing::debug!("Signature verified for peer: {}", signer_peer_id);
+    } else if security_config.strict_signatures {
+        warn!(
+            "No public key to verify message from peer {} (security.strict_signatures)",
+            signer_peer_id
+        );
+        metrics.reject_gossip("unverifiable");
+        return Ok(MessageAcceptance::Reject);
+    } else {
+        // For peers without a known public key, we trust based on whitelist only
+        info!(
+            "No public key for peer {}, trusting based on whitelist",
+            signer_peer_id
+        );
+    }
+
+    // ネームスペース付きの変更や暗号化された変更も Sync として扱い、書き込み権限を確認する
+    let (namespace, data, encrypted) = match signed_data.data {
+        P2PMessage::NamespaceSync {
+            namespace,
+            change,
+            seq,
+        } => (namespace, P2PMessage::Sync { change, seq }, false),
+        P2PMessage::EncryptedSync {
+            namespace,
+            value,
+            seq,
         } => {
-            // レート制限チェック
-            if let Err(e) = rate_limiter.check_rate_limit(&peer_id).await {
-                warn!("Rate limit exceeded for peer {}: {}", peer_id, e);
-                return Ok(());
+            let plaintext = match key_dist_manager.decrypt(&namespace, &value).await {
+                Ok(Some(plaintext)) => plaintext,
+                Ok(None) => {
+                    // 鍵を受け取った後の突き合わせで取りこぼした変更に追いつく
+                    if namespaces.can_read(&namespace, swarm.local_peer_id()) {
+                        if let Some(request) = key_dist_manager
+                            .request_group_key(&namespace, Some(value.key_id))
+                            .await
+                        {
+                            publish_key_distribution(
+                                swarm,
+                                topic,
+                                security_config,
+                                key_dist_manager.local_keypair(),
+                                request,
+                            )?;
+                        }
+                    }
+                    return Ok(MessageAcceptance::Ignore);
+                }
+                Err(e) => {
+                    warn!(
+                        "Failed to decrypt change to {} from {}: {}",
+                        namespace, signer_peer_id, e
+                    );
+                    metrics.reject_gossip("decrypt_failed");
+                    return Ok(MessageAcceptance::Ignore);
+                }
+            };
+            match serde_json::from_slice(&plaintext) {
+                Ok(change) => (namespace, P2PMessage::Sync { change, seq }, true),
+                Err(e) => {
+                    warn!("Invalid encrypted change from {}: {}", signer_peer_id, e);
+                    metrics.reject_gossip("malformed");
+                    return Ok(MessageAcceptance::Reject);
+                }
             }
+        }
+        data => (DEFAULT_NAMESPACE.to_string(), data, false),
+    };
+
+    match data {
+        P2PMessage::Sync { change, seq } => {
+            let sync_msg = &change.message;
+            info!(
+                "Got sync message for {} in namespace {} from {}",
+                sync_msg.key(),
+                namespace,
+                signer_peer_id
+            );
 
-            // 接続状況チェック
-            let active_connections = connection_manager.get_active_connections().await;
-            if !active_connections.contains_key(&peer_id) {
-                warn!("Message from unknown peer: {}", peer_id);
-                return Ok(());
+            if namespace::topic(&namespace).hash() != message.topic {
+                warn!(
+                    "Sync message for namespace {} published on topic {}",
+                    namespace, message.topic
+                );
+                metrics.reject_gossip("wrong_topic");
+                return Ok(MessageAcceptance::Reject);
+            }
+            if !namespaces.can_write(&namespace, &signer_peer_id) {
+                warn!(
+                    "Peer {} is not a writer of namespace {}",
+                    signer_peer_id, namespace
+                );
+                metrics.reject_gossip("not_writer");
+                return Ok(MessageAcceptance::Ignore);
+            }
+            if namespaces.is_encrypted(&namespace) && !encrypted {
+                warn!(
+                    "Unencrypted change to encrypted namespace {} from {}",
+                    namespace, signer_peer_id
+                );
+                metrics.reject_gossip("unencrypted");
+                return Ok(MessageAcceptance::Ignore);
             }
 
-            // メッセージサイズチェック
-            if message.data.len() > 1024 * 1024 {
-                // 1MB
+            // HLC の origin は署名者と一致しなければならない（タイブレークの偽装防止）
+            if sync_msg.timestamp().origin != signed_data.signer {
                 warn!(
-                    "Message too large from peer {}: {} bytes",
-                    peer_id,
-                    message.data.len()
+                    "Sync message origin {} does not match signer {}",
+                    sync_msg.timestamp().origin,
+                    signer_peer_id
                 );
-                return Ok(());
+                metrics.reject_gossip("origin_mismatch");
+                return Ok(MessageAcceptance::Reject);
             }
 
-            // Parse signed P2P message
-            let signed_data: SignedData<P2PMessage> = match serde_json::from_slice(&message.data) {
-                Ok(m) => m,
-                Err(e) => {
-                    warn!("Invalid signed message from peer {}: {}", peer_id, e);
-                    return Ok(());
-                }
-            };
+            // 入力検証
+            if let Err(reason) = security_config.check_sync_message(sync_msg) {
+                warn!("Rejected sync message from peer {}: {}", peer_id, reason);
+                metrics.reject_gossip("invalid_message");
+                return Ok(MessageAcceptance::Reject);
+            }
 
-            // Verify sender's signature
-            let signer_peer_id = match signed_data.signer.parse::<libp2p::PeerId>() {
-                Ok(id) => id,
-                Err(e) => {
-                    warn!("Invalid signer peer ID from {}: {}", peer_id, e);
-                    return Ok(());
+            // 行への署名は保存して突き合わせで配るので、ここで作成者のものか確かめる
+            let chunked = matches!(sync_msg, SyncMessage::PutChunked { .. });
+            let entry = change.into_entry();
+            if let Some(public_key) = whitelist.verification_key(&signer_peer_id).await? {
+                if !crypto::verify_entry(&public_key, &namespace, &entry)? {
+                    warn!("Invalid change signature from peer: {}", signer_peer_id);
+                    metrics.reject_gossip("invalid_signature");
+                    return Ok(MessageAcceptance::Reject);
                 }
-            };
+            }
 
-            // Check if signer is whitelisted or trusted through recommendations
-            if !whitelist.is_trusted_by_chain(&signer_peer_id).await? {
-                warn!("Message from non-whitelisted peer: {}", signer_peer_id);
-                return Ok(());
+            // 未来すぎる時刻は時計を進めて LWW を奪えるので、通し番号を記録する前に捨てる
+            if let Err(reason) = storage.check_timestamp(&entry.timestamp) {
+                warn!(
+                    "Rejected change to {} from {}: {}",
+                    namespace, signer_peer_id, reason
+                );
+                metrics.reject_gossip("clock_skew");
+                return Ok(MessageAcceptance::Reject);
             }
 
-            // Verify signature if public key is available
-            if let Some(public_key) = whitelist.get_public_key(&signer_peer_id).await? {
-                if !signed_data.verify_with_public_key(&public_key)? {
-                    warn!("Invalid signature from peer: {}", signer_peer_id);
-                    return Ok(());
+            // 送信元ごとの通し番号で重複・リプレイを捨て、取りこぼしを検出する
+            match storage.record_sequence(&namespace, &signed_data.signer, seq)? {
+                Sequence::InOrder => {}
+                // 順序が入れ替わった変更や、sync.db を作り直して番号がやり直しになった
+                // 送信元の変更は、保存済みのものより新しければ適用する
+                Sequence::Stale { high_water } if storage.is_newer(&namespace, &entry)? => {
+                    info!(
+                        "Applying change {} to {} from {} below its high-water mark {}",
+                        seq, namespace, signer_peer_id, high_water
+                    );
+                }
+                Sequence::Stale { high_water } => {
+                    warn!(
+                        "Dropping replayed change {} to {} from {} (already seen up to {})",
+                        seq, namespace, signer_peer_id, high_water
+                    );
+                    metrics.reject_gossip("replayed");
+                    return Ok(MessageAcceptance::Ignore);
+                }
+                Sequence::Gap { missed } => {
+                    // 送信元に接続していればそこから、いなければ中継したピアから取り寄せる
+                    let target = if swarm.is_connected(&signer_peer_id)
+                        && namespaces.can_read(&namespace, &signer_peer_id)
+                    {
+                        signer_peer_id
+                    } else {
+                        peer_id
+                    };
+                    warn!(
+                        "Missed {} change(s) to {} from {}, reconciling with {}",
+                        missed, namespace, signer_peer_id, target
+                    );
+                    start_reconciliation(swarm, storage, &target, &namespace, 0, None)?;
                 }
-                info!("Signature verified for peer: {}", signer_peer_id);
-            } else {
-                // For peers without stored public keys, we trust based on whitelist only
-                info!(
-                    "No public key stored for peer {}, trusting based on whitelist",
-                    signer_peer_id
-                );
             }
 
-            match signed_data.data {
-                P2PMessage::Sync(sync_msg) => {
-                    info!("Got sync message from {}: {:?}", signer_peer_id, sync_msg);
+            storage.apply_entry(&namespace, &entry)?;
+            // チャンク本体は署名者を優先してリクエスト・レスポンスで取得する
+            if chunked {
+                request_value_chunks(swarm, storage, value_fetches, Some(&signer_peer_id))?;
+            }
+        }
+        P2PMessage::KeyDistribution(key_msg) => {
+            info!(
+                "Got key distribution message from {}: {:?}",
+                signer_peer_id, key_msg
+            );
 
-                    match sync_msg {
-                        SyncMessage::Put {
-                            key,
-                            value,
-                            timestamp,
-                        } => {
-                            // 入力検証
-                            if let Err(e) = validate_key(&key, 256) {
-                                warn!("Invalid key from peer {}: {}", peer_id, e);
-                                return Ok(());
-                            }
-                            if let Err(e) = validate_value(&value, 64 * 1024) {
-                                warn!("Invalid value from peer {}: {}", peer_id, e);
-                                return Ok(());
-                            }
+            // 自分宛てのグループ鍵を受け取ったら、取りこぼした変更を突き合わせで取得する
+            let granted_namespace = match &key_msg {
+                KeyDistributionMessage::GroupKeyGrant {
+                    namespace, keys, ..
+                } if keys
+                    .iter()
+                    .any(|key| key.recipient == swarm.local_peer_id().to_string()) =>
+                {
+                    Some(namespace.clone())
+                }
+                _ => None,
+            };
+            let revoked_peer = match &key_msg {
+                KeyDistributionMessage::Revocation { revocation, .. } => {
+                    revocation.data.peer_id.parse::<libp2p::PeerId>().ok()
+                }
+                _ => None,
+            };
+            let was_whitelisted = match &revoked_peer {
+                Some(peer) => whitelist.is_whitelisted(peer).await?,
+                None => false,
+            };
 
-                            storage.put_with_timestamp(&key, &value, timestamp)?;
-                        }
-                        SyncMessage::Delete { key, timestamp } => {
-                            if let Err(e) = validate_key(&key, 256) {
-                                warn!("Invalid key from peer {}: {}", peer_id, e);
-                                return Ok(());
-                            }
+            // Create a new SignedData for just the key distribution message
+            let key_signed_data = SignedData {
+                data: key_msg,
+                signature: signed_data.signature,
+                signer: signed_data.signer,
+            };
 
-                            storage.delete_with_timestamp(&key, timestamp)?;
-                        }
+            // Handle key distribution message
+            if let Some(response) = key_dist_manager
+                .handle_message(key_signed_data, signer_peer_id)
+                .await?
+            {
+                // Send response if needed
+                let p2p_response = P2PMessage::KeyDistribution(response);
+                let response_signed =
+                    SignedData::new(p2p_response, key_dist_manager.local_keypair())?;
+
+                let response_json = serde_json::to_vec(&response_signed)?;
+                match security_config.check_message_size(response_json.len()) {
+                    Ok(()) => match swarm
+                        .behaviour_mut()
+                        .gossipsub
+                        .publish(topic.clone(), response_json)
+                    {
+                        Ok(_) => info!("Sent key distribution response to {}", signer_peer_id),
+                        Err(e) => warn!("Failed to send key distribution response: {}", e),
+                    },
+                    Err(reason) => {
+                        warn!("Not sending key distribution response: {}", reason)
                     }
                 }
-                P2PMessage::KeyDistribution(key_msg) => {
-                    info!(
-                        "Got key distribution message from {}: {:?}",
-                        signer_peer_id, key_msg
-                    );
+            }
 
-                    // Create a new SignedData for just the key distribution message
-                    let key_signed_data = SignedData {
-                        data: key_msg,
-                        signature: signed_data.signature,
-                        signer: signed_data.signer,
-                    };
+            // 失効が受け入れられたピアは以後の接続も含めて拒否する
+            if let Some(peer) = revoked_peer {
+                if whitelist.is_revoked(&peer).await? {
+                    swarm.behaviour_mut().blocked.block_peer(peer);
+                    // ホワイトリストから外れたピアには新しいグループ鍵を渡さない
+                    if was_whitelisted {
+                        rotate_group_keys(swarm, topic, security_config, key_dist_manager).await?;
+                    }
+                }
+            }
 
-                    // Handle key distribution message
-                    if let Some(response) = key_dist_manager
-                        .handle_message(key_signed_data, signer_peer_id)
-                        .await?
-                    {
-                        // Send response if needed
-                        let p2p_response = P2PMessage::KeyDistribution(response);
-                        let response_signed =
-                            SignedData::new(p2p_response, key_dist_manager.local_keypair())?;
-
-                        let response_json = serde_json::to_vec(&response_signed)?;
-                        if response_json.len() <= 1024 * 1024 {
-                            swarm
-                                .behaviour_mut()
-                                .gossipsub
-                                .publish(topic.clone(), response_json)?;
-                            info!("Sent key distribution response to {}", signer_peer_id);
-                        }
+            if let Some(namespace) = granted_namespace {
+                let peers: Vec<_> = swarm.connected_peers().cloned().collect();
+                for peer in peers {
+                    if namespaces.can_read(&namespace, &peer) {
+                        start_reconciliation(swarm, storage, &peer, &namespace, 0, None)?;
                     }
                 }
             }
         }
-        gossipsub::Event::Subscribed { peer_id, topic } => {
-            info!("Peer {peer_id} subscribed to topic: {topic}");
-        }
-        gossipsub::Event::Unsubscribed { peer_id, topic } => {
-            info!("Peer {peer_id} unsubscribed from topic: {topic}");
+        P2PMessage::NamespaceSync { .. } | P2PMessage::EncryptedSync { .. } => {
+            unreachable!("converted to Sync above")
         }
-        _ => {}
     }
 
-    Ok(())
+    Ok(MessageAcceptance::Accept)
 }
 
 fn install_service() -> Result<()> {
@@ -881,13 +3236,143 @@ fn install_service() -> Result<()> {
     Ok(())
 }
 
-async fn handle_whitelist_command(cmd: WhitelistCommands) -> Result<()> {
-    let data_dir = dirs::data_dir()
+async fn handle_ctl_command(socket: PathBuf, json: bool, command: CtlCommands) -> Result<()> {
+    let output = match &command {
+        CtlCommands::Get { output, .. } => output.clone(),
+        _ => None,
+    };
+    let request = command.into_request()?;
+
+    #[cfg(unix)]
+    {
+        if let ControlRequest::Watch { prefix, namespace } = &request {
+            let mut stream = control::watch(&socket, namespace.clone(), prefix.clone()).await?;
+            while let Some(response) = stream.next().await? {
+                if json {
+                    println!("{}", serde_json::to_string(&response)?);
+                } else if let ControlResponse::Error { message, .. } = response {
+                    eprintln!("Warning: {message}");
+                } else {
+                    print_response(&request, response);
+                }
+            }
+            return Ok(());
+        }
+
+        let mut response = control::send_request(&socket, &request).await?;
+        if let Some(path) = &output {
+            response = save_bytes(path, response)?;
+        }
+
+        if json {
+            println!("{}", serde_json::to_string_pretty(&response)?);
+        }
+        if let ControlResponse::Error { message, .. } = response {
+            anyhow::bail!(message);
+        }
+        if !json {
+            print_response(&request, response);
+        }
+
+        Ok(())
+    }
+
+    #[cfg(not(unix))]
+    {
+        let _ = (socket, request, json, output);
+        anyhow::bail!("The control socket is only supported on Unix platforms");
+    }
+}
+
+/// Write the data of a `bytes` response to `path`
+fn save_bytes(path: &std::path::Path, response: ControlResponse) -> Result<ControlResponse> {
+    match response {
+        ControlResponse::Bytes {
+            key,
+            value: Some(value),
+        } => {
+            let data = BASE64.decode(value)?;
+            std::fs::write(path, &data)?;
+            Ok(ControlResponse::Done {
+                message: format!("Wrote {key} ({} bytes) to {}", data.len(), path.display()),
+            })
+        }
+        other => Ok(other),
+    }
+}
+
+fn default_data_dir() -> PathBuf {
+    dirs::data_dir()
         .unwrap_or_else(|| PathBuf::from("."))
-        .join("p2p-sync");
+        .join("p2p-sync")
+}
+
+fn handle_config_command(cmd: ConfigCommands, loader: ConfigLoader) -> Result<()> {
+    let with_data_dir = |data_dir| loader.clone().with_data_dir(data_dir);
+
+    match cmd {
+        ConfigCommands::Show { data_dir } => {
+            let loaded = with_data_dir(data_dir).load()?;
+            let mut config = loaded.config;
+            if config.http.token.is_some() {
+                config.http.token = Some("<redacted>".to_string());
+            }
+
+            let source = match loaded.path.exists() {
+                true => loaded.path.display().to_string(),
+                false => format!("{} (not found, using defaults)", loaded.path.display()),
+            };
+            println!("# Config file: {source}");
+            println!("# Data dir:    {}", loaded.data_dir.display());
+            for key in &loaded.unknown_keys {
+                println!("# Unknown key: {key}");
+            }
+            println!();
+            print!("{}", toml::to_string_pretty(&config)?);
+        }
+
+        ConfigCommands::Validate { data_dir } => {
+            let loaded = with_data_dir(data_dir).load()?;
+            loaded.validate()?;
+            println!("✓ Configuration is valid ({})", loaded.path.display());
+        }
+
+        ConfigCommands::Init { data_dir, force } => {
+            let (path, _) = with_data_dir(data_dir).config_path();
+            if path.exists() && !force {
+                anyhow::bail!(
+                    "{} already exists, use --force to replace it",
+                    path.display()
+                );
+            }
+            if let Some(parent) = path.parent() {
+                std::fs::create_dir_all(parent)?;
+            }
+            config::save_config(&path, &config::Config::default())?;
+            println!("Wrote default configuration to {}", path.display());
+        }
+    }
+
+    Ok(())
+}
+
+/// `--data-dir` of a command, or else the data directory of the config
+fn resolve_data_dir(loader: &ConfigLoader, data_dir: Option<PathBuf>) -> Result<PathBuf> {
+    match data_dir {
+        Some(data_dir) => Ok(data_dir),
+        None => Ok(loader.load()?.data_dir),
+    }
+}
+
+async fn handle_whitelist_command(cmd: WhitelistCommands, loaded: LoadedConfig) -> Result<()> {
+    let LoadedConfig {
+        config, data_dir, ..
+    } = loaded;
 
     std::fs::create_dir_all(&data_dir)?;
-    let whitelist = PeerWhitelist::new(&data_dir.join("whitelist.db"))?;
+    let secret = storage_secret(&data_dir, &config)?;
+    let whitelist = PeerWhitelist::open(&data_dir.join("whitelist.db"), secret.as_ref())?
+        .with_trust(config.trust.clone());
 
     match cmd {
         WhitelistCommands::Add {
@@ -930,50 +3415,16 @@ async fn handle_whitelist_command(cmd: WhitelistCommands) -> Result<()> {
         }
 
         WhitelistCommands::List => {
-            let entries = whitelist.list_peers().await?;
-
-            if entries.is_empty() {
-                println!("No peers in whitelist");
-            } else {
-                println!("=== Whitelist Entries ===");
-                println!(
-                    "{:<60} {:<20} {:<20} {:<10}",
-                    "Peer ID", "Name", "Expires", "Has Key"
-                );
-                println!("{}", "-".repeat(110));
-
-                for entry in entries {
-                    let expires = entry
-                        .expires_at
-                        .map(|dt| dt.format("%Y-%m-%d %H:%M:%S").to_string())
-                        .unwrap_or_else(|| "Never".to_string());
-
-                    let has_key = if entry.public_key.is_some() {
-                        "Yes"
-                    } else {
-                        "No"
-                    };
-
-                    println!(
-                        "{:<60} {:<20} {:<20} {:<10}",
-                        entry.peer_id,
-                        entry.name.unwrap_or_else(|| "-".to_string()),
-                        expires,
-                        has_key
-                    );
-                }
-            }
+            print_whitelist(whitelist.list_peers().await?);
         }
 
         WhitelistCommands::Check { peer_id } => {
             let peer_id = peer_id.parse::<libp2p::PeerId>()?;
-            let is_whitelisted = whitelist.is_whitelisted(&peer_id).await?;
-
-            if is_whitelisted {
-                println!("Peer {peer_id} is whitelisted");
-            } else {
-                println!("Peer {peer_id} is NOT whitelisted");
-            }
+            print_whitelisted(
+                &peer_id.to_string(),
+                whitelist.is_whitelisted(&peer_id).await?,
+                whitelist.is_trusted_by_chain(&peer_id).await?,
+            );
         }
 
         WhitelistCommands::AddKey {
@@ -1008,37 +3459,338 @@ async fn handle_whitelist_command(cmd: WhitelistCommands) -> Result<()> {
                 println!("Error: Peer {peer_id} not found in whitelist");
             }
         }
+
+        WhitelistCommands::Pending => {
+            print_pending_requests(whitelist.list_pending_requests().await?);
+        }
+
+        WhitelistCommands::Approve { peer_id } => {
+            let peer_id = peer_id.parse::<libp2p::PeerId>()?;
+            whitelist.approve_request(&peer_id).await?;
+            println!("Approved whitelist request from {peer_id}");
+        }
+
+        WhitelistCommands::Reject { peer_id } => {
+            let peer_id = peer_id.parse::<libp2p::PeerId>()?;
+            whitelist.reject_request(&peer_id).await?;
+            println!("Rejected whitelist request from {peer_id}");
+        }
+
+        WhitelistCommands::Revoked => {
+            print_revocations(whitelist.list_revocations().await?);
+        }
+
+        WhitelistCommands::Recommendations { peer_id } => {
+            let peer_id = peer_id
+                .map(|peer_id| peer_id.parse::<libp2p::PeerId>())
+                .transpose()?;
+            print_recommendations(whitelist.list_recommendations(peer_id.as_ref()).await?);
+        }
     }
 
     Ok(())
 }
 
-fn load_public_key_from_file(path: &str) -> Result<libp2p::identity::PublicKey> {
-    use std::fs;
+fn print_pending_requests(requests: Vec<whitelist::PendingRequest>) {
+    if requests.is_empty() {
+        println!("No pending whitelist requests");
+    } else {
+        println!("=== Pending Whitelist Requests ===");
+        println!("{:<60} {:<20} {:<20}", "Peer ID", "Name", "Requested");
+        println!("{}", "-".repeat(100));
+
+        for request in requests {
+            println!(
+                "{:<60} {:<20} {:<20}",
+                request.peer_id,
+                request.name.unwrap_or_else(|| "-".to_string()),
+                request.requested_at.format("%Y-%m-%d %H:%M:%S")
+            );
+        }
+    }
+}
+
+fn print_revocations(revocations: Vec<SignedData<whitelist::Revocation>>) {
+    if revocations.is_empty() {
+        println!("No revoked peers");
+    } else {
+        println!("=== Revoked Peers ===");
+        println!(
+            "{:<60} {:<60} {:<20} {:<20}",
+            "Peer ID", "Revoked By", "Revoked", "Reason"
+        );
+        println!("{}", "-".repeat(160));
+
+        for revocation in revocations {
+            println!(
+                "{:<60} {:<60} {:<20} {:<20}",
+                revocation.data.peer_id,
+                revocation.signer,
+                revocation.data.revoked_at.format("%Y-%m-%d %H:%M:%S"),
+                revocation.data.reason.unwrap_or_else(|| "-".to_string())
+            );
+        }
+    }
+}
+
+fn print_whitelisted(peer_id: &str, whitelisted: bool, trusted: bool) {
+    if whitelisted {
+        println!("Peer {peer_id} is whitelisted");
+    } else if trusted {
+        println!("Peer {peer_id} is trusted through recommendations");
+    } else {
+        println!("Peer {peer_id} is NOT whitelisted");
+    }
+}
+
+fn print_recommendations(recommendations: Vec<trust::Recommendation>) {
+    if recommendations.is_empty() {
+        println!("No trust recommendations");
+    } else {
+        println!("=== Trust Recommendations ===");
+        println!(
+            "{:<60} {:<60} {:<20} {:<20} {:<10}",
+            "Peer ID", "Recommended By", "Name", "Recommended", "Status"
+        );
+        println!("{}", "-".repeat(175));
+
+        let now = chrono::Utc::now();
+        for recommendation in recommendations {
+            let status = if recommendation.revoked_at.is_some() {
+                "Revoked"
+            } else if !recommendation.is_active(now) {
+                "Expired"
+            } else {
+                "Active"
+            };
+
+            println!(
+                "{:<60} {:<60} {:<20} {:<20} {:<10}",
+                recommendation.peer_id,
+                recommendation.recommender,
+                recommendation.name.unwrap_or_else(|| "-".to_string()),
+                recommendation.recommended_at.format("%Y-%m-%d %H:%M:%S"),
+                status
+            );
+        }
+    }
+}
+
+fn print_whitelist(entries: Vec<whitelist::WhitelistEntry>) {
+    if entries.is_empty() {
+        println!("No peers in whitelist");
+    } else {
+        println!("=== Whitelist Entries ===");
+        println!(
+            "{:<60} {:<20} {:<20} {:<10}",
+            "Peer ID", "Name", "Expires", "Has Key"
+        );
+        println!("{}", "-".repeat(110));
+
+        for entry in entries {
+            let expires = entry
+                .expires_at
+                .map(|dt| dt.format("%Y-%m-%d %H:%M:%S").to_string())
+                .unwrap_or_else(|| "Never".to_string());
+
+            let has_key = if entry.public_key.is_some() {
+                "Yes"
+            } else {
+                "No"
+            };
+
+            println!(
+                "{:<60} {:<20} {:<20} {:<10}",
+                entry.peer_id,
+                entry.name.unwrap_or_else(|| "-".to_string()),
+                expires,
+                has_key
+            );
+        }
+    }
+}
 
-    let data = fs::read(path)?;
+/// Databases of `data_dir`: whitelist.db and sync.db
+fn databases(data_dir: &Path) -> [PathBuf; 2] {
+    [data_dir.join("whitelist.db"), data_dir.join("sync.db")]
+}
 
-    // Try different formats
-    // First try raw protobuf
-    if let Ok(pk) = libp2p::identity::PublicKey::try_decode_protobuf(&data) {
-        return Ok(pk);
+/// The at-rest secret of `data_dir` if `[storage] encrypt` is enabled
+fn storage_secret(data_dir: &Path, config: &config::Config) -> Result<Option<at_rest::Secret>> {
+    // storage rekey が途中で止まっていれば、鍵を読む前にデータベースと鍵ファイルを揃える
+    if at_rest::finish_rekey(data_dir, &databases(data_dir))? {
+        info!(
+            "Completed an interrupted storage rekey in {}",
+            data_dir.display()
+        );
+    }
+    if !config.storage.encrypt {
+        return Ok(None);
     }
+    at_rest::load_or_create_secret(data_dir).map(Some)
+}
+
+fn handle_storage_command(cmd: StorageCommands, loader: &ConfigLoader) -> Result<()> {
+    match cmd {
+        StorageCommands::Rekey { data_dir, decrypt } => {
+            let data_dir = resolve_data_dir(loader, data_dir)?;
+
+            // 動作中のノードが書き換え途中のデータベースを読まないようにする
+            #[cfg(unix)]
+            if std::os::unix::net::UnixStream::connect(control::socket_path(&data_dir)).is_ok() {
+                anyhow::bail!("Stop the node using {} before rekeying", data_dir.display());
+            }
+
+            // 前回の rekey が途中で止まっていれば先に完了させる
+            let databases = databases(&data_dir);
+            at_rest::finish_rekey(&data_dir, &databases)?;
+
+            let old = at_rest::existing_secret(&data_dir)?;
+            let new = match decrypt {
+                true => None,
+                false => Some(
+                    at_rest::passphrase_from_env(at_rest::NEW_PASSPHRASE_ENV)?
+                        .unwrap_or_else(at_rest::Secret::generate),
+                ),
+            };
+
+            // 両方のデータベースを一時コピー上で書き換え、どちらも成功してから置き換える
+            let [whitelist_path, sync_path] = &databases;
+            let abort = |e: anyhow::Error| {
+                e.context("Rekey failed, sync.db and whitelist.db are unchanged")
+            };
+            let whitelist_copy = rekey_copy(whitelist_path, |copy| {
+                PeerWhitelist::rekey(copy, old.as_ref(), new.as_ref())
+            })
+            .map_err(abort)?;
+            if let Err(e) = rekey_copy(sync_path, |copy| {
+                Storage::rekey(copy, old.as_ref(), new.as_ref())
+            }) {
+                let _ = std::fs::remove_file(&whitelist_copy);
+                return Err(abort(e));
+            }
+
+            // 新しい鍵を書いた時点で確定し、ここから先で止まっても次の起動時に
+            // finish_rekey がコピーを置き換えて鍵ファイルを切り替える
+            at_rest::save_pending_key(&data_dir, new.as_ref())?;
+            let key_path = at_rest::key_path(&data_dir);
+            let had_key_file = key_path.exists();
+            at_rest::finish_rekey(&data_dir, &databases)?;
 
-    // Try base64 decode then protobuf
-    use base64::Engine;
-    if let Ok(decoded) = base64::engine::general_purpose::STANDARD.decode(&data) {
-        if let Ok(pk) = libp2p::identity::PublicKey::try_decode_protobuf(&decoded) {
-            return Ok(pk);
+            if had_key_file {
+                println!(
+                    "Previous key file kept as {} for older backups",
+                    key_path.with_extension("key.old").display()
+                );
+            }
+            match &new {
+                Some(at_rest::Secret::Key(_)) => {
+                    println!(
+                        "✓ Re-encrypted sync.db and whitelist.db with a new key file: {}",
+                        key_path.display()
+                    );
+                }
+                Some(at_rest::Secret::Passphrase(_)) => {
+                    println!(
+                        "✓ Re-encrypted sync.db and whitelist.db with the new passphrase. Start the node with {} set to it.",
+                        at_rest::PASSPHRASE_ENV
+                    );
+                }
+                None => {
+                    println!("✓ Decrypted sync.db and whitelist.db. Set `encrypt = false` in [storage] or they are encrypted again on the next start.");
+                }
+            }
         }
     }
 
-    // Try as hex string
-    let hex_str = String::from_utf8_lossy(&data).trim().to_string();
-    if let Ok(decoded) = hex::decode(&hex_str) {
-        if let Ok(pk) = libp2p::identity::PublicKey::try_decode_protobuf(&decoded) {
-            return Ok(pk);
+    Ok(())
+}
+
+/// Apply `rekey` to a copy of the database at `path` and return the copy,
+/// leaving the database itself untouched
+fn rekey_copy(path: &Path, rekey: impl FnOnce(&Path) -> Result<()>) -> Result<PathBuf> {
+    let copy = at_rest::rekey_copy_path(path);
+    if copy.exists() {
+        std::fs::remove_file(&copy)?;
+    }
+    if path.exists() {
+        rusqlite::Connection::open(path)?.execute("VACUUM INTO ?1", [copy.to_string_lossy()])?;
+    }
+
+    let result = rekey(&copy).and_then(|()| Ok(std::fs::File::open(&copy)?.sync_all()?));
+    if let Err(e) = result {
+        let _ = std::fs::remove_file(&copy);
+        return Err(e.context(format!("Failed to rekey {}", path.display())));
+    }
+
+    Ok(copy)
+}
+
+fn handle_identity_command(cmd: IdentityCommands, loader: &ConfigLoader) -> Result<()> {
+    match cmd {
+        IdentityCommands::Show { data_dir } => {
+            let data_dir = resolve_data_dir(loader, data_dir)?;
+            std::fs::create_dir_all(&data_dir)?;
+            let keypair = identity::load_or_create(&data_dir)?;
+            let public_key =
+                identity::encode_public_key(&keypair.public(), PublicKeyFormat::Base64);
+
+            println!("Peer ID:    {}", keypair.public().to_peer_id());
+            println!("Public key: {}", String::from_utf8_lossy(&public_key));
+            println!(
+                "Identity:   {}",
+                identity::identity_path(&data_dir).display()
+            );
+        }
+
+        IdentityCommands::ExportPublic {
+            output,
+            format,
+            data_dir,
+        } => {
+            let data_dir = resolve_data_dir(loader, data_dir)?;
+            std::fs::create_dir_all(&data_dir)?;
+            let keypair = identity::load_or_create(&data_dir)?;
+            let encoded = identity::encode_public_key(&keypair.public(), format);
+
+            match output {
+                Some(path) => {
+                    std::fs::write(&path, &encoded)?;
+                    eprintln!(
+                        "Wrote public key of {} to {}",
+                        keypair.public().to_peer_id(),
+                        path.display()
+                    );
+                }
+                None => {
+                    let mut stdout = std::io::stdout();
+                    stdout.write_all(&encoded)?;
+                    if format != PublicKeyFormat::Raw {
+                        writeln!(stdout)?;
+                    }
+                    stdout.flush()?;
+                }
+            }
+        }
+
+        IdentityCommands::Rotate { data_dir } => {
+            let data_dir = resolve_data_dir(loader, data_dir)?;
+            std::fs::create_dir_all(&data_dir)?;
+            let old_peer_id = identity::identity_path(&data_dir)
+                .exists()
+                .then(|| identity::load_or_create(&data_dir))
+                .transpose()?
+                .map(|keypair| keypair.public().to_peer_id());
+
+            let keypair = identity::rotate(&data_dir)?;
+
+            if let Some(old_peer_id) = old_peer_id {
+                println!("Old Peer ID: {old_peer_id}");
+            }
+            println!("New Peer ID: {}", keypair.public().to_peer_id());
+            println!("Other nodes must whitelist the new Peer ID before they accept this node.");
         }
     }
 
-    anyhow::bail!("Unable to parse public key from file: {}", path);
+    Ok(())
 }
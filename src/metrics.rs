@@ -0,0 +1,275 @@
+use anyhow::{Context, Result};
+use prometheus_client::encoding::EncodeLabelSet;
+use prometheus_client::metrics::counter::Counter;
+use prometheus_client::metrics::family::Family;
+use prometheus_client::metrics::gauge::Gauge;
+use prometheus_client::registry::Registry;
+use serde::{Deserialize, Serialize};
+use std::sync::Arc;
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::{TcpListener, TcpStream};
+
+const CONTENT_TYPE: &str = "application/openmetrics-text; version=1.0.0; charset=utf-8";
+
+/// Largest request head read from a scraper
+const MAX_REQUEST_SIZE: usize = 8 * 1024;
+
+/// Settings for the Prometheus endpoint (`[metrics]` section)
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct MetricsConfig {
+    pub enabled: bool,
+    /// Address serving `GET /metrics`, keep it on a local or private interface
+    pub listen_addr: String,
+}
+
+impl Default for MetricsConfig {
+    fn default() -> Self {
+        Self {
+            enabled: false,
+            listen_addr: "127.0.0.1:9090".to_string(),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Hash, PartialEq, Eq, EncodeLabelSet)]
+pub struct ReasonLabels {
+    pub reason: &'static str,
+}
+
+#[derive(Debug, Clone, Hash, PartialEq, Eq, EncodeLabelSet)]
+pub struct OperationLabels {
+    pub op: &'static str,
+}
+
+#[derive(Debug, Clone, Hash, PartialEq, Eq, EncodeLabelSet)]
+pub struct NamespaceLabels {
+    pub namespace: String,
+}
+
+/// Counters and gauges of a node. Clones share the same values, so each
+/// component keeps its own handle. Values are recorded whether or not the
+/// endpoint is enabled.
+#[derive(Debug, Clone, Default)]
+pub struct Metrics {
+    /// Messages refused by `RateLimiter`, by `rate` or `burst` limit
+    pub rate_limited: Family<ReasonLabels, Counter>,
+    /// Peers refused by `AccessControl`
+    pub access_denied: Family<ReasonLabels, Counter>,
+    /// Gossipsub messages dropped before being applied
+    pub gossip_rejected: Family<ReasonLabels, Counter>,
+    /// Network events whose processing failed, by `gossip`, `reconcile` or
+    /// `chunks`. The node logs them and keeps running.
+    pub event_errors: Family<ReasonLabels, Counter>,
+    pub connections_accepted: Counter,
+    pub connections_rejected: Family<ReasonLabels, Counter>,
+    pub active_connections: Gauge,
+    /// Peers in the gossipsub mesh of any subscribed topic
+    pub mesh_peers: Gauge,
+    /// Writes applied to the store, stale writes are not counted
+    pub store_writes: Family<OperationLabels, Counter>,
+    /// Live (not deleted) keys per namespace
+    pub store_keys: Family<NamespaceLabels, Gauge>,
+}
+
+impl Metrics {
+    pub fn register(&self, registry: &mut Registry) {
+        registry.register(
+            "rate_limited",
+            "Messages rejected by the per-peer rate limit",
+            self.rate_limited.clone(),
+        );
+        registry.register(
+            "access_denied",
+            "Peers rejected by the blocklist or whitelist",
+            self.access_denied.clone(),
+        );
+        registry.register(
+            "gossip_rejected",
+            "Gossipsub messages dropped before being applied",
+            self.gossip_rejected.clone(),
+        );
+        registry.register(
+            "event_errors",
+            "Network events whose processing failed",
+            self.event_errors.clone(),
+        );
+        registry.register(
+            "connections_accepted",
+            "Connections that passed access control",
+            self.connections_accepted.clone(),
+        );
+        registry.register(
+            "connections_rejected",
+            "Connections closed by access control",
+            self.connections_rejected.clone(),
+        );
+        registry.register(
+            "active_connections",
+            "Currently accepted connections",
+            self.active_connections.clone(),
+        );
+        registry.register(
+            "mesh_peers",
+            "Peers in the gossipsub mesh",
+            self.mesh_peers.clone(),
+        );
+        registry.register(
+            "store_writes",
+            "Writes applied to the key-value store",
+            self.store_writes.clone(),
+        );
+        registry.register(
+            "store_keys",
+            "Live keys in the key-value store",
+            self.store_keys.clone(),
+        );
+    }
+
+    pub fn reject_gossip(&self, reason: &'static str) {
+        self.gossip_rejected
+            .get_or_create(&ReasonLabels { reason })
+            .inc();
+    }
+
+    pub fn event_failed(&self, reason: &'static str) {
+        self.event_errors
+            .get_or_create(&ReasonLabels { reason })
+            .inc();
+    }
+
+    /// Registry with every metric under the `p2p_sync` prefix
+    pub fn registry(&self) -> Registry {
+        let mut registry = Registry::with_prefix("p2p_sync");
+        self.register(&mut registry);
+        registry
+    }
+}
+
+/// Metrics in the Prometheus (OpenMetrics) text format
+pub fn encode(registry: &Registry) -> Result<String> {
+    let mut body = String::new();
+    prometheus_client::encoding::text::encode(&mut body, registry)?;
+    Ok(body)
+}
+
+/// Serve `GET /metrics` on `config.listen_addr`
+pub async fn spawn_server(
+    config: &MetricsConfig,
+    metrics: &Metrics,
+) -> Result<tokio::task::JoinHandle<()>> {
+    let listener = TcpListener::bind(&config.listen_addr)
+        .await
+        .with_context(|| format!("Failed to bind metrics endpoint to {}", config.listen_addr))?;
+    tracing::info!(
+        "Metrics endpoint listening on http://{}/metrics",
+        listener.local_addr()?
+    );
+
+    let registry = Arc::new(metrics.registry());
+    Ok(tokio::spawn(async move {
+        loop {
+            match listener.accept().await {
+                Ok((stream, _)) => {
+                    let registry = registry.clone();
+                    tokio::spawn(async move {
+                        if let Err(e) = serve_connection(stream, &registry).await {
+                            tracing::debug!("Metrics request failed: {}", e);
+                        }
+                    });
+                }
+                Err(e) => tracing::warn!("Failed to accept metrics connection: {}", e),
+            }
+        }
+    }))
+}
+
+// スクレイパー向けの最小限の HTTP/1.1 応答（1 リクエストごとに接続を閉じる）
+async fn serve_connection(mut stream: TcpStream, registry: &Registry) -> Result<()> {
+    let mut request = Vec::new();
+    let mut buf = [0u8; 1024];
+    while !request.windows(4).any(|w| w == b"\r\n\r\n") {
+        let n = stream.read(&mut buf).await?;
+        if n == 0 {
+            return Ok(());
+        }
+        request.extend_from_slice(&buf[..n]);
+        if request.len() > MAX_REQUEST_SIZE {
+            anyhow::bail!("Request head too large");
+        }
+    }
+
+    let request_line = String::from_utf8_lossy(&request);
+    let mut parts = request_line.split_whitespace();
+    let (status, content_type, body) = match (parts.next(), parts.next()) {
+        (Some("GET"), Some("/metrics")) => ("200 OK", CONTENT_TYPE, encode(registry)?),
+        (Some("GET"), Some(_)) => ("404 Not Found", "text/plain", "Not Found\n".to_string()),
+        _ => (
+            "405 Method Not Allowed",
+            "text/plain",
+            "Method Not Allowed\n".to_string(),
+        ),
+    };
+
+    let response = format!(
+        "HTTP/1.1 {status}\r\nContent-Type: {content_type}\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{body}",
+        body.len()
+    );
+    stream.write_all(response.as_bytes()).await?;
+    stream.shutdown().await?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_encode() {
+        let metrics = Metrics::default();
+        metrics.reject_gossip("invalid_signature");
+        metrics.reject_gossip("invalid_signature");
+        metrics.event_failed("reconcile");
+        metrics.mesh_peers.set(3);
+        metrics
+            .store_keys
+            .get_or_create(&NamespaceLabels {
+                namespace: "default".to_string(),
+            })
+            .set(5);
+
+        let body = encode(&metrics.registry()).unwrap();
+        assert!(body.contains("p2p_sync_gossip_rejected_total{reason=\"invalid_signature\"} 2"));
+        assert!(body.contains("p2p_sync_event_errors_total{reason=\"reconcile\"} 1"));
+        assert!(body.contains("p2p_sync_mesh_peers 3"));
+        assert!(body.contains("p2p_sync_store_keys{namespace=\"default\"} 5"));
+        assert!(body.ends_with("# EOF\n"));
+    }
+
+    #[tokio::test]
+    async fn test_http_endpoint() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let metrics = Metrics::default();
+        metrics.connections_accepted.inc();
+        let registry = metrics.registry();
+
+        let server = tokio::spawn(async move {
+            let (stream, _) = listener.accept().await.unwrap();
+            serve_connection(stream, &registry).await.unwrap();
+        });
+
+        let mut client = TcpStream::connect(addr).await.unwrap();
+        client
+            .write_all(b"GET /metrics HTTP/1.1\r\nHost: localhost\r\n\r\n")
+            .await
+            .unwrap();
+        let mut response = String::new();
+        client.read_to_string(&mut response).await.unwrap();
+        server.await.unwrap();
+
+        assert!(response.starts_with("HTTP/1.1 200 OK\r\n"));
+        assert!(response.contains(CONTENT_TYPE));
+        assert!(response.contains("p2p_sync_connections_accepted_total 1"));
+    }
+}
@@ -0,0 +1,211 @@
+use anyhow::{bail, Result};
+use libp2p::gossipsub::IdentTopic;
+use libp2p::PeerId;
+use serde::{Deserialize, Serialize};
+use std::collections::{BTreeMap, HashSet};
+
+use crate::control::RequestError;
+
+/// Namespace used when none is given. Its topic is the original `p2p-sync`
+/// topic, so nodes without namespaces keep syncing with each other.
+pub const DEFAULT_NAMESPACE: &str = "default";
+
+const TOPIC_NAME: &str = "p2p-sync";
+
+const MAX_NAME_LENGTH: usize = 64;
+
+/// Access lists of one namespace, loaded from `[namespaces.<name>]` in
+/// config.toml. Peers must also pass the whitelist.
+#[derive(Debug, Clone, PartialEq, Default, Serialize, Deserialize)]
+#[serde(default)]
+pub struct NamespaceConfig {
+    /// PeerIds that may receive this namespace's data, `None` allows every
+    /// whitelisted peer
+    pub readers: Option<HashSet<String>>,
+    /// PeerIds whose changes to this namespace are accepted, `None` allows
+    /// every whitelisted peer
+    pub writers: Option<HashSet<String>>,
+    /// Encrypt gossiped changes with a group key shared by the readers.
+    /// Writers must be readers too to obtain the key.
+    pub encrypt: bool,
+}
+
+impl NamespaceConfig {
+    pub fn can_read(&self, peer_id: &PeerId) -> bool {
+        allows(&self.readers, peer_id)
+    }
+
+    pub fn can_write(&self, peer_id: &PeerId) -> bool {
+        allows(&self.writers, peer_id)
+    }
+}
+
+fn allows(list: &Option<HashSet<String>>, peer_id: &PeerId) -> bool {
+    list.as_ref()
+        .map_or(true, |list| list.contains(&peer_id.to_string()))
+}
+
+/// The namespaces this node takes part in. Data of other namespaces is
+/// neither stored nor relayed.
+#[derive(Debug, Clone)]
+pub struct Namespaces {
+    configs: BTreeMap<String, NamespaceConfig>,
+}
+
+/// Only the default namespace
+impl Default for Namespaces {
+    fn default() -> Self {
+        let mut configs = BTreeMap::new();
+        configs.insert(DEFAULT_NAMESPACE.to_string(), NamespaceConfig::default());
+        Self { configs }
+    }
+}
+
+impl Namespaces {
+    /// The configured namespaces plus the default namespace, which is open to
+    /// every whitelisted peer unless it is configured too
+    pub fn new(configs: &BTreeMap<String, NamespaceConfig>) -> Result<Self> {
+        for name in configs.keys() {
+            validate_name(name)?;
+        }
+
+        let mut configs = configs.clone();
+        configs.entry(DEFAULT_NAMESPACE.to_string()).or_default();
+
+        Ok(Self { configs })
+    }
+
+    pub fn names(&self) -> impl Iterator<Item = &str> {
+        self.configs.keys().map(String::as_str)
+    }
+
+    pub fn get(&self, namespace: &str) -> Result<&NamespaceConfig> {
+        match self.configs.get(namespace) {
+            Some(config) => Ok(config),
+            None => Err(RequestError::not_found(format!("Unknown namespace: {namespace}")).into()),
+        }
+    }
+
+    /// Whether `peer_id` may receive data of `namespace`
+    pub fn can_read(&self, namespace: &str, peer_id: &PeerId) -> bool {
+        self.configs
+            .get(namespace)
+            .is_some_and(|config| config.can_read(peer_id))
+    }
+
+    /// Whether changes to `namespace` made by `peer_id` are accepted
+    pub fn can_write(&self, namespace: &str, peer_id: &PeerId) -> bool {
+        self.configs
+            .get(namespace)
+            .is_some_and(|config| config.can_write(peer_id))
+    }
+
+    /// Whether changes to `namespace` are gossiped encrypted
+    pub fn is_encrypted(&self, namespace: &str) -> bool {
+        self.configs
+            .get(namespace)
+            .is_some_and(|config| config.encrypt)
+    }
+
+    /// Names of the encrypted namespaces
+    pub fn encrypted(&self) -> impl Iterator<Item = &str> {
+        self.names().filter(|name| self.is_encrypted(name))
+    }
+
+    /// Like `can_write` for the origin recorded in an entry's timestamp,
+    /// which may predate PeerIds being recorded
+    pub fn can_write_origin(&self, namespace: &str, origin: &str) -> bool {
+        self.configs.get(namespace).is_some_and(|config| {
+            config
+                .writers
+                .as_ref()
+                .map_or(true, |writers| writers.contains(origin))
+        })
+    }
+}
+
+/// Gossipsub topic carrying the changes of `namespace`
+pub fn topic(namespace: &str) -> IdentTopic {
+    if namespace == DEFAULT_NAMESPACE {
+        IdentTopic::new(TOPIC_NAME)
+    } else {
+        IdentTopic::new(format!("{TOPIC_NAME}/{namespace}"))
+    }
+}
+
+/// Namespace names are used in topic names, so only ASCII letters, digits,
+/// `-` and `_` are allowed
+pub fn validate_name(name: &str) -> Result<()> {
+    if name.is_empty() || name.len() > MAX_NAME_LENGTH {
+        bail!("Namespace name must be 1 to {MAX_NAME_LENGTH} characters: {name:?}");
+    }
+    if !name
+        .bytes()
+        .all(|b| b.is_ascii_alphanumeric() || b == b'-' || b == b'_')
+    {
+        bail!("Namespace name may only contain letters, digits, '-' and '_': {name:?}");
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_default_namespace_is_always_present() {
+        let namespaces = Namespaces::new(&BTreeMap::new()).unwrap();
+        assert_eq!(namespaces.names().collect::<Vec<_>>(), vec!["default"]);
+        assert!(namespaces.can_write(DEFAULT_NAMESPACE, &PeerId::random()));
+        assert!(namespaces.get("secrets").is_err());
+    }
+
+    #[test]
+    fn test_access_lists() {
+        let reader = PeerId::random();
+        let writer = PeerId::random();
+        let other = PeerId::random();
+
+        let mut configs = BTreeMap::new();
+        configs.insert(
+            "secrets".to_string(),
+            NamespaceConfig {
+                readers: Some([reader.to_string(), writer.to_string()].into()),
+                writers: Some([writer.to_string()].into()),
+                encrypt: true,
+            },
+        );
+        let namespaces = Namespaces::new(&configs).unwrap();
+
+        assert!(namespaces.can_read("secrets", &reader));
+        assert!(!namespaces.can_write("secrets", &reader));
+        assert!(namespaces.can_write("secrets", &writer));
+        assert!(!namespaces.can_read("secrets", &other));
+
+        assert_eq!(namespaces.encrypted().collect::<Vec<_>>(), vec!["secrets"]);
+        assert!(namespaces.can_write_origin("secrets", &writer.to_string()));
+        assert!(!namespaces.can_write_origin("secrets", &reader.to_string()));
+
+        // 参加していないネームスペースは誰にも許可しない
+        assert!(!namespaces.can_read("unknown", &reader));
+    }
+
+    #[test]
+    fn test_topics() {
+        assert_eq!(topic(DEFAULT_NAMESPACE).to_string(), "p2p-sync");
+        assert_eq!(topic("config").to_string(), "p2p-sync/config");
+        assert_ne!(topic("config").hash(), topic("secrets").hash());
+    }
+
+    #[test]
+    fn test_invalid_names_are_rejected() {
+        assert!(validate_name("team-a_1").is_ok());
+        assert!(validate_name("").is_err());
+        assert!(validate_name("a/b").is_err());
+        assert!(validate_name(&"a".repeat(65)).is_err());
+
+        let mut configs = BTreeMap::new();
+        configs.insert("bad name".to_string(), NamespaceConfig::default());
+        assert!(Namespaces::new(&configs).is_err());
+    }
+}
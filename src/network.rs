@@ -1,11 +1,133 @@
-use libp2p::{gossipsub, identify, kad, mdns, swarm::NetworkBehaviour};
+use anyhow::{anyhow, Result};
+use libp2p::gossipsub::{PeerScoreParams, PeerScoreThresholds, TopicHash, TopicScoreParams};
+use libp2p::{allow_block_list, gossipsub, identify, mdns, swarm::NetworkBehaviour};
+use serde::{Deserialize, Serialize};
+use std::time::Duration;
+
+use crate::{anti_entropy, chunks, discovery};
 
 #[derive(NetworkBehaviour)]
 pub struct P2PSyncBehaviour {
+    /// Denies connections to and from `security.blocked_peers`
+    pub blocked: allow_block_list::Behaviour<allow_block_list::BlockedPeers>,
     pub gossipsub: gossipsub::Behaviour,
     pub mdns: mdns::tokio::Behaviour,
-    pub kad: kad::Behaviour<kad::store::MemoryStore>,
+    pub kad: discovery::Kademlia,
     pub identify: identify::Behaviour,
+    pub reconcile: anti_entropy::Behaviour,
+    pub chunks: chunks::Behaviour,
+}
+
+/// Gossipsub peer scoring (`[peer_scoring]` section). Messages rejected by
+/// validation lower the score of the peer that sent them, peers below zero are
+/// pruned from the mesh and peers below `graylist_threshold` are ignored.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct PeerScoringConfig {
+    pub enabled: bool,
+    /// Below this no gossip is exchanged with the peer
+    pub gossip_threshold: f64,
+    /// Below this our own messages are not published to the peer
+    pub publish_threshold: f64,
+    /// Below this every message from the peer is ignored
+    pub graylist_threshold: f64,
+    /// Above this peer exchange from the peer is accepted
+    pub accept_px_threshold: f64,
+    /// Median mesh score below which better peers are grafted
+    pub opportunistic_graft_threshold: f64,
+    /// Weight of each namespace topic in the score
+    pub topic_weight: f64,
+    /// Applied to the square of the number of rejected messages, must be negative
+    pub invalid_message_weight: f64,
+    /// Fraction of the rejected message count kept on each score refresh
+    /// (about once a second)
+    pub invalid_message_decay: f64,
+    /// Reward for being first to deliver a message
+    pub first_message_weight: f64,
+    pub first_message_cap: f64,
+    /// Reward per second spent in the mesh
+    pub time_in_mesh_weight: f64,
+    /// Applied to the square of protocol violations (e.g. bad GRAFTs), must be negative
+    pub behaviour_penalty_weight: f64,
+    /// Penalty for more than `ip_colocation_factor_threshold` peers on one IP
+    pub ip_colocation_factor_weight: f64,
+    pub ip_colocation_factor_threshold: f64,
+}
+
+impl Default for PeerScoringConfig {
+    fn default() -> Self {
+        let thresholds = PeerScoreThresholds::default();
+        Self {
+            enabled: true,
+            gossip_threshold: thresholds.gossip_threshold,
+            publish_threshold: thresholds.publish_threshold,
+            graylist_threshold: thresholds.graylist_threshold,
+            accept_px_threshold: thresholds.accept_px_threshold,
+            opportunistic_graft_threshold: thresholds.opportunistic_graft_threshold,
+            topic_weight: 1.0,
+            invalid_message_weight: -10.0,
+            invalid_message_decay: 0.9,
+            first_message_weight: 1.0,
+            first_message_cap: 20.0,
+            time_in_mesh_weight: 0.01,
+            behaviour_penalty_weight: -10.0,
+            ip_colocation_factor_weight: -5.0,
+            ip_colocation_factor_threshold: 10.0,
+        }
+    }
+}
+
+impl PeerScoringConfig {
+    pub fn thresholds(&self) -> PeerScoreThresholds {
+        PeerScoreThresholds {
+            gossip_threshold: self.gossip_threshold,
+            publish_threshold: self.publish_threshold,
+            graylist_threshold: self.graylist_threshold,
+            accept_px_threshold: self.accept_px_threshold,
+            opportunistic_graft_threshold: self.opportunistic_graft_threshold,
+        }
+    }
+
+    pub fn topic_params(&self) -> TopicScoreParams {
+        TopicScoreParams {
+            topic_weight: self.topic_weight,
+            time_in_mesh_weight: self.time_in_mesh_weight,
+            time_in_mesh_quantum: Duration::from_secs(1),
+            time_in_mesh_cap: 3600.0,
+            first_message_deliveries_weight: self.first_message_weight,
+            first_message_deliveries_decay: 0.9,
+            first_message_deliveries_cap: self.first_message_cap,
+            // 書き込みの少ないトピックで正直なピアが罰せられないよう、配送数の下限は見ない
+            mesh_message_deliveries_weight: 0.0,
+            mesh_failure_penalty_weight: 0.0,
+            invalid_message_deliveries_weight: self.invalid_message_weight,
+            invalid_message_deliveries_decay: self.invalid_message_decay,
+            ..Default::default()
+        }
+    }
+
+    pub fn params(&self, topics: &[TopicHash]) -> PeerScoreParams {
+        PeerScoreParams {
+            topics: topics
+                .iter()
+                .map(|topic| (topic.clone(), self.topic_params()))
+                .collect(),
+            behaviour_penalty_weight: self.behaviour_penalty_weight,
+            ip_colocation_factor_weight: self.ip_colocation_factor_weight,
+            ip_colocation_factor_threshold: self.ip_colocation_factor_threshold,
+            ..Default::default()
+        }
+    }
+
+    /// Enable peer scoring on `gossipsub` for `topics` if configured
+    pub fn apply(&self, gossipsub: &mut gossipsub::Behaviour, topics: &[TopicHash]) -> Result<()> {
+        if !self.enabled {
+            return Ok(());
+        }
+        gossipsub
+            .with_peer_score(self.params(topics), self.thresholds())
+            .map_err(|e| anyhow!("Invalid [peer_scoring] config: {e}"))
+    }
 }
 
 #[cfg(test)]
@@ -14,7 +136,7 @@ mod tests {
     use libp2p::{
         gossipsub::{Config as GossipsubConfig, MessageAuthenticity},
         identify::Config as IdentifyConfig,
-        kad::{store::MemoryStore, Config as KadConfig},
+        kad::{self, store::MemoryStore, Config as KadConfig},
         mdns, PeerId,
     };
 
@@ -46,10 +168,13 @@ mod tests {
         let identify = identify::Behaviour::new(identify_config);
 
         P2PSyncBehaviour {
+            blocked: Default::default(),
             gossipsub,
             mdns,
             kad,
             identify,
+            reconcile: anti_entropy::new_behaviour(),
+            chunks: chunks::new_behaviour(),
         }
     }
 
@@ -76,6 +201,9 @@ mod tests {
         assert!(matches!(behaviour.mdns, _));
         assert!(matches!(behaviour.kad, _));
         assert!(matches!(behaviour.identify, _));
+        assert!(matches!(behaviour.reconcile, _));
+        assert!(matches!(behaviour.chunks, _));
+        assert!(behaviour.blocked.blocked_peers().is_empty());
     }
 
     #[tokio::test]
@@ -131,4 +259,43 @@ mod tests {
         // Note: We can't directly verify this without a running swarm,
         // but the operation should not panic
     }
+
+    #[tokio::test]
+    async fn test_peer_scoring_config() {
+        let keypair = libp2p::identity::Keypair::generate_ed25519();
+        let peer_id = PeerId::from(keypair.public());
+        let topics = vec![gossipsub::IdentTopic::new("p2p-sync").hash()];
+
+        let mut behaviour = create_test_behaviour(peer_id, &keypair);
+        PeerScoringConfig::default()
+            .apply(&mut behaviour.gossipsub, &topics)
+            .unwrap();
+        assert_eq!(behaviour.gossipsub.peer_score(&PeerId::random()), Some(0.0));
+
+        let mut behaviour = create_test_behaviour(peer_id, &keypair);
+        let disabled = PeerScoringConfig {
+            enabled: false,
+            ..Default::default()
+        };
+        disabled.apply(&mut behaviour.gossipsub, &topics).unwrap();
+        assert_eq!(behaviour.gossipsub.peer_score(&PeerId::random()), None);
+
+        // 無効なパラメータは起動時にエラーにする
+        let mut behaviour = create_test_behaviour(peer_id, &keypair);
+        let rewarding_invalid = PeerScoringConfig {
+            invalid_message_weight: 1.0,
+            ..Default::default()
+        };
+        assert!(rewarding_invalid
+            .apply(&mut behaviour.gossipsub, &topics)
+            .is_err());
+        let graylist_above_publish = PeerScoringConfig {
+            graylist_threshold: -10.0,
+            publish_threshold: -50.0,
+            ..Default::default()
+        };
+        assert!(graylist_above_publish
+            .apply(&mut behaviour.gossipsub, &topics)
+            .is_err());
+    }
 }
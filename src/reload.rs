@@ -0,0 +1,278 @@
+use anyhow::{Context, Result};
+use libp2p::PeerId;
+use notify::{RecursiveMode, Watcher};
+use std::collections::HashSet;
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+use tokio::sync::mpsc;
+
+use crate::config::Config;
+
+/// Time to wait for further events after a change, so that an editor's save
+/// or a `whitelist` command is applied once
+const SETTLE_DELAY: Duration = Duration::from_millis(300);
+
+#[cfg(unix)]
+type Hangup = tokio::signal::unix::Signal;
+#[cfg(not(unix))]
+type Hangup = ();
+
+/// Parts of the node to reload
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct Reload {
+    pub config: bool,
+    pub whitelist: bool,
+}
+
+impl Reload {
+    pub const ALL: Reload = Reload {
+        config: true,
+        whitelist: true,
+    };
+}
+
+/// Watches the config file and whitelist.db for changes made outside the
+/// node, and SIGHUP on Unix
+pub struct ReloadWatcher {
+    config_path: PathBuf,
+    whitelist_path: PathBuf,
+    _watcher: notify::RecommendedWatcher,
+    events: mpsc::UnboundedReceiver<notify::Result<notify::Event>>,
+    hangup: Hangup,
+}
+
+impl ReloadWatcher {
+    pub fn new(config_path: &Path, whitelist_path: &Path) -> Result<Self> {
+        let config_path = absolute(config_path)?;
+        let whitelist_path = absolute(whitelist_path)?;
+
+        let (tx, events) = mpsc::unbounded_channel();
+        let mut watcher = notify::recommended_watcher(move |event| {
+            let _ = tx.send(event);
+        })?;
+
+        // エディタは一時ファイルの rename で保存することがあるため、ファイルを含むディレクトリを監視する
+        let mut dirs: Vec<&Path> = [&config_path, &whitelist_path]
+            .into_iter()
+            .filter_map(|path| path.parent())
+            .collect();
+        dirs.dedup();
+        for dir in dirs {
+            watcher
+                .watch(dir, RecursiveMode::NonRecursive)
+                .with_context(|| format!("Failed to watch {}", dir.display()))?;
+        }
+
+        Ok(Self {
+            config_path,
+            whitelist_path,
+            _watcher: watcher,
+            events,
+            #[cfg(unix)]
+            hangup: tokio::signal::unix::signal(tokio::signal::unix::SignalKind::hangup())?,
+            #[cfg(not(unix))]
+            hangup: (),
+        })
+    }
+
+    /// Wait until a watched file changes or SIGHUP is received
+    pub async fn next(&mut self) -> Reload {
+        loop {
+            let mut reload = Reload::default();
+            tokio::select! {
+                Some(event) = self.events.recv() => self.collect(event, &mut reload),
+                _ = hangup(&mut self.hangup) => {
+                    tracing::info!("Received SIGHUP");
+                    return Reload::ALL;
+                }
+            }
+            if reload == Reload::default() {
+                continue;
+            }
+
+            tokio::time::sleep(SETTLE_DELAY).await;
+            while let Ok(event) = self.events.try_recv() {
+                self.collect(event, &mut reload);
+            }
+            return reload;
+        }
+    }
+
+    fn collect(&self, event: notify::Result<notify::Event>, reload: &mut Reload) {
+        let event = match event {
+            Ok(event) if !event.kind.is_access() => event,
+            Ok(_) => return,
+            Err(e) => {
+                tracing::warn!("Config watcher error: {}", e);
+                return;
+            }
+        };
+
+        for path in &event.paths {
+            if *path == self.config_path {
+                reload.config = true;
+            }
+            // whitelist.db-journal などの付随ファイルの変更も書き込みとして扱う
+            if is_same_or_companion(path, &self.whitelist_path) {
+                reload.whitelist = true;
+            }
+        }
+    }
+}
+
+#[cfg(unix)]
+async fn hangup(signal: &mut Hangup) {
+    if signal.recv().await.is_none() {
+        std::future::pending::<()>().await;
+    }
+}
+
+#[cfg(not(unix))]
+async fn hangup(_signal: &mut Hangup) {
+    std::future::pending::<()>().await
+}
+
+// 通知されるパスと比べられるよう、親ディレクトリを正規化する
+fn absolute(path: &Path) -> Result<PathBuf> {
+    let file_name = path
+        .file_name()
+        .with_context(|| format!("Not a file: {}", path.display()))?;
+    let parent = match path.parent() {
+        Some(parent) if !parent.as_os_str().is_empty() => parent,
+        _ => Path::new("."),
+    };
+    let parent = std::fs::canonicalize(parent)
+        .with_context(|| format!("Failed to resolve {}", parent.display()))?;
+    Ok(parent.join(file_name))
+}
+
+fn is_same_or_companion(path: &Path, target: &Path) -> bool {
+    match (path.file_name(), target.file_name()) {
+        (Some(name), Some(target_name)) => {
+            path.parent() == target.parent()
+                && name
+                    .to_string_lossy()
+                    .starts_with(&*target_name.to_string_lossy())
+        }
+        _ => false,
+    }
+}
+
+/// How a reloaded config differs from the running one
+#[derive(Debug, Default, PartialEq)]
+pub struct ConfigChanges {
+    /// Whether `[security]` changed; it is applied without a restart
+    pub security: bool,
+    /// Peers added to `blocked_peers`
+    pub blocked: Vec<PeerId>,
+    /// Peers removed from `blocked_peers`
+    pub unblocked: Vec<PeerId>,
+    /// Changed settings that are only read at startup
+    pub restart_required: Vec<&'static str>,
+}
+
+impl ConfigChanges {
+    pub fn new(old: &Config, new: &Config) -> Self {
+        let old_blocked: HashSet<_> = old.security.blocked_peer_ids().into_iter().collect();
+        let new_blocked: HashSet<_> = new.security.blocked_peer_ids().into_iter().collect();
+
+        let restart_required = [
+            ("port", old.port != new.port),
+            ("data_dir", old.data_dir != new.data_dir),
+            (
+                "bootstrap_peers",
+                old.bootstrap_peers != new.bootstrap_peers,
+            ),
+            // gossipsub の max_transmit_size は起動時に決まる
+            (
+                "security.max_message_size",
+                old.security.max_message_size != new.security.max_message_size,
+            ),
+            ("storage", old.storage != new.storage),
+            ("http", old.http != new.http),
+            (
+                "key_distribution",
+                old.key_distribution != new.key_distribution,
+            ),
+            ("discovery", old.discovery != new.discovery),
+            ("metrics", old.metrics != new.metrics),
+            ("peer_scoring", old.peer_scoring != new.peer_scoring),
+            ("trust", old.trust != new.trust),
+            ("namespaces", old.namespaces != new.namespaces),
+        ]
+        .into_iter()
+        .filter_map(|(section, changed)| changed.then_some(section))
+        .collect();
+
+        let mut blocked: Vec<_> = new_blocked.difference(&old_blocked).copied().collect();
+        let mut unblocked: Vec<_> = old_blocked.difference(&new_blocked).copied().collect();
+        blocked.sort();
+        unblocked.sort();
+
+        Self {
+            security: old.security != new.security,
+            blocked,
+            unblocked,
+            restart_required,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tempfile::tempdir;
+
+    #[test]
+    fn test_config_changes() {
+        let old_peer = PeerId::random();
+        let new_peer = PeerId::random();
+
+        let mut old = Config::default();
+        old.security.blocked_peers.insert(old_peer.to_string());
+
+        let mut new = old.clone();
+        assert_eq!(ConfigChanges::new(&old, &new), ConfigChanges::default());
+
+        new.security.blocked_peers = [new_peer.to_string()].into_iter().collect();
+        new.security.rate_limit_per_minute = 120;
+        new.port = 4002;
+        new.metrics.enabled = true;
+
+        let changes = ConfigChanges::new(&old, &new);
+        assert!(changes.security);
+        assert_eq!(changes.blocked, vec![new_peer]);
+        assert_eq!(changes.unblocked, vec![old_peer]);
+        assert_eq!(changes.restart_required, vec!["port", "metrics"]);
+    }
+
+    #[tokio::test]
+    async fn test_watch_files() {
+        let dir = tempdir().unwrap();
+        let config_path = dir.path().join("config.toml");
+        let whitelist_path = dir.path().join("whitelist.db");
+        std::fs::write(&config_path, "port = 4001\n").unwrap();
+
+        let mut watcher = ReloadWatcher::new(&config_path, &whitelist_path).unwrap();
+
+        // 関係のないファイルは無視される
+        std::fs::write(dir.path().join("sync.db"), b"x").unwrap();
+        std::fs::write(&config_path, "port = 4002\n").unwrap();
+        let reload = tokio::time::timeout(Duration::from_secs(5), watcher.next())
+            .await
+            .unwrap();
+        assert_eq!(
+            reload,
+            Reload {
+                config: true,
+                whitelist: false
+            }
+        );
+
+        std::fs::write(dir.path().join("whitelist.db-journal"), b"x").unwrap();
+        let reload = tokio::time::timeout(Duration::from_secs(5), watcher.next())
+            .await
+            .unwrap();
+        assert!(reload.whitelist);
+    }
+}
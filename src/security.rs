@@ -2,17 +2,27 @@ use anyhow::{bail, Result};
 use libp2p::PeerId;
 use serde::{Deserialize, Serialize};
 use std::collections::{HashMap, HashSet};
+use std::fmt;
 use std::net::IpAddr;
 use std::sync::Arc;
 use std::time::{Duration, Instant};
 use tokio::sync::RwLock;
 
+use crate::chunks::ChunkManifest;
+use crate::file_sync;
+use crate::metrics::{Metrics, ReasonLabels};
+use crate::storage::Value;
+use crate::sync::SyncMessage;
 use crate::whitelist::PeerWhitelist;
 
 type RequestMap = Arc<RwLock<HashMap<PeerId, Vec<Instant>>>>;
 type ConnectionMap = Arc<RwLock<HashMap<IpAddr, usize>>>;
 
-#[derive(Debug, Clone, Serialize, Deserialize)]
+/// Room for the signature, source and sequence number gossipsub wraps
+/// around each payload
+const GOSSIPSUB_ENVELOPE_SIZE: usize = 1024;
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
 pub struct SecurityConfig {
     // レート制限設定
     pub rate_limit_per_minute: u32,
@@ -22,6 +32,9 @@ pub struct SecurityConfig {
     pub max_message_size: usize,
     pub max_key_length: usize,
     pub max_value_length: usize,
+    /// 大きなバイナリ値（チャンク分割される）の最大サイズ
+    #[serde(default = "default_max_object_size")]
+    pub max_object_size: usize,
 
     // 接続制限
     pub max_connections_per_ip: usize,
@@ -30,6 +43,11 @@ pub struct SecurityConfig {
     // ブロックリスト
     pub blocked_peers: HashSet<String>,
     pub allowed_peers: Option<HashSet<String>>,
+
+    /// Reject gossip messages whose signature cannot be verified, because
+    /// the signer's key is neither stored nor embedded in its PeerId
+    #[serde(default = "default_strict_signatures")]
+    pub strict_signatures: bool,
 }
 
 impl Default for SecurityConfig {
@@ -40,28 +58,213 @@ impl Default for SecurityConfig {
             max_message_size: 1024 * 1024, // 1MB
             max_key_length: 256,
             max_value_length: 1024 * 64, // 64KB
+            max_object_size: default_max_object_size(),
             max_connections_per_ip: 10,
             connection_timeout: Duration::from_secs(30),
             blocked_peers: HashSet::new(),
             allowed_peers: None,
+            strict_signatures: default_strict_signatures(),
+        }
+    }
+}
+
+fn default_max_object_size() -> usize {
+    256 * 1024 * 1024 // 256MB
+}
+
+fn default_strict_signatures() -> bool {
+    true
+}
+
+/// Why a message or value was refused by the limits in `SecurityConfig`
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Rejection {
+    MessageTooLarge { size: usize, max: usize },
+    EmptyKey,
+    KeyTooLong { length: usize, max: usize },
+    InvalidKeyCharacters,
+    UnsafeKeyPath,
+    ValueTooLong { length: usize, max: usize },
+    ObjectTooLarge { size: u64, max: usize },
+    InvalidManifest(String),
+}
+
+impl fmt::Display for Rejection {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Rejection::MessageTooLarge { size, max } => {
+                write!(f, "Message too large: {size} > {max} bytes")
+            }
+            Rejection::EmptyKey => write!(f, "Key cannot be empty"),
+            Rejection::KeyTooLong { length, max } => write!(f, "Key too long: {length} > {max}"),
+            Rejection::InvalidKeyCharacters => {
+                write!(f, "Key contains invalid control characters")
+            }
+            Rejection::UnsafeKeyPath => {
+                write!(f, "Key contains potentially unsafe path characters")
+            }
+            Rejection::ValueTooLong { length, max } => {
+                write!(f, "Value too long: {length} > {max}")
+            }
+            Rejection::ObjectTooLarge { size, max } => write!(f, "Value too large: {size} > {max}"),
+            Rejection::InvalidManifest(reason) => write!(f, "Invalid chunk manifest: {reason}"),
         }
     }
 }
 
+impl std::error::Error for Rejection {}
+
+impl SecurityConfig {
+    /// Gossipsub frame limit, large enough for a `max_message_size` payload
+    pub fn gossipsub_max_transmit_size(&self) -> usize {
+        self.max_message_size + GOSSIPSUB_ENVELOPE_SIZE
+    }
+
+    /// Size check for serialized messages, both received and published
+    pub fn check_message_size(&self, size: usize) -> Result<(), Rejection> {
+        if size > self.max_message_size {
+            return Err(Rejection::MessageTooLarge {
+                size,
+                max: self.max_message_size,
+            });
+        }
+        Ok(())
+    }
+
+    /// `blocked_peers` as PeerIds for the swarm's block list. Entries that are
+    /// not valid PeerIds are skipped with a warning.
+    pub fn blocked_peer_ids(&self) -> Vec<PeerId> {
+        self.blocked_peers
+            .iter()
+            .filter_map(|peer| match peer.parse() {
+                Ok(peer_id) => Some(peer_id),
+                Err(e) => {
+                    tracing::warn!("Ignoring invalid blocked peer {}: {}", peer, e);
+                    None
+                }
+            })
+            .collect()
+    }
+
+    pub fn check_key(&self, key: &str) -> Result<(), Rejection> {
+        validate_key(key, self.max_key_length)
+    }
+
+    /// Size check for values of any type. Text and inline bytes must fit in
+    /// `max_value_length`, chunked values in `max_object_size`.
+    pub fn check_value(&self, value: &Value) -> Result<(), Rejection> {
+        match value {
+            Value::Text(text) => validate_value(text, self.max_value_length),
+            Value::Bytes(data) => check_length(data.len(), self.max_value_length),
+            Value::Chunked(manifest) => self.check_manifest(manifest),
+        }
+    }
+
+    /// Binary data before it is chunked for storage
+    pub fn check_object_size(&self, size: usize) -> Result<(), Rejection> {
+        if size > self.max_object_size {
+            return Err(Rejection::ObjectTooLarge {
+                size: size as u64,
+                max: self.max_object_size,
+            });
+        }
+        Ok(())
+    }
+
+    /// Key and value checks for a sync message received from a peer
+    pub fn check_sync_message(&self, message: &SyncMessage) -> Result<(), Rejection> {
+        match message {
+            SyncMessage::Put { key, value, .. } => {
+                self.check_key(key)?;
+                validate_value(value, self.max_value_length)?;
+                self.check_file_manifest(key, value)
+            }
+            SyncMessage::PutBytes { key, value, .. } => {
+                self.check_key(key)?;
+                check_length(value.len(), self.max_value_length)
+            }
+            SyncMessage::PutChunked { key, manifest, .. } => {
+                self.check_key(key)?;
+                self.check_manifest(manifest)
+            }
+            SyncMessage::Delete { key, .. } => self.check_key(key),
+        }
+    }
+
+    /// Key and value checks for an entry received during reconciliation
+    pub fn check_entry(&self, key: &str, value: Option<&Value>) -> Result<(), Rejection> {
+        self.check_key(key)?;
+        match value {
+            Some(Value::Text(text)) => {
+                validate_value(text, self.max_value_length)?;
+                self.check_file_manifest(key, text)
+            }
+            Some(value) => self.check_value(value),
+            None => Ok(()),
+        }
+    }
+
+    /// Values of directory sync keys (`file:<path>`) are chunk manifests
+    fn check_file_manifest(&self, key: &str, value: &str) -> Result<(), Rejection> {
+        if !key.starts_with(file_sync::KEY_PREFIX) {
+            return Ok(());
+        }
+        let manifest = ChunkManifest::from_json(value)
+            .map_err(|e| Rejection::InvalidManifest(e.to_string()))?;
+        self.check_manifest(&manifest)
+    }
+
+    fn check_manifest(&self, manifest: &ChunkManifest) -> Result<(), Rejection> {
+        if manifest.size > self.max_object_size as u64 {
+            return Err(Rejection::ObjectTooLarge {
+                size: manifest.size,
+                max: self.max_object_size,
+            });
+        }
+        manifest
+            .validate(self.max_object_size)
+            .map_err(|e| Rejection::InvalidManifest(e.to_string()))
+    }
+}
+
 pub struct RateLimiter {
     requests: RequestMap,
-    config: SecurityConfig,
+    config: RwLock<SecurityConfig>,
+    metrics: Metrics,
 }
 
 impl RateLimiter {
     pub fn new(config: SecurityConfig) -> Self {
         Self {
             requests: Arc::new(RwLock::new(HashMap::new())),
-            config,
+            config: RwLock::new(config),
+            metrics: Metrics::default(),
         }
     }
 
+    /// Apply new limits to the following messages
+    pub async fn set_config(&self, config: SecurityConfig) {
+        *self.config.write().await = config;
+    }
+
+    /// Count rejections in `metrics`
+    pub fn with_metrics(mut self, metrics: Metrics) -> Self {
+        self.metrics = metrics;
+        self
+    }
+
+    fn reject(&self, reason: &'static str) {
+        self.metrics
+            .rate_limited
+            .get_or_create(&ReasonLabels { reason })
+            .inc();
+    }
+
     pub async fn check_rate_limit(&self, peer_id: &PeerId) -> Result<()> {
+        let (per_minute, burst) = {
+            let config = self.config.read().await;
+            (config.rate_limit_per_minute, config.rate_limit_burst)
+        };
         let now = Instant::now();
         let minute_ago = now - Duration::from_secs(60);
 
@@ -72,7 +275,8 @@ impl RateLimiter {
         peer_requests.retain(|&instant| instant > minute_ago);
 
         // レート制限チェック
-        if peer_requests.len() >= self.config.rate_limit_per_minute as usize {
+        if peer_requests.len() >= per_minute as usize {
+            self.reject("rate");
             bail!("Rate limit exceeded for peer: {}", peer_id);
         }
 
@@ -82,7 +286,8 @@ impl RateLimiter {
             .filter(|&&instant| instant > now - Duration::from_secs(1))
             .count();
 
-        if recent_requests >= self.config.rate_limit_burst as usize {
+        if recent_requests >= burst as usize {
+            self.reject("burst");
             bail!("Burst limit exceeded for peer: {}", peer_id);
         }
 
@@ -92,45 +297,80 @@ impl RateLimiter {
 }
 
 pub struct AccessControl {
-    config: SecurityConfig,
+    config: RwLock<SecurityConfig>,
     connections_per_ip: ConnectionMap,
     whitelist: Option<Arc<PeerWhitelist>>,
+    metrics: Metrics,
 }
 
 impl AccessControl {
     pub fn new(config: SecurityConfig) -> Self {
         Self {
-            config,
+            config: RwLock::new(config),
             connections_per_ip: Arc::new(RwLock::new(HashMap::new())),
             whitelist: None,
+            metrics: Metrics::default(),
         }
     }
 
     pub fn with_whitelist(config: SecurityConfig, whitelist: Arc<PeerWhitelist>) -> Self {
         Self {
-            config,
+            config: RwLock::new(config),
             connections_per_ip: Arc::new(RwLock::new(HashMap::new())),
             whitelist: Some(whitelist),
+            metrics: Metrics::default(),
         }
     }
 
+    /// Count denied peers in `metrics`
+    pub fn with_metrics(mut self, metrics: Metrics) -> Self {
+        self.metrics = metrics;
+        self
+    }
+
+    /// Apply a new blocklist and limits to the following checks. Connected
+    /// peers are not re-checked here.
+    pub async fn set_config(&self, config: SecurityConfig) {
+        *self.config.write().await = config;
+    }
+
+    fn deny(&self, reason: &'static str) {
+        self.metrics
+            .access_denied
+            .get_or_create(&ReasonLabels { reason })
+            .inc();
+    }
+
     pub async fn check_peer_allowed(&self, peer_id: &PeerId) -> Result<()> {
         let peer_str = peer_id.to_string();
+        let (blocked, allowed) = {
+            let config = self.config.read().await;
+            (
+                config.blocked_peers.contains(&peer_str),
+                config
+                    .allowed_peers
+                    .as_ref()
+                    .map(|allowed| allowed.contains(&peer_str)),
+            )
+        };
 
         // ブロックリストチェック
-        if self.config.blocked_peers.contains(&peer_str) {
+        if blocked {
+            self.deny("blocked");
             bail!("Peer is blocked: {}", peer_id);
         }
 
-        // データベースベースのホワイトリストチェック（設定されている場合）
+        // データベースベースのホワイトリストチェック（設定されている場合、推薦による信頼も含む）
         if let Some(whitelist) = &self.whitelist {
-            if !whitelist.is_whitelisted(peer_id).await? {
+            if !whitelist.is_trusted_by_chain(peer_id).await? {
+                self.deny("not_whitelisted");
                 bail!("Peer not in whitelist: {}", peer_id);
             }
         }
         // 設定ベースのホワイトリストチェック（後方互換性のため）
-        else if let Some(allowed) = &self.config.allowed_peers {
-            if !allowed.contains(&peer_str) {
+        else if let Some(allowed) = allowed {
+            if !allowed {
+                self.deny("not_allowed");
                 bail!("Peer not in allowed list: {}", peer_id);
             }
         }
@@ -139,10 +379,11 @@ impl AccessControl {
     }
 
     pub async fn check_connection_limit(&self, ip: &IpAddr) -> Result<()> {
+        let max_connections = self.config.read().await.max_connections_per_ip;
         let mut connections = self.connections_per_ip.write().await;
         let count = connections.entry(*ip).or_insert(0);
 
-        if *count >= self.config.max_connections_per_ip {
+        if *count >= max_connections {
             bail!("Connection limit exceeded for IP: {}", ip);
         }
 
@@ -161,13 +402,16 @@ impl AccessControl {
     }
 }
 
-pub fn validate_key(key: &str, max_length: usize) -> Result<()> {
+pub fn validate_key(key: &str, max_length: usize) -> Result<(), Rejection> {
     if key.is_empty() {
-        bail!("Key cannot be empty");
+        return Err(Rejection::EmptyKey);
     }
 
     if key.len() > max_length {
-        bail!("Key too long: {} > {}", key.len(), max_length);
+        return Err(Rejection::KeyTooLong {
+            length: key.len(),
+            max: max_length,
+        });
     }
 
     // 制御文字のチェック
@@ -175,29 +419,25 @@ pub fn validate_key(key: &str, max_length: usize) -> Result<()> {
         .chars()
         .any(|c| c.is_control() && c != '\t' && c != '\n')
     {
-        bail!("Key contains invalid control characters");
+        return Err(Rejection::InvalidKeyCharacters);
     }
 
     // パストラバーサル攻撃の防止
     if key.contains("..") || key.contains("//") || key.starts_with('/') {
-        bail!("Key contains potentially unsafe path characters");
+        return Err(Rejection::UnsafeKeyPath);
     }
 
     Ok(())
 }
 
-pub fn validate_value(value: &str, max_length: usize) -> Result<()> {
-    if value.len() > max_length {
-        bail!("Value too long: {} > {}", value.len(), max_length);
+pub fn validate_value(value: &str, max_length: usize) -> Result<(), Rejection> {
+    check_length(value.len(), max_length)
+}
+
+fn check_length(length: usize, max: usize) -> Result<(), Rejection> {
+    if length > max {
+        return Err(Rejection::ValueTooLong { length, max });
     }
 
     Ok(())
 }
-
-pub fn sanitize_input(input: &str) -> String {
-    input
-        .chars()
-        .filter(|&c| !c.is_control() || c == '\t' || c == '\n')
-        .take(1024) // 最大1024文字
-        .collect()
-}
@@ -1,131 +1,1168 @@
-use anyhow::Result;
+use anyhow::{bail, Result};
 use chrono::{DateTime, Utc};
-use rusqlite::{params, Connection, OptionalExtension};
+use libp2p::identity::Keypair;
+use rusqlite::types::Value as SqlValue;
+use rusqlite::{params, Connection, OptionalExtension, Row};
+use serde::{Deserialize, Serialize};
+use std::collections::{BTreeSet, HashSet};
+use std::fmt;
 use std::path::Path;
 
+use crate::at_rest::{self, Codec, Secret};
+use crate::chunks::{self, ChunkManifest, CHUNK_SIZE};
+use crate::crypto;
+use crate::hlc::{HlcTimestamp, HybridClock};
+use crate::metrics::{Metrics, NamespaceLabels, OperationLabels};
+use crate::watch::{ChangeEvent, ChangeFeed};
+
 type KeyValueList = Vec<(String, String)>;
+type NamespacedManifests = Vec<(String, ChunkManifest)>;
+
+const KV_STORE_COLUMNS: &str = "
+    namespace TEXT NOT NULL DEFAULT 'default',
+    key TEXT NOT NULL,
+    value BLOB NOT NULL,
+    timestamp INTEGER NOT NULL,
+    deleted INTEGER NOT NULL DEFAULT 0,
+    logical INTEGER NOT NULL DEFAULT 0,
+    origin TEXT NOT NULL DEFAULT '',
+    kind INTEGER NOT NULL DEFAULT 0,
+    signature BLOB,
+    PRIMARY KEY (namespace, key)";
+
+// v2 までの kv_store の列（移行時にコピーする）
+const KV_STORE_V2_COLUMNS: &str = "key, value, timestamp, deleted, logical, origin, kind";
+
+// kv_store.kind
+const KIND_TEXT: i64 = 0;
+const KIND_BYTES: i64 = 1;
+const KIND_CHUNKED: i64 = 2;
+
+/// Storage settings loaded from the `[storage]` section of config.toml
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct StorageConfig {
+    /// How long delete tombstones are kept before they are purged. Must be
+    /// longer than any peer is expected to stay offline, otherwise a deleted
+    /// key can come back from that peer.
+    pub tombstone_retention_hours: u64,
+    /// How long superseded versions are kept in the key history, 0 keeps
+    /// them forever. Point-in-time reads work back to this window.
+    pub history_retention_hours: u64,
+    /// Encrypt values in sync.db and whitelist.db at rest with a key derived
+    /// from the `P2P_SYNC_PASSPHRASE` passphrase or `data_dir/storage.key`
+    pub encrypt: bool,
+    /// How far ahead of local time a peer's timestamp may be. Writes
+    /// stamped further in the future are rejected.
+    pub max_clock_skew_secs: u64,
+}
+
+impl Default for StorageConfig {
+    fn default() -> Self {
+        Self {
+            tombstone_retention_hours: 24 * 30, // 30 days
+            history_retention_hours: 24 * 30,
+            encrypt: false,
+            max_clock_skew_secs: crate::hlc::DEFAULT_MAX_SKEW_MS / 1000,
+        }
+    }
+}
+
+/// A stored value
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub enum Value {
+    Text(String),
+    Bytes(#[serde(with = "serde_bytes")] Vec<u8>),
+    /// Large binary value. Its chunks are kept in `value_chunks` and fetched
+    /// from peers when they are missing.
+    Chunked(ChunkManifest),
+}
+
+impl Value {
+    /// Size of the value in bytes
+    pub fn size(&self) -> u64 {
+        match self {
+            Value::Text(text) => text.len() as u64,
+            Value::Bytes(data) => data.len() as u64,
+            Value::Chunked(manifest) => manifest.size,
+        }
+    }
+
+    fn kind(&self) -> i64 {
+        match self {
+            Value::Text(_) => KIND_TEXT,
+            Value::Bytes(_) => KIND_BYTES,
+            Value::Chunked(_) => KIND_CHUNKED,
+        }
+    }
+
+    fn to_sql(&self) -> Result<SqlValue> {
+        Ok(match self {
+            Value::Text(text) => SqlValue::Text(text.clone()),
+            Value::Bytes(data) => SqlValue::Blob(data.clone()),
+            Value::Chunked(manifest) => SqlValue::Text(manifest.to_json()?),
+        })
+    }
+}
+
+impl From<&str> for Value {
+    fn from(text: &str) -> Self {
+        Value::Text(text.to_string())
+    }
+}
+
+impl From<&String> for Value {
+    fn from(text: &String) -> Self {
+        Value::Text(text.clone())
+    }
+}
+
+impl From<String> for Value {
+    fn from(text: String) -> Self {
+        Value::Text(text)
+    }
+}
+
+/// Text values are shown as is, binary values as their size
+impl fmt::Display for Value {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Value::Text(text) => f.write_str(text),
+            _ => write!(f, "<binary, {} bytes>", self.size()),
+        }
+    }
+}
+
+/// A stored row together with the timestamp used for last-writer-wins
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Entry {
+    pub key: String,
+    /// `None` marks a deleted key
+    pub value: Option<Value>,
+    pub timestamp: HlcTimestamp,
+    /// The author's signature over the row (see `crypto::sign_entry`).
+    /// `None` for rows stored before changes were signed.
+    #[serde(default)]
+    pub signature: Option<Vec<u8>>,
+}
+
+/// How the sequence number of a received change compares with the highest one
+/// seen from its origin
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Sequence {
+    /// The next number, or the first one seen from the origin
+    InOrder,
+    /// `missed` changes between the previous high-water mark and this one
+    /// never arrived
+    Gap { missed: u64 },
+    /// At or below the high-water mark: a duplicate or a replayed change
+    Stale { high_water: u64 },
+}
+
+/// A version of a key kept in the history
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Version {
+    pub version: u64,
+    /// `None` marks a delete
+    pub value: Option<Value>,
+    /// When and by which peer (`timestamp.origin`) the version was written
+    pub timestamp: HlcTimestamp,
+}
 
 pub struct Storage {
     conn: Connection,
+    codec: Codec,
+    clock: HybridClock,
+    max_skew_ms: u64,
+    keypair: Option<Keypair>,
+    changes: ChangeFeed,
+    metrics: Metrics,
 }
 
 impl Storage {
+    /// Open an unencrypted store, same as `open(path, None)`
+    #[allow(dead_code)] // バイナリでは使わないがライブラリの API として残す
     pub fn new<P: AsRef<Path>>(path: P) -> Result<Self> {
+        Self::open(path, None)
+    }
+
+    /// Open the store, encrypting values at rest with a key derived from
+    /// `secret`. Fails if the store is encrypted and `secret` is missing or wrong.
+    pub fn open<P: AsRef<Path>>(path: P, secret: Option<&Secret>) -> Result<Self> {
+        let path = path.as_ref();
+        let conn = Self::connect(path)?;
+        let codec = at_rest::open(&conn, path, secret, Self::reencode)?;
+
+        Ok(Self {
+            conn,
+            codec,
+            clock: HybridClock::new(""),
+            max_skew_ms: crate::hlc::DEFAULT_MAX_SKEW_MS,
+            keypair: None,
+            changes: ChangeFeed::new(),
+            metrics: Metrics::default(),
+        })
+    }
+
+    /// Re-encrypt the store at `path` with `new`, or decrypt it when `new` is `None`
+    pub fn rekey<P: AsRef<Path>>(
+        path: P,
+        old: Option<&Secret>,
+        new: Option<&Secret>,
+    ) -> Result<()> {
+        let path = path.as_ref();
+        let conn = Self::connect(path)?;
+        at_rest::rekey(&conn, path, old, new, Self::reencode)
+    }
+
+    fn connect(path: &Path) -> Result<Connection> {
         let conn = Connection::open(path)?;
 
         conn.execute(
-            "CREATE TABLE IF NOT EXISTS kv_store (
-                key TEXT PRIMARY KEY,
-                value TEXT NOT NULL,
-                timestamp INTEGER NOT NULL
+            &format!("CREATE TABLE IF NOT EXISTS kv_store ({KV_STORE_COLUMNS})"),
+            [],
+        )?;
+
+        // 大きな値のチャンク（SHA-256 でアドレス指定）
+        conn.execute(
+            "CREATE TABLE IF NOT EXISTS value_chunks (
+                hash TEXT PRIMARY KEY,
+                data BLOB NOT NULL
+            )",
+            [],
+        )?;
+
+        // キーごとの値の履歴（追記のみ、保持期間を過ぎた古い版は削除する）
+        conn.execute(
+            "CREATE TABLE IF NOT EXISTS kv_history (
+                version INTEGER PRIMARY KEY AUTOINCREMENT,
+                namespace TEXT NOT NULL,
+                key TEXT NOT NULL,
+                value BLOB NOT NULL,
+                timestamp INTEGER NOT NULL,
+                deleted INTEGER NOT NULL,
+                logical INTEGER NOT NULL,
+                origin TEXT NOT NULL,
+                kind INTEGER NOT NULL
+            )",
+            [],
+        )?;
+        conn.execute(
+            "CREATE INDEX IF NOT EXISTS kv_history_key ON kv_history (namespace, key, timestamp)",
+            [],
+        )?;
+
+        // このノードが配信した変更の通し番号（ネームスペースごと）
+        conn.execute(
+            "CREATE TABLE IF NOT EXISTS sequences (
+                namespace TEXT PRIMARY KEY,
+                seq INTEGER NOT NULL
+            )",
+            [],
+        )?;
+
+        // 受信した変更の通し番号の最大値（送信元とネームスペースごと）
+        conn.execute(
+            "CREATE TABLE IF NOT EXISTS origin_sequences (
+                origin TEXT NOT NULL,
+                namespace TEXT NOT NULL,
+                high_water INTEGER NOT NULL,
+                updated_at TEXT NOT NULL,
+                PRIMARY KEY (origin, namespace)
             )",
             [],
         )?;
 
-        Ok(Self { conn })
+        // Add new columns if they don't exist (for existing databases)
+        let _ = conn.execute(
+            "ALTER TABLE kv_store ADD COLUMN deleted INTEGER NOT NULL DEFAULT 0",
+            [],
+        );
+        let _ = conn.execute(
+            "ALTER TABLE kv_store ADD COLUMN logical INTEGER NOT NULL DEFAULT 0",
+            [],
+        );
+        let _ = conn.execute(
+            "ALTER TABLE kv_store ADD COLUMN origin TEXT NOT NULL DEFAULT ''",
+            [],
+        );
+        let _ = conn.execute(
+            "ALTER TABLE kv_store ADD COLUMN kind INTEGER NOT NULL DEFAULT 0",
+            [],
+        );
+        let _ = conn.execute("ALTER TABLE kv_store ADD COLUMN signature BLOB", []);
+
+        // v1: timestamp は秒からミリ秒（HLC の物理時刻）に変更
+        let version: i64 = conn.query_row("PRAGMA user_version", [], |row| row.get(0))?;
+        if version < 1 {
+            conn.execute("UPDATE kv_store SET timestamp = timestamp * 1000", [])?;
+            conn.execute("PRAGMA user_version = 1", [])?;
+        }
+
+        // v2: value を TEXT から BLOB に変更（SQLite は列の型を変更できないので作り直す）
+        if version < 2 {
+            conn.execute_batch(&format!(
+                "BEGIN;
+                CREATE TABLE kv_store_v2 ({KV_STORE_COLUMNS});
+                INSERT INTO kv_store_v2 ({KV_STORE_V2_COLUMNS}) SELECT {KV_STORE_V2_COLUMNS} FROM kv_store;
+                DROP TABLE kv_store;
+                ALTER TABLE kv_store_v2 RENAME TO kv_store;
+                PRAGMA user_version = 2;
+                COMMIT;"
+            ))?;
+        }
+
+        // v3: ネームスペースごとにキーを分ける（既存のキーは default ネームスペースに入る）
+        if version < 3 {
+            conn.execute_batch(&format!(
+                "BEGIN;
+                CREATE TABLE kv_store_v3 ({KV_STORE_COLUMNS});
+                INSERT INTO kv_store_v3 ({KV_STORE_V2_COLUMNS}) SELECT {KV_STORE_V2_COLUMNS} FROM kv_store;
+                DROP TABLE kv_store;
+                ALTER TABLE kv_store_v3 RENAME TO kv_store;
+                PRAGMA user_version = 3;
+                COMMIT;"
+            ))?;
+        }
+
+        // v4: 現在の値を履歴の最初の版にする（値は同じ AAD で暗号化されているのでそのままコピーできる）
+        if version < 4 {
+            conn.execute_batch(&format!(
+                "BEGIN;
+                INSERT INTO kv_history (namespace, {KV_STORE_V2_COLUMNS})
+                    SELECT namespace, {KV_STORE_V2_COLUMNS} FROM kv_store ORDER BY timestamp, logical;
+                PRAGMA user_version = 4;
+                COMMIT;"
+            ))?;
+        }
+
+        Ok(conn)
     }
 
-    pub fn put(&self, key: &str, value: &str) -> Result<()> {
-        self.put_with_timestamp(key, value, Utc::now())
+    fn reencode(conn: &Connection, from: &Codec, to: &Codec) -> Result<()> {
+        let rows = conn
+            .prepare("SELECT namespace, key, value FROM kv_store WHERE deleted = 0")?
+            .query_map([], |row| {
+                Ok((
+                    row.get::<_, String>(0)?,
+                    row.get::<_, String>(1)?,
+                    row.get::<_, SqlValue>(2)?,
+                ))
+            })?
+            .collect::<Result<Vec<_>, _>>()?;
+
+        for (namespace, key, value) in rows {
+            let aad = value_aad(&namespace, &key);
+            conn.execute(
+                "UPDATE kv_store SET value = ?1 WHERE namespace = ?2 AND key = ?3",
+                params![to.seal(&aad, from.open(&aad, value)?)?, namespace, key],
+            )?;
+        }
+
+        let versions = conn
+            .prepare("SELECT version, namespace, key, value FROM kv_history WHERE deleted = 0")?
+            .query_map([], |row| {
+                Ok((
+                    row.get::<_, i64>(0)?,
+                    row.get::<_, String>(1)?,
+                    row.get::<_, String>(2)?,
+                    row.get::<_, SqlValue>(3)?,
+                ))
+            })?
+            .collect::<Result<Vec<_>, _>>()?;
+
+        for (version, namespace, key, value) in versions {
+            let aad = value_aad(&namespace, &key);
+            conn.execute(
+                "UPDATE kv_history SET value = ?1 WHERE version = ?2",
+                params![to.seal(&aad, from.open(&aad, value)?)?, version],
+            )?;
+        }
+
+        at_rest::reencode_column(conn, "value_chunks", "hash", "data", from, to)
     }
 
+    /// Stamp local writes with `origin` (the local PeerId)
+    pub fn with_origin(mut self, origin: impl Into<String>) -> Self {
+        self.clock = HybridClock::new(origin).with_max_skew(self.max_skew_ms);
+        self
+    }
+
+    /// Reject writes of other peers stamped more than `secs` ahead of local time
+    pub fn with_max_clock_skew(mut self, secs: u64) -> Self {
+        self.max_skew_ms = secs.saturating_mul(1000);
+        self.clock = HybridClock::new(self.clock.origin()).with_max_skew(self.max_skew_ms);
+        self
+    }
+
+    /// Fail if `timestamp` of another peer is too far in the future to be stored
+    pub fn check_timestamp(&self, timestamp: &HlcTimestamp) -> Result<()> {
+        self.clock.check(timestamp)
+    }
+
+    /// Stamp local writes with the PeerId of `keypair` and sign them with it
+    pub fn with_keypair(mut self, keypair: Keypair) -> Self {
+        let origin = keypair.public().to_peer_id().to_string();
+        self.keypair = Some(keypair);
+        self.with_origin(origin)
+    }
+
+    /// Count writes and live keys in `metrics`
+    pub fn with_metrics(mut self, metrics: Metrics) -> Result<Self> {
+        let mut stmt = self.conn.prepare(
+            "SELECT namespace, COUNT(*) FROM kv_store WHERE deleted = 0 GROUP BY namespace",
+        )?;
+        let counts = stmt
+            .query_map([], |row| {
+                Ok((row.get::<_, String>(0)?, row.get::<_, i64>(1)?))
+            })?
+            .collect::<rusqlite::Result<Vec<_>>>()?;
+        drop(stmt);
+
+        for (namespace, count) in counts {
+            metrics
+                .store_keys
+                .get_or_create(&NamespaceLabels { namespace })
+                .set(count);
+        }
+        self.metrics = metrics;
+        Ok(self)
+    }
+
+    /// Feed of every change applied to this store
+    pub fn changes(&self) -> ChangeFeed {
+        self.changes.clone()
+    }
+
+    /// Store a local write, returning the stored entry
+    #[allow(dead_code)] // ノードは local_entry と apply_entry を使う
+    pub fn put(&self, namespace: &str, key: &str, value: impl Into<Value>) -> Result<Entry> {
+        let entry = self.local_entry(namespace, key, Some(value.into()))?;
+        self.apply_entry(namespace, &entry)?;
+        Ok(entry)
+    }
+
+    /// Store a write of another peer together with the author's `signature`
     pub fn put_with_timestamp(
         &self,
+        namespace: &str,
         key: &str,
-        value: &str,
-        timestamp: DateTime<Utc>,
+        value: impl Into<Value>,
+        timestamp: &HlcTimestamp,
+        signature: Option<&[u8]>,
     ) -> Result<()> {
-        let existing_timestamp: Option<i64> = self
+        self.write_put(namespace, key, value.into(), timestamp, signature, false)
+    }
+
+    fn write_put(
+        &self,
+        namespace: &str,
+        key: &str,
+        value: Value,
+        timestamp: &HlcTimestamp,
+        signature: Option<&[u8]>,
+        repair: bool,
+    ) -> Result<()> {
+        self.clock.check(timestamp)?;
+        self.clock.observe(timestamp);
+
+        let existing = self.existing_entry(namespace, key)?;
+        if !supersedes(&existing, timestamp, signature, repair) {
+            return Ok(());
+        }
+
+        self.conn.execute(
+            "INSERT OR REPLACE INTO kv_store (namespace, key, value, timestamp, deleted, logical, origin, kind, signature) VALUES (?1, ?2, ?3, ?4, 0, ?5, ?6, ?7, ?8)",
+            params![namespace, key, self.codec.seal(&value_aad(namespace, key), value.to_sql()?)?, sql_wall_ms(timestamp)?, timestamp.logical, timestamp.origin, value.kind(), signature],
+        )?;
+        self.record_version(namespace, key, Some(&value), timestamp)?;
+        self.record_write("put", namespace, &existing, true);
+
+        self.notify(namespace, key, existing, Some(&value), timestamp);
+
+        Ok(())
+    }
+
+    /// Prepare binary data for `put`. Data larger than `inline_limit`
+    /// is split into chunks stored locally, so that only the manifest has to
+    /// be published.
+    pub fn encode_bytes(&self, data: Vec<u8>, inline_limit: usize) -> Result<Value> {
+        if data.len() <= inline_limit {
+            return Ok(Value::Bytes(data));
+        }
+
+        let mut manifest = ChunkManifest {
+            size: data.len() as u64,
+            chunks: Vec::new(),
+        };
+        for chunk in data.chunks(CHUNK_SIZE) {
+            manifest.chunks.push(self.insert_chunk(chunk)?);
+        }
+
+        Ok(Value::Chunked(manifest))
+    }
+
+    /// Text value of a key. Fails for binary values, use `get_bytes` for those.
+    pub fn get(&self, namespace: &str, key: &str) -> Result<Option<String>> {
+        match self.get_value(namespace, key)? {
+            Some(Value::Text(text)) => Ok(Some(text)),
+            Some(value) => bail!("{key} holds a binary value ({} bytes)", value.size()),
+            None => Ok(None),
+        }
+    }
+
+    pub fn get_value(&self, namespace: &str, key: &str) -> Result<Option<Value>> {
+        let value = self
             .conn
             .query_row(
-                "SELECT timestamp FROM kv_store WHERE key = ?1",
-                params![key],
-                |row| row.get(0),
+                "SELECT value, kind FROM kv_store WHERE namespace = ?1 AND key = ?2 AND deleted = 0",
+                params![namespace, key],
+                |row| self.read_value(namespace, key, row, 0, 1),
             )
             .optional()?;
 
-        if let Some(existing) = existing_timestamp {
-            if existing > timestamp.timestamp() {
-                return Ok(());
+        Ok(value)
+    }
+
+    /// Contents of a key as bytes, assembling chunked values. Fails while
+    /// chunks of the value are still being fetched from peers.
+    pub fn get_bytes(&self, namespace: &str, key: &str) -> Result<Option<Vec<u8>>> {
+        let manifest = match self.get_value(namespace, key)? {
+            Some(Value::Text(text)) => return Ok(Some(text.into_bytes())),
+            Some(Value::Bytes(data)) => return Ok(Some(data)),
+            Some(Value::Chunked(manifest)) => manifest,
+            None => return Ok(None),
+        };
+
+        let mut data = Vec::with_capacity(manifest.size as usize);
+        for hash in &manifest.chunks {
+            match self.get_chunk(hash)? {
+                Some(chunk) => data.extend_from_slice(&chunk),
+                None => bail!("{key} is still being fetched from peers"),
             }
         }
 
+        Ok(Some(data))
+    }
+
+    /// Delete a key locally, returning the tombstone
+    #[allow(dead_code)] // ノードは local_entry と apply_entry を使う
+    pub fn delete(&self, namespace: &str, key: &str) -> Result<Entry> {
+        let entry = self.local_entry(namespace, key, None)?;
+        self.apply_entry(namespace, &entry)?;
+        Ok(entry)
+    }
+
+    /// Record a delete as a tombstone so that older puts arriving later are ignored
+    pub fn delete_with_timestamp(
+        &self,
+        namespace: &str,
+        key: &str,
+        timestamp: &HlcTimestamp,
+        signature: Option<&[u8]>,
+    ) -> Result<()> {
+        self.write_delete(namespace, key, timestamp, signature, false)
+    }
+
+    fn write_delete(
+        &self,
+        namespace: &str,
+        key: &str,
+        timestamp: &HlcTimestamp,
+        signature: Option<&[u8]>,
+        repair: bool,
+    ) -> Result<()> {
+        self.clock.check(timestamp)?;
+        self.clock.observe(timestamp);
+
+        let existing = self.existing_entry(namespace, key)?;
+        if !supersedes(&existing, timestamp, signature, repair) {
+            return Ok(());
+        }
+
         self.conn.execute(
-            "INSERT OR REPLACE INTO kv_store (key, value, timestamp) VALUES (?1, ?2, ?3)",
-            params![key, value, timestamp.timestamp()],
+            "INSERT OR REPLACE INTO kv_store (namespace, key, value, timestamp, deleted, logical, origin, kind, signature) VALUES (?1, ?2, '', ?3, 1, ?4, ?5, 0, ?6)",
+            params![namespace, key, sql_wall_ms(timestamp)?, timestamp.logical, timestamp.origin, signature],
         )?;
+        self.record_version(namespace, key, None, timestamp)?;
+        self.record_write("delete", namespace, &existing, false);
+
+        self.notify(namespace, key, existing, None, timestamp);
 
         Ok(())
     }
 
-    pub fn get(&self, key: &str) -> Result<Option<String>> {
-        let value = self
+    pub fn list(&self, namespace: &str) -> Result<KeyValueList> {
+        let mut stmt = self.conn.prepare(
+            "SELECT key, value, kind FROM kv_store WHERE namespace = ?1 AND deleted = 0 ORDER BY key",
+        )?;
+
+        // バイナリ値はサイズのみ表示する
+        let items = stmt
+            .query_map(params![namespace], |row| {
+                let key: String = row.get(0)?;
+                let value = self.read_value(namespace, &key, row, 1, 2)?;
+                Ok((key, value.to_string()))
+            })?
+            .collect::<Result<Vec<_>, _>>()?;
+
+        Ok(items)
+    }
+
+    /// All rows of a namespace including tombstones with their timestamps,
+    /// ordered by key
+    pub fn entries(&self, namespace: &str) -> Result<Vec<Entry>> {
+        let mut stmt = self.conn.prepare(
+            "SELECT key, value, timestamp, deleted, logical, origin, kind, signature FROM kv_store WHERE namespace = ?1 ORDER BY key",
+        )?;
+
+        let entries = stmt
+            .query_map(params![namespace], |row| {
+                let key: String = row.get(0)?;
+                let deleted: bool = row.get(3)?;
+                Ok(Entry {
+                    value: if deleted {
+                        None
+                    } else {
+                        Some(self.read_value(namespace, &key, row, 1, 6)?)
+                    },
+                    key,
+                    timestamp: HlcTimestamp::new(
+                        row.get::<_, i64>(2)? as u64,
+                        row.get(4)?,
+                        row.get::<_, String>(5)?,
+                    ),
+                    signature: row.get(7)?,
+                })
+            })?
+            .collect::<Result<Vec<_>, _>>()?;
+
+        Ok(entries)
+    }
+
+    /// Apply a replicated entry using the same last-writer-wins rules as live
+    /// messages, keeping its signature to serve it to other peers
+    pub fn apply_entry(&self, namespace: &str, entry: &Entry) -> Result<()> {
+        let signature = entry.signature.as_deref();
+        match &entry.value {
+            Some(value) => self.put_with_timestamp(
+                namespace,
+                &entry.key,
+                value.clone(),
+                &entry.timestamp,
+                signature,
+            ),
+            None => self.delete_with_timestamp(namespace, &entry.key, &entry.timestamp, signature),
+        }
+    }
+
+    /// `apply_entry` for an entry whose signature was verified against its
+    /// origin. It also replaces a stored copy of the same write that carries
+    /// another signature or none, e.g. one accepted before signatures were
+    /// enforced.
+    pub fn apply_verified_entry(&self, namespace: &str, entry: &Entry) -> Result<()> {
+        let signature = entry.signature.as_deref();
+        let repair = signature.is_some();
+        match &entry.value {
+            Some(value) => self.write_put(
+                namespace,
+                &entry.key,
+                value.clone(),
+                &entry.timestamp,
+                signature,
+                repair,
+            ),
+            None => self.write_delete(namespace, &entry.key, &entry.timestamp, signature, repair),
+        }
+    }
+
+    /// Whether `apply_entry` would replace the stored copy of the entry's key
+    pub fn is_newer(&self, namespace: &str, entry: &Entry) -> Result<bool> {
+        let existing = self.existing_entry(namespace, &entry.key)?;
+        Ok(supersedes(
+            &existing,
+            &entry.timestamp,
+            entry.signature.as_deref(),
+            false,
+        ))
+    }
+
+    /// Value a key had at `at`: the newest version written at or before it
+    pub fn get_value_at(
+        &self,
+        namespace: &str,
+        key: &str,
+        at: DateTime<Utc>,
+    ) -> Result<Option<Value>> {
+        let version = self
             .conn
             .query_row(
-                "SELECT value FROM kv_store WHERE key = ?1",
-                params![key],
-                |row| row.get(0),
+                "SELECT version, value, timestamp, deleted, logical, origin, kind FROM kv_history
+                 WHERE namespace = ?1 AND key = ?2 AND timestamp <= ?3
+                 ORDER BY timestamp DESC, logical DESC, origin DESC LIMIT 1",
+                params![namespace, key, at.timestamp_millis()],
+                |row| self.read_version(namespace, key, row),
             )
             .optional()?;
 
-        Ok(value)
+        Ok(version.and_then(|version| version.value))
     }
 
-    pub fn delete_with_timestamp(&self, key: &str, timestamp: DateTime<Utc>) -> Result<()> {
-        let existing_timestamp: Option<i64> = self
+    /// Versions of a key still in the history, oldest first
+    pub fn history(&self, namespace: &str, key: &str) -> Result<Vec<Version>> {
+        let mut stmt = self.conn.prepare(
+            "SELECT version, value, timestamp, deleted, logical, origin, kind FROM kv_history
+             WHERE namespace = ?1 AND key = ?2
+             ORDER BY timestamp, logical, origin",
+        )?;
+
+        let versions = stmt
+            .query_map(params![namespace, key], |row| {
+                self.read_version(namespace, key, row)
+            })?
+            .collect::<Result<Vec<_>, _>>()?;
+
+        Ok(versions)
+    }
+
+    /// One version of a key, for rolling back to it
+    pub fn version(&self, namespace: &str, key: &str, version: u64) -> Result<Option<Version>> {
+        let version = self
             .conn
             .query_row(
-                "SELECT timestamp FROM kv_store WHERE key = ?1",
-                params![key],
-                |row| row.get(0),
+                "SELECT version, value, timestamp, deleted, logical, origin, kind FROM kv_history
+                 WHERE namespace = ?1 AND key = ?2 AND version = ?3",
+                params![namespace, key, version as i64],
+                |row| self.read_version(namespace, key, row),
+            )
+            .optional()?;
+
+        Ok(version)
+    }
+
+    /// Remove versions written before `cutoff` that a later version also
+    /// written before `cutoff` replaced, so that reads at or after `cutoff`
+    /// still see the same value. Returns how many were purged.
+    pub fn purge_history(&self, cutoff: DateTime<Utc>) -> Result<usize> {
+        let cutoff = cutoff.timestamp_millis();
+        let mut purged = self.conn.execute(
+            "DELETE FROM kv_history AS old WHERE timestamp < ?1 AND EXISTS (
+                SELECT 1 FROM kv_history AS new
+                WHERE new.namespace = old.namespace AND new.key = old.key AND new.timestamp < ?1
+                AND (new.timestamp, new.logical, new.origin) > (old.timestamp, old.logical, old.origin)
+            )",
+            params![cutoff],
+        )?;
+
+        // 保持期間より前に削除されたキーは、その時点以降の読み取りに影響しない
+        purged += self.conn.execute(
+            "DELETE FROM kv_history WHERE deleted = 1 AND timestamp < ?1",
+            params![cutoff],
+        )?;
+
+        Ok(purged)
+    }
+
+    /// Remove tombstones older than `cutoff`, returning how many were purged
+    pub fn purge_tombstones(&self, cutoff: DateTime<Utc>) -> Result<usize> {
+        let purged = self.conn.execute(
+            "DELETE FROM kv_store WHERE deleted = 1 AND timestamp < ?1",
+            params![cutoff.timestamp_millis()],
+        )?;
+
+        Ok(purged)
+    }
+
+    /// Next sequence number for a change this node publishes to `namespace`.
+    /// Numbers start at 1 and survive restarts.
+    pub fn next_sequence(&self, namespace: &str) -> Result<u64> {
+        let seq: i64 = self.conn.query_row(
+            "INSERT INTO sequences (namespace, seq) VALUES (?1, 1)
+             ON CONFLICT (namespace) DO UPDATE SET seq = seq + 1
+             RETURNING seq",
+            params![namespace],
+            |row| row.get(0),
+        )?;
+
+        Ok(seq as u64)
+    }
+
+    /// Check sequence number `seq` of a change `origin` published to
+    /// `namespace` and raise the high-water mark unless the change is stale
+    pub fn record_sequence(&self, namespace: &str, origin: &str, seq: u64) -> Result<Sequence> {
+        let high_water = self
+            .conn
+            .query_row(
+                "SELECT high_water FROM origin_sequences WHERE origin = ?1 AND namespace = ?2",
+                params![origin, namespace],
+                |row| row.get::<_, i64>(0),
+            )
+            .optional()?
+            .map(|high_water| high_water as u64);
+
+        let sequence = match high_water {
+            Some(high_water) if seq <= high_water => return Ok(Sequence::Stale { high_water }),
+            Some(high_water) if seq > high_water + 1 => Sequence::Gap {
+                missed: seq - high_water - 1,
+            },
+            _ => Sequence::InOrder,
+        };
+
+        self.conn.execute(
+            "INSERT INTO origin_sequences (origin, namespace, high_water, updated_at)
+             VALUES (?1, ?2, ?3, ?4)
+             ON CONFLICT (origin, namespace) DO UPDATE SET high_water = ?3, updated_at = ?4",
+            params![origin, namespace, seq as i64, Utc::now().to_rfc3339()],
+        )?;
+
+        Ok(sequence)
+    }
+
+    /// Store a chunk of a large value, returning its hash
+    pub fn insert_chunk(&self, data: &[u8]) -> Result<String> {
+        let hash = chunks::hash(data);
+        let data = self
+            .codec
+            .seal(&chunk_aad(&hash), SqlValue::Blob(data.to_vec()))?;
+        let inserted = self.conn.execute(
+            "INSERT OR IGNORE INTO value_chunks (hash, data) VALUES (?1, ?2)",
+            params![hash, data],
+        )?;
+        if inserted > 0 {
+            self.metrics
+                .store_writes
+                .get_or_create(&OperationLabels { op: "chunk" })
+                .inc();
+        }
+        Ok(hash)
+    }
+
+    pub fn get_chunk(&self, hash: &str) -> Result<Option<Vec<u8>>> {
+        let data = self
+            .conn
+            .query_row(
+                "SELECT data FROM value_chunks WHERE hash = ?1",
+                params![hash],
+                |row| row.get::<_, SqlValue>(0),
             )
             .optional()?;
 
-        if let Some(existing) = existing_timestamp {
-            if existing < timestamp.timestamp() {
-                self.conn
-                    .execute("DELETE FROM kv_store WHERE key = ?1", params![key])?;
+        match data {
+            Some(data) => match self.codec.open(&chunk_aad(hash), data)? {
+                SqlValue::Blob(data) => Ok(Some(data)),
+                _ => bail!("Chunk {hash} is not binary"),
+            },
+            None => Ok(None),
+        }
+    }
+
+    /// Chunks referenced by stored values that are not held locally
+    pub fn missing_chunks(&self) -> Result<Vec<String>> {
+        let mut missing = BTreeSet::new();
+        for (_, manifest) in self.chunked_values("kv_store")? {
+            for hash in manifest.chunks {
+                if !missing.contains(&hash) && self.get_chunk(&hash)?.is_none() {
+                    missing.insert(hash);
+                }
             }
         }
 
-        Ok(())
+        Ok(missing.into_iter().collect())
     }
 
-    pub fn list(&self) -> Result<KeyValueList> {
-        let mut stmt = self
+    /// Remove chunks that no stored value or version in the history refers
+    /// to any more, returning how many were removed
+    pub fn purge_chunks(&self) -> Result<usize> {
+        let referenced: HashSet<String> = self
+            .chunked_values("kv_store")?
+            .into_iter()
+            .chain(self.chunked_values("kv_history")?)
+            .flat_map(|(_, manifest)| manifest.chunks)
+            .collect();
+
+        let hashes = self
             .conn
-            .prepare("SELECT key, value FROM kv_store ORDER BY key")?;
+            .prepare("SELECT hash FROM value_chunks")?
+            .query_map([], |row| row.get::<_, String>(0))?
+            .collect::<Result<Vec<_>, _>>()?;
 
-        let items = stmt
-            .query_map([], |row| Ok((row.get(0)?, row.get(1)?)))?
+        let mut purged = 0;
+        for hash in hashes {
+            if !referenced.contains(&hash) {
+                purged += self
+                    .conn
+                    .execute("DELETE FROM value_chunks WHERE hash = ?1", params![hash])?;
+            }
+        }
+
+        Ok(purged)
+    }
+
+    /// Namespaces holding a value that refers to the chunk `hash`, used to
+    /// check that a peer may read a chunk before serving it
+    pub fn chunk_namespaces(&self, hash: &str) -> Result<Vec<String>> {
+        let mut namespaces = BTreeSet::new();
+        for (namespace, manifest) in self.chunked_values("kv_store")? {
+            if manifest.chunks.iter().any(|chunk| chunk == hash) {
+                namespaces.insert(namespace);
+            }
+        }
+
+        Ok(namespaces.into_iter().collect())
+    }
+
+    /// Manifests of the chunked values in `table` (`kv_store` or
+    /// `kv_history`) with their namespaces
+    fn chunked_values(&self, table: &str) -> Result<NamespacedManifests> {
+        let mut stmt = self.conn.prepare(&format!(
+            "SELECT namespace, key, value, kind FROM {table} WHERE deleted = 0 AND kind = ?1"
+        ))?;
+
+        let manifests = stmt
+            .query_map(params![KIND_CHUNKED], |row| {
+                let namespace: String = row.get(0)?;
+                let key: String = row.get(1)?;
+                let value = self.read_value(&namespace, &key, row, 2, 3)?;
+                Ok((namespace, value))
+            })?
+            .filter_map(|row| match row {
+                Ok((namespace, Value::Chunked(manifest))) => Some(Ok((namespace, manifest))),
+                Ok(_) => None,
+                Err(e) => Some(Err(e)),
+            })
             .collect::<Result<Vec<_>, _>>()?;
 
-        Ok(items)
+        Ok(manifests)
+    }
+
+    /// Stamp a local write and sign it as its author if the store has a
+    /// keypair, without storing it. Store it with `apply_entry`.
+    pub fn local_entry(&self, namespace: &str, key: &str, value: Option<Value>) -> Result<Entry> {
+        let mut entry = Entry {
+            key: key.to_string(),
+            value,
+            timestamp: self.clock.now(),
+            signature: None,
+        };
+        if let Some(keypair) = &self.keypair {
+            entry.signature = Some(crypto::sign_entry(keypair, namespace, &entry)?);
+        }
+
+        Ok(entry)
+    }
+
+    fn existing_entry(&self, namespace: &str, key: &str) -> Result<Option<Entry>> {
+        let existing = self
+            .conn
+            .query_row(
+                "SELECT value, timestamp, deleted, logical, origin, kind, signature FROM kv_store WHERE namespace = ?1 AND key = ?2",
+                params![namespace, key],
+                |row| {
+                    let deleted: bool = row.get(2)?;
+                    Ok(Entry {
+                        key: key.to_string(),
+                        value: if deleted {
+                            None
+                        } else {
+                            Some(self.read_value(namespace, key, row, 0, 5)?)
+                        },
+                        timestamp: HlcTimestamp::new(
+                            row.get::<_, i64>(1)? as u64,
+                            row.get(3)?,
+                            row.get::<_, String>(4)?,
+                        ),
+                        signature: row.get(6)?,
+                    })
+                },
+            )
+            .optional()?;
+
+        Ok(existing)
+    }
+
+    fn record_version(
+        &self,
+        namespace: &str,
+        key: &str,
+        value: Option<&Value>,
+        timestamp: &HlcTimestamp,
+    ) -> Result<()> {
+        let (stored, kind) = match value {
+            Some(value) => (
+                self.codec
+                    .seal(&value_aad(namespace, key), value.to_sql()?)?,
+                value.kind(),
+            ),
+            None => (SqlValue::Text(String::new()), KIND_TEXT),
+        };
+
+        self.conn.execute(
+            "INSERT INTO kv_history (namespace, key, value, timestamp, deleted, logical, origin, kind) VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8)",
+            params![namespace, key, stored, sql_wall_ms(timestamp)?, value.is_none(), timestamp.logical, timestamp.origin, kind],
+        )?;
+
+        Ok(())
+    }
+
+    // version, value, timestamp, deleted, logical, origin, kind の順の行
+    fn read_version(&self, namespace: &str, key: &str, row: &Row) -> rusqlite::Result<Version> {
+        let deleted: bool = row.get(3)?;
+        Ok(Version {
+            version: row.get::<_, i64>(0)? as u64,
+            value: if deleted {
+                None
+            } else {
+                Some(self.read_value(namespace, key, row, 1, 6)?)
+            },
+            timestamp: HlcTimestamp::new(
+                row.get::<_, i64>(2)? as u64,
+                row.get(4)?,
+                row.get::<_, String>(5)?,
+            ),
+        })
+    }
+
+    fn read_value(
+        &self,
+        namespace: &str,
+        key: &str,
+        row: &Row,
+        value: usize,
+        kind: usize,
+    ) -> rusqlite::Result<Value> {
+        let stored = self
+            .codec
+            .open(&value_aad(namespace, key), row.get(value)?)
+            .map_err(|e| at_rest::to_sql_error(value, e))?;
+
+        Ok(match (row.get::<_, i64>(kind)?, stored) {
+            (KIND_BYTES, SqlValue::Blob(data)) => Value::Bytes(data),
+            (KIND_CHUNKED, SqlValue::Text(json)) => {
+                let manifest =
+                    ChunkManifest::from_json(&json).map_err(|e| at_rest::to_sql_error(value, e))?;
+                Value::Chunked(manifest)
+            }
+            (_, SqlValue::Text(text)) => Value::Text(text),
+            (_, stored) => {
+                return Err(rusqlite::Error::InvalidColumnType(
+                    value,
+                    "value".to_string(),
+                    stored.data_type(),
+                ))
+            }
+        })
+    }
+
+    fn record_write(
+        &self,
+        op: &'static str,
+        namespace: &str,
+        existing: &Option<Entry>,
+        live: bool,
+    ) {
+        self.metrics
+            .store_writes
+            .get_or_create(&OperationLabels { op })
+            .inc();
+
+        let was_live = existing.as_ref().is_some_and(|entry| entry.value.is_some());
+        if was_live != live {
+            let keys = self.metrics.store_keys.get_or_create(&NamespaceLabels {
+                namespace: namespace.to_string(),
+            });
+            if live {
+                keys.inc();
+            } else {
+                keys.dec();
+            }
+        }
+    }
+
+    fn notify(
+        &self,
+        namespace: &str,
+        key: &str,
+        existing: Option<Entry>,
+        new: Option<&Value>,
+        timestamp: &HlcTimestamp,
+    ) {
+        let old = existing.and_then(|entry| entry.value);
+
+        // 存在しないキーの削除は見た目上の変化がないので通知しない
+        if old.is_none() && new.is_none() {
+            return;
+        }
+
+        self.changes.publish(ChangeEvent {
+            namespace: namespace.to_string(),
+            key: key.to_string(),
+            old: old.map(|value| value.to_string()),
+            new: new.map(Value::to_string),
+            origin_peer: timestamp.origin.clone(),
+            timestamp: timestamp.clone(),
+        });
     }
 }
+
+// 値の暗号文は行（ネームスペースとキー）に結び付ける
+// 新しい書き込みか、repair のときは同じ書き込みで署名の異なる複製なら置き換える
+fn supersedes(
+    existing: &Option<Entry>,
+    timestamp: &HlcTimestamp,
+    signature: Option<&[u8]>,
+    repair: bool,
+) -> bool {
+    match existing {
+        Some(existing) if existing.timestamp == *timestamp => {
+            repair && existing.signature.as_deref() != signature
+        }
+        Some(existing) => existing.timestamp < *timestamp,
+        None => true,
+    }
+}
+
+// SQLite の INTEGER は符号付きなので、範囲外の時刻は負にせず拒否する
+fn sql_wall_ms(timestamp: &HlcTimestamp) -> Result<i64> {
+    i64::try_from(timestamp.wall_ms)
+        .map_err(|_| anyhow::anyhow!("timestamp {} ms is out of range", timestamp.wall_ms))
+}
+
+fn value_aad(namespace: &str, key: &str) -> Vec<u8> {
+    at_rest::column_aad("kv_store", "value", &format!("{namespace}\0{key}"))
+}
+
+fn chunk_aad(hash: &str) -> Vec<u8> {
+    at_rest::column_aad("value_chunks", "data", hash)
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
+    use crate::namespace::DEFAULT_NAMESPACE as NS;
     use chrono::Utc;
 
     use tempfile::tempdir;
 
+    fn ts(time: DateTime<Utc>) -> HlcTimestamp {
+        HlcTimestamp::new(time.timestamp_millis() as u64, 0, "peer")
+    }
+
     fn create_test_storage() -> (Storage, tempfile::TempDir) {
         let temp_dir = tempdir().unwrap();
         let db_path = temp_dir.path().join("test.db");
-        let storage = Storage::new(&db_path).expect("Failed to create storage");
+        let storage = Storage::open(&db_path, None).expect("Failed to create storage");
         (storage, temp_dir)
     }
 
     #[test]
     fn test_storage_creation() {
         let (storage, _dir) = create_test_storage();
-        assert!(storage.list().is_ok());
+        assert!(storage.list(NS).is_ok());
     }
 
     #[test]
     fn test_storage_creation_invalid_path() {
-        let result = Storage::new("/invalid/path/that/does/not/exist/test.db");
+        let result = Storage::open("/invalid/path/that/does/not/exist/test.db", None);
         assert!(result.is_err());
     }
 
@@ -134,8 +1171,8 @@ mod tests {
         let (storage, _dir) = create_test_storage();
 
         // Test basic put and get
-        storage.put("key1", "value1").unwrap();
-        let value = storage.get("key1").unwrap();
+        storage.put(NS, "key1", "value1").unwrap();
+        let value = storage.get(NS, "key1").unwrap();
         assert_eq!(value, Some("value1".to_string()));
     }
 
@@ -144,8 +1181,8 @@ mod tests {
         let (storage, _dir) = create_test_storage();
 
         // Empty key should be allowed
-        storage.put("", "value").unwrap();
-        let value = storage.get("").unwrap();
+        storage.put(NS, "", "value").unwrap();
+        let value = storage.get(NS, "").unwrap();
         assert_eq!(value, Some("value".to_string()));
     }
 
@@ -154,8 +1191,8 @@ mod tests {
         let (storage, _dir) = create_test_storage();
 
         // Empty value should be allowed
-        storage.put("key", "").unwrap();
-        let value = storage.get("key").unwrap();
+        storage.put(NS, "key", "").unwrap();
+        let value = storage.get(NS, "key").unwrap();
         assert_eq!(value, Some("".to_string()));
     }
 
@@ -164,11 +1201,11 @@ mod tests {
         let (storage, _dir) = create_test_storage();
 
         // Test Unicode support
-        storage.put("키", "값").unwrap();
-        storage.put("🔑", "🎁").unwrap();
+        storage.put(NS, "키", "값").unwrap();
+        storage.put(NS, "🔑", "🎁").unwrap();
 
-        assert_eq!(storage.get("키").unwrap(), Some("값".to_string()));
-        assert_eq!(storage.get("🔑").unwrap(), Some("🎁".to_string()));
+        assert_eq!(storage.get(NS, "키").unwrap(), Some("값".to_string()));
+        assert_eq!(storage.get(NS, "🔑").unwrap(), Some("🎁".to_string()));
     }
 
     #[test]
@@ -179,8 +1216,8 @@ mod tests {
         let large_key = "k".repeat(1000);
         let large_value = "v".repeat(10000);
 
-        storage.put(&large_key, &large_value).unwrap();
-        let value = storage.get(&large_key).unwrap();
+        storage.put(NS, &large_key, &large_value).unwrap();
+        let value = storage.get(NS, &large_key).unwrap();
         assert_eq!(value, Some(large_value));
     }
 
@@ -189,10 +1226,10 @@ mod tests {
         let (storage, _dir) = create_test_storage();
 
         // Test overwriting existing key
-        storage.put("key", "value1").unwrap();
-        storage.put("key", "value2").unwrap();
+        storage.put(NS, "key", "value1").unwrap();
+        storage.put(NS, "key", "value2").unwrap();
 
-        let value = storage.get("key").unwrap();
+        let value = storage.get(NS, "key").unwrap();
         assert_eq!(value, Some("value2".to_string()));
     }
 
@@ -205,15 +1242,15 @@ mod tests {
 
         // Put with earlier timestamp
         storage
-            .put_with_timestamp("key", "old_value", early_time)
+            .put_with_timestamp(NS, "key", "old_value", &ts(early_time), None)
             .unwrap();
 
         // Put with later timestamp should overwrite
         storage
-            .put_with_timestamp("key", "new_value", late_time)
+            .put_with_timestamp(NS, "key", "new_value", &ts(late_time), None)
             .unwrap();
 
-        let value = storage.get("key").unwrap();
+        let value = storage.get(NS, "key").unwrap();
         assert_eq!(value, Some("new_value".to_string()));
     }
 
@@ -226,23 +1263,125 @@ mod tests {
 
         // Put with later timestamp first
         storage
-            .put_with_timestamp("key", "new_value", late_time)
+            .put_with_timestamp(NS, "key", "new_value", &ts(late_time), None)
             .unwrap();
 
         // Put with earlier timestamp should be ignored
         storage
-            .put_with_timestamp("key", "old_value", early_time)
+            .put_with_timestamp(NS, "key", "old_value", &ts(early_time), None)
             .unwrap();
 
-        let value = storage.get("key").unwrap();
+        let value = storage.get(NS, "key").unwrap();
         assert_eq!(value, Some("new_value".to_string()));
     }
 
+    #[test]
+    fn test_concurrent_writes_converge() {
+        let (replica_a, _dir1) = create_test_storage();
+        let (replica_b, _dir2) = create_test_storage();
+
+        // Same millisecond and counter, different origins
+        let from_a = HlcTimestamp::new(1_000, 0, "peer-a");
+        let from_b = HlcTimestamp::new(1_000, 0, "peer-b");
+
+        replica_a
+            .put_with_timestamp(NS, "key", "a", &from_a, None)
+            .unwrap();
+        replica_a
+            .put_with_timestamp(NS, "key", "b", &from_b, None)
+            .unwrap();
+
+        replica_b
+            .put_with_timestamp(NS, "key", "b", &from_b, None)
+            .unwrap();
+        replica_b
+            .put_with_timestamp(NS, "key", "a", &from_a, None)
+            .unwrap();
+
+        assert_eq!(replica_a.get(NS, "key").unwrap(), Some("b".to_string()));
+        assert_eq!(replica_b.get(NS, "key").unwrap(), Some("b".to_string()));
+    }
+
+    #[test]
+    fn test_local_write_after_remote_future_write() {
+        let (storage, _dir) = create_test_storage();
+
+        // A peer with a clock ahead of ours wrote the key
+        let future = ts(Utc::now() + chrono::Duration::seconds(30));
+        storage
+            .put_with_timestamp(NS, "key", "remote", &future, None)
+            .unwrap();
+
+        // A later local write must still win
+        let local = storage.put(NS, "key", "local").unwrap();
+        assert!(local.timestamp > future);
+        assert_eq!(storage.get(NS, "key").unwrap(), Some("local".to_string()));
+    }
+
+    #[test]
+    fn test_far_future_write_is_rejected_without_moving_clock() {
+        let (storage, _dir) = create_test_storage();
+
+        let far_future = ts(Utc::now() + chrono::Duration::days(365));
+        assert!(storage
+            .put_with_timestamp(NS, "key", "remote", &far_future, None)
+            .is_err());
+        let overflow = HlcTimestamp::new(u64::MAX, 0, "remote");
+        assert!(storage
+            .delete_with_timestamp(NS, "key", &overflow, None)
+            .is_err());
+        assert_eq!(storage.get(NS, "key").unwrap(), None);
+
+        // The local clock was not pushed forward, so local writes keep real time
+        let local = storage.put(NS, "key", "local").unwrap();
+        assert!(local.timestamp < far_future);
+        assert!(local.timestamp.wall_ms <= Utc::now().timestamp_millis() as u64);
+    }
+
+    #[test]
+    fn test_max_logical_counter_does_not_overflow() {
+        let (storage, _dir) = create_test_storage();
+
+        let remote = HlcTimestamp::new(Utc::now().timestamp_millis() as u64, u32::MAX, "remote");
+        storage
+            .put_with_timestamp(NS, "key", "remote", &remote, None)
+            .unwrap();
+
+        let local = storage.put(NS, "key", "local").unwrap();
+        assert!(local.timestamp > remote);
+        assert_eq!(storage.get(NS, "key").unwrap(), Some("local".to_string()));
+    }
+
+    #[test]
+    fn test_legacy_second_timestamps_are_migrated() {
+        let temp_dir = tempdir().unwrap();
+        let db_path = temp_dir.path().join("legacy.db");
+
+        {
+            let conn = Connection::open(&db_path).unwrap();
+            conn.execute(
+                "CREATE TABLE kv_store (key TEXT PRIMARY KEY, value TEXT NOT NULL, timestamp INTEGER NOT NULL)",
+                [],
+            )
+            .unwrap();
+            conn.execute(
+                "INSERT INTO kv_store (key, value, timestamp) VALUES ('key', 'value', 1700000000)",
+                [],
+            )
+            .unwrap();
+        }
+
+        let storage = Storage::open(&db_path, None).unwrap();
+        let entries = storage.entries(NS).unwrap();
+        assert_eq!(entries[0].timestamp.wall_ms, 1_700_000_000_000);
+        assert_eq!(storage.get(NS, "key").unwrap(), Some("value".to_string()));
+    }
+
     #[test]
     fn test_get_nonexistent_key() {
         let (storage, _dir) = create_test_storage();
 
-        let value = storage.get("nonexistent").unwrap();
+        let value = storage.get(NS, "nonexistent").unwrap();
         assert_eq!(value, None);
     }
 
@@ -255,13 +1394,15 @@ mod tests {
 
         // Put a value
         storage
-            .put_with_timestamp("key", "value", put_time)
+            .put_with_timestamp(NS, "key", "value", &ts(put_time), None)
             .unwrap();
-        assert!(storage.get("key").unwrap().is_some());
+        assert!(storage.get(NS, "key").unwrap().is_some());
 
         // Delete with later timestamp
-        storage.delete_with_timestamp("key", delete_time).unwrap();
-        assert!(storage.get("key").unwrap().is_none());
+        storage
+            .delete_with_timestamp(NS, "key", &ts(delete_time), None)
+            .unwrap();
+        assert!(storage.get(NS, "key").unwrap().is_none());
     }
 
     #[test]
@@ -273,16 +1414,16 @@ mod tests {
 
         // Put a value
         storage
-            .put_with_timestamp("key", "value", put_time)
+            .put_with_timestamp(NS, "key", "value", &ts(put_time), None)
             .unwrap();
 
         // Delete with earlier timestamp should be ignored
         storage
-            .delete_with_timestamp("key", early_delete_time)
+            .delete_with_timestamp(NS, "key", &ts(early_delete_time), None)
             .unwrap();
 
         // Value should still exist
-        assert_eq!(storage.get("key").unwrap(), Some("value".to_string()));
+        assert_eq!(storage.get(NS, "key").unwrap(), Some("value".to_string()));
     }
 
     #[test]
@@ -291,15 +1432,277 @@ mod tests {
 
         // Delete non-existent key should not error
         storage
-            .delete_with_timestamp("nonexistent", Utc::now())
+            .delete_with_timestamp(NS, "nonexistent", &ts(Utc::now()), None)
             .unwrap();
     }
 
+    #[test]
+    fn test_delete_before_put_prevents_resurrection() {
+        let (storage, _dir) = create_test_storage();
+
+        let put_time = Utc::now() - chrono::Duration::hours(1);
+        let delete_time = Utc::now();
+
+        // The delete arrives before the put it supersedes
+        storage
+            .delete_with_timestamp(NS, "key", &ts(delete_time), None)
+            .unwrap();
+        storage
+            .put_with_timestamp(NS, "key", "old_value", &ts(put_time), None)
+            .unwrap();
+
+        assert!(storage.get(NS, "key").unwrap().is_none());
+        assert!(storage.list(NS).unwrap().is_empty());
+    }
+
+    #[test]
+    fn test_put_after_tombstone() {
+        let (storage, _dir) = create_test_storage();
+
+        storage
+            .delete_with_timestamp(
+                NS,
+                "key",
+                &ts(Utc::now() - chrono::Duration::hours(1)),
+                None,
+            )
+            .unwrap();
+        storage.put(NS, "key", "value").unwrap();
+
+        assert_eq!(storage.get(NS, "key").unwrap(), Some("value".to_string()));
+    }
+
+    #[test]
+    fn test_tombstones_in_entries() {
+        let (storage, _dir) = create_test_storage();
+
+        let delete_time = Utc::now() + chrono::Duration::seconds(1);
+        storage.put(NS, "key", "value").unwrap();
+        storage
+            .delete_with_timestamp(NS, "key", &ts(delete_time), None)
+            .unwrap();
+
+        let entries = storage.entries(NS).unwrap();
+        assert_eq!(entries.len(), 1);
+        assert_eq!(entries[0].value, None);
+        assert_eq!(entries[0].timestamp, ts(delete_time));
+    }
+
+    #[test]
+    fn test_purge_tombstones() {
+        let (storage, _dir) = create_test_storage();
+
+        let old_delete = Utc::now() - chrono::Duration::days(60);
+        let recent_delete = Utc::now();
+        storage
+            .delete_with_timestamp(NS, "old", &ts(old_delete), None)
+            .unwrap();
+        storage
+            .delete_with_timestamp(NS, "recent", &ts(recent_delete), None)
+            .unwrap();
+        storage.put(NS, "live", "value").unwrap();
+
+        let purged = storage
+            .purge_tombstones(Utc::now() - chrono::Duration::days(30))
+            .unwrap();
+        assert_eq!(purged, 1);
+
+        let keys: Vec<_> = storage
+            .entries(NS)
+            .unwrap()
+            .into_iter()
+            .map(|entry| entry.key)
+            .collect();
+        assert_eq!(keys, vec!["live".to_string(), "recent".to_string()]);
+    }
+
+    #[test]
+    fn test_history() {
+        let (storage, _dir) = create_test_storage();
+        let now = Utc::now();
+        let at = |days: i64| now - chrono::Duration::days(days);
+
+        storage
+            .put_with_timestamp(NS, "key", "first", &ts(at(10)), None)
+            .unwrap();
+        storage
+            .put_with_timestamp(NS, "key", "second", &ts(at(5)), None)
+            .unwrap();
+        storage
+            .delete_with_timestamp(NS, "key", &ts(at(3)), None)
+            .unwrap();
+        storage
+            .put_with_timestamp(NS, "key", "third", &ts(at(1)), None)
+            .unwrap();
+        // 最終書き込み優先で負けた書き込みは履歴に残らない
+        storage
+            .put_with_timestamp(NS, "key", "stale", &ts(at(2)), None)
+            .unwrap();
+
+        let history = storage.history(NS, "key").unwrap();
+        let values: Vec<_> = history.iter().map(|v| v.value.clone()).collect();
+        assert_eq!(
+            values,
+            vec![
+                Some(Value::from("first")),
+                Some(Value::from("second")),
+                None,
+                Some(Value::from("third")),
+            ]
+        );
+        assert_eq!(history[1].timestamp.origin, "peer");
+
+        assert_eq!(storage.get_value_at(NS, "key", at(11)).unwrap(), None);
+        assert_eq!(
+            storage.get_value_at(NS, "key", at(7)).unwrap(),
+            Some(Value::from("first"))
+        );
+        assert_eq!(
+            storage.get_value_at(NS, "key", at(5)).unwrap(),
+            Some(Value::from("second"))
+        );
+        assert_eq!(storage.get_value_at(NS, "key", at(2)).unwrap(), None);
+        assert_eq!(
+            storage.get_value_at(NS, "key", now).unwrap(),
+            Some(Value::from("third"))
+        );
+
+        let second = storage.version(NS, "key", history[1].version).unwrap();
+        assert_eq!(second, Some(history[1].clone()));
+        assert_eq!(
+            storage.version("other", "key", history[1].version).unwrap(),
+            None
+        );
+
+        // 保持期間の境界での値は残すので、それ以降の読み取りは変わらない
+        assert_eq!(storage.purge_history(at(4)).unwrap(), 1);
+        assert_eq!(
+            storage.get_value_at(NS, "key", at(4)).unwrap(),
+            Some(Value::from("second"))
+        );
+        assert_eq!(storage.purge_history(now).unwrap(), 2);
+        let history = storage.history(NS, "key").unwrap();
+        assert_eq!(history.len(), 1);
+        assert_eq!(history[0].value, Some(Value::from("third")));
+    }
+
+    #[test]
+    fn test_history_is_seeded_from_existing_values() {
+        let temp_dir = tempdir().unwrap();
+        let db_path = temp_dir.path().join("test.db");
+
+        let storage = Storage::open(&db_path, None).unwrap();
+        storage.put(NS, "key", "value").unwrap();
+        storage
+            .conn
+            .execute_batch("DELETE FROM kv_history; PRAGMA user_version = 3;")
+            .unwrap();
+        drop(storage);
+
+        let storage = Storage::open(&db_path, None).unwrap();
+        let history = storage.history(NS, "key").unwrap();
+        assert_eq!(history.len(), 1);
+        assert_eq!(history[0].value, Some(Value::from("value")));
+    }
+
+    #[test]
+    fn test_sequences() {
+        let temp_dir = tempdir().unwrap();
+        let db_path = temp_dir.path().join("test.db");
+
+        let storage = Storage::open(&db_path, None).unwrap();
+        assert_eq!(storage.next_sequence(NS).unwrap(), 1);
+        assert_eq!(storage.next_sequence(NS).unwrap(), 2);
+        assert_eq!(storage.next_sequence("other").unwrap(), 1);
+
+        assert_eq!(
+            storage.record_sequence(NS, "peer", 5).unwrap(),
+            Sequence::InOrder
+        );
+        assert_eq!(
+            storage.record_sequence(NS, "peer", 6).unwrap(),
+            Sequence::InOrder
+        );
+        // 重複と古い番号は再送（リプレイ）として扱う
+        assert_eq!(
+            storage.record_sequence(NS, "peer", 6).unwrap(),
+            Sequence::Stale { high_water: 6 }
+        );
+        assert_eq!(
+            storage.record_sequence(NS, "peer", 2).unwrap(),
+            Sequence::Stale { high_water: 6 }
+        );
+        assert_eq!(
+            storage.record_sequence(NS, "peer", 9).unwrap(),
+            Sequence::Gap { missed: 2 }
+        );
+        // 送信元とネームスペースごとに数える
+        assert_eq!(
+            storage.record_sequence("other", "peer", 1).unwrap(),
+            Sequence::InOrder
+        );
+        assert_eq!(
+            storage.record_sequence(NS, "other", 1).unwrap(),
+            Sequence::InOrder
+        );
+        drop(storage);
+
+        // 再起動後も続きから数え、取りこぼした番号は再送として拒否される
+        let storage = Storage::open(&db_path, None).unwrap();
+        assert_eq!(storage.next_sequence(NS).unwrap(), 3);
+        assert_eq!(
+            storage.record_sequence(NS, "peer", 8).unwrap(),
+            Sequence::Stale { high_water: 9 }
+        );
+        assert_eq!(
+            storage.record_sequence(NS, "peer", 10).unwrap(),
+            Sequence::InOrder
+        );
+    }
+
+    #[test]
+    fn test_stale_sequence_from_reset_origin() {
+        let temp_dir = tempdir().unwrap();
+        let (receiver, _dir) = create_test_storage();
+
+        // 送信元が sync.db を作り直すと通し番号は 1 からやり直しになる
+        let origin = Storage::open(temp_dir.path().join("origin.db"), None)
+            .unwrap()
+            .with_origin("origin");
+        let old = origin
+            .local_entry(NS, "key", Some(Value::from("old")))
+            .unwrap();
+        receiver.record_sequence(NS, "origin", 10).unwrap();
+        receiver.apply_entry(NS, &old).unwrap();
+        drop(origin);
+        std::fs::remove_file(temp_dir.path().join("origin.db")).unwrap();
+        std::thread::sleep(std::time::Duration::from_millis(2));
+
+        let origin = Storage::open(temp_dir.path().join("origin.db"), None)
+            .unwrap()
+            .with_origin("origin");
+        let seq = origin.next_sequence(NS).unwrap();
+        let new = origin
+            .local_entry(NS, "key", Some(Value::from("new")))
+            .unwrap();
+        assert_eq!(
+            receiver.record_sequence(NS, "origin", seq).unwrap(),
+            Sequence::Stale { high_water: 10 }
+        );
+
+        // 番号が古くても LWW で新しい変更は適用し、本当の再送は捨てる
+        assert!(receiver.is_newer(NS, &new).unwrap());
+        assert!(!receiver.is_newer(NS, &old).unwrap());
+        receiver.apply_entry(NS, &new).unwrap();
+        assert_eq!(receiver.get(NS, "key").unwrap(), Some("new".to_string()));
+        assert!(!receiver.is_newer(NS, &new).unwrap());
+    }
+
     #[test]
     fn test_list_empty() {
         let (storage, _dir) = create_test_storage();
 
-        let items = storage.list().unwrap();
+        let items = storage.list(NS).unwrap();
         assert_eq!(items.len(), 0);
     }
 
@@ -307,11 +1710,11 @@ mod tests {
     fn test_list_multiple_items() {
         let (storage, _dir) = create_test_storage();
 
-        storage.put("key1", "value1").unwrap();
-        storage.put("key2", "value2").unwrap();
-        storage.put("key3", "value3").unwrap();
+        storage.put(NS, "key1", "value1").unwrap();
+        storage.put(NS, "key2", "value2").unwrap();
+        storage.put(NS, "key3", "value3").unwrap();
 
-        let items = storage.list().unwrap();
+        let items = storage.list(NS).unwrap();
         assert_eq!(items.len(), 3);
 
         // Convert to HashMap for easier testing
@@ -325,15 +1728,20 @@ mod tests {
     fn test_list_after_delete() {
         let (storage, _dir) = create_test_storage();
 
-        storage.put("key1", "value1").unwrap();
-        storage.put("key2", "value2").unwrap();
+        storage.put(NS, "key1", "value1").unwrap();
+        storage.put(NS, "key2", "value2").unwrap();
 
         // Ensure delete happens after put by adding 1 second
         storage
-            .delete_with_timestamp("key1", Utc::now() + chrono::Duration::seconds(1))
+            .delete_with_timestamp(
+                NS,
+                "key1",
+                &ts(Utc::now() + chrono::Duration::seconds(1)),
+                None,
+            )
             .unwrap();
 
-        let items = storage.list().unwrap();
+        let items = storage.list(NS).unwrap();
         assert_eq!(items.len(), 1);
         assert_eq!(items[0], ("key2".to_string(), "value2".to_string()));
     }
@@ -347,13 +1755,13 @@ mod tests {
             let key = format!("key{i}");
             let value = format!("value{i}");
 
-            storage.put(&key, &value).unwrap();
-            let retrieved = storage.get(&key).unwrap();
+            storage.put(NS, &key, &value).unwrap();
+            let retrieved = storage.get(NS, &key).unwrap();
             assert_eq!(retrieved, Some(value));
         }
 
         // Verify all items exist
-        let items = storage.list().unwrap();
+        let items = storage.list(NS).unwrap();
         assert_eq!(items.len(), 10);
     }
 
@@ -375,15 +1783,89 @@ mod tests {
         ];
 
         for key in &special_keys {
-            storage.put(key, "value").unwrap();
-            let value = storage.get(key).unwrap();
+            storage.put(NS, key, "value").unwrap();
+            let value = storage.get(NS, key).unwrap();
             assert_eq!(value, Some("value".to_string()));
         }
 
-        let items = storage.list().unwrap();
+        let items = storage.list(NS).unwrap();
         assert_eq!(items.len(), special_keys.len());
     }
 
+    #[test]
+    fn test_entries_and_apply_entry() {
+        let (source, _dir1) = create_test_storage();
+        let (replica, _dir2) = create_test_storage();
+
+        source.put(NS, "key1", "value1").unwrap();
+        source.put(NS, "key2", "value2").unwrap();
+
+        for entry in source.entries(NS).unwrap() {
+            replica.apply_entry(NS, &entry).unwrap();
+        }
+
+        assert_eq!(replica.entries(NS).unwrap(), source.entries(NS).unwrap());
+
+        // An older replicated entry must not overwrite a newer local value
+        let stale = Entry {
+            key: "key1".to_string(),
+            value: Some(Value::Text("stale".to_string())),
+            timestamp: ts(Utc::now() - chrono::Duration::hours(1)),
+            signature: None,
+        };
+        replica.apply_entry(NS, &stale).unwrap();
+        assert_eq!(replica.get(NS, "key1").unwrap(), Some("value1".to_string()));
+
+        // 署名は行と一緒に保存され、突き合わせで配られる
+        let signed = Entry {
+            key: "key3".to_string(),
+            value: None,
+            timestamp: ts(Utc::now()),
+            signature: Some(vec![1, 2, 3]),
+        };
+        replica.apply_entry(NS, &signed).unwrap();
+        let entries = replica.entries(NS).unwrap();
+        assert_eq!(entries.last(), Some(&signed));
+        assert_eq!(entries[0].signature, None);
+
+        // 鍵を持つストアはローカルの書き込みに署名する
+        let keypair = Keypair::generate_ed25519();
+        let public_key = keypair.public();
+        let signing = replica.with_keypair(keypair);
+        let entry = signing.put(NS, "key4", "value4").unwrap();
+        assert_eq!(entry.timestamp.origin, public_key.to_peer_id().to_string());
+        assert!(crypto::verify_entry(&public_key, NS, &entry).unwrap());
+        assert_eq!(signing.entries(NS).unwrap().last(), Some(&entry));
+    }
+
+    #[test]
+    fn test_verified_entry_repairs_unsigned_copy() {
+        let (source, _dir1) = create_test_storage();
+        let (replica, _dir2) = create_test_storage();
+        let source = source.with_keypair(Keypair::generate_ed25519());
+
+        let signed = source.put(NS, "key", "value").unwrap();
+        // 署名の検証を強制する前に、署名なしの複製（値も偽物）を受け入れていた
+        let unsigned = Entry {
+            value: Some(Value::Text("forged".to_string())),
+            signature: None,
+            ..signed.clone()
+        };
+        replica.apply_entry(NS, &unsigned).unwrap();
+
+        // 同じ時刻の書き込みは通常の適用では置き換わらない
+        replica.apply_entry(NS, &signed).unwrap();
+        assert_eq!(replica.entries(NS).unwrap(), vec![unsigned.clone()]);
+
+        replica.apply_verified_entry(NS, &signed).unwrap();
+        assert_eq!(replica.entries(NS).unwrap(), vec![signed.clone()]);
+        assert_eq!(replica.get(NS, "key").unwrap(), Some("value".to_string()));
+
+        // 検証済みの複製を署名のない複製で戻すことはできない
+        replica.apply_verified_entry(NS, &unsigned).unwrap();
+        assert_eq!(replica.entries(NS).unwrap(), vec![signed]);
+    }
+
     #[test]
     fn test_binary_data_as_strings() {
         let (storage, _dir) = create_test_storage();
@@ -391,9 +1873,281 @@ mod tests {
         // Test storing binary-like data as strings
         let binary_like = "binary_data_00FF8040201008040201";
 
-        storage.put("binary_key", binary_like).unwrap();
-        let retrieved = storage.get("binary_key").unwrap();
+        storage.put(NS, "binary_key", binary_like).unwrap();
+        let retrieved = storage.get(NS, "binary_key").unwrap();
 
         assert_eq!(retrieved, Some(binary_like.to_string()));
     }
+
+    #[test]
+    fn test_binary_values() {
+        let (storage, _dir) = create_test_storage();
+
+        let data = vec![0u8, 159, 146, 150, 255];
+        storage.put(NS, "blob", Value::Bytes(data.clone())).unwrap();
+        storage.put(NS, "text", "value").unwrap();
+
+        assert_eq!(storage.get_bytes(NS, "blob").unwrap(), Some(data));
+        assert_eq!(
+            storage.get_bytes(NS, "text").unwrap(),
+            Some(b"value".to_vec())
+        );
+        assert!(storage.get(NS, "blob").is_err());
+
+        let items = storage.list(NS).unwrap();
+        assert_eq!(
+            items[0],
+            ("blob".to_string(), "<binary, 5 bytes>".to_string())
+        );
+    }
+
+    #[test]
+    fn test_large_values_are_chunked() {
+        let (source, _dir1) = create_test_storage();
+        let (replica, _dir2) = create_test_storage();
+
+        let data: Vec<u8> = (0..CHUNK_SIZE + 10).map(|i| (i % 251) as u8).collect();
+        let value = source.encode_bytes(data.clone(), 1024).unwrap();
+        let Value::Chunked(manifest) = &value else {
+            panic!("Expected a chunked value");
+        };
+        assert_eq!(manifest.chunks.len(), 2);
+
+        source.put(NS, "large", value.clone()).unwrap();
+        assert_eq!(source.get_bytes(NS, "large").unwrap(), Some(data.clone()));
+        assert!(source.missing_chunks().unwrap().is_empty());
+
+        // The replica only receives the manifest until the chunks are fetched
+        for entry in source.entries(NS).unwrap() {
+            replica.apply_entry(NS, &entry).unwrap();
+        }
+        assert!(replica.get_bytes(NS, "large").is_err());
+
+        let mut missing = manifest.chunks.clone();
+        missing.sort();
+        assert_eq!(replica.missing_chunks().unwrap(), missing);
+
+        for hash in &missing {
+            let chunk = source.get_chunk(hash).unwrap().unwrap();
+            replica.insert_chunk(&chunk).unwrap();
+        }
+        assert!(replica.missing_chunks().unwrap().is_empty());
+        assert_eq!(replica.get_bytes(NS, "large").unwrap(), Some(data));
+    }
+
+    #[test]
+    fn test_purge_unreferenced_chunks() {
+        let (storage, _dir) = create_test_storage();
+
+        let value = storage.encode_bytes(vec![7; 2048], 1024).unwrap();
+        storage.put(NS, "large", value).unwrap();
+        assert_eq!(storage.purge_chunks().unwrap(), 0);
+
+        // 履歴に残っている版が参照するチャンクは、その版が消えるまで残す
+        storage.put(NS, "large", "small now").unwrap();
+        assert_eq!(storage.purge_chunks().unwrap(), 0);
+
+        storage
+            .purge_history(Utc::now() + chrono::Duration::seconds(1))
+            .unwrap();
+        assert_eq!(storage.purge_chunks().unwrap(), 1);
+    }
+
+    #[test]
+    fn test_text_value_column_is_migrated_to_blob() {
+        let temp_dir = tempdir().unwrap();
+        let db_path = temp_dir.path().join("v1.db");
+
+        {
+            let conn = Connection::open(&db_path).unwrap();
+            conn.execute_batch(
+                "CREATE TABLE kv_store (key TEXT PRIMARY KEY, value TEXT NOT NULL, timestamp INTEGER NOT NULL,
+                    deleted INTEGER NOT NULL DEFAULT 0, logical INTEGER NOT NULL DEFAULT 0, origin TEXT NOT NULL DEFAULT '');
+                INSERT INTO kv_store (key, value, timestamp) VALUES ('key', 'value', 1700000000000);
+                PRAGMA user_version = 1;",
+            )
+            .unwrap();
+        }
+
+        let storage = Storage::open(&db_path, None).unwrap();
+        let value_type: String = storage
+            .conn
+            .query_row(
+                "SELECT type FROM pragma_table_info('kv_store') WHERE name = 'value'",
+                [],
+                |row| row.get(0),
+            )
+            .unwrap();
+        assert_eq!(value_type, "BLOB");
+        assert_eq!(storage.get(NS, "key").unwrap(), Some("value".to_string()));
+        assert_eq!(
+            storage.entries(NS).unwrap()[0].timestamp.wall_ms,
+            1_700_000_000_000
+        );
+    }
+
+    #[test]
+    fn test_values_are_encrypted_at_rest() {
+        let temp_dir = tempdir().unwrap();
+        let db_path = temp_dir.path().join("sync.db");
+
+        // 暗号化前に書かれた値も有効化時に暗号化される
+        Storage::open(&db_path, None)
+            .unwrap()
+            .put(NS, "before", "plain secret")
+            .unwrap();
+
+        let secret = Secret::generate();
+        let storage = Storage::open(&db_path, Some(&secret)).unwrap();
+        storage.put(NS, "after", "new secret").unwrap();
+        let large = storage.encode_bytes(vec![7; 2048], 1024).unwrap();
+        storage.put(NS, "large", large).unwrap();
+        drop(storage);
+
+        let raw = std::fs::read(&db_path).unwrap();
+        for needle in [&b"plain secret"[..], b"new secret", &[7; 64]] {
+            assert!(!raw.windows(needle.len()).any(|window| window == needle));
+        }
+
+        assert!(Storage::open(&db_path, None).is_err());
+        assert!(Storage::open(&db_path, Some(&Secret::generate())).is_err());
+
+        let new_secret = Secret::generate();
+        Storage::rekey(&db_path, Some(&secret), Some(&new_secret)).unwrap();
+        let storage = Storage::open(&db_path, Some(&new_secret)).unwrap();
+        assert_eq!(
+            storage.get(NS, "before").unwrap(),
+            Some("plain secret".to_string())
+        );
+        assert_eq!(
+            storage.get(NS, "after").unwrap(),
+            Some("new secret".to_string())
+        );
+        assert_eq!(storage.get_bytes(NS, "large").unwrap(), Some(vec![7; 2048]));
+        assert_eq!(storage.entries(NS).unwrap().len(), 3);
+        assert_eq!(
+            storage.history(NS, "before").unwrap()[0].value,
+            Some(Value::from("plain secret"))
+        );
+        assert!(storage.missing_chunks().unwrap().is_empty());
+        drop(storage);
+
+        // ディスク上で平文に書き換えられた値は認証できないので読まない
+        Connection::open(&db_path)
+            .unwrap()
+            .execute(
+                "UPDATE kv_store SET value = 'planted' WHERE key = 'after'",
+                [],
+            )
+            .unwrap();
+        let storage = Storage::open(&db_path, Some(&new_secret)).unwrap();
+        assert!(storage.get(NS, "after").is_err());
+        assert!(Storage::rekey(&db_path, Some(&new_secret), None).is_err());
+    }
+
+    #[tokio::test]
+    async fn test_changes_are_broadcast() {
+        let (storage, _dir) = create_test_storage();
+        let storage = storage.with_origin("local");
+        let mut changes = storage.changes().subscribe(None, Some("app/".to_string()));
+
+        storage.put(NS, "app/name", "v1").unwrap();
+        storage.put(NS, "other", "ignored").unwrap();
+        storage
+            .put_with_timestamp(
+                NS,
+                "app/name",
+                "v2",
+                &ts(Utc::now() + chrono::Duration::seconds(30)),
+                None,
+            )
+            .unwrap();
+        // Stale writes and deletes of missing keys do not change anything
+        storage
+            .put_with_timestamp(
+                NS,
+                "app/name",
+                "stale",
+                &ts(Utc::now() - chrono::Duration::hours(1)),
+                None,
+            )
+            .unwrap();
+        storage.delete(NS, "app/missing").unwrap();
+        storage.delete(NS, "app/name").unwrap();
+
+        let first = changes.recv().await.unwrap();
+        assert_eq!(first.key, "app/name");
+        assert_eq!(first.old, None);
+        assert_eq!(first.new, Some("v1".to_string()));
+        assert_eq!(first.origin_peer, "local");
+
+        let second = changes.recv().await.unwrap();
+        assert_eq!(second.old, Some("v1".to_string()));
+        assert_eq!(second.new, Some("v2".to_string()));
+        assert_eq!(second.origin_peer, "peer");
+
+        let third = changes.recv().await.unwrap();
+        assert_eq!(third.old, Some("v2".to_string()));
+        assert_eq!(third.new, None);
+        assert_eq!(third.origin_peer, "local");
+    }
+
+    #[test]
+    fn test_metrics() {
+        let temp_dir = tempdir().unwrap();
+        let db_path = temp_dir.path().join("sync.db");
+        Storage::open(&db_path, None)
+            .unwrap()
+            .put(NS, "existing", "value")
+            .unwrap();
+
+        let metrics = Metrics::default();
+        let storage = Storage::open(&db_path, None)
+            .unwrap()
+            .with_metrics(metrics.clone())
+            .unwrap();
+        let keys = || {
+            metrics
+                .store_keys
+                .get_or_create(&NamespaceLabels {
+                    namespace: NS.to_string(),
+                })
+                .get()
+        };
+        let writes = |op| {
+            metrics
+                .store_writes
+                .get_or_create(&OperationLabels { op })
+                .get()
+        };
+
+        // 既存のキーも数える
+        assert_eq!(keys(), 1);
+
+        storage.put(NS, "new", "value").unwrap();
+        storage.put(NS, "new", "updated").unwrap();
+        assert_eq!(keys(), 2);
+        assert_eq!(writes("put"), 2);
+
+        storage.delete(NS, "existing").unwrap();
+        storage.delete(NS, "missing").unwrap();
+        assert_eq!(keys(), 1);
+        assert_eq!(writes("delete"), 2);
+
+        // 古い書き込みは適用されないので数えない
+        storage
+            .put_with_timestamp(
+                NS,
+                "new",
+                "stale",
+                &ts(Utc::now() - chrono::Duration::hours(1)),
+                None,
+            )
+            .unwrap();
+        assert_eq!(writes("put"), 2);
+
+        storage.insert_chunk(b"chunk").unwrap();
+        storage.insert_chunk(b"chunk").unwrap();
+        assert_eq!(writes("chunk"), 1);
+    }
 }
@@ -1,41 +1,245 @@
-use chrono::{DateTime, Utc};
 use serde::{Deserialize, Serialize};
 
+use crate::chunks::ChunkManifest;
+use crate::group_key::EncryptedValue;
+use crate::hlc::HlcTimestamp;
 use crate::key_distribution::KeyDistributionMessage;
+use crate::namespace::DEFAULT_NAMESPACE;
+use crate::storage::{Entry, Value};
 
-#[derive(Debug, Clone, Serialize, Deserialize)]
+/// Data change. `timestamp.origin` is the PeerId of the node that made the change.
+#[derive(Clone, Serialize, Deserialize)]
 pub enum SyncMessage {
     Put {
         key: String,
         value: String,
-        timestamp: DateTime<Utc>,
+        timestamp: HlcTimestamp,
     },
     Delete {
         key: String,
-        timestamp: DateTime<Utc>,
+        timestamp: HlcTimestamp,
+    },
+    /// Binary value small enough to be sent inline
+    PutBytes {
+        key: String,
+        #[serde(with = "serde_bytes")]
+        value: Vec<u8>,
+        timestamp: HlcTimestamp,
+    },
+    /// Large value. Only the manifest is gossiped, receivers fetch the chunks
+    /// over the chunk protocol.
+    PutChunked {
+        key: String,
+        manifest: ChunkManifest,
+        timestamp: HlcTimestamp,
     },
 }
 
-/// Combined message type that can handle both data sync and key distribution
+/// Values are never printed, only their size, so changes to encrypted
+/// namespaces cannot end up in logs
+impl std::fmt::Debug for SyncMessage {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            SyncMessage::Put {
+                key,
+                value,
+                timestamp,
+            } => f
+                .debug_struct("Put")
+                .field("key", key)
+                .field("value_len", &value.len())
+                .field("timestamp", timestamp)
+                .finish(),
+            SyncMessage::Delete { key, timestamp } => f
+                .debug_struct("Delete")
+                .field("key", key)
+                .field("timestamp", timestamp)
+                .finish(),
+            SyncMessage::PutBytes {
+                key,
+                value,
+                timestamp,
+            } => f
+                .debug_struct("PutBytes")
+                .field("key", key)
+                .field("value_len", &value.len())
+                .field("timestamp", timestamp)
+                .finish(),
+            // チャンクのハッシュからも推測できる値があるので大きさだけ出す
+            SyncMessage::PutChunked {
+                key,
+                manifest,
+                timestamp,
+            } => f
+                .debug_struct("PutChunked")
+                .field("key", key)
+                .field("value_len", &manifest.size)
+                .field("timestamp", timestamp)
+                .finish(),
+        }
+    }
+}
+
+impl SyncMessage {
+    /// Message announcing that `key` was set to `value`
+    pub fn put(key: String, value: Value, timestamp: HlcTimestamp) -> Self {
+        match value {
+            Value::Text(value) => SyncMessage::Put {
+                key,
+                value,
+                timestamp,
+            },
+            Value::Bytes(value) => SyncMessage::PutBytes {
+                key,
+                value,
+                timestamp,
+            },
+            Value::Chunked(manifest) => SyncMessage::PutChunked {
+                key,
+                manifest,
+                timestamp,
+            },
+        }
+    }
+
+    /// Key the change applies to
+    pub fn key(&self) -> &str {
+        match self {
+            SyncMessage::Put { key, .. }
+            | SyncMessage::Delete { key, .. }
+            | SyncMessage::PutBytes { key, .. }
+            | SyncMessage::PutChunked { key, .. } => key,
+        }
+    }
+
+    pub fn timestamp(&self) -> &HlcTimestamp {
+        match self {
+            SyncMessage::Put { timestamp, .. }
+            | SyncMessage::Delete { timestamp, .. }
+            | SyncMessage::PutBytes { timestamp, .. }
+            | SyncMessage::PutChunked { timestamp, .. } => timestamp,
+        }
+    }
+
+    /// The row this change results in
+    pub fn into_entry(self, signature: Option<Vec<u8>>) -> Entry {
+        let (key, value, timestamp) = match self {
+            SyncMessage::Put {
+                key,
+                value,
+                timestamp,
+            } => (key, Some(Value::Text(value)), timestamp),
+            SyncMessage::Delete { key, timestamp } => (key, None, timestamp),
+            SyncMessage::PutBytes {
+                key,
+                value,
+                timestamp,
+            } => (key, Some(Value::Bytes(value)), timestamp),
+            SyncMessage::PutChunked {
+                key,
+                manifest,
+                timestamp,
+            } => (key, Some(Value::Chunked(manifest)), timestamp),
+        };
+
+        Entry {
+            key,
+            value,
+            timestamp,
+            signature,
+        }
+    }
+}
+
+/// A change with its author's signature over the resulting row (see
+/// `crypto::sign_entry`). Receivers store the signature with the row, so
+/// entries served during reconciliation can be checked against their origin.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct SignedChange {
+    pub message: SyncMessage,
+    #[serde(with = "serde_bytes")]
+    pub signature: Vec<u8>,
+}
+
+impl SignedChange {
+    /// Change replicating `entry`, which must carry its author's signature
+    pub fn from_entry(entry: Entry) -> anyhow::Result<Self> {
+        let Some(signature) = entry.signature else {
+            anyhow::bail!("Entry {} is not signed", entry.key);
+        };
+        let message = match entry.value {
+            Some(value) => SyncMessage::put(entry.key, value, entry.timestamp),
+            None => SyncMessage::Delete {
+                key: entry.key,
+                timestamp: entry.timestamp,
+            },
+        };
+
+        Ok(Self { message, signature })
+    }
+
+    pub fn into_entry(self) -> Entry {
+        self.message.into_entry(Some(self.signature))
+    }
+}
+
+/// Combined message type that can handle both data sync and key distribution.
+///
+/// Changes carry `seq`, the number of changes their origin has published to
+/// the namespace so far. It is signed with the change, so receivers can drop
+/// duplicates and replays and notice changes they missed.
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub enum P2PMessage {
-    /// Data synchronization message
-    Sync(SyncMessage),
+    /// Data synchronization message for the default namespace
+    Sync { change: SignedChange, seq: u64 },
     /// Key distribution message
     KeyDistribution(KeyDistributionMessage),
+    /// Data synchronization message for another namespace. The namespace is
+    /// part of the signed data so a change cannot be replayed into another one.
+    NamespaceSync {
+        namespace: String,
+        change: SignedChange,
+        seq: u64,
+    },
+    /// Change to an encrypted namespace. `value` is the JSON encoded
+    /// `SignedChange` encrypted with the namespace's group key.
+    EncryptedSync {
+        namespace: String,
+        value: EncryptedValue,
+        seq: u64,
+    },
+}
+
+impl P2PMessage {
+    /// Message carrying change number `seq` to `namespace`
+    pub fn sync(namespace: &str, change: SignedChange, seq: u64) -> Self {
+        if namespace == DEFAULT_NAMESPACE {
+            P2PMessage::Sync { change, seq }
+        } else {
+            P2PMessage::NamespaceSync {
+                namespace: namespace.to_string(),
+                change,
+                seq,
+            }
+        }
+    }
 }
 
 #[cfg(test)]
 mod tests {
     use super::*;
-    use chrono::Utc;
+    use crate::hlc::HybridClock;
+
+    fn now() -> HlcTimestamp {
+        HybridClock::new("peer").now()
+    }
 
     #[test]
     fn test_sync_message_put_serialization() {
         let msg = SyncMessage::Put {
             key: "test_key".to_string(),
             value: "test_value".to_string(),
-            timestamp: Utc::now(),
+            timestamp: now(),
         };
 
         // Test serialization and deserialization
@@ -55,7 +259,7 @@ mod tests {
     fn test_sync_message_delete_serialization() {
         let msg = SyncMessage::Delete {
             key: "test_key".to_string(),
-            timestamp: Utc::now(),
+            timestamp: now(),
         };
 
         // Test serialization and deserialization
@@ -75,7 +279,7 @@ mod tests {
         let msg = SyncMessage::Put {
             key: "test_key".to_string(),
             value: "test_value".to_string(),
-            timestamp: Utc::now(),
+            timestamp: now(),
         };
 
         // Test bincode serialization
@@ -91,12 +295,69 @@ mod tests {
         }
     }
 
+    #[test]
+    fn test_put_bytes_bincode_serialization() {
+        let msg = SyncMessage::put("blob".to_string(), Value::Bytes(vec![0, 159, 255]), now());
+
+        let encoded = bincode::serialize(&msg).unwrap();
+        match bincode::deserialize(&encoded).unwrap() {
+            SyncMessage::PutBytes { key, value, .. } => {
+                assert_eq!(key, "blob");
+                assert_eq!(value, vec![0, 159, 255]);
+            }
+            _ => panic!("Expected PutBytes message"),
+        }
+    }
+
+    #[test]
+    fn test_namespace_is_only_added_outside_default() {
+        let msg = SignedChange {
+            message: SyncMessage::Delete {
+                key: "k".to_string(),
+                timestamp: now(),
+            },
+            signature: vec![1],
+        };
+
+        assert!(matches!(
+            P2PMessage::sync(DEFAULT_NAMESPACE, msg.clone(), 1),
+            P2PMessage::Sync { seq: 1, .. }
+        ));
+        match P2PMessage::sync("secrets", msg, 2) {
+            P2PMessage::NamespaceSync { namespace, seq, .. } => {
+                assert_eq!(namespace, "secrets");
+                assert_eq!(seq, 2);
+            }
+            other => panic!("Expected NamespaceSync message, got {other:?}"),
+        }
+    }
+
+    #[test]
+    fn test_signed_change_round_trips_entry() {
+        let entry = Entry {
+            key: "blob".to_string(),
+            value: Some(Value::Bytes(vec![0, 255])),
+            timestamp: now(),
+            signature: Some(vec![1, 2]),
+        };
+
+        let change = SignedChange::from_entry(entry.clone()).unwrap();
+        assert!(matches!(change.message, SyncMessage::PutBytes { .. }));
+        assert_eq!(change.into_entry(), entry);
+
+        let unsigned = Entry {
+            signature: None,
+            ..entry
+        };
+        assert!(SignedChange::from_entry(unsigned).is_err());
+    }
+
     #[test]
     fn test_sync_message_clone() {
         let original = SyncMessage::Put {
             key: "test_key".to_string(),
             value: "test_value".to_string(),
-            timestamp: Utc::now(),
+            timestamp: now(),
         };
 
         let cloned = original.clone();
@@ -127,12 +388,28 @@ mod tests {
         let msg = SyncMessage::Put {
             key: "test_key".to_string(),
             value: "test_value".to_string(),
-            timestamp: Utc::now(),
+            timestamp: now(),
         };
 
         let debug_str = format!("{msg:?}");
         assert!(debug_str.contains("Put"));
         assert!(debug_str.contains("test_key"));
-        assert!(debug_str.contains("test_value"));
+        assert!(debug_str.contains("value_len: 10"));
+        // 値はログに出さない
+        assert!(!debug_str.contains("test_value"));
+
+        let bytes = SyncMessage::PutBytes {
+            key: "test_key".to_string(),
+            value: b"secret bytes".to_vec(),
+            timestamp: now(),
+        };
+        let change = SignedChange {
+            message: bytes,
+            signature: vec![0; 64],
+        };
+        let debug_str = format!("{change:?}");
+        assert!(debug_str.contains("PutBytes"));
+        assert!(debug_str.contains("value_len: 12"));
+        assert!(!debug_str.contains(&format!("{:?}", b"secret bytes".to_vec())));
     }
 }
@@ -0,0 +1,201 @@
+use chrono::{DateTime, Utc};
+use libp2p::PeerId;
+use serde::{Deserialize, Serialize};
+use std::collections::{BTreeMap, HashMap, HashSet};
+
+/// When peers that are not whitelisted are trusted through recommendations
+/// (`[trust]` section). The defaults trust a peer recommended by a single
+/// whitelisted peer.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct TrustConfig {
+    /// Distinct trusted peers that must recommend a peer
+    pub min_recommenders: u32,
+    /// Total weight of those recommenders a peer needs
+    pub min_weight: f64,
+    /// Longest chain of recommendations starting at a whitelisted peer, 0
+    /// only trusts whitelisted peers
+    pub max_depth: u32,
+    /// Hours after which a recommendation no longer counts, 0 keeps them
+    pub recommendation_ttl_hours: u64,
+    /// Weight of recommendations by PeerId, peers not listed weigh 1.0
+    pub weights: BTreeMap<String, f64>,
+}
+
+impl Default for TrustConfig {
+    fn default() -> Self {
+        Self {
+            min_recommenders: 1,
+            min_weight: 1.0,
+            max_depth: 1,
+            recommendation_ttl_hours: 0,
+            weights: BTreeMap::new(),
+        }
+    }
+}
+
+/// A recommendation of `peer_id` by `recommender`, kept after it is revoked
+/// so that trust decisions can be audited
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Recommendation {
+    pub peer_id: String,
+    pub recommender: String,
+    pub name: Option<String>,
+    pub recommended_at: DateTime<Utc>,
+    /// Set from `recommendation_ttl_hours` when the recommendation is read
+    pub expires_at: Option<DateTime<Utc>>,
+    pub revoked_at: Option<DateTime<Utc>>,
+}
+
+impl Recommendation {
+    /// Whether the recommendation still counts at `now`
+    pub fn is_active(&self, now: DateTime<Utc>) -> bool {
+        self.revoked_at.is_none() && self.expires_at.map_or(true, |expires_at| expires_at > now)
+    }
+}
+
+impl TrustConfig {
+    pub fn weight(&self, recommender: &str) -> f64 {
+        self.weights.get(recommender).copied().unwrap_or(1.0)
+    }
+
+    /// When a recommendation made at `recommended_at` stops counting
+    pub fn expires_at(&self, recommended_at: DateTime<Utc>) -> Option<DateTime<Utc>> {
+        (self.recommendation_ttl_hours > 0)
+            .then(|| recommended_at + chrono::Duration::hours(self.recommendation_ttl_hours as i64))
+    }
+
+    /// Whether `peer_id` is trusted, starting from the `whitelisted` peers and
+    /// following active `recommendations` at most `max_depth` times
+    pub fn is_trusted(
+        &self,
+        peer_id: &PeerId,
+        whitelisted: &HashSet<PeerId>,
+        recommendations: &[Recommendation],
+        now: DateTime<Utc>,
+    ) -> bool {
+        if whitelisted.contains(peer_id) {
+            return true;
+        }
+
+        // 推薦されたピアごとの推薦者（自己推薦と重複は除く）
+        let mut recommenders: HashMap<PeerId, HashSet<PeerId>> = HashMap::new();
+        for recommendation in recommendations {
+            if !recommendation.is_active(now) {
+                continue;
+            }
+            let (Ok(recommended), Ok(recommender)) = (
+                recommendation.peer_id.parse::<PeerId>(),
+                recommendation.recommender.parse::<PeerId>(),
+            ) else {
+                continue;
+            };
+            if recommended != recommender {
+                recommenders
+                    .entry(recommended)
+                    .or_default()
+                    .insert(recommender);
+            }
+        }
+
+        // 深さ 1 ごとに、信頼済みのピアからの推薦が条件を満たすピアを加える
+        let mut trusted = whitelisted.clone();
+        for _ in 0..self.max_depth {
+            let newly_trusted: Vec<PeerId> = recommenders
+                .iter()
+                .filter(|(peer, _)| !trusted.contains(*peer))
+                .filter(|(_, by)| {
+                    let by: Vec<_> = by.iter().filter(|r| trusted.contains(*r)).collect();
+                    let weight: f64 = by.iter().map(|r| self.weight(&r.to_string())).sum();
+                    by.len() as u32 >= self.min_recommenders && weight >= self.min_weight
+                })
+                .map(|(peer, _)| *peer)
+                .collect();
+            if newly_trusted.is_empty() {
+                break;
+            }
+            trusted.extend(newly_trusted);
+            if trusted.contains(peer_id) {
+                return true;
+            }
+        }
+
+        false
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn recommend(peer_id: &PeerId, recommender: &PeerId) -> Recommendation {
+        Recommendation {
+            peer_id: peer_id.to_string(),
+            recommender: recommender.to_string(),
+            name: None,
+            recommended_at: Utc::now(),
+            expires_at: None,
+            revoked_at: None,
+        }
+    }
+
+    #[test]
+    fn test_trust_policy() {
+        let admin = PeerId::random();
+        let other = PeerId::random();
+        let first = PeerId::random();
+        let second = PeerId::random();
+        let whitelisted: HashSet<_> = [admin, other].into_iter().collect();
+        let now = Utc::now();
+
+        let recommendations = vec![recommend(&first, &admin), recommend(&second, &first)];
+        let policy = TrustConfig::default();
+        assert!(policy.is_trusted(&first, &whitelisted, &recommendations, now));
+        // 既定では推薦の連鎖は 1 段まで
+        assert!(!policy.is_trusted(&second, &whitelisted, &recommendations, now));
+
+        let deeper = TrustConfig {
+            max_depth: 2,
+            ..Default::default()
+        };
+        assert!(deeper.is_trusted(&second, &whitelisted, &recommendations, now));
+
+        // 推薦者の数と重みの両方を満たす必要がある
+        let strict = TrustConfig {
+            min_recommenders: 2,
+            ..Default::default()
+        };
+        assert!(!strict.is_trusted(&first, &whitelisted, &recommendations, now));
+        let both = [recommendations.clone(), vec![recommend(&first, &other)]].concat();
+        assert!(strict.is_trusted(&first, &whitelisted, &both, now));
+
+        let weighted = TrustConfig {
+            min_weight: 2.0,
+            weights: [(admin.to_string(), 2.0)].into_iter().collect(),
+            ..Default::default()
+        };
+        assert!(weighted.is_trusted(&first, &whitelisted, &recommendations, now));
+        let light = [recommend(&first, &other)];
+        assert!(!weighted.is_trusted(&first, &whitelisted, &light, now));
+
+        // 取り消された推薦と期限切れの推薦は数えない
+        let mut revoked = recommend(&first, &admin);
+        revoked.revoked_at = Some(now);
+        assert!(!policy.is_trusted(&first, &whitelisted, &[revoked], now));
+
+        let expiring = TrustConfig {
+            recommendation_ttl_hours: 24,
+            ..Default::default()
+        };
+        let mut old = recommend(&first, &admin);
+        old.recommended_at = now - chrono::Duration::hours(25);
+        old.expires_at = expiring.expires_at(old.recommended_at);
+        assert!(!expiring.is_trusted(&first, &whitelisted, &[old], now));
+
+        let disabled = TrustConfig {
+            max_depth: 0,
+            ..Default::default()
+        };
+        assert!(!disabled.is_trusted(&first, &whitelisted, &recommendations, now));
+    }
+}
@@ -0,0 +1,149 @@
+use serde::{Deserialize, Serialize};
+use tokio::sync::broadcast;
+
+use crate::hlc::HlcTimestamp;
+
+/// Number of events buffered per subscriber. Subscribers that fall further
+/// behind skip the oldest events and get `RecvError::Lagged`.
+const CHANNEL_CAPACITY: usize = 1024;
+
+/// A change to one key, emitted after it has been written to `Storage`
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct ChangeEvent {
+    pub namespace: String,
+    pub key: String,
+    /// Value before the change, `None` if the key did not exist or was deleted
+    pub old: Option<String>,
+    /// Value after the change, `None` for a delete
+    pub new: Option<String>,
+    /// PeerId of the node that made the change
+    pub origin_peer: String,
+    pub timestamp: HlcTimestamp,
+}
+
+/// Broadcasts every change applied to a `Storage`, whether it was made locally
+/// or received from a peer
+#[derive(Clone)]
+pub struct ChangeFeed {
+    sender: broadcast::Sender<ChangeEvent>,
+}
+
+impl ChangeFeed {
+    pub fn new() -> Self {
+        let (sender, _) = broadcast::channel(CHANNEL_CAPACITY);
+        Self { sender }
+    }
+
+    /// Receive changes to keys of `namespace` starting with `prefix`. `None`
+    /// matches every namespace or key.
+    pub fn subscribe(
+        &self,
+        namespace: Option<String>,
+        prefix: Option<String>,
+    ) -> ChangeSubscription {
+        ChangeSubscription {
+            receiver: self.sender.subscribe(),
+            namespace,
+            prefix,
+        }
+    }
+
+    pub(crate) fn publish(&self, event: ChangeEvent) {
+        // 購読者がいない場合のエラーは無視する
+        let _ = self.sender.send(event);
+    }
+}
+
+impl Default for ChangeFeed {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+pub struct ChangeSubscription {
+    receiver: broadcast::Receiver<ChangeEvent>,
+    namespace: Option<String>,
+    prefix: Option<String>,
+}
+
+impl ChangeSubscription {
+    /// Wait for the next matching change
+    pub async fn recv(&mut self) -> Result<ChangeEvent, broadcast::error::RecvError> {
+        loop {
+            let event = self.receiver.recv().await?;
+            if self.matches(&event) {
+                return Ok(event);
+            }
+        }
+    }
+
+    fn matches(&self, event: &ChangeEvent) -> bool {
+        self.namespace
+            .as_deref()
+            .map_or(true, |namespace| event.namespace == namespace)
+            && self
+                .prefix
+                .as_deref()
+                .map_or(true, |prefix| event.key.starts_with(prefix))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn event(key: &str) -> ChangeEvent {
+        ChangeEvent {
+            namespace: "default".to_string(),
+            key: key.to_string(),
+            old: None,
+            new: Some("v".to_string()),
+            origin_peer: "peer".to_string(),
+            timestamp: HlcTimestamp::new(1, 0, "peer"),
+        }
+    }
+
+    #[tokio::test]
+    async fn test_prefix_filter() {
+        let feed = ChangeFeed::new();
+        let mut all = feed.subscribe(None, None);
+        let mut app = feed.subscribe(None, Some("app/".to_string()));
+
+        feed.publish(event("other"));
+        feed.publish(event("app/name"));
+
+        assert_eq!(all.recv().await.unwrap().key, "other");
+        assert_eq!(all.recv().await.unwrap().key, "app/name");
+        assert_eq!(app.recv().await.unwrap().key, "app/name");
+    }
+
+    #[tokio::test]
+    async fn test_namespace_filter() {
+        let feed = ChangeFeed::new();
+        let mut secrets = feed.subscribe(Some("secrets".to_string()), None);
+
+        feed.publish(event("key"));
+        feed.publish(ChangeEvent {
+            namespace: "secrets".to_string(),
+            ..event("token")
+        });
+
+        assert_eq!(secrets.recv().await.unwrap().key, "token");
+    }
+
+    #[tokio::test]
+    async fn test_slow_subscriber_lags() {
+        let feed = ChangeFeed::new();
+        let mut subscription = feed.subscribe(None, None);
+
+        for i in 0..CHANNEL_CAPACITY + 1 {
+            feed.publish(event(&format!("key{i}")));
+        }
+
+        assert!(matches!(
+            subscription.recv().await,
+            Err(broadcast::error::RecvError::Lagged(1))
+        ));
+        assert_eq!(subscription.recv().await.unwrap().key, "key1");
+    }
+}
@@ -1,12 +1,18 @@
 use anyhow::Result;
 use libp2p::PeerId;
-use rusqlite::{params, Connection};
+use rusqlite::types::Value as SqlValue;
+use rusqlite::{params, Connection, OptionalExtension};
 use serde::{Deserialize, Serialize};
 use std::collections::HashSet;
 use std::path::Path;
 use std::sync::Arc;
 use tokio::sync::RwLock;
 
+use crate::at_rest::{self, Codec, Secret};
+use crate::crypto::{self, SignedData};
+use crate::group_key::GroupKey;
+use crate::trust::{Recommendation, TrustConfig};
+
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct WhitelistEntry {
     pub peer_id: String,
@@ -16,18 +22,77 @@ pub struct WhitelistEntry {
     pub expires_at: Option<chrono::DateTime<chrono::Utc>>,
 
     // Simple trust chain fields
-    pub recommended_by: Vec<String>, // Peer IDs that recommended this peer
-    pub recommendation_count: u32,   // Total number of recommendations received
+    pub recommended_by: Vec<String>, // Peer IDs with an active recommendation of this peer
+    pub recommendation_count: u32,   // Total number of recommendations received, revoked included
+}
+
+/// A verified `WhitelistRequest` waiting for an administrator decision
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct PendingRequest {
+    pub peer_id: String,
+    pub name: Option<String>,
+    /// Protobuf-encoded public key, checked against `peer_id` when received
+    pub public_key: Vec<u8>,
+    pub requested_at: chrono::DateTime<chrono::Utc>,
+}
+
+/// Revocation of a compromised peer, issued by an admin peer. It is stored
+/// with the admin's signature so that it can be relayed to peers that missed it.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Revocation {
+    pub peer_id: String,
+    pub reason: Option<String>,
+    pub revoked_at: chrono::DateTime<chrono::Utc>,
 }
 
 pub struct PeerWhitelist {
     db: Arc<RwLock<Connection>>,
+    codec: Codec,
     cache: Arc<RwLock<HashSet<PeerId>>>,
+    trust: TrustConfig,
 }
 
 impl PeerWhitelist {
-    #[allow(clippy::arc_with_non_send_sync)]
+    /// Open an unencrypted whitelist, same as `open(db_path, None)`
+    #[allow(dead_code)] // バイナリでは使わないがライブラリの API として残す
     pub fn new(db_path: &Path) -> Result<Self> {
+        Self::open(db_path, None)
+    }
+
+    /// Open the whitelist, encrypting names, public keys, recommendations and
+    /// group keys at rest with a key derived from `secret`. PeerIds stay in
+    /// plaintext so that they can be looked up.
+    #[allow(clippy::arc_with_non_send_sync)]
+    pub fn open(db_path: &Path, secret: Option<&Secret>) -> Result<Self> {
+        let mut db = Self::connect(db_path)?;
+        let codec = at_rest::open(&db, db_path, secret, Self::reencode)?;
+        Self::migrate_recommendations(&mut db, &codec)?;
+
+        // キャッシュはホワイトリスト全体を写し、再読み込み時に削除されたピアを検出できるようにする
+        let cache = Self::query_whitelisted_peers(&db)?;
+        let whitelist = Self {
+            db: Arc::new(RwLock::new(db)),
+            codec,
+            cache: Arc::new(RwLock::new(cache)),
+            trust: TrustConfig::default(),
+        };
+
+        Ok(whitelist)
+    }
+
+    /// Policy used by `is_trusted_by_chain`
+    pub fn with_trust(mut self, trust: TrustConfig) -> Self {
+        self.trust = trust;
+        self
+    }
+
+    /// Re-encrypt the whitelist at `db_path` with `new`, or decrypt it when `new` is `None`
+    pub fn rekey(db_path: &Path, old: Option<&Secret>, new: Option<&Secret>) -> Result<()> {
+        let db = Self::connect(db_path)?;
+        at_rest::rekey(&db, db_path, old, new, Self::reencode)
+    }
+
+    fn connect(db_path: &Path) -> Result<Connection> {
         let db = Connection::open(db_path)?;
 
         db.execute(
@@ -52,13 +117,199 @@ impl PeerWhitelist {
             "ALTER TABLE peer_whitelist ADD COLUMN recommendation_count INTEGER DEFAULT 0",
             [],
         );
+        // 推薦だけで追加されたエントリ（recommendations テーブルへの移行時に削除する）
+        let _ = db.execute(
+            "ALTER TABLE peer_whitelist ADD COLUMN via_recommendation INTEGER DEFAULT 0",
+            [],
+        );
+        // 推薦のない行の recommended_by は列の既定値 '[]' が平文のまま入っているので消しておく
+        db.execute(
+            "UPDATE peer_whitelist SET recommended_by = NULL WHERE recommendation_count = 0 AND recommended_by IS NOT NULL",
+            [],
+        )?;
 
-        let whitelist = Self {
-            db: Arc::new(RwLock::new(db)),
-            cache: Arc::new(RwLock::new(HashSet::new())),
-        };
+        db.execute(
+            "CREATE TABLE IF NOT EXISTS pending_requests (
+                peer_id TEXT PRIMARY KEY,
+                name TEXT,
+                public_key BLOB NOT NULL,
+                requested_at TEXT NOT NULL
+            )",
+            [],
+        )?;
 
-        Ok(whitelist)
+        // 暗号化されたネームスペースのグループ鍵（ローテーション前の鍵も復号用に残す）
+        db.execute(
+            "CREATE TABLE IF NOT EXISTS group_keys (
+                namespace TEXT NOT NULL,
+                key_id TEXT NOT NULL,
+                epoch INTEGER NOT NULL,
+                key BLOB NOT NULL,
+                added_at TEXT NOT NULL,
+                recipients TEXT,
+                PRIMARY KEY (namespace, key_id)
+            )",
+            [],
+        )?;
+        // ローテーションした鍵を受け取ったピア（JSON の PeerId 配列、NULL は制限なし）
+        let _ = db.execute("ALTER TABLE group_keys ADD COLUMN recipients TEXT", []);
+
+        // 管理者が失効させたピア（署名付きの失効通知ごと保存し、他のピアに中継する）
+        db.execute(
+            "CREATE TABLE IF NOT EXISTS revoked_peers (
+                peer_id TEXT PRIMARY KEY,
+                revocation TEXT NOT NULL,
+                revoked_at TEXT NOT NULL
+            )",
+            [],
+        )?;
+
+        // 推薦の履歴（信頼グラフを隠すため PeerId も暗号化し、取り消した推薦も監査用に残す）
+        db.execute(
+            "CREATE TABLE IF NOT EXISTS recommendations (
+                id TEXT PRIMARY KEY,
+                peer_id TEXT NOT NULL,
+                recommender TEXT NOT NULL,
+                name TEXT,
+                recommended_at TEXT NOT NULL,
+                revoked_at TEXT
+            )",
+            [],
+        )?;
+
+        Ok(db)
+    }
+
+    fn reencode(db: &Connection, from: &Codec, to: &Codec) -> Result<()> {
+        for column in ["name", "public_key", "recommended_by"] {
+            at_rest::reencode_column(db, "peer_whitelist", "peer_id", column, from, to)?;
+        }
+        for column in ["name", "public_key"] {
+            at_rest::reencode_column(db, "pending_requests", "peer_id", column, from, to)?;
+        }
+        at_rest::reencode_column(db, "revoked_peers", "peer_id", "revocation", from, to)?;
+        for column in ["peer_id", "recommender", "name"] {
+            at_rest::reencode_column(db, "recommendations", "id", column, from, to)?;
+        }
+
+        let keys = db
+            .prepare("SELECT namespace, key_id, key FROM group_keys")?
+            .query_map([], |row| {
+                Ok((
+                    row.get::<_, String>(0)?,
+                    row.get::<_, String>(1)?,
+                    row.get::<_, SqlValue>(2)?,
+                ))
+            })?
+            .collect::<Result<Vec<_>, _>>()?;
+        for (namespace, key_id, key) in keys {
+            let aad = group_key_aad(&namespace, &key_id);
+            db.execute(
+                "UPDATE group_keys SET key = ?1 WHERE namespace = ?2 AND key_id = ?3",
+                params![to.seal(&aad, from.open(&aad, key)?)?, namespace, key_id],
+            )?;
+        }
+
+        Ok(())
+    }
+
+    // 以前の peer_whitelist.recommended_by の推薦を recommendations テーブルに移し、
+    // 推薦だけで追加されたエントリを削除する
+    fn migrate_recommendations(db: &mut Connection, codec: &Codec) -> Result<()> {
+        let tx = db.transaction()?;
+        let rows = tx
+            .prepare(
+                "SELECT peer_id, name, added_at, recommended_by FROM peer_whitelist WHERE recommendation_count > 0",
+            )?
+            .query_map([], |row| {
+                let peer_id: String = row.get(0)?;
+                let open = |column: &str, index: usize| {
+                    codec
+                        .open_text(
+                            &at_rest::column_aad("peer_whitelist", column, &peer_id),
+                            row.get(index)?,
+                        )
+                        .map_err(|e| at_rest::to_sql_error(index, e))
+                };
+                let name = open("name", 1)?;
+                let recommended_by = open("recommended_by", 3)?.unwrap_or_default();
+                Ok((peer_id.clone(), name, row.get::<_, String>(2)?, recommended_by))
+            })?
+            .collect::<Result<Vec<_>, _>>()?;
+        if rows.is_empty() {
+            return Ok(());
+        }
+
+        for (peer_id, name, added_at, recommended_by) in rows {
+            let recommenders: Vec<String> =
+                serde_json::from_str(&recommended_by).unwrap_or_default();
+            for recommender in recommenders {
+                let id = uuid::Uuid::new_v4().to_string();
+                let seal = |column: &str, value: Option<String>| {
+                    codec.seal(
+                        &at_rest::column_aad("recommendations", column, &id),
+                        value.into(),
+                    )
+                };
+                tx.execute(
+                    "INSERT INTO recommendations (id, peer_id, recommender, name, recommended_at) VALUES (?1, ?2, ?3, ?4, ?5)",
+                    params![
+                        id,
+                        seal("peer_id", Some(peer_id.clone()))?,
+                        seal("recommender", Some(recommender))?,
+                        seal("name", name.clone())?,
+                        added_at
+                    ],
+                )?;
+            }
+        }
+        // 初期の add_recommendation は INSERT OR REPLACE で公開鍵なしの行を作り、
+        // via_recommendation 列もなかったので、推薦があって公開鍵のない行も推薦だけのものとみなす
+        tx.execute(
+            "DELETE FROM peer_whitelist WHERE via_recommendation = 1
+                OR (recommendation_count > 0 AND public_key IS NULL)",
+            [],
+        )?;
+        tx.execute(
+            "UPDATE peer_whitelist SET recommended_by = NULL, recommendation_count = 0",
+            [],
+        )?;
+        tx.commit()?;
+
+        Ok(())
+    }
+
+    fn seal(
+        &self,
+        table: &str,
+        column: &str,
+        row_id: &str,
+        value: impl Into<SqlValue>,
+    ) -> Result<SqlValue> {
+        self.codec
+            .seal(&at_rest::column_aad(table, column, row_id), value.into())
+    }
+
+    fn open_text(
+        &self,
+        table: &str,
+        column: &str,
+        row_id: &str,
+        value: SqlValue,
+    ) -> Result<Option<String>> {
+        self.codec
+            .open_text(&at_rest::column_aad(table, column, row_id), value)
+    }
+
+    fn open_blob(
+        &self,
+        table: &str,
+        column: &str,
+        row_id: &str,
+        value: SqlValue,
+    ) -> Result<Option<Vec<u8>>> {
+        self.codec
+            .open_blob(&at_rest::column_aad(table, column, row_id), value)
     }
 
     pub async fn add_peer(
@@ -68,21 +319,23 @@ impl PeerWhitelist {
         public_key: Option<&libp2p::identity::PublicKey>,
         expires_at: Option<chrono::DateTime<chrono::Utc>>,
     ) -> Result<()> {
+        if self.is_revoked(peer_id).await? {
+            anyhow::bail!("Peer {} has been revoked", peer_id);
+        }
+
         let peer_id_str = peer_id.to_string();
         let added_at = chrono::Utc::now();
         let public_key_bytes = public_key.map(|pk| pk.encode_protobuf());
 
         let db = self.db.write().await;
         db.execute(
-            "INSERT OR REPLACE INTO peer_whitelist (peer_id, name, public_key, added_at, expires_at, recommended_by, recommendation_count) VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7)",
+            "INSERT OR REPLACE INTO peer_whitelist (peer_id, name, public_key, added_at, expires_at, recommended_by) VALUES (?1, ?2, ?3, ?4, ?5, NULL)",
             params![
                 peer_id_str,
-                name,
-                public_key_bytes,
+                self.seal("peer_whitelist", "name", &peer_id_str, name)?,
+                self.seal("peer_whitelist", "public_key", &peer_id_str, public_key_bytes)?,
                 added_at.to_rfc3339(),
-                expires_at.map(|dt| dt.to_rfc3339()),
-                "[]",  // Empty JSON array for recommended_by
-                0      // Initial recommendation_count
+                expires_at.map(|dt| dt.to_rfc3339())
             ],
         )?;
 
@@ -172,20 +425,25 @@ impl PeerWhitelist {
     }
 
     pub async fn list_peers(&self) -> Result<Vec<WhitelistEntry>> {
+        let recommendations = self.list_recommendations(None).await?;
+        let now = chrono::Utc::now();
+
         let db = self.db.read().await;
         let mut stmt = db.prepare(
-            "SELECT peer_id, name, public_key, added_at, expires_at, recommended_by, recommendation_count FROM peer_whitelist ORDER BY added_at DESC"
+            "SELECT peer_id, name, public_key, added_at, expires_at FROM peer_whitelist ORDER BY added_at DESC"
         )?;
 
         let entries = stmt
             .query_map([], |row| {
                 let peer_id: String = row.get(0)?;
-                let name: Option<String> = row.get(1)?;
-                let public_key: Option<Vec<u8>> = row.get(2)?;
+                let name = self
+                    .open_text("peer_whitelist", "name", &peer_id, row.get(1)?)
+                    .map_err(|e| at_rest::to_sql_error(1, e))?;
+                let public_key = self
+                    .open_blob("peer_whitelist", "public_key", &peer_id, row.get(2)?)
+                    .map_err(|e| at_rest::to_sql_error(2, e))?;
                 let added_at_str: String = row.get(3)?;
                 let expires_at_str: Option<String> = row.get(4)?;
-                let recommended_by_json: String = row.get(5).unwrap_or_else(|_| "[]".to_string());
-                let recommendation_count: u32 = row.get(6).unwrap_or(0);
 
                 let added_at = chrono::DateTime::parse_from_rfc3339(&added_at_str)
                     .map(|dt| dt.with_timezone(&chrono::Utc))
@@ -195,8 +453,16 @@ impl PeerWhitelist {
                     .and_then(|s| chrono::DateTime::parse_from_rfc3339(&s).ok())
                     .map(|dt| dt.with_timezone(&chrono::Utc));
 
-                let recommended_by: Vec<String> =
-                    serde_json::from_str(&recommended_by_json).unwrap_or_else(|_| Vec::new());
+                let received: Vec<_> = recommendations
+                    .iter()
+                    .filter(|recommendation| recommendation.peer_id == peer_id)
+                    .collect();
+                let recommendation_count = received.len() as u32;
+                let recommended_by = received
+                    .into_iter()
+                    .filter(|recommendation| recommendation.is_active(now))
+                    .map(|recommendation| recommendation.recommender.clone())
+                    .collect();
 
                 Ok(WhitelistEntry {
                     peer_id,
@@ -221,8 +487,13 @@ impl PeerWhitelist {
         let mut stmt = db.prepare("SELECT public_key FROM peer_whitelist WHERE peer_id = ?1")?;
 
         let result = stmt.query_row(params![peer_id.to_string()], |row| {
-            let public_key_bytes: Option<Vec<u8>> = row.get(0)?;
-            Ok(public_key_bytes)
+            self.open_blob(
+                "peer_whitelist",
+                "public_key",
+                &peer_id.to_string(),
+                row.get(0)?,
+            )
+            .map_err(|e| at_rest::to_sql_error(0, e))
         });
 
         match result {
@@ -236,111 +507,587 @@ impl PeerWhitelist {
         }
     }
 
-    pub async fn reload_cache(&self) -> Result<()> {
+    /// Key to verify signatures of `peer_id`: the stored one, or else the one
+    /// embedded in the PeerId (Ed25519 and secp256k1)
+    pub async fn verification_key(
+        &self,
+        peer_id: &PeerId,
+    ) -> Result<Option<libp2p::identity::PublicKey>> {
+        match self.get_public_key(peer_id).await? {
+            Some(public_key) => Ok(Some(public_key)),
+            None => Ok(crypto::public_key_from_peer_id(peer_id)),
+        }
+    }
+
+    /// Re-read the whitelist from the database, e.g. after another process
+    /// changed it. Returns the peers that are no longer whitelisted.
+    pub async fn reload_cache(&self) -> Result<Vec<PeerId>> {
+        let peers = self.whitelisted_peers().await?;
+
+        let mut cache = self.cache.write().await;
+        let removed = cache.difference(&peers).copied().collect();
+        *cache = peers;
+
+        Ok(removed)
+    }
+
+    /// Queue a whitelist request, replacing an earlier one from the same peer.
+    /// Returns false if the queue already holds `max_pending` other requests.
+    pub async fn add_pending_request(
+        &self,
+        peer_id: &PeerId,
+        name: Option<String>,
+        public_key: &libp2p::identity::PublicKey,
+        max_pending: usize,
+    ) -> Result<bool> {
+        let peer_id_str = peer_id.to_string();
+
+        let db = self.db.write().await;
+        let others: usize = db.query_row(
+            "SELECT COUNT(*) FROM pending_requests WHERE peer_id != ?1",
+            params![peer_id_str],
+            |row| row.get(0),
+        )?;
+        if others >= max_pending {
+            return Ok(false);
+        }
+
+        db.execute(
+            "INSERT OR REPLACE INTO pending_requests (peer_id, name, public_key, requested_at) VALUES (?1, ?2, ?3, ?4)",
+            params![
+                peer_id_str,
+                self.seal("pending_requests", "name", &peer_id_str, name)?,
+                self.seal(
+                    "pending_requests",
+                    "public_key",
+                    &peer_id_str,
+                    public_key.encode_protobuf()
+                )?,
+                chrono::Utc::now().to_rfc3339()
+            ],
+        )?;
+
+        Ok(true)
+    }
+
+    pub async fn list_pending_requests(&self) -> Result<Vec<PendingRequest>> {
         let db = self.db.read().await;
         let mut stmt = db.prepare(
-            "SELECT peer_id FROM peer_whitelist WHERE expires_at IS NULL OR expires_at > datetime('now')"
+            "SELECT peer_id, name, public_key, requested_at FROM pending_requests ORDER BY requested_at",
         )?;
 
-        let peer_ids: Vec<String> = stmt
-            .query_map([], |row| row.get::<_, String>(0))?
+        let requests = stmt
+            .query_map([], |row| {
+                let requested_at_str: String = row.get(3)?;
+                let requested_at = chrono::DateTime::parse_from_rfc3339(&requested_at_str)
+                    .map(|dt| dt.with_timezone(&chrono::Utc))
+                    .unwrap_or_else(|_| chrono::Utc::now());
+
+                let peer_id: String = row.get(0)?;
+                let name = self
+                    .open_text("pending_requests", "name", &peer_id, row.get(1)?)
+                    .map_err(|e| at_rest::to_sql_error(1, e))?;
+                let public_key = self
+                    .open_blob("pending_requests", "public_key", &peer_id, row.get(2)?)
+                    .map_err(|e| at_rest::to_sql_error(2, e))?
+                    .unwrap_or_default();
+
+                Ok(PendingRequest {
+                    peer_id,
+                    name,
+                    public_key,
+                    requested_at,
+                })
+            })?
             .collect::<Result<Vec<_>, _>>()?;
 
-        let mut cache = self.cache.write().await;
-        cache.clear();
+        Ok(requests)
+    }
+
+    pub async fn get_pending_request(&self, peer_id: &PeerId) -> Result<Option<PendingRequest>> {
+        Ok(self
+            .list_pending_requests()
+            .await?
+            .into_iter()
+            .find(|request| request.peer_id == peer_id.to_string()))
+    }
 
-        for peer_id_str in peer_ids {
-            if let Ok(peer_id) = peer_id_str.parse::<PeerId>() {
-                cache.insert(peer_id);
+    /// Whitelist the requesting peer with the public key from its request
+    pub async fn approve_request(&self, peer_id: &PeerId) -> Result<PendingRequest> {
+        let Some(request) = self.get_pending_request(peer_id).await? else {
+            anyhow::bail!("No pending whitelist request from {}", peer_id);
+        };
+        let public_key = libp2p::identity::PublicKey::try_decode_protobuf(&request.public_key)?;
+
+        self.add_peer(peer_id, request.name.clone(), Some(&public_key), None)
+            .await?;
+        self.remove_pending_request(peer_id).await?;
+
+        Ok(request)
+    }
+
+    pub async fn reject_request(&self, peer_id: &PeerId) -> Result<()> {
+        if !self.remove_pending_request(peer_id).await? {
+            anyhow::bail!("No pending whitelist request from {}", peer_id);
+        }
+        Ok(())
+    }
+
+    async fn remove_pending_request(&self, peer_id: &PeerId) -> Result<bool> {
+        let db = self.db.write().await;
+        let removed = db.execute(
+            "DELETE FROM pending_requests WHERE peer_id = ?1",
+            params![peer_id.to_string()],
+        )?;
+        Ok(removed > 0)
+    }
+
+    /// Number of currently whitelisted peers with an active recommendation of `peer_id`
+    pub async fn count_whitelisted_recommenders(&self, peer_id: &PeerId) -> Result<u32> {
+        let now = chrono::Utc::now();
+        let mut count = 0;
+        for recommendation in self.list_recommendations(Some(peer_id)).await? {
+            if !recommendation.is_active(now) {
+                continue;
+            }
+            if let Ok(recommender_peer_id) = recommendation.recommender.parse::<PeerId>() {
+                if self.is_whitelisted(&recommender_peer_id).await? {
+                    count += 1;
+                }
             }
         }
 
-        Ok(())
+        Ok(count)
     }
 
     /// Check if a peer is trusted through direct whitelist or recommendations
+    /// meeting the trust policy
     pub async fn is_trusted_by_chain(&self, peer_id: &PeerId) -> Result<bool> {
         // 1. Check if directly whitelisted
         if self.is_whitelisted(peer_id).await? {
             return Ok(true);
         }
+        if self.trust.max_depth == 0 || self.is_revoked(peer_id).await? {
+            return Ok(false);
+        }
 
-        // 2. Check if recommended by whitelisted peers
-        let entries = self.list_peers().await?;
-        let peer_id_str = peer_id.to_string();
-
-        for entry in entries {
-            if entry.peer_id == peer_id_str && entry.recommendation_count > 0 {
-                // Check if any recommender is still whitelisted
-                for recommender_id_str in &entry.recommended_by {
-                    if let Ok(recommender_peer_id) = recommender_id_str.parse::<libp2p::PeerId>() {
-                        if self.is_whitelisted(&recommender_peer_id).await? {
-                            return Ok(true);
-                        }
-                    }
-                }
-            }
+        // 2. Follow active recommendations starting at whitelisted peers
+        let recommendations = self.list_recommendations(None).await?;
+        if !recommendations
+            .iter()
+            .any(|recommendation| recommendation.peer_id == peer_id.to_string())
+        {
+            return Ok(false);
         }
+        let whitelisted = self.whitelisted_peers().await?;
 
-        Ok(false)
+        Ok(self
+            .trust
+            .is_trusted(peer_id, &whitelisted, &recommendations, chrono::Utc::now()))
     }
 
-    /// Add a trust recommendation for a peer
+    // 有効期限内のホワイトリストのピア
+    async fn whitelisted_peers(&self) -> Result<HashSet<PeerId>> {
+        Self::query_whitelisted_peers(&*self.db.read().await)
+    }
+
+    fn query_whitelisted_peers(db: &Connection) -> Result<HashSet<PeerId>> {
+        let now = chrono::Utc::now();
+        let mut stmt = db.prepare("SELECT peer_id, expires_at FROM peer_whitelist")?;
+
+        let rows = stmt
+            .query_map([], |row| {
+                Ok((row.get::<_, String>(0)?, row.get::<_, Option<String>>(1)?))
+            })?
+            .collect::<Result<Vec<_>, _>>()?;
+
+        Ok(rows
+            .into_iter()
+            .filter(|(_, expires_at)| {
+                expires_at
+                    .as_deref()
+                    .and_then(|s| chrono::DateTime::parse_from_rfc3339(s).ok())
+                    .map_or(true, |expires_at| expires_at > now)
+            })
+            .filter_map(|(peer_id, _)| peer_id.parse().ok())
+            .collect())
+    }
+
+    /// Add a trust recommendation for a peer, or renew an earlier one by the
+    /// same recommender. The recommender must itself be trusted.
     pub async fn add_recommendation(
         &self,
         peer_id: &PeerId,
         recommender_id: &PeerId,
         name: Option<String>,
     ) -> Result<()> {
-        let peer_id_str = peer_id.to_string();
-        let recommender_str = recommender_id.to_string();
+        if peer_id == recommender_id {
+            anyhow::bail!("Peer {} cannot recommend itself", peer_id);
+        }
+        if !self.is_trusted_by_chain(recommender_id).await? {
+            anyhow::bail!("Recommender {} is not trusted", recommender_id);
+        }
+        if self.is_revoked(peer_id).await? {
+            anyhow::bail!("Peer {} has been revoked", peer_id);
+        }
 
-        // Check if recommender is whitelisted
-        if !self.is_whitelisted(recommender_id).await? {
-            anyhow::bail!("Recommender {} is not whitelisted", recommender_str);
+        let now = chrono::Utc::now().to_rfc3339();
+        let db = self.db.write().await;
+        let existing = self
+            .read_recommendations(&db)?
+            .into_iter()
+            .find(|(_, recommendation)| {
+                recommendation.peer_id == peer_id.to_string()
+                    && recommendation.recommender == recommender_id.to_string()
+            });
+
+        match existing {
+            Some((id, recommendation)) => {
+                // 取り消した推薦もやり直せる（推薦日時は有効期限の起点になる）
+                db.execute(
+                    "UPDATE recommendations SET name = ?1, recommended_at = ?2, revoked_at = NULL WHERE id = ?3",
+                    params![
+                        self.seal("recommendations", "name", &id, name.or(recommendation.name))?,
+                        now,
+                        id
+                    ],
+                )?;
+            }
+            None => {
+                let id = uuid::Uuid::new_v4().to_string();
+                db.execute(
+                    "INSERT INTO recommendations (id, peer_id, recommender, name, recommended_at) VALUES (?1, ?2, ?3, ?4, ?5)",
+                    params![
+                        id,
+                        self.seal("recommendations", "peer_id", &id, peer_id.to_string())?,
+                        self.seal(
+                            "recommendations",
+                            "recommender",
+                            &id,
+                            recommender_id.to_string()
+                        )?,
+                        self.seal("recommendations", "name", &id, name)?,
+                        now
+                    ],
+                )?;
+            }
         }
 
+        Ok(())
+    }
+
+    /// Withdraw the recommendation of `peer_id` by `recommender_id`. It is kept
+    /// for auditing but no longer counts. Returns false if there was no active
+    /// recommendation.
+    pub async fn revoke_recommendation(
+        &self,
+        peer_id: &PeerId,
+        recommender_id: &PeerId,
+    ) -> Result<bool> {
         let db = self.db.write().await;
+        let Some((id, _)) =
+            self.read_recommendations(&db)?
+                .into_iter()
+                .find(|(_, recommendation)| {
+                    recommendation.peer_id == peer_id.to_string()
+                        && recommendation.recommender == recommender_id.to_string()
+                        && recommendation.revoked_at.is_none()
+                })
+        else {
+            return Ok(false);
+        };
+
+        db.execute(
+            "UPDATE recommendations SET revoked_at = ?1 WHERE id = ?2",
+            params![chrono::Utc::now().to_rfc3339(), id],
+        )?;
+        Ok(true)
+    }
 
-        // Get existing entry or create new one
+    /// Recommendations of `peer_id`, or of every peer, oldest first. Revoked
+    /// and expired recommendations are included.
+    pub async fn list_recommendations(
+        &self,
+        peer_id: Option<&PeerId>,
+    ) -> Result<Vec<Recommendation>> {
+        let db = self.db.read().await;
+        Ok(self
+            .read_recommendations(&db)?
+            .into_iter()
+            .map(|(_, recommendation)| recommendation)
+            .filter(|recommendation| {
+                peer_id.map_or(true, |peer_id| {
+                    recommendation.peer_id == peer_id.to_string()
+                })
+            })
+            .collect())
+    }
+
+    // PeerId が暗号化されているため、推薦は全件を復号してから絞り込む
+    fn read_recommendations(&self, db: &Connection) -> Result<Vec<RecommendationRow>> {
         let mut stmt = db.prepare(
-            "SELECT recommended_by, recommendation_count FROM peer_whitelist WHERE peer_id = ?1",
+            "SELECT id, peer_id, recommender, name, recommended_at, revoked_at FROM recommendations ORDER BY recommended_at",
         )?;
 
-        let existing = stmt.query_row([&peer_id_str], |row| {
-            let recommended_by_json: String = row.get(0).unwrap_or_else(|_| "[]".to_string());
-            let recommendation_count: u32 = row.get(1).unwrap_or(0);
-            let recommended_by: Vec<String> =
-                serde_json::from_str(&recommended_by_json).unwrap_or_else(|_| Vec::new());
-            Ok((recommended_by, recommendation_count))
-        });
+        let recommendations = stmt
+            .query_map([], |row| {
+                let id: String = row.get(0)?;
+                let open = |index: usize, column: &str| {
+                    self.open_text("recommendations", column, &id, row.get(index)?)
+                        .map_err(|e| at_rest::to_sql_error(index, e))
+                };
+                let parse = |index: usize| -> rusqlite::Result<_> {
+                    Ok(row
+                        .get::<_, Option<String>>(index)?
+                        .and_then(|s| chrono::DateTime::parse_from_rfc3339(&s).ok())
+                        .map(|dt| dt.with_timezone(&chrono::Utc)))
+                };
 
-        let (mut recommended_by, mut recommendation_count) = match existing {
-            Ok((rec_by, rec_count)) => (rec_by, rec_count),
-            Err(_) => (Vec::new(), 0),
-        };
+                let recommended_at = parse(4)?.unwrap_or_else(chrono::Utc::now);
+                let recommendation = Recommendation {
+                    peer_id: open(1, "peer_id")?.unwrap_or_default(),
+                    recommender: open(2, "recommender")?.unwrap_or_default(),
+                    name: open(3, "name")?,
+                    recommended_at,
+                    expires_at: self.trust.expires_at(recommended_at),
+                    revoked_at: parse(5)?,
+                };
+                Ok((id.clone(), recommendation))
+            })?
+            .collect::<Result<Vec<_>, _>>()?;
 
-        // Add recommender if not already present
-        if !recommended_by.contains(&recommender_str) {
-            recommended_by.push(recommender_str);
-            recommendation_count += 1;
+        Ok(recommendations)
+    }
 
-            let recommended_by_json = serde_json::to_string(&recommended_by)?;
+    /// Revoke a peer: remove it from the whitelist and pending requests,
+    /// withdraw every recommendation it made or received and keep it out for
+    /// good. Peers only trusted through its recommendations lose that trust.
+    /// Returns false if the peer was already revoked.
+    pub async fn revoke_peer(&self, revocation: &SignedData<Revocation>) -> Result<bool> {
+        let peer_id = revocation.data.peer_id.parse::<PeerId>()?;
+        let peer_id_str = peer_id.to_string();
 
-            db.execute(
-                "INSERT OR REPLACE INTO peer_whitelist (peer_id, name, added_at, recommended_by, recommendation_count) VALUES (?1, ?2, ?3, ?4, ?5)",
-                params![
-                    peer_id_str,
-                    name,
-                    chrono::Utc::now().to_rfc3339(),
-                    recommended_by_json,
-                    recommendation_count
-                ],
-            )?;
+        let mut db = self.db.write().await;
+        let tx = db.transaction()?;
+        let added = tx.execute(
+            "INSERT OR IGNORE INTO revoked_peers (peer_id, revocation, revoked_at) VALUES (?1, ?2, ?3)",
+            params![
+                peer_id_str,
+                self.seal(
+                    "revoked_peers",
+                    "revocation",
+                    &peer_id_str,
+                    serde_json::to_string(revocation)?
+                )?,
+                revocation.data.revoked_at.to_rfc3339()
+            ],
+        )?;
+        if added == 0 {
+            return Ok(false);
+        }
+
+        tx.execute(
+            "DELETE FROM peer_whitelist WHERE peer_id = ?1",
+            params![peer_id_str],
+        )?;
+        tx.execute(
+            "DELETE FROM pending_requests WHERE peer_id = ?1",
+            params![peer_id_str],
+        )?;
+
+        // 失効したピアが出した推薦と受けた推薦を取り消す（監査用に行は残す）
+        let revoked_at = revocation.data.revoked_at.to_rfc3339();
+        for (id, recommendation) in self.read_recommendations(&tx)? {
+            if recommendation.revoked_at.is_none()
+                && (recommendation.peer_id == peer_id_str
+                    || recommendation.recommender == peer_id_str)
+            {
+                tx.execute(
+                    "UPDATE recommendations SET revoked_at = ?1 WHERE id = ?2",
+                    params![revoked_at, id],
+                )?;
+            }
         }
+        tx.commit()?;
+
+        let mut cache = self.cache.write().await;
+        cache.remove(&peer_id);
+
+        Ok(true)
+    }
+
+    pub async fn is_revoked(&self, peer_id: &PeerId) -> Result<bool> {
+        let db = self.db.read().await;
+        let revoked = db
+            .query_row(
+                "SELECT 1 FROM revoked_peers WHERE peer_id = ?1",
+                params![peer_id.to_string()],
+                |_| Ok(()),
+            )
+            .optional()?;
+        Ok(revoked.is_some())
+    }
+
+    /// Signed revocations, oldest first
+    pub async fn list_revocations(&self) -> Result<Vec<SignedData<Revocation>>> {
+        let db = self.db.read().await;
+        let mut stmt =
+            db.prepare("SELECT peer_id, revocation FROM revoked_peers ORDER BY revoked_at")?;
+
+        let revocations = stmt
+            .query_map([], |row| {
+                let peer_id: String = row.get(0)?;
+                let revocation = self
+                    .open_text("revoked_peers", "revocation", &peer_id, row.get(1)?)
+                    .map_err(|e| at_rest::to_sql_error(1, e))?
+                    .unwrap_or_default();
+                serde_json::from_str(&revocation).map_err(|e| at_rest::to_sql_error(1, e.into()))
+            })?
+            .collect::<Result<Vec<_>, _>>()?;
 
+        Ok(revocations)
+    }
+
+    /// Store a group key of `namespace`. Returns false if it was already known.
+    pub async fn add_group_key(&self, namespace: &str, key: &GroupKey) -> Result<bool> {
+        let db = self.db.write().await;
+        let added = db.execute(
+            "INSERT OR IGNORE INTO group_keys (namespace, key_id, epoch, key, added_at) VALUES (?1, ?2, ?3, ?4, ?5)",
+            params![
+                namespace,
+                key.id(),
+                key.epoch() as i64,
+                self.codec.seal(
+                    &group_key_aad(namespace, &key.id()),
+                    SqlValue::Blob(key.as_bytes().to_vec())
+                )?,
+                chrono::Utc::now().to_rfc3339()
+            ],
+        )?;
+        Ok(added > 0)
+    }
+
+    /// Limit who members pass a rotated group key on to: the `recipients` the
+    /// rotating member wrapped it for
+    pub async fn restrict_group_key(
+        &self,
+        namespace: &str,
+        key_id: &str,
+        recipients: &[String],
+    ) -> Result<()> {
+        let db = self.db.write().await;
+        db.execute(
+            "UPDATE group_keys SET recipients = ?1 WHERE namespace = ?2 AND key_id = ?3",
+            params![serde_json::to_string(recipients)?, namespace, key_id],
+        )?;
         Ok(())
     }
+
+    /// Recipients set by `restrict_group_key`, `None` if the key is unrestricted
+    pub async fn group_key_recipients(
+        &self,
+        namespace: &str,
+        key_id: &str,
+    ) -> Result<Option<Vec<String>>> {
+        let db = self.db.read().await;
+        let recipients: Option<String> = db
+            .query_row(
+                "SELECT recipients FROM group_keys WHERE namespace = ?1 AND key_id = ?2",
+                params![namespace, key_id],
+                |row| row.get(0),
+            )
+            .optional()?
+            .flatten();
+
+        Ok(recipients
+            .map(|recipients| serde_json::from_str(&recipients))
+            .transpose()?)
+    }
+
+    /// Whether a group key may be passed on to `peer_id`: the key is
+    /// unrestricted, lists the peer, or the peer was whitelisted after the key
+    /// arrived. Peers removed elsewhere but still whitelisted here were added
+    /// before the rotation and are refused.
+    pub async fn may_receive_group_key(
+        &self,
+        namespace: &str,
+        key_id: &str,
+        peer_id: &PeerId,
+    ) -> Result<bool> {
+        let Some(recipients) = self.group_key_recipients(namespace, key_id).await? else {
+            return Ok(true);
+        };
+        if recipients.contains(&peer_id.to_string()) {
+            return Ok(true);
+        }
+
+        let db = self.db.read().await;
+        let added_after: Option<bool> = db
+            .query_row(
+                "SELECT w.added_at > k.added_at FROM peer_whitelist w, group_keys k
+                 WHERE w.peer_id = ?1 AND k.namespace = ?2 AND k.key_id = ?3",
+                params![peer_id.to_string(), namespace, key_id],
+                |row| row.get(0),
+            )
+            .optional()?;
+
+        Ok(added_after.unwrap_or(false))
+    }
+
+    pub async fn get_group_key(&self, namespace: &str, key_id: &str) -> Result<Option<GroupKey>> {
+        let db = self.db.read().await;
+        let key = db
+            .query_row(
+                "SELECT epoch, key_id, key FROM group_keys WHERE namespace = ?1 AND key_id = ?2",
+                params![namespace, key_id],
+                group_key_row,
+            )
+            .optional()?;
+
+        key.map(|row| self.read_group_key(namespace, row))
+            .transpose()
+    }
+
+    /// The key new values of `namespace` are encrypted with: the one with the
+    /// highest epoch, ties broken by key id so every peer picks the same key
+    pub async fn current_group_key(&self, namespace: &str) -> Result<Option<GroupKey>> {
+        let db = self.db.read().await;
+        let key = db
+            .query_row(
+                "SELECT epoch, key_id, key FROM group_keys WHERE namespace = ?1 ORDER BY epoch DESC, key_id DESC LIMIT 1",
+                params![namespace],
+                group_key_row,
+            )
+            .optional()?;
+
+        key.map(|row| self.read_group_key(namespace, row))
+            .transpose()
+    }
+
+    fn read_group_key(
+        &self,
+        namespace: &str,
+        (epoch, key_id, key): GroupKeyRow,
+    ) -> Result<GroupKey> {
+        let key = self
+            .codec
+            .open_blob(&group_key_aad(namespace, &key_id), key)?
+            .unwrap_or_default();
+        GroupKey::from_bytes(epoch as u64, &key)
+    }
+}
+
+type GroupKeyRow = (i64, String, SqlValue);
+
+/// Row id and decrypted contents of a recommendation
+type RecommendationRow = (String, Recommendation);
+
+fn group_key_row(row: &rusqlite::Row) -> rusqlite::Result<GroupKeyRow> {
+    Ok((row.get(0)?, row.get(1)?, row.get(2)?))
+}
+
+fn group_key_aad(namespace: &str, key_id: &str) -> Vec<u8> {
+    at_rest::column_aad("group_keys", "key", &format!("{namespace}\0{key_id}"))
 }
 
 #[cfg(test)]
@@ -352,7 +1099,7 @@ mod tests {
     async fn test_whitelist_add_remove() {
         let dir = tempdir().unwrap();
         let db_path = dir.path().join("whitelist.db");
-        let whitelist = PeerWhitelist::new(&db_path).unwrap();
+        let whitelist = PeerWhitelist::open(&db_path, None).unwrap();
 
         let peer_id = PeerId::random();
 
@@ -366,13 +1113,24 @@ mod tests {
         // Remove peer
         whitelist.remove_peer(&peer_id).await.unwrap();
         assert!(!whitelist.is_whitelisted(&peer_id).await.unwrap());
+
+        // 別のプロセス（whitelist remove）による削除は再読み込みで検出する
+        let running = PeerWhitelist::open(&db_path, None).unwrap();
+        whitelist
+            .add_peer(&peer_id, None, None, None)
+            .await
+            .unwrap();
+        assert_eq!(running.reload_cache().await.unwrap(), vec![]);
+        whitelist.remove_peer(&peer_id).await.unwrap();
+        assert_eq!(running.reload_cache().await.unwrap(), vec![peer_id]);
+        assert!(!running.is_whitelisted(&peer_id).await.unwrap());
     }
 
     #[tokio::test]
     async fn test_whitelist_expiration() {
         let dir = tempdir().unwrap();
         let db_path = dir.path().join("whitelist.db");
-        let whitelist = PeerWhitelist::new(&db_path).unwrap();
+        let whitelist = PeerWhitelist::open(&db_path, None).unwrap();
 
         let peer_id = PeerId::random();
         let expires_at = chrono::Utc::now() - chrono::Duration::hours(1); // Already expired
@@ -388,7 +1146,7 @@ mod tests {
     async fn test_whitelist_list() {
         let dir = tempdir().unwrap();
         let db_path = dir.path().join("whitelist.db");
-        let whitelist = PeerWhitelist::new(&db_path).unwrap();
+        let whitelist = PeerWhitelist::open(&db_path, None).unwrap();
 
         let peer1 = PeerId::random();
         let peer2 = PeerId::random();
@@ -405,4 +1163,462 @@ mod tests {
         let entries = whitelist.list_peers().await.unwrap();
         assert_eq!(entries.len(), 2);
     }
+
+    #[tokio::test]
+    async fn test_pending_request_approval() {
+        let dir = tempdir().unwrap();
+        let db_path = dir.path().join("whitelist.db");
+        let whitelist = PeerWhitelist::open(&db_path, None).unwrap();
+
+        let keypair = libp2p::identity::Keypair::generate_ed25519();
+        let peer_id = keypair.public().to_peer_id();
+
+        assert!(whitelist
+            .add_pending_request(&peer_id, Some("Laptop".to_string()), &keypair.public(), 10)
+            .await
+            .unwrap());
+        assert!(!whitelist.is_whitelisted(&peer_id).await.unwrap());
+        assert_eq!(whitelist.list_pending_requests().await.unwrap().len(), 1);
+
+        let request = whitelist.approve_request(&peer_id).await.unwrap();
+        assert_eq!(request.name.as_deref(), Some("Laptop"));
+        assert!(whitelist.is_whitelisted(&peer_id).await.unwrap());
+        assert_eq!(
+            whitelist.get_public_key(&peer_id).await.unwrap(),
+            Some(keypair.public())
+        );
+        assert!(whitelist.list_pending_requests().await.unwrap().is_empty());
+
+        // 承認済みのリクエストは二度処理できない
+        assert!(whitelist.approve_request(&peer_id).await.is_err());
+    }
+
+    #[tokio::test]
+    async fn test_pending_request_rejection_and_limit() {
+        let dir = tempdir().unwrap();
+        let db_path = dir.path().join("whitelist.db");
+        let whitelist = PeerWhitelist::open(&db_path, None).unwrap();
+
+        let first = libp2p::identity::Keypair::generate_ed25519();
+        let second = libp2p::identity::Keypair::generate_ed25519();
+        let first_id = first.public().to_peer_id();
+
+        assert!(whitelist
+            .add_pending_request(&first_id, None, &first.public(), 1)
+            .await
+            .unwrap());
+        // 同じピアからの再送は置き換え、別のピアは上限で拒否する
+        assert!(whitelist
+            .add_pending_request(&first_id, None, &first.public(), 1)
+            .await
+            .unwrap());
+        assert!(!whitelist
+            .add_pending_request(&second.public().to_peer_id(), None, &second.public(), 1)
+            .await
+            .unwrap());
+
+        whitelist.reject_request(&first_id).await.unwrap();
+        assert!(!whitelist.is_whitelisted(&first_id).await.unwrap());
+        assert!(whitelist.list_pending_requests().await.unwrap().is_empty());
+        assert!(whitelist.reject_request(&first_id).await.is_err());
+    }
+
+    #[tokio::test]
+    async fn test_encryption_at_rest() {
+        let dir = tempdir().unwrap();
+        let db_path = dir.path().join("whitelist.db");
+        let secret = Secret::generate();
+        let keypair = libp2p::identity::Keypair::generate_ed25519();
+        let peer_id = keypair.public().to_peer_id();
+        let recommender = PeerId::random();
+        let group_key = GroupKey::generate(1);
+
+        {
+            let whitelist = PeerWhitelist::open(&db_path, Some(&secret)).unwrap();
+            whitelist
+                .add_peer(
+                    &peer_id,
+                    Some("hidden-name".to_string()),
+                    Some(&keypair.public()),
+                    None,
+                )
+                .await
+                .unwrap();
+            whitelist
+                .add_peer(&recommender, None, None, None)
+                .await
+                .unwrap();
+            let candidate = PeerId::random();
+            whitelist
+                .add_recommendation(&candidate, &recommender, None)
+                .await
+                .unwrap();
+            whitelist
+                .add_group_key("secrets", &group_key)
+                .await
+                .unwrap();
+        }
+
+        let raw = std::fs::read(&db_path).unwrap();
+        let count = |needle: &[u8]| {
+            raw.windows(needle.len())
+                .filter(|window| *window == needle)
+                .count()
+        };
+        assert_eq!(count(b"hidden-name"), 0);
+        assert_eq!(count(&keypair.public().encode_protobuf()), 0);
+        assert_eq!(count(group_key.as_bytes()), 0);
+        // 推薦の関係（信頼グラフ）も読めない
+        let recommendations: Vec<(SqlValue, SqlValue)> = Connection::open(&db_path)
+            .unwrap()
+            .prepare("SELECT peer_id, recommender FROM recommendations")
+            .unwrap()
+            .query_map([], |row| Ok((row.get(0)?, row.get(1)?)))
+            .unwrap()
+            .collect::<Result<_, _>>()
+            .unwrap();
+        assert_eq!(recommendations.len(), 1);
+        assert!(recommendations.iter().all(|(peer_id, recommender)| {
+            matches!(peer_id, SqlValue::Blob(_)) && matches!(recommender, SqlValue::Blob(_))
+        }));
+
+        assert!(PeerWhitelist::open(&db_path, None).is_err());
+
+        let whitelist = PeerWhitelist::open(&db_path, Some(&secret)).unwrap();
+        assert_eq!(
+            whitelist.get_public_key(&peer_id).await.unwrap(),
+            Some(keypair.public())
+        );
+        let entries = whitelist.list_peers().await.unwrap();
+        let entry = entries
+            .iter()
+            .find(|entry| entry.peer_id == peer_id.to_string())
+            .unwrap();
+        assert_eq!(entry.name, Some("hidden-name".to_string()));
+        let recommendations = whitelist.list_recommendations(None).await.unwrap();
+        assert_eq!(recommendations[0].recommender, recommender.to_string());
+        assert_eq!(
+            whitelist.current_group_key("secrets").await.unwrap(),
+            Some(group_key)
+        );
+    }
+
+    #[tokio::test]
+    async fn test_verification_key() {
+        let dir = tempdir().unwrap();
+        let whitelist = PeerWhitelist::open(&dir.path().join("whitelist.db"), None).unwrap();
+
+        // Ed25519 の PeerId は公開鍵を含むので、保存されていなくても検証できる
+        let keypair = libp2p::identity::Keypair::generate_ed25519();
+        let peer_id = keypair.public().to_peer_id();
+        assert_eq!(
+            whitelist.verification_key(&peer_id).await.unwrap(),
+            Some(keypair.public())
+        );
+        whitelist
+            .add_peer(&peer_id, None, Some(&keypair.public()), None)
+            .await
+            .unwrap();
+        assert_eq!(
+            whitelist.verification_key(&peer_id).await.unwrap(),
+            Some(keypair.public())
+        );
+
+        // 鍵のハッシュだけを持つ PeerId は鍵を保存するまで検証できない
+        assert!(whitelist
+            .verification_key(&PeerId::random())
+            .await
+            .unwrap()
+            .is_none());
+    }
+
+    #[tokio::test]
+    async fn test_group_keys() {
+        let dir = tempdir().unwrap();
+        let whitelist = PeerWhitelist::open(&dir.path().join("whitelist.db"), None).unwrap();
+
+        assert!(whitelist
+            .current_group_key("secrets")
+            .await
+            .unwrap()
+            .is_none());
+
+        let first = GroupKey::generate(1);
+        let second = GroupKey::generate(2);
+        assert!(whitelist.add_group_key("secrets", &second).await.unwrap());
+        assert!(whitelist.add_group_key("secrets", &first).await.unwrap());
+        assert!(!whitelist.add_group_key("secrets", &first).await.unwrap());
+
+        assert_eq!(
+            whitelist.current_group_key("secrets").await.unwrap(),
+            Some(second)
+        );
+        assert_eq!(
+            whitelist
+                .get_group_key("secrets", &first.id())
+                .await
+                .unwrap(),
+            Some(first.clone())
+        );
+        assert!(whitelist
+            .get_group_key("config", &first.id())
+            .await
+            .unwrap()
+            .is_none());
+    }
+
+    #[tokio::test]
+    async fn test_revoke_peer() {
+        let dir = tempdir().unwrap();
+        let whitelist = PeerWhitelist::open(&dir.path().join("whitelist.db"), None).unwrap();
+
+        let admin = libp2p::identity::Keypair::generate_ed25519();
+        let compromised = PeerId::random();
+        let trusted = PeerId::random();
+        let recommended = PeerId::random();
+        whitelist
+            .add_peer(&compromised, None, None, None)
+            .await
+            .unwrap();
+        whitelist
+            .add_peer(&trusted, None, None, None)
+            .await
+            .unwrap();
+
+        // 直接追加されたピアと、推薦だけで追加されたピア
+        whitelist
+            .add_recommendation(&trusted, &compromised, None)
+            .await
+            .unwrap();
+        whitelist
+            .add_recommendation(&recommended, &compromised, None)
+            .await
+            .unwrap();
+        assert!(whitelist.is_trusted_by_chain(&recommended).await.unwrap());
+
+        let revocation = SignedData::new(
+            Revocation {
+                peer_id: compromised.to_string(),
+                reason: Some("Stolen laptop".to_string()),
+                revoked_at: chrono::Utc::now(),
+            },
+            &admin,
+        )
+        .unwrap();
+        assert!(whitelist.revoke_peer(&revocation).await.unwrap());
+        assert!(!whitelist.revoke_peer(&revocation).await.unwrap());
+
+        assert!(whitelist.is_revoked(&compromised).await.unwrap());
+        assert!(!whitelist.is_whitelisted(&compromised).await.unwrap());
+        assert!(!whitelist.is_trusted_by_chain(&recommended).await.unwrap());
+        assert!(whitelist.is_whitelisted(&trusted).await.unwrap());
+        let entries = whitelist.list_peers().await.unwrap();
+        assert_eq!(entries.len(), 1);
+        assert!(entries[0].recommended_by.is_empty());
+        assert_eq!(entries[0].recommendation_count, 1);
+        // 取り消された推薦も監査用に残る
+        let recommendations = whitelist.list_recommendations(None).await.unwrap();
+        assert_eq!(recommendations.len(), 2);
+        assert!(recommendations
+            .iter()
+            .all(|recommendation| recommendation.revoked_at.is_some()));
+
+        // 失効したピアは再び追加も推薦もできない
+        assert!(whitelist
+            .add_peer(&compromised, None, None, None)
+            .await
+            .is_err());
+        assert!(whitelist
+            .add_recommendation(&compromised, &trusted, None)
+            .await
+            .is_err());
+
+        let revocations = whitelist.list_revocations().await.unwrap();
+        assert_eq!(revocations.len(), 1);
+        assert_eq!(revocations[0].data.reason.as_deref(), Some("Stolen laptop"));
+        assert!(revocations[0]
+            .verify_with_public_key(&admin.public())
+            .unwrap());
+    }
+
+    #[tokio::test]
+    async fn test_recommendation_policy() {
+        let dir = tempdir().unwrap();
+        let trust = TrustConfig {
+            min_recommenders: 2,
+            max_depth: 2,
+            ..Default::default()
+        };
+        let whitelist = PeerWhitelist::open(&dir.path().join("whitelist.db"), None)
+            .unwrap()
+            .with_trust(trust);
+
+        let first = PeerId::random();
+        let second = PeerId::random();
+        let candidate = PeerId::random();
+        for peer_id in [&first, &second] {
+            whitelist.add_peer(peer_id, None, None, None).await.unwrap();
+        }
+
+        // 推薦者が 1 人では足りず、推薦だけではホワイトリストに載らない
+        whitelist
+            .add_recommendation(&candidate, &first, Some("candidate".to_string()))
+            .await
+            .unwrap();
+        assert!(!whitelist.is_trusted_by_chain(&candidate).await.unwrap());
+        whitelist
+            .add_recommendation(&candidate, &first, None)
+            .await
+            .unwrap();
+        assert_eq!(
+            whitelist
+                .count_whitelisted_recommenders(&candidate)
+                .await
+                .unwrap(),
+            1
+        );
+        whitelist
+            .add_recommendation(&candidate, &second, None)
+            .await
+            .unwrap();
+        assert!(whitelist.is_trusted_by_chain(&candidate).await.unwrap());
+        assert!(!whitelist.is_whitelisted(&candidate).await.unwrap());
+
+        let recommendations = whitelist
+            .list_recommendations(Some(&candidate))
+            .await
+            .unwrap();
+        assert_eq!(recommendations.len(), 2);
+        assert_eq!(recommendations[0].name.as_deref(), Some("candidate"));
+
+        // 推薦を取り消すと信頼も外れる
+        assert!(whitelist
+            .revoke_recommendation(&candidate, &second)
+            .await
+            .unwrap());
+        assert!(!whitelist
+            .revoke_recommendation(&candidate, &second)
+            .await
+            .unwrap());
+        assert!(!whitelist.is_trusted_by_chain(&candidate).await.unwrap());
+        assert_eq!(whitelist.list_recommendations(None).await.unwrap().len(), 2);
+
+        // 信頼されていないピアは推薦できない
+        assert!(whitelist
+            .add_recommendation(&PeerId::random(), &candidate, None)
+            .await
+            .is_err());
+    }
+
+    #[tokio::test]
+    async fn test_migrate_recommendations() {
+        let dir = tempdir().unwrap();
+        let db_path = dir.path().join("whitelist.db");
+        let recommender = PeerId::random();
+        let direct = PeerId::random();
+        let recommended = PeerId::random();
+        drop(PeerWhitelist::open(&db_path, None).unwrap());
+
+        // 以前の形式では推薦者の一覧を peer_whitelist に持っていた
+        let db = Connection::open(&db_path).unwrap();
+        let now = chrono::Utc::now().to_rfc3339();
+        let public_key = libp2p::identity::Keypair::generate_ed25519()
+            .public()
+            .encode_protobuf();
+        for (peer_id, recommended_by, via_recommendation) in [
+            (&recommender, vec![], false),
+            (&direct, vec![recommender.to_string()], false),
+            (&recommended, vec![recommender.to_string()], true),
+        ] {
+            db.execute(
+                "INSERT INTO peer_whitelist (peer_id, public_key, added_at, recommended_by, recommendation_count, via_recommendation) VALUES (?1, ?2, ?3, ?4, ?5, ?6)",
+                params![
+                    peer_id.to_string(),
+                    public_key,
+                    now,
+                    serde_json::to_string(&recommended_by).unwrap(),
+                    recommended_by.len() as u32,
+                    via_recommendation
+                ],
+            )
+            .unwrap();
+        }
+        drop(db);
+
+        let whitelist = PeerWhitelist::open(&db_path, None).unwrap();
+        let recommendations = whitelist.list_recommendations(None).await.unwrap();
+        assert_eq!(recommendations.len(), 2);
+        assert!(whitelist.is_whitelisted(&direct).await.unwrap());
+        assert!(!whitelist.is_whitelisted(&recommended).await.unwrap());
+        assert!(whitelist.is_trusted_by_chain(&recommended).await.unwrap());
+
+        // 2 回目以降は何もしない
+        drop(whitelist);
+        let whitelist = PeerWhitelist::open(&db_path, None).unwrap();
+        assert_eq!(whitelist.list_recommendations(None).await.unwrap().len(), 2);
+    }
+
+    #[tokio::test]
+    async fn test_migrate_recommendations_from_baseline_schema() {
+        let dir = tempdir().unwrap();
+        let db_path = dir.path().join("whitelist.db");
+        let recommender = PeerId::random();
+        let direct = PeerId::random();
+        let recommended = PeerId::random();
+
+        // via_recommendation 列がない初期のスキーマ。推薦は公開鍵なしの行として追加されていた
+        let db = Connection::open(&db_path).unwrap();
+        db.execute(
+            "CREATE TABLE peer_whitelist (
+                peer_id TEXT PRIMARY KEY,
+                name TEXT,
+                public_key BLOB,
+                added_at TEXT NOT NULL,
+                expires_at TEXT,
+                recommended_by TEXT DEFAULT '[]',
+                recommendation_count INTEGER DEFAULT 0
+            )",
+            [],
+        )
+        .unwrap();
+        let now = chrono::Utc::now().to_rfc3339();
+        let public_key = libp2p::identity::Keypair::generate_ed25519()
+            .public()
+            .encode_protobuf();
+        for peer_id in [&recommender, &direct] {
+            db.execute(
+                "INSERT INTO peer_whitelist (peer_id, name, public_key, added_at, expires_at, recommended_by, recommendation_count) VALUES (?1, NULL, ?2, ?3, NULL, '[]', 0)",
+                params![peer_id.to_string(), public_key, now],
+            )
+            .unwrap();
+        }
+        db.execute(
+            "INSERT OR REPLACE INTO peer_whitelist (peer_id, name, added_at, recommended_by, recommendation_count) VALUES (?1, ?2, ?3, ?4, ?5)",
+            params![
+                recommended.to_string(),
+                "recommended",
+                now,
+                serde_json::to_string(&[recommender.to_string()]).unwrap(),
+                1
+            ],
+        )
+        .unwrap();
+        drop(db);
+
+        let whitelist = PeerWhitelist::open(&db_path, None).unwrap();
+        assert!(whitelist.is_whitelisted(&recommender).await.unwrap());
+        assert!(whitelist.is_whitelisted(&direct).await.unwrap());
+        // 推薦だけで追加されたピアは信頼ポリシーを通さなければ信頼しない
+        assert!(!whitelist.is_whitelisted(&recommended).await.unwrap());
+        assert_eq!(whitelist.list_recommendations(None).await.unwrap().len(), 1);
+
+        let strict = PeerWhitelist::open(&db_path, None)
+            .unwrap()
+            .with_trust(TrustConfig {
+                min_recommenders: 2,
+                ..TrustConfig::default()
+            });
+        assert!(!strict.is_trusted_by_chain(&recommended).await.unwrap());
+        assert!(whitelist.is_trusted_by_chain(&recommended).await.unwrap());
+    }
 }
@@ -1,5 +1,6 @@
 use libp2p::identity;
 use p2p_sync::crypto::{SignedData, SignedSyncMessage, SyncOperation};
+use p2p_sync::hlc::HybridClock;
 use serde::{Deserialize, Serialize};
 
 #[derive(Debug, Clone, Serialize, Deserialize, PartialEq)]
@@ -38,7 +39,7 @@ fn test_signed_sync_message() {
     let sync_msg = SignedSyncMessage {
         key: "test_key".to_string(),
         value: Some("test_value".to_string()),
-        timestamp: chrono::Utc::now(),
+        timestamp: HybridClock::new(keypair.public().to_peer_id().to_string()).now(),
         operation: SyncOperation::Put,
     };
 
@@ -1,4 +1,4 @@
-use p2p_sync::{security::SecurityConfig, storage::Storage};
+use p2p_sync::{namespace::DEFAULT_NAMESPACE as NS, security::SecurityConfig, storage::Storage};
 use tempfile::tempdir;
 
 #[tokio::test]
@@ -9,20 +9,27 @@ async fn test_storage_operations() {
     let storage = Storage::new(&db_path).expect("Failed to create storage");
 
     // テスト: put and get
-    storage.put("key1", "value1").expect("Failed to put");
-    let value = storage.get("key1").expect("Failed to get");
+    storage.put(NS, "key1", "value1").expect("Failed to put");
+    let value = storage.get(NS, "key1").expect("Failed to get");
     assert_eq!(value, Some("value1".to_string()));
 
     // テスト: non-existent key
-    let value = storage.get("non_existent").expect("Failed to get");
+    let value = storage.get(NS, "non_existent").expect("Failed to get");
     assert_eq!(value, None);
 
     // テスト: list all items
-    storage.put("key2", "value2").expect("Failed to put");
-    let items = storage.list().expect("Failed to list");
+    storage.put(NS, "key2", "value2").expect("Failed to put");
+    let items = storage.list(NS).expect("Failed to list");
     assert_eq!(items.len(), 2);
     assert!(items.contains(&("key1".to_string(), "value1".to_string())));
     assert!(items.contains(&("key2".to_string(), "value2".to_string())));
+
+    // テスト: namespaces are separate partitions
+    storage
+        .put("secrets", "key1", "hidden")
+        .expect("Failed to put");
+    assert_eq!(storage.get(NS, "key1").unwrap(), Some("value1".to_string()));
+    assert_eq!(storage.list("secrets").unwrap().len(), 1);
 }
 
 #[test]
@@ -67,6 +74,102 @@ fn test_input_validation() {
     assert!(validate_value(&"x".repeat(2000), 1024).is_err()); // too long
 }
 
+#[test]
+fn test_limits_follow_security_config() {
+    use p2p_sync::hlc::HlcTimestamp;
+    use p2p_sync::security::Rejection;
+    use p2p_sync::sync::SyncMessage;
+
+    let config = SecurityConfig {
+        max_message_size: 2048,
+        max_key_length: 8,
+        max_value_length: 16,
+        ..Default::default()
+    };
+    let put = |key: &str, value: &str| SyncMessage::Put {
+        key: key.to_string(),
+        value: value.to_string(),
+        timestamp: HlcTimestamp::new(1, 0, "peer"),
+    };
+
+    assert_eq!(config.check_sync_message(&put("key", "value")), Ok(()));
+    assert_eq!(
+        config.check_sync_message(&put("long_key_", "value")),
+        Err(Rejection::KeyTooLong { length: 9, max: 8 })
+    );
+    assert_eq!(
+        config.check_sync_message(&put("key", &"x".repeat(17))),
+        Err(Rejection::ValueTooLong {
+            length: 17,
+            max: 16
+        })
+    );
+    assert_eq!(
+        config.check_sync_message(&put("../key", "value")),
+        Err(Rejection::UnsafeKeyPath)
+    );
+    assert_eq!(
+        config.check_sync_message(&SyncMessage::PutBytes {
+            key: "key".to_string(),
+            value: vec![0; 17],
+            timestamp: HlcTimestamp::new(1, 0, "peer"),
+        }),
+        Err(Rejection::ValueTooLong {
+            length: 17,
+            max: 16
+        })
+    );
+
+    assert_eq!(config.check_message_size(2048), Ok(()));
+    assert_eq!(
+        config.check_message_size(2049),
+        Err(Rejection::MessageTooLarge {
+            size: 2049,
+            max: 2048
+        })
+    );
+    assert!(config.gossipsub_max_transmit_size() > config.max_message_size);
+}
+
+#[test]
+fn test_file_manifests_are_validated() {
+    use p2p_sync::hlc::HlcTimestamp;
+    use p2p_sync::security::Rejection;
+    use p2p_sync::sync::SyncMessage;
+
+    let config = SecurityConfig {
+        max_object_size: 1024,
+        ..Default::default()
+    };
+    let put = |key: &str, value: &str| SyncMessage::Put {
+        key: key.to_string(),
+        value: value.to_string(),
+        timestamp: HlcTimestamp::new(1, 0, "peer"),
+    };
+
+    // ディレクトリ同期のキーの値はマニフェストとして検証する
+    let huge = format!(r#"{{"size":{},"chunks":[]}}"#, u64::MAX);
+    assert_eq!(
+        config.check_sync_message(&put("file:a.txt", &huge)),
+        Err(Rejection::ObjectTooLarge {
+            size: u64::MAX,
+            max: 1024
+        })
+    );
+    assert!(matches!(
+        config.check_sync_message(&put("file:a.txt", "not a manifest")),
+        Err(Rejection::InvalidManifest(_))
+    ));
+    assert!(config
+        .check_entry("file:a.txt", Some(&huge.as_str().into()))
+        .is_err());
+    assert_eq!(config.check_sync_message(&put("a.txt", &huge)), Ok(()));
+    assert_eq!(
+        config.check_sync_message(&put("file:empty", r#"{"size":0,"chunks":[]}"#)),
+        Ok(())
+    );
+}
+
 #[tokio::test]
 async fn test_rate_limiter() {
     use libp2p::PeerId;
@@ -100,6 +203,8 @@ async fn test_access_control() {
     // Test blocked peer
     let mut config = SecurityConfig::default();
     config.blocked_peers.insert(peer_str.clone());
+    config.blocked_peers.insert("not-a-peer-id".to_string());
+    assert_eq!(config.blocked_peer_ids(), vec![peer_id]);
 
     let access_control = AccessControl::new(config);
     assert!(access_control.check_peer_allowed(&peer_id).await.is_err());
@@ -113,3 +218,71 @@ async fn test_access_control() {
     let access_control = AccessControl::new(config);
     assert!(access_control.check_peer_allowed(&peer_id).await.is_ok());
 }
+
+#[tokio::test]
+async fn test_security_config_reload() {
+    use libp2p::PeerId;
+    use p2p_sync::security::{AccessControl, RateLimiter, SecurityConfig};
+
+    let peer_id = PeerId::random();
+    let access_control = AccessControl::new(SecurityConfig::default());
+    assert!(access_control.check_peer_allowed(&peer_id).await.is_ok());
+
+    // 再読み込みでブロックしたピアは次のチェックから拒否される
+    let mut config = SecurityConfig::default();
+    config.blocked_peers.insert(peer_id.to_string());
+    access_control.set_config(config).await;
+    assert!(access_control.check_peer_allowed(&peer_id).await.is_err());
+
+    let rate_limiter = RateLimiter::new(SecurityConfig {
+        rate_limit_burst: 1,
+        ..Default::default()
+    });
+    assert!(rate_limiter.check_rate_limit(&peer_id).await.is_ok());
+    assert!(rate_limiter.check_rate_limit(&peer_id).await.is_err());
+
+    rate_limiter
+        .set_config(SecurityConfig {
+            rate_limit_burst: 5,
+            ..Default::default()
+        })
+        .await;
+    assert!(rate_limiter.check_rate_limit(&peer_id).await.is_ok());
+}
+
+#[tokio::test]
+async fn test_rejections_are_counted() {
+    use libp2p::PeerId;
+    use p2p_sync::metrics::{Metrics, ReasonLabels};
+    use p2p_sync::security::{AccessControl, RateLimiter, SecurityConfig};
+
+    let metrics = Metrics::default();
+    let peer_id = PeerId::random();
+
+    let config = SecurityConfig {
+        rate_limit_burst: 1,
+        ..Default::default()
+    };
+    let rate_limiter = RateLimiter::new(config).with_metrics(metrics.clone());
+    rate_limiter.check_rate_limit(&peer_id).await.unwrap();
+    assert!(rate_limiter.check_rate_limit(&peer_id).await.is_err());
+
+    let mut config = SecurityConfig::default();
+    config.blocked_peers.insert(peer_id.to_string());
+    let access_control = AccessControl::new(config).with_metrics(metrics.clone());
+    assert!(access_control.check_peer_allowed(&peer_id).await.is_err());
+
+    let reason = |reason| ReasonLabels { reason };
+    assert_eq!(
+        metrics.rate_limited.get_or_create(&reason("burst")).get(),
+        1
+    );
+    assert_eq!(metrics.rate_limited.get_or_create(&reason("rate")).get(), 0);
+    assert_eq!(
+        metrics
+            .access_denied
+            .get_or_create(&reason("blocked"))
+            .get(),
+        1
+    );
+}